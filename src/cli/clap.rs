@@ -51,6 +51,12 @@ pub enum CliCommand {
     // Stop,
     /// Display the redundancy status for all files
     RedundancyStatus(IdentifyPodArgs),
+    /// Let a peer waiting for approval join the network (`demand` access mode)
+    Approve(ApproveArgs),
+    /// Keep a file or folder available offline on this pod
+    Pin(PinArgs),
+    /// Stop keeping a pinned file or folder available offline
+    Unpin(PinArgs),
 }
 
 fn canonicalize(path: PathBuf) -> std::io::Result<PathBuf> {
@@ -127,6 +133,14 @@ pub struct GetHostsArgs {
     pub path: PathBuf,
 }
 
+#[derive(Debug, Args, Clone)]
+#[command(about, long_about = None)]
+pub struct PinArgs {
+    /// Path of the file or folder
+    #[arg(required = true, value_parser=parse_canonicalize)]
+    pub path: PathBuf,
+}
+
 #[derive(Debug, Args, Clone)]
 #[command(about, long_about = None)]
 pub struct NewArgs {
@@ -136,7 +150,7 @@ pub struct NewArgs {
     /// Mount point to create the pod in. By default creates a mount point in the working directory with the name of the pod
     #[arg(long = "mount", short, value_parser=parse_canonicalize_non_existant)]
     pub mountpoint: Option<PathBuf>,
-    /// Network to join
+    /// Network to join, as {ip}:{port} for websocket or as a full multiaddr (/ip4/{ip}/udp/{port}/quic-v1)
     #[arg(long, short)]
     pub url: Option<String>,
     /// Ip address this Pod listen [default: 0.0.0.0]
@@ -151,6 +165,9 @@ pub struct NewArgs {
     /// Allow other users to access the mounted pod
     #[arg(short, long, default_value_t = false)]
     pub allow_other_users: bool,
+    /// Pre-shared key of a private network (64 hexadecimal characters), only peers with the same key can connect
+    #[arg(long)]
+    pub key: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ValueEnum, TS)]
@@ -170,6 +187,15 @@ pub enum Mode {
     Take,
 }
 
+#[derive(Debug, Args, Clone)]
+#[command(about, long_about = None)]
+pub struct ApproveArgs {
+    /// PeerId or nickname of the peer to approve (listed by `inspect`)
+    pub peer: String,
+    #[clap(flatten)]
+    pub group: IdentifyPodGroup,
+}
+
 // Structure RemoveArgs modifiée
 #[derive(Debug, Args, Clone)]
 #[command(about, long_about = None)]
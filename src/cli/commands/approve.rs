@@ -0,0 +1,37 @@
+use interprocess::local_socket::tokio::Stream;
+use std::io;
+
+use crate::{
+    cli::connection::{recieve_answer, send_command},
+    cli::ApproveArgs,
+    ipc::answers::ApproveAnswer,
+    ipc::commands::{ApproveRequest, Command, PodId},
+};
+
+pub async fn approve(args: ApproveArgs, mut stream: Stream) -> io::Result<String> {
+    let pod = PodId::from(args.group);
+
+    send_command(
+        Command::Approve(ApproveRequest {
+            pod,
+            peer: args.peer.clone(),
+        }),
+        &mut stream,
+    )
+    .await?;
+    match recieve_answer::<ApproveAnswer>(&mut stream).await? {
+        ApproveAnswer::Success(peer_id) => Ok(format!("Peer '{peer_id}' approved.")),
+        ApproveAnswer::PodNotFound => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "The given pod couldn't be found.",
+        )),
+        ApproveAnswer::PeerNotPending => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No peer '{}' is waiting for approval.", args.peer),
+        )),
+        ApproveAnswer::ApproveFailed(err) => Err(io::Error::new(
+            err.kind,
+            format!("The peer couldn't be approved:\n{}", err.error),
+        )),
+    }
+}
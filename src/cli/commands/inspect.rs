@@ -9,7 +9,7 @@ use crate::{
     ipc::commands::{Command, PodId},
 };
 
-fn display_peers(peers: Vec<ipc::PeerInfo>) -> String {
+fn display_peers<Peer: std::fmt::Display>(peers: Vec<Peer>) -> String {
     if peers.is_empty() {
         "[ ]".to_string()
     } else {
@@ -27,6 +27,22 @@ fn display_peers(peers: Vec<ipc::PeerInfo>) -> String {
     }
 }
 
+fn display_pending_peers(peers: Vec<(String, ipc::PeerInfo)>) -> String {
+    if peers.is_empty() {
+        "[ ]".to_string()
+    } else {
+        let mut string = String::from("[");
+        for (idx, (id, peer)) in peers.iter().enumerate() {
+            string.push_str(&format!("\n      {{ PeerId: \"{id}\", {peer} }}"));
+            if idx + 1 != peers.len() {
+                string.push(',');
+            }
+        }
+        string.push_str("\n   ]");
+        string
+    }
+}
+
 pub async fn inspect(args: IdentifyPodArgs, mut stream: Stream) -> Result<String, io::Error> {
     let id = PodId::from(args);
 
@@ -35,14 +51,20 @@ pub async fn inspect(args: IdentifyPodArgs, mut stream: Stream) -> Result<String
         InspectAnswer::Information(info) => Ok(format!(
             "Pod informations: {}\n\
             \x20  Name:\t\t{}\n\
+            \x20  PeerId:\t\t{}\n\
             \x20  Mount:\t\t{:#?}\n\
             \x20  Listen Addresses:\t[ {} ]\n\
             \x20  Connected peers:\t{}\n\
+            \x20  Pending peers:\t{}\n\
+            \x20  Zone:\t\t{}\n\
+            \x20  Peer scores:\t{}\n\
+            \x20  Compression:\t{}\n\
             \x20  Free space:\t{}\n\
             \x20  Used space:\t{}\n\
             \x20  Total space:\t{}",
             if info.frozen { "Frozen" } else { "Running" },
             info.name,
+            info.peer_id.as_deref().unwrap_or("Unknown"),
             info.mount,
             info.listen_addrs
                 .iter()
@@ -54,6 +76,15 @@ pub async fn inspect(args: IdentifyPodArgs, mut stream: Stream) -> Result<String
             } else {
                 display_peers(info.connected_peers)
             },
+            display_pending_peers(info.pending_peers),
+            info.zone.as_deref().unwrap_or("None"),
+            if info.frozen {
+                "Disconnected (Frozen)".to_string()
+            } else {
+                display_peers(info.scores)
+            },
+            info.compression
+                .map_or("Disconnected (Frozen)".to_owned(), |c| c.to_string()),
             info.disk_space
                 .as_ref()
                 .map_or("Error".to_owned(), |s| s.free_size.to_string()),
@@ -1,9 +1,11 @@
+mod approve;
 mod config;
 mod freeze;
 mod gethosts;
 mod inspect;
 mod list_pods;
 mod new;
+mod pin;
 mod redundancy_status;
 mod remove;
 mod restart;
@@ -11,6 +13,7 @@ mod status;
 mod tree;
 mod unfreeze;
 
+pub use approve::approve;
 pub use config::check::check;
 pub use config::generate::generate;
 pub use config::show::show;
@@ -19,6 +22,7 @@ pub use gethosts::gethosts;
 pub use inspect::inspect;
 pub use list_pods::list_pods;
 pub use new::new;
+pub use pin::{pin, unpin};
 pub use redundancy_status::redundancy_status;
 pub use remove::remove;
 pub use restart::restart;
@@ -25,6 +25,7 @@ pub async fn new(args: NewArgs, mut stream: Stream) -> io::Result<String> {
         allow_other_users,
         ip_address,
         port,
+        key,
         ..
     } = args;
 
@@ -39,6 +40,7 @@ pub async fn new(args: NewArgs, mut stream: Stream) -> io::Result<String> {
         port,
         hosts: additional_hosts,
         allow_other_users,
+        key,
     };
     send_command(Command::New(request), &mut stream).await?;
 
@@ -69,6 +71,10 @@ pub async fn new(args: NewArgs, mut stream: Stream) -> io::Result<String> {
             io::ErrorKind::InvalidInput,
             format!("Invalid Ip given, couldn't create: {e}"),
         )),
+        NewAnswer::InvalidNetworkKey(e) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid network key given, couldn't create: {e}"),
+        )),
         NewAnswer::FailedToCreatePod(e) => {
             print_err("Failed to create the given pod:");
             Err(e.into())
@@ -0,0 +1,35 @@
+use interprocess::local_socket::tokio::Stream;
+use std::io;
+
+use crate::cli::connection::{recieve_answer, send_command};
+use crate::ipc::commands::{Command, PinRequest};
+use crate::{cli::PinArgs, ipc::answers::PinAnswer};
+
+pub async fn pin(args: PinArgs, mut stream: Stream) -> io::Result<String> {
+    send_command(Command::Pin(PinRequest { path: args.path }), &mut stream).await?;
+    answer(recieve_answer::<PinAnswer>(&mut stream).await?, "pinned")
+}
+
+pub async fn unpin(args: PinArgs, mut stream: Stream) -> io::Result<String> {
+    send_command(Command::Unpin(PinRequest { path: args.path }), &mut stream).await?;
+    answer(recieve_answer::<PinAnswer>(&mut stream).await?, "unpinned")
+}
+
+fn answer(answer: PinAnswer, done: &str) -> io::Result<String> {
+    match answer {
+        PinAnswer::Success => Ok(format!("Path successfully {done}.")),
+        PinAnswer::FileNotInsideARunningPod => Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "The given path isn't inside a pod.",
+        )),
+        PinAnswer::FileNotFound => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "The given path couldn't be found inside the pod.",
+        )),
+        PinAnswer::NotPinned => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The given path isn't pinned itself, unpin the folder it is pinned with.",
+        )),
+        PinAnswer::PinFailed(io_error) => Err(io_error.into()),
+    }
+}
@@ -20,6 +20,10 @@ fn display_status(status: HashMap<RedundancyStatus, u64>) -> String {
             RedundancyStatus::BelowTarget => format!("\tFiles below target:\t\t{nb}"),
             RedundancyStatus::NotRedundant => format!("\tFiles without any redundancies:\t{nb}"),
             RedundancyStatus::OnTarget => format!("\tFiles on target:\t\t{nb}"),
+            RedundancyStatus::ShardsComplete => format!("\tFiles with all their shards:\t{nb}"),
+            RedundancyStatus::ShardsDegraded => format!("\tFiles missing shards:\t\t{nb}"),
+            RedundancyStatus::ShardsPending => format!("\tFiles not sharded yet:\t\t{nb}"),
+            RedundancyStatus::ShardsLost => format!("\tFiles lost:\t\t\t{nb}"),
         })
         .collect::<Vec<String>>()
         .join("\n")
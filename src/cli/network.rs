@@ -2,8 +2,8 @@ use interprocess::local_socket::tokio::Stream;
 
 use crate::cli::{
     commands::{
-        check, freeze, generate, gethosts, inspect, list_pods, new, redundancy_status, remove,
-        restart, show, status, tree, unfreeze,
+        approve, check, freeze, generate, gethosts, inspect, list_pods, new, pin,
+        redundancy_status, remove, restart, show, status, tree, unfreeze, unpin,
     },
     CliCommand, ConfigCommand,
 };
@@ -26,5 +26,8 @@ pub async fn command_network(cmd: CliCommand, stream: Stream) -> Result<Answer,
         CliCommand::Status => status(stream).await,
         CliCommand::ListPods => list_pods(stream).await,
         CliCommand::RedundancyStatus(args) => redundancy_status(args, stream).await,
+        CliCommand::Approve(args) => approve(args, stream).await,
+        CliCommand::Pin(args) => pin(args, stream).await,
+        CliCommand::Unpin(args) => unpin(args, stream).await,
     }
 }
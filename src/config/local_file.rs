@@ -5,4 +5,8 @@ pub struct LocalConfigFile {
     pub name: Option<String>,
     pub listen_addrs: Vec<String>,
     pub restart: Option<bool>,
+    /// Location of the pod, peers in the same zone are read from first
+    pub zone: Option<String>,
+    /// Disk space the copies of files this pod doesn't host can take, in MiB
+    pub cache_size: Option<u64>,
 }
@@ -1,11 +1,16 @@
-use std::{fs, path::Path, str, sync::Arc};
+use std::{fmt, fs, path::Path, str, sync::Arc, time::Duration};
 
+use custom_error::custom_error;
+use libp2p::PeerId;
 use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::{
     error::{WhError, WhResult},
-    pods::itree::LOCK_TIMEOUT,
+    pods::{
+        itree::LOCK_TIMEOUT,
+        network::{codec::DEFAULT_MAX_MESSAGE_SIZE, compression::DEFAULT_COMPRESSION_LEVEL},
+    },
 };
 
 /** NOTE
@@ -54,6 +59,12 @@ impl<T: Serialize + DeserializeOwned> Config for T {}
 pub struct GlobalConfig {
     pub general: GeneralGlobalConfig,
     pub redundancy: RedundancyConfig,
+    #[serde(default)]
+    pub network: NetworkConfig,
+    /// secret of a private network, never written in the shared configuration file
+    /// nor sent to other peers
+    #[serde(skip)]
+    pub key: Option<NetworkKey>,
 }
 
 impl GlobalConfig {
@@ -70,15 +81,156 @@ pub struct GeneralGlobalConfig {
     pub name: String,
     /// network urls to join the netwoek from
     pub entrypoints: Vec<String>,
+    /// how new pods are admitted into the network
+    #[serde(default)]
+    pub access: NetworkAccess,
+    /// pods always admitted, approved pods are added here in `demand` mode
+    #[serde(default)]
+    pub whitelist: Vec<PeerId>,
+    /// pods refused in `blacklist` mode
+    #[serde(default)]
+    pub blacklist: Vec<PeerId>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkAccess {
+    /// Any pod able to reach the network can join
+    #[default]
+    Open,
+    /// Unknown pods wait until a member approves them
+    Demand,
+    /// Only pods from the whitelist can join
+    Whitelist,
+    /// Every pod can join, except the ones from the blacklist
+    Blacklist,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// find and join pods of the same network on the local network (mDNS)
+    pub discovery: bool,
+    /// biggest message accepted from a peer, in MiB. File contents are streamed and not limited
+    pub max_message_size: u64,
+    /// seconds during which broadcasted operations are kept to be sent together, 0 adapts to the activity
+    pub frequency: f64,
+    /// KiB fetched past a read of a file hosted elsewhere, in prevision of the next reads
+    pub readahead: u64,
+    /// zstd level of the messages and file contents sent to other pods, 0 disables the compression
+    pub compression: i32,
+}
+
+impl NetworkConfig {
+    /// `frequency` as a duration, invalid values fall back to the automatic frequency
+    pub fn frequency(&self) -> Duration {
+        Duration::try_from_secs_f64(self.frequency).unwrap_or_default()
+    }
+
+    /// `readahead` in bytes
+    pub fn readahead(&self) -> u64 {
+        self.readahead.saturating_mul(1024)
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            discovery: true,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            frequency: 0.0,
+            readahead: 1024,
+            compression: DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+}
+
+custom_error! {pub NetworkKeyError
+    InvalidLength{ len: usize } = "The network key must be 64 hexadecimal characters long, got {len}",
+    InvalidCharacter{ c: char } = "The network key contains a non hexadecimal character: '{c}'",
+}
+
+/// 256 bits pre-shared key of a private network, written as 64 hexadecimal characters
+/// Peers without the same key can't complete a transport handshake
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct NetworkKey([u8; 32]);
+
+impl NetworkKey {
+    pub fn as_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+}
+
+impl str::FromStr for NetworkKey {
+    type Err = NetworkKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.len() != 64 {
+            return Err(NetworkKeyError::InvalidLength { len: s.len() });
+        }
+        let mut key = [0u8; 32];
+        for (i, c) in s.chars().enumerate() {
+            let digit = c
+                .to_digit(16)
+                .ok_or(NetworkKeyError::InvalidCharacter { c })?;
+            key[i / 2] |= (digit as u8) << if i % 2 == 0 { 4 } else { 0 };
+        }
+        Ok(Self(key))
+    }
+}
+
+impl fmt::Display for NetworkKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+/// Never print the secret in the logs
+impl fmt::Debug for NetworkKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("NetworkKey(**hidden**)")
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct RedundancyConfig {
     pub number: u64,
+    /// seconds a disconnected pod is still counted as hosting its files, before they are replicated elsewhere
+    pub grace_period: u64,
+    /// whole copies of the files, or erasure-coded shards
+    pub mode: RedundancyMode,
+    /// pieces a file is split in, in `erasure` mode
+    pub data_shards: u64,
+    /// pieces computed from the data shards to rebuild lost ones, in `erasure` mode
+    pub parity_shards: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RedundancyMode {
+    /// Whole copies of each file on `number` pods
+    #[default]
+    Replicas,
+    /// Each file split in data and parity shards, one per pod, see [erasure](crate::pods::network::erasure)
+    Erasure,
+}
+
+impl RedundancyConfig {
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period)
+    }
 }
 
 impl Default for RedundancyConfig {
     fn default() -> Self {
-        Self { number: 2 }
+        Self {
+            number: 2,
+            grace_period: 120,
+            mode: RedundancyMode::default(),
+            data_shards: 4,
+            parity_shards: 2,
+        }
     }
 }
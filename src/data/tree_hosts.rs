@@ -12,6 +12,7 @@ use crate::{
     error::WhError,
     ipc::answers::TreeAnswer,
     pods::{
+        filesystem::pins::Pins,
         itree::{EntrySymlink, FsEntry, ITreeIndex, Ino, Inode, ROOT},
         pod::Pod,
         whpath::WhPath,
@@ -50,14 +51,25 @@ struct InodeInfo {
     name: String,
     ino: Ino,
     entry: FsEntryInfo,
+    /// Kept available offline by this pod, itself or through a parent
+    pinned: bool,
 }
 
 impl InodeInfo {
-    fn from(value: Inode, infos: &HashMap<PeerId, String>) -> Self {
+    fn from(value: Inode, infos: &HashMap<PeerId, String>, pinned: bool) -> Self {
         Self {
             name: value.name.to_string(),
             ino: value.id,
             entry: FsEntryInfo::from(value.entry, infos),
+            pinned,
+        }
+    }
+
+    fn pin_mark(&self) -> &'static str {
+        if self.pinned {
+            " [pinned]"
+        } else {
+            ""
         }
     }
 }
@@ -140,7 +152,8 @@ impl fmt::Debug for TreeEntry {
             TreeEntry::Directory(inode, items) => {
                 let name = &inode.name;
                 let ino = &inode.ino;
-                write!(f, "{name} ({ino})")?;
+                let pinned = inode.pin_mark();
+                write!(f, "{name} ({ino}){pinned}")?;
                 if !items.is_empty() {
                     f.write_char('\n')?;
                 }
@@ -169,7 +182,8 @@ impl fmt::Debug for TreeEntry {
                     // should never happen, but is a sane fallback:
                     FsEntryInfo::Directory => "".to_owned(),
                 };
-                write!(f, "{name} ({ino}){data}")
+                let pinned = inode.pin_mark();
+                write!(f, "{name} ({ino}){pinned}{data}")
             }
         }
     }
@@ -185,20 +199,23 @@ fn recurse_build_tree(
     itree: &mut ITreeIndex,
     ino: Ino,
     infos: &HashMap<PeerId, String>,
+    pins: &Pins,
+    parent_pinned: bool,
 ) -> Option<TreeEntry> {
     if let Some(inode) = itree.remove(&ino) {
+        let pinned = parent_pinned || pins.roots().contains(&ino);
         match &inode.entry {
             crate::pods::itree::FsEntry::Directory(children) => {
                 let children = children
                     .iter()
-                    .flat_map(|ino| recurse_build_tree(itree, *ino, infos))
+                    .flat_map(|ino| recurse_build_tree(itree, *ino, infos, pins, pinned))
                     .collect();
                 Some(TreeEntry::Directory(
-                    InodeInfo::from(inode, infos),
+                    InodeInfo::from(inode, infos, pinned),
                     children,
                 ))
             }
-            _ => Some(TreeEntry::File(InodeInfo::from(inode, infos))),
+            _ => Some(TreeEntry::File(InodeInfo::from(inode, infos, pinned))),
         }
     } else {
         None
@@ -215,9 +232,13 @@ pub fn get_tree(pod: &Pod, path: Option<&WhPath>) -> TreeAnswer {
             .chain([(pod.network_interface.id, pod.nickname.clone())]),
     );
     let itree = pod.fs_interface.network_interface.itree.read();
+    let pins = pod.fs_interface.pins.read().clone();
     let start = path
         .map(|p| itree.get_inode_from_path(p).map(|inode| inode.id))
         .unwrap_or(Ok(ROOT));
+    let start_pinned = start
+        .as_ref()
+        .is_ok_and(|start| pins.covers(&itree, *start));
 
     let mut itree = {
         let owned = itree.raw_entries().clone();
@@ -226,7 +247,8 @@ pub fn get_tree(pod: &Pod, path: Option<&WhPath>) -> TreeAnswer {
     };
 
     let tree = start.and_then(|start| {
-        recurse_build_tree(&mut itree, start, &infos).ok_or(WhError::InodeNotFound)
+        recurse_build_tree(&mut itree, start, &infos, &pins, start_pinned)
+            .ok_or(WhError::InodeNotFound)
     });
 
     match tree {
@@ -251,21 +273,25 @@ mod test {
             name: "/".to_owned(),
             ino: ROOT,
             entry: FsEntryInfo::Directory,
+            pinned: false,
         };
         let folder = InodeInfo {
             name: "folder".to_owned(),
             ino: 10,
             entry: FsEntryInfo::Directory,
+            pinned: false,
         };
         let file = InodeInfo {
             name: "file".to_owned(),
             ino: 11,
             entry: FsEntryInfo::File(vec![]),
+            pinned: true,
         };
         let empty = InodeInfo {
             name: "empty".to_owned(),
             ino: 12,
             entry: FsEntryInfo::Directory,
+            pinned: false,
         };
         let link = InodeInfo {
             name: "link".to_owned(),
@@ -274,6 +300,7 @@ mod test {
                 EntrySymlink::parse("/mountpoint/folder/file", "/mountpoint")
                     .expect("this symlink is valid"),
             ),
+            pinned: false,
         };
 
         let t_file = TreeEntry::File(file.clone());
@@ -288,9 +315,9 @@ mod test {
         let expected = "\
 / (1)
 └── folder (10)
-    ├── file (11) : []
+    ├── file (11) [pinned] : []
     ├── empty (12)
-    ├── file (11) : []
+    ├── file (11) [pinned] : []
     └── link (13) -> //folder/file";
         assert_eq!(&format!("{t_root:#?}"), expected);
     }
@@ -1,3 +1,4 @@
+use crate::error::WhResult;
 use crate::fuse::linux_attrs::time_or_now_to_system_time;
 use crate::fuse::linux_mknod::filetype_from_mode;
 use crate::pods::filesystem::attrs::SetAttrError;
@@ -16,11 +17,13 @@ use crate::pods::filesystem::write::WriteError;
 use crate::pods::filesystem::xattrs::GetXAttrError;
 use crate::pods::itree::EntrySymlink;
 use crate::pods::itree::FsEntry;
+use crate::pods::network::locks::{FileLock, LockKind, LockOwner, LockRefusal};
 use crate::pods::network::pull_file::PullError;
 use crate::pods::whpath::{osstr_to_str, InodeName};
+use fuser::consts::{FUSE_FLOCK_LOCKS, FUSE_POSIX_LOCKS};
 use fuser::{
-    BackgroundSession, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyEntry, ReplyXattr, Request,
+    BackgroundSession, Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyLock, ReplyXattr, Request,
 };
 use libc::{XATTR_CREATE, XATTR_REPLACE};
 use std::ffi::OsStr;
@@ -37,7 +40,70 @@ pub struct FuseController {
     pub fs_interface: Arc<FsInterface>,
 }
 
+impl FuseController {
+    /// Inode of an open handle, it follows the file if it is renumbered while open
+    fn handle_ino(&self, ino: u64, file_handle: u64) -> u64 {
+        self.fs_interface
+            .file_handles
+            .read()
+            .handles
+            .get(&file_handle)
+            .map_or(ino, |handle| handle.ino)
+    }
+
+    fn lock_owner(&self, owner: u64) -> LockOwner {
+        LockOwner {
+            peer: self.fs_interface.network_interface.id,
+            owner,
+        }
+    }
+}
+
+/// Read the data asked by the kernel, pulling it if needed, and answer it
+fn answer_read(
+    fs_interface: &FsInterface,
+    ino: u64,
+    file_handle: u64,
+    offset: usize,
+    size: u32,
+    reply: ReplyData,
+) {
+    let mut buf = vec![0; size as usize];
+    match fs_interface.read_file(ino, offset, &mut buf, file_handle) {
+        Ok(size) => {
+            buf.resize(size, 0);
+            reply.data(&buf)
+        }
+        Err(ReadError::WhError { source }) => reply.error(source.to_libc()),
+        Err(ReadError::PullError {
+            source: PullError::WhError { source },
+        }) => reply.error(source.to_libc()),
+        Err(ReadError::CantPull) => reply.error(libc::ENETUNREACH),
+        Err(ReadError::LocalReadFailed { io }) => reply.error(
+            io.raw_os_error()
+                .expect("Local read error should always be the underling libc::open os error"),
+        ),
+        Err(ReadError::PullError {
+            source: PullError::NoHostAvailable,
+        }) => reply.error(libc::ENETUNREACH),
+        Err(ReadError::PullError {
+            source: PullError::WriteError { io: _ },
+        }) => reply.error(libc::ENETUNREACH),
+        Err(ReadError::NoFileHandle) => reply.error(libc::EBADFD), // Shouldn't happend
+        //According to the man EBADF if the fd is not a valid file descriptor or is not open for reading.
+        Err(ReadError::NoReadPermission) => reply.error(libc::EBADFD),
+    }
+}
+
 impl Filesystem for FuseController {
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), libc::c_int> {
+        // Locks are handled by the network instead of each kernel on its own
+        if let Err(unsupported) = config.add_capabilities(FUSE_POSIX_LOCKS | FUSE_FLOCK_LOCKS) {
+            log::warn!("The kernel doesn't support distributed locks ({unsupported:#x}), they will only apply locally");
+        }
+        Ok(())
+    }
+
     ////////////////////////////////////////////////////////////////////////////
     // READING
     ////////////////////////////////////////////////////////////////////////////
@@ -89,13 +155,25 @@ impl Filesystem for FuseController {
     fn release(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         file_handle: u64,
         _flags: i32,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
         _flush: bool,
         reply: fuser::ReplyEmpty,
     ) {
+        let ino = self.handle_ino(ino, file_handle);
+        // Given when the file holds flock locks
+        if let Some(owner) = lock_owner {
+            let owner = self.lock_owner(owner);
+            if let Err(err) = self
+                .fs_interface
+                .network_interface
+                .unlock(ino, owner, 0, u64::MAX)
+            {
+                log::error!("release: couldn't release the locks of {ino}: {err}");
+            }
+        }
         match self.fs_interface.release(file_handle) {
             Ok(()) => reply.ok(),
             Err(FlushError::DiffError { source: _ }) => reply.error(libc::EWOULDBLOCK),
@@ -103,6 +181,7 @@ impl Filesystem for FuseController {
             Err(FlushError::ReadError { source: _ }) => reply.error(libc::EWOULDBLOCK),
             Err(FlushError::WriteError { source: _ }) => reply.error(libc::EWOULDBLOCK),
             Err(FlushError::WhError { source }) => reply.error(source.to_libc()),
+            Err(FlushError::ConflictCopyFailed { reason: _ }) => reply.error(libc::EIO),
         }
     }
 
@@ -141,36 +220,21 @@ impl Filesystem for FuseController {
         _lock: Option<u64>,
         reply: ReplyData,
     ) {
-        let mut buf = vec![0; size as usize];
-        match self.fs_interface.read_file(
-            ino,
-            offset.try_into().expect("read::read offset negative"),
-            &mut buf,
-            file_handle,
-        ) {
-            Ok(size) => {
-                buf.resize(size, 0);
-                reply.data(&buf)
-            }
-            Err(ReadError::WhError { source }) => reply.error(source.to_libc()),
-            Err(ReadError::PullError {
-                source: PullError::WhError { source },
-            }) => reply.error(source.to_libc()),
-            Err(ReadError::CantPull) => reply.error(libc::ENETUNREACH),
-            Err(ReadError::LocalReadFailed { io }) => reply.error(
-                io.raw_os_error()
-                    .expect("Local read error should always be the underling libc::open os error"),
-            ),
-            Err(ReadError::PullError {
-                source: PullError::NoHostAvailable,
-            }) => reply.error(libc::ENETUNREACH),
-            Err(ReadError::PullError {
-                source: PullError::WriteError { io: _ },
-            }) => reply.error(libc::ENETUNREACH),
-            Err(ReadError::NoFileHandle) => reply.error(libc::EBADFD), // Shouldn't happend
-            //According to the man EBADF if the fd is not a valid file descriptor or is not open for reading.
-            Err(ReadError::NoReadPermission) => reply.error(libc::EBADFD),
+        let offset = offset.try_into().expect("read::read offset negative");
+        let ino = self.handle_ino(ino, file_handle);
+        if self.fs_interface.is_stored_locally(ino) {
+            return answer_read(&self.fs_interface, ino, file_handle, offset, size, reply);
         }
+
+        // The data must be pulled first: the request is parked on an other thread until it arrives,
+        // so the other operations on the mount don't wait for the transfer
+        let fs_interface = self.fs_interface.clone();
+        self.fs_interface
+            .network_interface
+            .runtime
+            .spawn_blocking(move || {
+                answer_read(&fs_interface, ino, file_handle, offset, size, reply)
+            });
     }
 
     fn readdir(
@@ -308,6 +372,7 @@ impl Filesystem for FuseController {
             Err(MakeInodeError::ParentNotFolder) => reply.error(libc::ENOTDIR),
             Err(MakeInodeError::ProtectedNameIsFolder) => reply.error(libc::EISDIR),
             Err(MakeInodeError::PermissionDenied) => reply.error(libc::EACCES),
+            Err(MakeInodeError::Removed) => reply.error(libc::ENOENT),
         }
     }
 
@@ -342,6 +407,7 @@ impl Filesystem for FuseController {
             Err(MakeInodeError::ParentNotFolder) => reply.error(libc::ENOTDIR),
             Err(MakeInodeError::ProtectedNameIsFolder) => reply.error(libc::EISDIR),
             Err(MakeInodeError::PermissionDenied) => reply.error(libc::EACCES),
+            Err(MakeInodeError::Removed) => reply.error(libc::ENOENT),
         }
     }
 
@@ -506,6 +572,7 @@ impl Filesystem for FuseController {
         let offset = offset
             .try_into()
             .expect("fuser write: can't convert i64 to u64");
+        let ino = self.handle_ino(ino, file_handle);
 
         match self.fs_interface.write(ino, data, offset, file_handle) {
             Ok(written) => reply.written(
@@ -605,6 +672,7 @@ impl Filesystem for FuseController {
                 }
             };
         let handle = file_handles.handles.get_mut(&fh);
+        let ino = handle.as_ref().map_or(ino, |handle| handle.ino);
         match self.fs_interface.flush(ino, handle) {
             Ok(_) => reply.ok(),
             Err(FlushError::DiffError { source: _ }) => reply.error(libc::EWOULDBLOCK),
@@ -612,6 +680,7 @@ impl Filesystem for FuseController {
             Err(FlushError::ReadError { source: _ }) => reply.error(libc::EWOULDBLOCK),
             Err(FlushError::WriteError { source: _ }) => reply.error(libc::EWOULDBLOCK),
             Err(FlushError::WhError { source }) => reply.error(source.to_libc()),
+            Err(FlushError::ConflictCopyFailed { reason: _ }) => reply.error(libc::EIO),
         }
     }
 
@@ -707,6 +776,7 @@ impl Filesystem for FuseController {
             Err(MakeInodeError::ParentNotFolder) => reply.error(libc::ENOTDIR),
             Err(MakeInodeError::ProtectedNameIsFolder) => reply.error(libc::EISDIR),
             Err(MakeInodeError::PermissionDenied) => reply.error(libc::EACCES),
+            Err(MakeInodeError::Removed) => reply.error(libc::ENOENT),
         }
     }
 
@@ -723,6 +793,130 @@ impl Filesystem for FuseController {
     //     );
     //     reply.error(libc::EPERM);
     // }
+
+    ////////////////////////////////////////////////////////////////////////////
+    // ^ WRITING
+    ////////////////////////////////////////////////////////////////////////////
+    // LOCKING
+    ////////////////////////////////////////////////////////////////////////////
+
+    /// Called on each close, the POSIX locks of the closing process are released
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        let owner = self.lock_owner(lock_owner);
+        match self
+            .fs_interface
+            .network_interface
+            .unlock(ino, owner, 0, u64::MAX)
+        {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.to_libc()),
+        }
+    }
+
+    fn getlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        let kind = match typ {
+            libc::F_RDLCK => LockKind::Shared,
+            libc::F_WRLCK => LockKind::Exclusive,
+            _ => return reply.error(libc::EINVAL),
+        };
+        let lock = FileLock {
+            owner: self.lock_owner(lock_owner),
+            kind,
+            start,
+            end,
+            pid,
+        };
+
+        match self.fs_interface.network_interface.get_lock(ino, &lock) {
+            Some(held) => reply.locked(
+                held.start,
+                held.end,
+                match held.kind {
+                    LockKind::Shared => libc::F_RDLCK,
+                    LockKind::Exclusive => libc::F_WRLCK,
+                },
+                // The process may be on an other pod, its pid means nothing here
+                if held.owner.peer == lock.owner.peer {
+                    held.pid
+                } else {
+                    0
+                },
+            ),
+            None => reply.locked(0, 0, libc::F_UNLCK, 0),
+        }
+    }
+
+    fn setlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        let owner = self.lock_owner(lock_owner);
+        let kind = match typ {
+            libc::F_RDLCK => LockKind::Shared,
+            libc::F_WRLCK => LockKind::Exclusive,
+            libc::F_UNLCK => {
+                return match self
+                    .fs_interface
+                    .network_interface
+                    .unlock(ino, owner, start, end)
+                {
+                    Ok(()) => reply.ok(),
+                    Err(err) => reply.error(err.to_libc()),
+                }
+            }
+            _ => return reply.error(libc::EINVAL),
+        };
+        let lock = FileLock {
+            owner,
+            kind,
+            start,
+            end,
+            pid,
+        };
+        let network_interface = self.fs_interface.network_interface.clone();
+
+        let reply_lock = move |outcome: WhResult<Result<(), LockRefusal>>| match outcome {
+            Ok(Ok(())) => reply.ok(),
+            Ok(Err(LockRefusal::Unsupported(_))) => reply.error(libc::ENOLCK),
+            Ok(Err(_)) => reply.error(libc::EAGAIN),
+            Err(err) => reply.error(err.to_libc()),
+        };
+
+        // Waiting here would block the whole filesystem, including the holder releasing the lock
+        if sleep {
+            network_interface.wait_lock(ino, lock, reply_lock);
+        } else {
+            let runtime = network_interface.runtime.clone();
+            runtime.spawn(async move { reply_lock(network_interface.set_lock(ino, lock).await) });
+        }
+    }
 }
 
 pub fn mount_fuse(
@@ -744,7 +938,11 @@ pub fn mount_fuse(
     if allow_other_users {
         options.push(MountOption::AllowOther);
     };
-    let ctrl = FuseController { fs_interface };
+    let ctrl = FuseController {
+        fs_interface: fs_interface.clone(),
+    };
 
-    fuser::spawn_mount2(ctrl, mount_point, &options)
+    let session = fuser::spawn_mount2(ctrl, mount_point, &options)?;
+    *fs_interface.kernel.lock() = Some(session.notifier());
+    Ok(session)
 }
@@ -30,9 +30,21 @@ pub enum NewAnswer {
     PortAlreadyTaken,
     NoFreePortInRage,
     ConflictWithConfig(String),
+    InvalidNetworkKey(String),
     FailedToCreatePod(PodCreationError),
 }
 
+#[derive(Debug, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum PinAnswer {
+    Success,
+    FileNotInsideARunningPod,
+    FileNotFound,
+    /// Only unpinning, the path is not pinned itself
+    NotPinned,
+    PinFailed(IoError),
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetHostsAnswer {
     Hosts(Hosts),
@@ -128,14 +140,81 @@ impl std::fmt::Display for PeerInfoIPC {
     }
 }
 
+/// Measures of a connected peer, see [PeerScores](crate::pods::network::peer_scores::PeerScores)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerScoreIPC {
+    pub nickname: String,
+    pub zone: Option<String>,
+    /// Round trip time, in milliseconds
+    pub rtt: Option<f64>,
+    /// Throughput of the recent transfers, in bytes per second
+    pub throughput: Option<f64>,
+    pub in_flight: usize,
+    /// Expected time to read a MiB, in milliseconds
+    pub cost: f64,
+}
+
+impl std::fmt::Display for PeerScoreIPC {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Nickname: \"{}\", Zone: {}, RTT: {}, Throughput: {}, In flight: {}, Cost: {:.1} ms",
+            self.nickname,
+            self.zone.as_deref().unwrap_or("None"),
+            self.rtt
+                .map_or("Unknown".to_owned(), |rtt| format!("{rtt:.1} ms")),
+            self.throughput
+                .map_or("Unknown".to_owned(), |throughput| format!(
+                    "{:.2} MiB/s",
+                    throughput / (1024.0 * 1024.0)
+                )),
+            self.in_flight,
+            self.cost,
+        )
+    }
+}
+
+/// Bytes sent to the peers understanding compression, see [CompressionStats](crate::pods::network::compression::CompressionStats)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressionIPC {
+    /// Size of the content before compression
+    pub raw: u64,
+    pub sent: u64,
+}
+
+impl std::fmt::Display for CompressionIPC {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const MIB: f64 = 1024.0 * 1024.0;
+        if self.sent == 0 {
+            return write!(f, "Nothing sent");
+        }
+        write!(
+            f,
+            "{:.2}x ({:.2} MiB sent as {:.2} MiB)",
+            self.raw as f64 / self.sent as f64,
+            self.raw as f64 / MIB,
+            self.sent as f64 / MIB,
+        )
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InspectInfo {
     pub frozen: bool,
     pub listen_addrs: Vec<String>,
     pub name: String,
+    /// PeerId of the pod, None if it was never started
+    pub peer_id: Option<String>,
     pub connected_peers: Vec<PeerInfoIPC>,
+    /// (PeerId, info) of the peers waiting for approval
+    pub pending_peers: Vec<(String, PeerInfoIPC)>,
     pub mount: PathBuf,
     pub disk_space: Option<DiskSizeInfo>,
+    pub zone: Option<String>,
+    /// Connected peers, from the best to read from to the worst
+    pub scores: Vec<PeerScoreIPC>,
+    /// None if the pod isn't running
+    pub compression: Option<CompressionIPC>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -144,6 +223,16 @@ pub enum InspectAnswer {
     PodNotFound,
 }
 
+#[derive(Debug, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum ApproveAnswer {
+    /// PeerId of the approved peer
+    Success(String),
+    PodNotFound,
+    PeerNotPending,
+    ApproveFailed(IoError),
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum TreeAnswer {
     Tree(Box<TreeData>),
@@ -45,6 +45,8 @@ pub struct NewRequest {
     pub port: Option<u16>,
     pub hosts: Vec<String>,
     pub allow_other_users: bool,
+    /// Pre-shared key of a private network
+    pub key: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, TS)]
@@ -60,6 +62,20 @@ pub struct RemoveRequest {
     pub mode: Mode,
 }
 
+#[derive(Debug, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct ApproveRequest {
+    pub pod: PodId,
+    /// PeerId or nickname of the pending peer
+    pub peer: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct PinRequest {
+    pub path: PathBuf,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Command {
     Unfreeze(PodId),
@@ -77,4 +93,7 @@ pub enum Command {
     ListPods,
     RedundancyStatus(PodId),
     StatsPerFiletype(PodId),
+    Approve(ApproveRequest),
+    Pin(PinRequest),
+    Unpin(PinRequest),
 }
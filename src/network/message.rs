@@ -1,18 +1,21 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::{self, Debug},
+    num::NonZeroU8,
     sync::Arc,
 };
 
-use libp2p::{Multiaddr, PeerId};
+use libp2p::{swarm::dial_opts::DialOpts, Multiaddr, PeerId};
 use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot;
 
 use crate::{
     config::GlobalConfig,
+    network::protocol::Capability,
     pods::{
         filesystem::diffs::{Delta, Signature},
-        itree::{ITree, Ino, Inode, Metadata},
+        itree::{ITree, Ino, Inode, Metadata, Shards, Tombstone, VersionVector},
+        network::locks::{FileLock, LockOwner},
         whpath::InodeName,
     },
 };
@@ -24,34 +27,142 @@ use crate::{
 pub enum Request {
     Inode(Inode),
 
+    /// Store this whole file, sent by and to the pods of 1.0 which have no file streams
     RedundancyFile(Ino, Arc<Vec<u8>>),
     /// Parent, New Parent, Name, New Name, overwrite
     Rename(Ino, Ino, InodeName, InodeName, bool),
     AddHosts(Ino, Vec<PeerId>),
     RemoveHosts(Ino, Vec<PeerId>),
 
-    /// A delta on file write with given base signature
-    FileDelta(Ino, Metadata, Signature, Delta),
-    /// File contents were changed.
+    /// A delta on file write with given base signature, producing this version of the file
+    FileDelta(
+        Ino,
+        Metadata,
+        #[serde(with = "crate::network::wire::since_2_0")] VersionVector,
+        Signature,
+        Delta,
+    ),
+    /// File contents were changed, this is the new version.
     /// Peers also tracking this file should follow up with a [Request::DeltaRequest]
-    FileChanged(Ino, Metadata),
+    FileChanged(
+        Ino,
+        Metadata,
+        #[serde(with = "crate::network::wire::since_2_0")] VersionVector,
+    ),
 
     // RequestFileSignature(Ino),
     // FileSignature(Ino, Vec<u8>),
+    /// Send me this whole file, answered with [Response::RequestedFile], for the pods of 1.0
     RequestFile(Ino),
 
-    Remove(Ino),
+    /// The inode was removed, peers keep the tombstone until every one of them knows
+    Remove(
+        Ino,
+        #[serde(with = "crate::network::wire::tombstone_since_2_0")] Tombstone,
+    ),
     EditMetadata(Ino, Metadata),
     SetXAttr(Ino, String, Vec<u8>),
     RemoveXAttr(Ino, String),
 
     RequestFs,
+    /// A member approved this pod, it is now part of the network
+    AdmitPeer(PeerId),
+    /// Several broadcasted operations sent at once, applied in order (`[network] frequency`)
+    Batch(Vec<Request>),
+    /// Record this lock, answered with [Response::LockHeld] if it conflicts with a known one
+    Lock(Ino, FileLock),
+    /// Owner, start, end
+    Unlock(Ino, LockOwner, u64, u64),
+    /// Sent by a pod meeting again a peer, with the operations it applied since they parted
+    /// Answered with [Response::Operations], or [Response::FsAnswer] if the peer's log can't tell
+    RequestOperations(Vec<Request>),
+    /// The shards of a file were placed again, see [Shards]
+    SetShards(Ino, Shards),
+    /// Next page of a catch-up, the operations after this sequence number
+    /// Answered with [Response::OperationsPage]
+    ContinueOperations(u64),
+    /// Request a file delta from this base signature, like [Response::DeltaRequest]
+    /// for the changes replayed during a catch-up, which aren't requests to answer
+    RequestDelta(Ino, Signature),
+}
+
+impl Request {
+    /// Capability a peer needs to understand this request
+    pub fn required_capability(&self) -> Option<Capability> {
+        match self {
+            Request::AdmitPeer(_) => Some(Capability::Admission),
+            Request::Batch(_) => Some(Capability::Batches),
+            Request::Lock(_, _) | Request::Unlock(_, _, _, _) => Some(Capability::Locks),
+            Request::RequestOperations(_) => Some(Capability::OperationLog),
+            Request::SetShards(_, _) => Some(Capability::Shards),
+            Request::ContinueOperations(_) | Request::RequestDelta(_, _) => {
+                Some(Capability::OperationPages)
+            }
+            _ => None,
+        }
+    }
+
+    /// Adapt this request to a peer with the given capabilities
+    /// Returns the requests to send instead, none if the peer can't understand it in any form
+    /// A batch is split for peers that don't understand batches, and the operations
+    /// a peer can't understand are left out of batches and catch-ups
+    pub fn downgrade(self, capabilities: &HashSet<Capability>) -> Vec<Request> {
+        match self {
+            Request::Batch(requests) => {
+                let requests: Vec<Request> = requests
+                    .into_iter()
+                    .flat_map(|request| request.downgrade(capabilities))
+                    .collect();
+                if capabilities.contains(&Capability::Batches) {
+                    vec![Request::Batch(requests)]
+                } else {
+                    requests
+                }
+            }
+            Request::RequestOperations(operations)
+                if capabilities.contains(&Capability::OperationLog) =>
+            {
+                vec![Request::RequestOperations(
+                    operations
+                        .into_iter()
+                        .flat_map(|operation| operation.downgrade(capabilities))
+                        .collect(),
+                )]
+            }
+            request => match request.required_capability() {
+                Some(capability) if !capabilities.contains(&capability) => vec![],
+                _ => vec![request],
+            },
+        }
+    }
+
+    /// Inode this request is about, if it concerns a single one
+    pub fn inode(&self) -> Option<Ino> {
+        match self {
+            Request::Inode(inode) => Some(inode.id),
+            Request::RedundancyFile(ino, _)
+            | Request::RequestFile(ino)
+            | Request::AddHosts(ino, _)
+            | Request::RemoveHosts(ino, _)
+            | Request::FileDelta(ino, _, _, _, _)
+            | Request::FileChanged(ino, _, _)
+            | Request::Remove(ino, _)
+            | Request::EditMetadata(ino, _)
+            | Request::SetXAttr(ino, _, _)
+            | Request::RemoveXAttr(ino, _)
+            | Request::Lock(ino, _)
+            | Request::Unlock(ino, _, _, _)
+            | Request::SetShards(ino, _)
+            | Request::RequestDelta(ino, _) => Some(*ino),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for Request {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let name = match self {
-            Request::Remove(_) => "Remove",
+            Request::Remove(_, _) => "Remove",
             Request::Inode(_) => "Inode",
             Request::RequestFile(_) => "RequestFile",
             Request::Rename(_, _, _, _, _) => "Rename",
@@ -62,8 +173,16 @@ impl fmt::Display for Request {
             Request::RemoveXAttr(_, _) => "RemoveXAttr",
             Request::RequestFs => "RequestFs",
             Request::RedundancyFile(_, _) => "RedundancyFile",
-            Request::FileDelta(_, _, _, _) => "FileDelta",
-            Request::FileChanged(_, _) => "FileChanged",
+            Request::FileDelta(_, _, _, _, _) => "FileDelta",
+            Request::FileChanged(_, _, _) => "FileChanged",
+            Request::AdmitPeer(_) => "AdmitPeer",
+            Request::Batch(_) => "Batch",
+            Request::Lock(_, _) => "Lock",
+            Request::Unlock(_, _, _, _) => "Unlock",
+            Request::RequestOperations(_) => "RequestOperations",
+            Request::SetShards(_, _) => "SetShards",
+            Request::ContinueOperations(_) => "ContinueOperations",
+            Request::RequestDelta(_, _) => "RequestDelta",
             // Request::RequestFileSignature(_) => "RequestFileSignature",
             // Request::FileSignature(_, _) => "FileSignature",
         };
@@ -87,7 +206,7 @@ impl fmt::Debug for Request {
                 }
             ),
             Request::RedundancyFile(id, _) => write!(f, "RedundancyFile({id}, <bin>)"),
-            Request::Remove(id) => write!(f, "Remove({id})"),
+            Request::Remove(id, tombstone) => write!(f, "Remove({id}, by: {})", tombstone.by),
             Request::RequestFile(id) => write!(f, "RequestFile({id})"),
             Request::Rename(parent, new_parent, name, new_name, overwrite) => write!(
                 f,
@@ -110,13 +229,32 @@ impl fmt::Debug for Request {
             ),
             Request::RemoveXAttr(id, name) => write!(f, "RemoveXAttr({id}, {name})"),
             Request::RequestFs => write!(f, "RequestFs"),
-            Request::FileDelta(ino, meta, _, _) => {
-                write!(f, "FileDelta({ino}, {:?})", meta.mtime)
+            Request::FileDelta(ino, meta, version, _, _) => {
+                write!(f, "FileDelta({ino}, {:?}, {version:?})", meta.mtime)
             }
-            Request::FileChanged(ino, meta) => {
-                write!(f, "FileChanged({ino}, {:?})", meta.mtime)
-            } // Request::RequestFileSignature(ino) => write!(f, "RequestFileSignature({ino}, <bin>)"),
-              // Request::FileSignature(ino, _) => write!(f, "FileSignature({ino}, <bin>)"),
+            Request::FileChanged(ino, meta, version) => {
+                write!(f, "FileChanged({ino}, {:?}, {version:?})", meta.mtime)
+            }
+            Request::AdmitPeer(peer) => write!(f, "AdmitPeer({peer})"),
+            Request::Batch(requests) => write!(f, "Batch({requests:?})"),
+            Request::Lock(ino, lock) => write!(f, "Lock({ino}, {lock:?})"),
+            Request::Unlock(ino, owner, start, end) => {
+                write!(f, "Unlock({ino}, {owner:?}, {start}..={end})")
+            }
+            Request::RequestOperations(operations) => {
+                write!(f, "RequestOperations({} operations)", operations.len())
+            }
+            Request::SetShards(ino, shards) => {
+                write!(
+                    f,
+                    "SetShards({ino}, {:?}, {:?})",
+                    shards.version, shards.holders
+                )
+            }
+            Request::ContinueOperations(after) => write!(f, "ContinueOperations({after})"),
+            Request::RequestDelta(ino, _) => write!(f, "RequestDelta({ino})"),
+            // Request::RequestFileSignature(ino) => write!(f, "RequestFileSignature({ino}, <bin>)"),
+            // Request::FileSignature(ino, _) => write!(f, "FileSignature({ino}, <bin>)"),
         }
     }
 }
@@ -133,12 +271,14 @@ pub struct PeerInfoNet {
 
 impl PeerInfoNet {
     /// convert a MultiAddr to a simple {hostname}:{port} or {ip}:{port} address
+    /// websocket addresses are left as is, other transports are suffixed: {ip}:{port} (quic)
     /// error handling here is simple because we don't expect to run into any errors
     /// it's there to ensure we fail safe and to document behavior for future debugging
     pub fn display_address(multi: &Multiaddr) -> Result<String, &'static str> {
         use libp2p::multiaddr::Protocol as P;
         let mut host = None;
         let mut port = None;
+        let mut transport = Some("tcp");
         for protocol in multi.iter() {
             match protocol {
                 P::Dns(cow) | P::Dns4(cow) | P::Dns6(cow) | P::Dnsaddr(cow) => {
@@ -159,23 +299,65 @@ impl PeerInfoNet {
                         .ok_or("multiple addresses set")?;
                     host = Some(addr.to_string().into())
                 }
-                P::Tcp(tcp) => {
+                P::Tcp(p) | P::Udp(p) => {
                     port.is_none()
                         .then_some(())
                         .ok_or("multiple port names set")?;
-                    port = Some(tcp)
+                    port = Some(p)
                 }
-                P::Udp(_) => Err("transport set to udp")?,
+                P::Ws(_) | P::Wss(_) => transport = None,
+                P::QuicV1 | P::Quic => transport = Some("quic"),
                 _ => {}
             }
         }
         if let Some((host, port)) = host.zip(port) {
-            Ok(format!("{host}:{port}"))
+            Ok(match transport {
+                Some(transport) => format!("{host}:{port} ({transport})"),
+                None => format!("{host}:{port}"),
+            })
         } else {
             Err("missing port or hostname/ip")
         }
     }
 
+    /// parse an address given by the user
+    /// either a full multiaddr (/ip4/127.0.0.1/udp/40000/quic-v1) or a simple {host}:{port},
+    /// which is reached through websocket
+    pub fn parse_address(address: &str) -> Result<Multiaddr, String> {
+        if address.starts_with('/') {
+            address.parse().map_err(|err| format!("{err}"))
+        } else {
+            libp2p::multiaddr::from_url(&format!("ws://{address}")).map_err(|err| format!("{err}"))
+        }
+    }
+
+    /// lower is better: QUIC, then plain TCP, then websocket
+    pub fn transport_rank(multi: &Multiaddr) -> u8 {
+        use libp2p::multiaddr::Protocol as P;
+        if multi.iter().any(|p| matches!(p, P::Ws(_) | P::Wss(_))) {
+            2
+        } else if multi.iter().any(|p| matches!(p, P::QuicV1 | P::Quic)) {
+            0
+        } else {
+            1
+        }
+    }
+
+    /// listen addresses ordered by preferred transport, to be dialed in order
+    pub fn preferred_addrs(&self) -> Vec<Multiaddr> {
+        let mut addrs = self.listen_addrs.clone();
+        addrs.sort_by_key(Self::transport_rank);
+        addrs
+    }
+
+    /// dial the peer on its preferred transport, each next address only once the previous failed
+    pub fn dial_opts(&self, peer: PeerId) -> DialOpts {
+        DialOpts::peer_id(peer)
+            .addresses(self.preferred_addrs())
+            .override_dial_concurrency_factor(NonZeroU8::MIN)
+            .build()
+    }
+
     pub fn to_ipc(&self) -> crate::ipc::PeerInfo {
         crate::ipc::PeerInfo {
             nickname: self.nickname.clone(),
@@ -193,10 +375,30 @@ pub enum Response {
     /// Request a file delta from this base signature
     DeltaRequest(Ino, Signature),
     // (ITree, peers, global_config)
-    FsAnswer(ITree, HashMap<PeerId, PeerInfoNet>, GlobalConfig),
+    FsAnswer(
+        ITree,
+        HashMap<PeerId, PeerInfoNet>,
+        #[serde(with = "crate::network::wire::global_config")] GlobalConfig,
+    ),
+    /// Answer to a [Request::RequestFile]
     RequestedFile(Vec<u8>),
     Success,
     Failed,
+    /// The requesting pod waits for a member's approval to join
+    AdmissionPending,
+    /// The requesting pod isn't allowed in this network
+    AccessDenied,
+    /// Answers to a [Request::Batch], in the same order
+    Batch(Vec<Response>),
+    /// The requested lock conflicts with this one
+    LockHeld(FileLock),
+    /// Operations missed by a returning peer, in the order they were applied
+    Operations(Vec<Request>),
+    /// Part of the operations missed by a returning peer, kept under the message size limit
+    /// With the sequence number to ask the next page after, if some are left
+    OperationsPage(Vec<Request>, Option<u64>),
+    /// The requesting pod was forgotten after its grace period, it has to fetch the whole tree
+    Forgotten,
 }
 
 impl fmt::Display for Response {
@@ -207,6 +409,13 @@ impl fmt::Display for Response {
             Response::RequestedFile(_) => "RequestedFile",
             Response::Success => "Success!",
             Response::Failed => "Failed...",
+            Response::AdmissionPending => "AdmissionPending",
+            Response::AccessDenied => "AccessDenied",
+            Response::Batch(_) => "Batch",
+            Response::LockHeld(_) => "LockHeld",
+            Response::Operations(_) => "Operations",
+            Response::OperationsPage(_, _) => "OperationsPage",
+            Response::Forgotten => "Forgotten",
         };
         write!(f, "{}", name)
     }
@@ -222,6 +431,21 @@ impl fmt::Debug for Response {
             Response::RequestedFile(_) => write!(f, "RequestedFile(<bin>)"),
             Response::Success => write!(f, "Succes!"),
             Response::Failed => write!(f, "Failed..."),
+            Response::AdmissionPending => write!(f, "AdmissionPending"),
+            Response::AccessDenied => write!(f, "AccessDenied"),
+            Response::Batch(responses) => write!(f, "Batch({responses:?})"),
+            Response::LockHeld(lock) => write!(f, "LockHeld({lock:?})"),
+            Response::Operations(operations) => {
+                write!(f, "Operations({} operations)", operations.len())
+            }
+            Response::OperationsPage(operations, next) => {
+                write!(
+                    f,
+                    "OperationsPage({} operations, {next:?})",
+                    operations.len()
+                )
+            }
+            Response::Forgotten => write!(f, "Forgotten"),
         }
     }
 }
@@ -231,6 +455,9 @@ impl fmt::Debug for Response {
 #[derive(Debug)]
 pub enum ToNetworkMessage {
     BroadcastMessage(Request),
+    /// Broadcast that every peer must acknowledge, retried until it does
+    /// Peers that never do are recorded as behind and caught up when they reconnect
+    AcknowledgedBroadcast(Request),
     SpecificMessage(Request, Vec<PeerId>),
     AnswerMessage(Request, oneshot::Sender<Option<Response>>, PeerId),
     CloseNetwork,
@@ -242,6 +469,9 @@ impl fmt::Display for ToNetworkMessage {
             ToNetworkMessage::BroadcastMessage(content) => {
                 write!(f, "ToNetworkMessage::BroadcastMessage({})", content)
             }
+            ToNetworkMessage::AcknowledgedBroadcast(content) => {
+                write!(f, "ToNetworkMessage::AcknowledgedBroadcast({})", content)
+            }
             ToNetworkMessage::SpecificMessage(content, peer) => {
                 write!(
                     f,
@@ -1,4 +1,6 @@
 pub mod ip;
 pub mod message;
+pub mod protocol;
+pub mod wire;
 
 pub use message::PeerInfoNet as PeerInfo;
@@ -0,0 +1,127 @@
+//! Versions of the wormhole protocol and capabilities of the peers
+//!
+//! Peers negotiate the request-response version with multistream-select: the first version
+//! of [PROTOCOL_VERSIONS] both support is used, so a mesh can be upgraded one pod at a time.
+//! Capabilities are features a peer may or may not understand within a version,
+//! they are exchanged during identify as part of the supported protocols.
+
+use std::collections::HashSet;
+
+use libp2p::StreamProtocol;
+
+use super::wire::WireVersion;
+use crate::pods::network::file_stream::FILE_PROTOCOL;
+
+/// Versions of the request-response protocol spoken by this release, newest first
+/// When the encoding of [Request](super::message::Request) or [Response](super::message::Response) changes,
+/// add the new version in front and keep the previous ones until no deployed pod uses them anymore
+/// The layout of each version is in [wire](super::wire)
+pub const PROTOCOL_VERSIONS: &[StreamProtocol] =
+    &[WireVersion::V2_0.protocol(), WireVersion::V1_0.protocol()];
+
+/// Prefix of the protocol advertising the zone of a pod, see [zone_protocol]
+const ZONE_PREFIX: &str = "/wormhole/zone/";
+
+/// Optional features a peer can understand
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Admission control, see [Request::AdmitPeer](super::message::Request::AdmitPeer)
+    Admission,
+    /// File contents on dedicated streams, see [file_stream](crate::pods::network::file_stream)
+    FileStreams,
+    /// Several operations in one message, see [Request::Batch](super::message::Request::Batch)
+    Batches,
+    /// Advisory file locks, see [Request::Lock](super::message::Request::Lock)
+    Locks,
+    /// Incremental catch-up, see [Request::RequestOperations](super::message::Request::RequestOperations)
+    OperationLog,
+    /// Parts of files sent on the file streams, see [FsInterface::pull_range](crate::pods::filesystem::fs_interface::FsInterface::pull_range)
+    RangedReads,
+    /// Checksums of whole files on the file streams, to check files pulled from several hosts
+    ParallelPulls,
+    /// Pushes answered right away when a cached copy is promoted to a replica
+    CachePromotion,
+    /// File contents compressed on the file streams, see [compression](crate::pods::network::compression)
+    Compression,
+    /// Shards of the `erasure` redundancy mode, see [erasure](crate::pods::network::erasure)
+    Shards,
+    /// Catch-ups answered in pages, see [Request::ContinueOperations](super::message::Request::ContinueOperations)
+    OperationPages,
+}
+
+impl Capability {
+    /// Capabilities of this release
+    pub const ALL: &[Capability] = &[
+        Capability::Admission,
+        Capability::FileStreams,
+        Capability::Batches,
+        Capability::Locks,
+        Capability::OperationLog,
+        Capability::RangedReads,
+        Capability::ParallelPulls,
+        Capability::CachePromotion,
+        Capability::Compression,
+        Capability::Shards,
+        Capability::OperationPages,
+    ];
+
+    /// Protocol advertised through identify for this capability
+    pub fn protocol(&self) -> StreamProtocol {
+        match self {
+            Capability::Admission => StreamProtocol::new("/wormhole/capability/admission"),
+            Capability::FileStreams => FILE_PROTOCOL,
+            Capability::Batches => StreamProtocol::new("/wormhole/capability/batches"),
+            Capability::Locks => StreamProtocol::new("/wormhole/capability/locks"),
+            Capability::OperationLog => StreamProtocol::new("/wormhole/capability/operation-log"),
+            Capability::RangedReads => StreamProtocol::new("/wormhole/capability/ranged-reads"),
+            Capability::ParallelPulls => StreamProtocol::new("/wormhole/capability/parallel-pulls"),
+            Capability::CachePromotion => {
+                StreamProtocol::new("/wormhole/capability/cache-promotion")
+            }
+            Capability::Compression => StreamProtocol::new("/wormhole/capability/compression"),
+            Capability::Shards => StreamProtocol::new("/wormhole/capability/shards"),
+            Capability::OperationPages => {
+                StreamProtocol::new("/wormhole/capability/operation-pages")
+            }
+        }
+    }
+
+    /// Capabilities with no protocol of their own, that need to be advertised
+    /// as inbound-only request-response protocols to show up in identify
+    pub fn markers() -> impl Iterator<Item = StreamProtocol> {
+        Self::ALL
+            .iter()
+            .filter(|capability| !matches!(capability, Capability::FileStreams))
+            .map(Capability::protocol)
+    }
+
+    /// Capabilities of a peer, from the protocols it announced through identify
+    pub fn from_protocols(protocols: &[StreamProtocol]) -> HashSet<Capability> {
+        Self::ALL
+            .iter()
+            .filter(|capability| protocols.contains(&capability.protocol()))
+            .copied()
+            .collect()
+    }
+}
+
+/// Version used with a peer, from the protocols it announced through identify
+/// None if the peer doesn't speak any version this release knows
+pub fn common_version(protocols: &[StreamProtocol]) -> Option<&'static StreamProtocol> {
+    PROTOCOL_VERSIONS
+        .iter()
+        .find(|version| protocols.contains(version))
+}
+
+/// Protocol advertised through identify to tell the zone of this pod, like a capability marker
+pub fn zone_protocol(zone: &str) -> Option<StreamProtocol> {
+    StreamProtocol::try_from_owned(format!("{ZONE_PREFIX}{zone}")).ok()
+}
+
+/// Zone of a peer, from the protocols it announced through identify
+pub fn zone_from_protocols(protocols: &[StreamProtocol]) -> Option<String> {
+    protocols
+        .iter()
+        .find_map(|protocol| protocol.as_ref().strip_prefix(ZONE_PREFIX))
+        .map(ToOwned::to_owned)
+}
@@ -0,0 +1,409 @@
+//! Layout of the messages in each version of the protocol
+//!
+//! Messages are encoded with bincode, which isn't self-describing: a peer only decodes the exact
+//! layout of its own release. The version negotiated with a peer is set while its messages are
+//! encoded and decoded, and the fields added by a later version are left out for it, see the
+//! `since_*` modules, so the same [Request](super::message::Request) can be sent to every peer.
+
+use std::cell::Cell;
+
+use libp2p::{PeerId, StreamProtocol};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    config::types::{GeneralGlobalConfig, GlobalConfig, RedundancyConfig},
+    pods::itree::Tombstone,
+};
+
+/// Versions of the request-response protocol, oldest first
+/// Also written in front of the trees and logs saved by the pods: once released, a version keeps its place
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum WireVersion {
+    /// First release, one message per stream
+    V1_0,
+    /// Capabilities exchanged, version vectors, tombstones and hashes of the files,
+    /// placement of the shards, compressed messages and configuration sent self-describing
+    V2_0,
+}
+
+impl WireVersion {
+    /// Versions spoken by this release, newest first
+    pub const ALL: &[WireVersion] = &[WireVersion::V2_0, WireVersion::V1_0];
+
+    /// Version of this release, used for everything that isn't sent to a peer
+    pub const CURRENT: WireVersion = WireVersion::V2_0;
+
+    pub const fn protocol(self) -> StreamProtocol {
+        match self {
+            WireVersion::V1_0 => StreamProtocol::new("/wormhole/1.0.0"),
+            WireVersion::V2_0 => StreamProtocol::new("/wormhole/2.0.0"),
+        }
+    }
+
+    /// Version negotiated as `protocol`, None if it isn't a version of this release
+    pub fn of(protocol: &StreamProtocol) -> Option<WireVersion> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|version| version.protocol() == *protocol)
+    }
+
+    /// If messages may be compressed, see [compression](crate::pods::network::compression)
+    pub fn compressed(self) -> bool {
+        self >= WireVersion::V2_0
+    }
+}
+
+thread_local! {
+    static ENCODING: Cell<WireVersion> = const { Cell::new(WireVersion::CURRENT) };
+}
+
+/// Version being encoded or decoded on this thread
+pub fn encoding() -> WireVersion {
+    ENCODING.with(Cell::get)
+}
+
+/// Restores the previous version when dropped, even if serde panics
+struct Encoding(WireVersion);
+
+impl Encoding {
+    fn set(version: WireVersion) -> Self {
+        Self(ENCODING.with(|encoding| encoding.replace(version)))
+    }
+}
+
+impl Drop for Encoding {
+    fn drop(&mut self) {
+        ENCODING.with(|encoding| encoding.set(self.0));
+    }
+}
+
+/// Encode `message` in the layout of `version`
+pub fn encode<M: Serialize>(version: WireVersion, message: &M) -> bincode::Result<Vec<u8>> {
+    let _encoding = Encoding::set(version);
+    bincode::serialize(message)
+}
+
+/// Decode a message sent in the layout of `version`
+pub fn decode<M: DeserializeOwned>(version: WireVersion, bytes: &[u8]) -> bincode::Result<M> {
+    let _encoding = Encoding::set(version);
+    bincode::deserialize(bytes)
+}
+
+/// Serde `with` module for a field added by a version
+/// The field is left out of the messages to older peers, and takes its default value when
+/// received from them. Bincode writes nothing for a unit, which keeps their layout.
+macro_rules! since {
+    ($module:ident, $version:ident) => {
+        pub mod $module {
+            use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+            pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                T: Serialize,
+                S: Serializer,
+            {
+                if super::encoding() < super::WireVersion::$version {
+                    serializer.serialize_unit()
+                } else {
+                    value.serialize(serializer)
+                }
+            }
+
+            pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+            where
+                T: Deserialize<'de> + Default,
+                D: Deserializer<'de>,
+            {
+                if super::encoding() < super::WireVersion::$version {
+                    <()>::deserialize(deserializer)?;
+                    Ok(T::default())
+                } else {
+                    T::deserialize(deserializer)
+                }
+            }
+        }
+    };
+}
+
+since!(since_2_0, V2_0);
+
+/// Tombstone of a [Request::Remove](super::message::Request::Remove), sent since 2.0
+/// A removal received from an older peer is recorded as made now by an unknown pod,
+/// its tombstone is then kept until every absent peer saw it
+pub mod tombstone_since_2_0 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        tombstone: &Tombstone,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        super::since_2_0::serialize(tombstone, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Tombstone, D::Error> {
+        if encoding() < WireVersion::V2_0 {
+            <()>::deserialize(deserializer)?;
+            Ok(Tombstone::now(PeerId::random()))
+        } else {
+            Tombstone::deserialize(deserializer)
+        }
+    }
+}
+
+/// [GlobalConfig] of a [Response::FsAnswer](super::message::Response::FsAnswer)
+/// 1.0 sends the name, entrypoints and number of copies, the other fields take their default
+/// Since 2.0 it is sent as JSON: fields unknown to a peer are skipped, and the missing ones
+/// take their default, so adding one to the configuration doesn't need a new version
+pub mod global_config {
+    use serde::{de, ser};
+
+    use super::*;
+
+    /// Configuration of 1.0
+    #[derive(Serialize, Deserialize)]
+    struct LayoutV1 {
+        general: GeneralV1,
+        redundancy: RedundancyV1,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct GeneralV1 {
+        name: String,
+        entrypoints: Vec<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct RedundancyV1 {
+        number: u64,
+    }
+
+    impl From<&GlobalConfig> for LayoutV1 {
+        fn from(config: &GlobalConfig) -> Self {
+            Self {
+                general: GeneralV1 {
+                    name: config.general.name.clone(),
+                    entrypoints: config.general.entrypoints.clone(),
+                },
+                redundancy: RedundancyV1 {
+                    number: config.redundancy.number,
+                },
+            }
+        }
+    }
+
+    impl From<LayoutV1> for GlobalConfig {
+        fn from(layout: LayoutV1) -> Self {
+            Self {
+                general: GeneralGlobalConfig {
+                    name: layout.general.name,
+                    entrypoints: layout.general.entrypoints,
+                    ..Default::default()
+                },
+                redundancy: RedundancyConfig {
+                    number: layout.redundancy.number,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }
+        }
+    }
+
+    pub fn serialize<S: Serializer>(
+        config: &GlobalConfig,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match encoding() {
+            WireVersion::V1_0 => LayoutV1::from(config).serialize(serializer),
+            WireVersion::V2_0 => serializer
+                .serialize_str(&serde_json::to_string(config).map_err(ser::Error::custom)?),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<GlobalConfig, D::Error> {
+        match encoding() {
+            WireVersion::V1_0 => LayoutV1::deserialize(deserializer).map(GlobalConfig::from),
+            WireVersion::V2_0 => {
+                serde_json::from_str(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc};
+
+    use super::*;
+    use crate::{
+        config::types::RedundancyMode,
+        network::{
+            message::{PeerInfoNet, Request, Response},
+            protocol,
+        },
+        pods::itree::{FsEntry, ITree, Inode, VersionVector},
+    };
+
+    fn file_changed() -> Request {
+        let mut inode = Inode::new(
+            "file".to_owned().try_into().unwrap(),
+            1,
+            42,
+            FsEntry::new_file(),
+            0o644,
+        );
+        inode.meta.hash = Some([7; 32]);
+        let mut version = VersionVector::default();
+        version.increment(PeerId::random());
+        Request::FileChanged(inode.id, inode.meta, version)
+    }
+
+    fn fs_answer() -> Response {
+        let mut itree = ITree::new();
+        itree.bury(42, Tombstone::now(PeerId::random()), &[PeerId::random()]);
+        let peers = HashMap::from([(
+            PeerId::random(),
+            PeerInfoNet {
+                nickname: "pod".to_owned(),
+                listen_addrs: vec!["/ip4/10.0.0.1/tcp/40000".parse().unwrap()],
+            },
+        )]);
+        let mut config = GlobalConfig::default();
+        config.general.name = "network".to_owned();
+        config.redundancy.number = 3;
+        config.redundancy.grace_period = 30;
+        config.redundancy.mode = RedundancyMode::Erasure;
+        config.network.readahead = 64;
+        config.network.compression = 9;
+        Response::FsAnswer(itree, peers, config)
+    }
+
+    #[test]
+    fn versions_exchange_fs_answers_and_file_changes() {
+        // a pod of this release meeting a pod that only speaks 1.0
+        let first = WireVersion::V1_0.protocol();
+        assert_eq!(protocol::common_version(&[first.clone()]), Some(&first));
+        assert_eq!(
+            protocol::common_version(&[first, WireVersion::CURRENT.protocol()]),
+            Some(&WireVersion::CURRENT.protocol())
+        );
+
+        for &version in WireVersion::ALL {
+            let current = version == WireVersion::CURRENT;
+
+            let sent = encode(version, &file_changed()).unwrap();
+            let Request::FileChanged(ino, meta, vector) = decode(version, &sent).unwrap() else {
+                panic!("{version:?}: not received as a FileChanged");
+            };
+            assert_eq!(ino, 42);
+            assert_eq!(meta.perm, 0o644);
+            assert_eq!(meta.hash.is_some(), current);
+            assert_eq!(vector.is_empty(), !current);
+            // and sent back as the pod of that version would
+            let answered = encode(version, &Request::FileChanged(ino, meta, vector)).unwrap();
+            assert_eq!(answered, sent, "{version:?}");
+
+            let sent = encode(version, &fs_answer()).unwrap();
+            let Response::FsAnswer(itree, peers, config) = decode(version, &sent).unwrap() else {
+                panic!("{version:?}: not received as an FsAnswer");
+            };
+            assert!(itree.get_inode(1).is_ok());
+            assert_eq!(itree.is_buried(42), current);
+            assert_eq!(peers.values().next().unwrap().nickname, "pod");
+            assert_eq!(config.general.name, "network");
+            assert_eq!(config.redundancy.number, 3);
+            let defaults = GlobalConfig::default();
+            let (grace_period, readahead, compression) = if current {
+                (30, 64, 9)
+            } else {
+                (
+                    defaults.redundancy.grace_period,
+                    defaults.network.readahead,
+                    defaults.network.compression,
+                )
+            };
+            assert_eq!(config.redundancy.grace_period, grace_period);
+            assert_eq!(config.network.readahead, readahead);
+            assert_eq!(config.network.compression, compression);
+            assert_eq!(config.redundancy.mode == RedundancyMode::Erasure, current);
+            let answered = encode(version, &Response::FsAnswer(itree, peers, config)).unwrap();
+            assert_eq!(answered.len(), sent.len(), "{version:?}");
+        }
+    }
+
+    #[test]
+    fn configurations_of_other_releases_are_read() {
+        #[derive(Serialize, Deserialize)]
+        struct Sent(#[serde(with = "global_config")] GlobalConfig);
+
+        let Response::FsAnswer(_, _, config) = fs_answer() else {
+            unreachable!()
+        };
+        let mut json: serde_json::Value = serde_json::to_value(&config).unwrap();
+        // a later release with a new field, and an older one without readahead
+        json["network"]["future_field"] = 12.into();
+        json["network"].as_object_mut().unwrap().remove("readahead");
+        let sent = bincode::serialize(&json.to_string()).unwrap();
+
+        let Sent(received) = decode(WireVersion::V2_0, &sent).unwrap();
+        assert_eq!(received.redundancy.grace_period, 30);
+        assert_eq!(received.network.compression, 9);
+        assert_eq!(
+            received.network.readahead,
+            GlobalConfig::default().network.readahead
+        );
+        assert!(received.key.is_none());
+    }
+
+    #[test]
+    fn messages_of_old_layouts_are_read() {
+        let Request::FileChanged(ino, meta, version) = file_changed() else {
+            unreachable!()
+        };
+        // FileChanged(Ino, Metadata) of 1.0.0, the metadata without its hash, the last field
+        let mut legacy_meta = bincode::serialize(&meta).unwrap();
+        legacy_meta
+            .truncate(legacy_meta.len() - bincode::serialized_size(&meta.hash).unwrap() as usize);
+        let legacy = [bincode::serialize(&(6u32, ino)).unwrap(), legacy_meta].concat();
+        let Request::FileChanged(_, received, received_version) =
+            decode(WireVersion::V1_0, &legacy).unwrap()
+        else {
+            panic!("not received as a FileChanged");
+        };
+        assert_eq!(received.mtime, meta.mtime);
+        assert!(received.hash.is_none());
+        assert!(received_version.is_empty());
+        assert_eq!(
+            encode(WireVersion::V1_0, &Request::FileChanged(ino, meta, version)).unwrap(),
+            legacy
+        );
+
+        // Remove(Ino) of 1.0.0, without a tombstone
+        let legacy = bincode::serialize(&(8u32, 42u64)).unwrap();
+        let Request::Remove(ino, _) = decode(WireVersion::V1_0, &legacy).unwrap() else {
+            panic!("not received as a Remove");
+        };
+        assert_eq!(ino, 42);
+
+        // the files exchanged in messages with the pods of 1.0.0
+        let legacy = bincode::serialize(&(7u32, 42u64)).unwrap();
+        let Request::RequestFile(ino) = decode(WireVersion::V1_0, &legacy).unwrap() else {
+            panic!("not received as a RequestFile");
+        };
+        assert_eq!(ino, 42);
+        let content = vec![1u8, 2, 3];
+        assert_eq!(
+            encode(WireVersion::V1_0, &Response::RequestedFile(content.clone())).unwrap(),
+            bincode::serialize(&(2u32, &content)).unwrap()
+        );
+        assert_eq!(
+            encode(
+                WireVersion::V1_0,
+                &Request::RedundancyFile(42, Arc::new(content.clone()))
+            )
+            .unwrap(),
+            bincode::serialize(&(1u32, 42u64, &content)).unwrap()
+        );
+    }
+}
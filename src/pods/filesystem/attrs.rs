@@ -86,7 +86,7 @@ impl FsInterface {
             }
         }
 
-        itree.get_inode_mut(ino)?.meta = meta;
+        itree.set_inode_meta(ino, meta)?;
         Ok(Response::Success)
     }
 
@@ -144,6 +144,10 @@ impl FsInterface {
                     self.disk
                         .set_file_size(&path, size as usize)
                         .map_err(|io| SetAttrError::SetFileSizeIoError { io })?;
+                    if size != meta.size {
+                        // the content changed without a new version
+                        meta.hash = None;
+                    }
                     meta.size = size;
                     meta.blocks = size.div_ceil(BLOCK_SIZE);
                 }
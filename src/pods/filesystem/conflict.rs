@@ -0,0 +1,320 @@
+use std::cmp::Ordering;
+
+use libp2p::PeerId;
+
+use crate::{
+    error::WhError,
+    network::message::{Request, ToNetworkMessage},
+    pods::{
+        filesystem::{
+            flush::FlushError, fs_interface::FsInterface, make_inode::MakeInodeError,
+            write::WriteError,
+        },
+        itree::{FsEntry, ITree, Ino, Metadata, VersionVector, BLOCK_SIZE},
+        whpath::InodeName,
+    },
+};
+
+/// How a version of a file received from a peer relates to the local one
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReceivedVersion {
+    /// Same or newer than the local version, it can be applied
+    Newer,
+    /// Already superseded locally, or losing a conflict. It must be ignored
+    Outdated,
+    /// Concurrent with the local version, and winning the conflict
+    /// `local_edit` tells if the losing local version contains changes made on this pod
+    Conflict { local_edit: bool },
+}
+
+/// Name given to the copy keeping the losing side of a conflict
+fn conflict_name(name: &str, nickname: &str, attempt: usize) -> String {
+    match attempt {
+        0 => format!("{name} (conflict from {nickname})"),
+        n => format!("{name} (conflict from {nickname} {})", n + 1),
+    }
+}
+
+/// Copies for the same file and pod can pile up, give up after this many
+const MAX_CONFLICT_COPIES: usize = 100;
+
+impl FsInterface {
+    /// Compare a received version of a file with the local one
+    ///
+    /// Concurrent versions are settled the same way on every pod: the last modified wins,
+    /// so each pod keeps or drops the same side without having to agree on it.
+    pub fn check_version(
+        &self,
+        ino: Ino,
+        meta: &Metadata,
+        version: &VersionVector,
+    ) -> Result<ReceivedVersion, FlushError> {
+        let itree = ITree::read_lock(&self.network_interface.itree, "check_version")?;
+        let inode = itree.get_inode(ino)?;
+
+        // Versions merged after a conflict are ahead of the winning side,
+        // its content is still applied when it is the one this pod settled on
+        let same_content = meta.hash.is_some() && meta.hash == inode.meta.hash;
+
+        Ok(match inode.version.compare(version) {
+            Some(Ordering::Less | Ordering::Equal) => ReceivedVersion::Newer,
+            Some(Ordering::Greater) | None if same_content => ReceivedVersion::Newer,
+            Some(Ordering::Greater) => ReceivedVersion::Outdated,
+            None => {
+                let remote_wins = meta
+                    .mtime
+                    .cmp(&inode.meta.mtime)
+                    .then_with(|| version.tie_break(&inode.version))
+                    .is_gt();
+                if remote_wins {
+                    let id = &self.network_interface.id;
+                    ReceivedVersion::Conflict {
+                        local_edit: inode.version.get(id) > version.get(id),
+                    }
+                } else {
+                    ReceivedVersion::Outdated
+                }
+            }
+        })
+    }
+
+    /// Adopt a received version of a file, once its content is accepted
+    /// It is merged into the local version, to keep counting the changes lost to a conflict
+    /// Returns true if the local version is then ahead of the received one,
+    /// see [FsInterface::announce_version]
+    pub fn set_version(&self, ino: Ino, version: VersionVector) -> Result<bool, WhError> {
+        let mut itree = ITree::write_lock(&self.network_interface.itree, "set_version")?;
+        let local = &mut itree.get_inode_mut(ino)?.version;
+        local.merge(&version);
+        Ok(*local != version)
+    }
+
+    /// Send the merged version of a file once its content is in place,
+    /// so that peers stop taking the changes it lost to a conflict as concurrent ones
+    pub fn announce_version(&self, ino: Ino) -> Result<(), WhError> {
+        let inode = ITree::read_lock(&self.network_interface.itree, "announce_version")?
+            .get_inode(ino)?
+            .clone();
+        self.network_interface
+            .to_network_message_tx
+            .send(ToNetworkMessage::BroadcastMessage(Request::FileChanged(
+                ino,
+                inode.meta,
+                inode.version,
+            )))
+            .map_err(|e| WhError::WouldBlock {
+                called_from: e.to_string(),
+            })
+    }
+
+    /// Version of a change received from `peer`
+    /// Peers older than version vectors send none: their change is taken as following
+    /// the local version, the last change wins as it does between them
+    pub fn received_version(
+        &self,
+        ino: Ino,
+        version: VersionVector,
+        peer: PeerId,
+    ) -> Result<VersionVector, WhError> {
+        if !version.is_empty() {
+            return Ok(version);
+        }
+        let mut version = ITree::read_lock(&self.network_interface.itree, "received_version")?
+            .get_inode(ino)?
+            .version
+            .clone();
+        version.increment(peer);
+        Ok(version)
+    }
+
+    /// Settle a version received from a peer before applying it
+    /// Returns false if it must be ignored
+    ///
+    /// When it wins over changes made on this pod, they are kept in a conflict copy
+    /// next to the file before being overwritten.
+    pub fn settle_version(
+        &self,
+        ino: Ino,
+        meta: &Metadata,
+        version: &VersionVector,
+    ) -> Result<bool, FlushError> {
+        match self.check_version(ino, meta, version)? {
+            ReceivedVersion::Newer => Ok(true),
+            ReceivedVersion::Outdated => {
+                log::debug!("Ignoring an outdated version of {ino}");
+                Ok(false)
+            }
+            ReceivedVersion::Conflict { local_edit } => {
+                log::warn!("Concurrent writes on {ino}, the local version lost");
+                if local_edit {
+                    self.save_conflict_copy(ino)?;
+                }
+                // From now on, the received version is the reference
+                self.set_version(ino, version.clone())?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Keep the local content of a file in a new sibling file
+    fn save_conflict_copy(&self, ino: Ino) -> Result<(), FlushError> {
+        let Some(file) = self.get_local_file(ino)? else {
+            // Only written on this pod if it is a host, nothing else to keep
+            return Ok(());
+        };
+        let (parent, name, mut meta) = {
+            let itree = ITree::read_lock(&self.network_interface.itree, "save_conflict_copy")?;
+            let inode = itree.get_inode(ino)?;
+            (inode.parent, inode.name.clone(), inode.meta.clone())
+        };
+        let nickname = self.network_interface.nickname.as_str();
+        // the pod's name isn't helpful to the user, the host's is
+        let nickname = nickname.split_once(':').map_or(nickname, |(host, _)| host);
+
+        let mut attempt = 0;
+        let copy = loop {
+            let copy_name = InodeName::try_from(conflict_name(name.as_ref(), nickname, attempt))
+                .map_err(|err| FlushError::ConflictCopyFailed {
+                    reason: err.to_string(),
+                })?;
+            match self.make_inode(parent, copy_name, meta.perm, FsEntry::File(vec![])) {
+                Ok(copy) => break copy,
+                Err(MakeInodeError::AlreadyExist) if attempt < MAX_CONFLICT_COPIES => attempt += 1,
+                Err(MakeInodeError::WhError { source }) => return Err(source.into()),
+                Err(err) => {
+                    return Err(FlushError::ConflictCopyFailed {
+                        reason: err.to_string(),
+                    })
+                }
+            }
+        };
+
+        let path = ITree::read_lock(&self.network_interface.itree, "save_conflict_copy")?
+            .get_path_from_inode_id(copy.id)?;
+        self.disk
+            .write_file(&path, &file.0, 0)
+            .map_err(WriteError::from)?;
+
+        meta.ino = copy.id;
+        meta.hash = Some(file.hash());
+        meta.size = file.0.len() as u64;
+        meta.blocks = meta.size.div_ceil(BLOCK_SIZE);
+        let hash = meta.hash;
+        self.network_interface.update_metadata(copy.id, meta)?;
+        ITree::write_lock(&self.network_interface.itree, "save_conflict_copy")?
+            .set_inode_hash(copy.id, hash)?;
+        self.network_interface.apply_redundancy(copy.id);
+
+        log::info!("Kept the local version of {name} as {}", copy.name);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use parking_lot::RwLock;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        config::GlobalConfig,
+        pods::{
+            disk_managers::unix_disk_manager::UnixDiskManager,
+            itree::ROOT,
+            network::{
+                compression::CompressionStats, network_interface::NetworkInterface,
+                operation_log::OperationLog,
+            },
+        },
+    };
+
+    #[test]
+    fn losing_local_changes_are_kept_in_a_copy() {
+        // the interfaces keep a handle on the runtime of the pod
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _runtime = runtime.enter();
+        let mountpoint = assert_fs::TempDir::new().unwrap();
+        let (network_tx, _network_rx) = mpsc::unbounded_channel();
+        let (redundancy_tx, _redundancy_rx) = mpsc::unbounded_channel();
+        let id = PeerId::random();
+        let network_interface = Arc::new(NetworkInterface::new(
+            Arc::new(RwLock::new(ITree::new())),
+            id,
+            "laptop:pod".to_owned(),
+            network_tx,
+            redundancy_tx,
+            Arc::new(RwLock::new(vec![])),
+            Arc::new(RwLock::new(GlobalConfig::default())),
+            libp2p::stream::Behaviour::new().new_control(),
+            OperationLog::default(),
+            None,
+            Arc::new(CompressionStats::default()),
+        ));
+        let fs = FsInterface::new(
+            network_interface,
+            Box::new(UnixDiskManager::new(mountpoint.path()).unwrap()),
+            mountpoint.path().to_owned(),
+            0,
+        );
+
+        let name = InodeName::try_from("notes.txt".to_owned()).unwrap();
+        let file = fs
+            .make_inode(ROOT, name, 0o644, FsEntry::File(vec![]))
+            .unwrap();
+        std::fs::write(mountpoint.path().join("notes.txt"), b"local").unwrap();
+        let local_meta = {
+            let mut itree = fs.network_interface.itree.write();
+            let inode = itree.get_inode_mut(file.id).unwrap();
+            inode.meta.size = 5;
+            inode.version.increment(id);
+            inode.meta.clone()
+        };
+
+        // written at the same time on an other pod, a bit later
+        let remote = PeerId::random();
+        let mut remote_version = VersionVector::default();
+        remote_version.increment(remote);
+        let mut remote_meta = local_meta.clone();
+        remote_meta.mtime = local_meta.mtime + Duration::from_secs(1);
+
+        assert_eq!(
+            fs.check_version(file.id, &remote_meta, &remote_version)
+                .unwrap(),
+            ReceivedVersion::Conflict { local_edit: true }
+        );
+        assert!(fs
+            .settle_version(file.id, &remote_meta, &remote_version)
+            .unwrap());
+
+        let itree = fs.network_interface.itree.read();
+        let copy = itree
+            .get_inode_child_by_name(
+                itree.get_inode(ROOT).unwrap(),
+                "notes.txt (conflict from laptop)",
+            )
+            .unwrap();
+        assert_eq!(copy.meta.size, 5);
+        let content = std::fs::read(mountpoint.path().join(copy.name.as_ref())).unwrap();
+        assert_eq!(content, b"local");
+
+        // the lost local change stays counted
+        let version = &itree.get_inode(file.id).unwrap().version;
+        assert_eq!((version.get(&id), version.get(&remote)), (1, 1));
+        drop(itree);
+
+        // an older write of the losing side is still ignored
+        let mut old_meta = local_meta;
+        old_meta.mtime = SystemTime::UNIX_EPOCH;
+        let mut old_version = VersionVector::default();
+        old_version.increment(id);
+        assert_eq!(
+            fs.check_version(file.id, &old_meta, &old_version).unwrap(),
+            ReceivedVersion::Outdated
+        );
+    }
+}
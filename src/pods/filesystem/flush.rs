@@ -10,7 +10,7 @@ use crate::{
             read::ReadError,
             write::WriteError,
         },
-        itree::{FsEntry, ITree, Ino, Metadata},
+        itree::{ContentHash, FsEntry, ITree, Ino, Metadata, VersionVector},
         network::pull_file::PullError,
     },
 };
@@ -25,9 +25,24 @@ custom_error! {
     WriteError{source: WriteError} = "{source}",
     DiffError{source: DiffError} = "{source}",
     PullError{source: PullError} = "{source}",
+    ConflictCopyFailed{reason: String} = "Couldn't keep the conflicting version: {reason}",
 }
 
 impl FsInterface {
+    /// Count a change of the file's content made on this pod, with the hash of the new content
+    /// Returns the new version and metadata
+    fn new_version(
+        &self,
+        ino: Ino,
+        hash: Option<ContentHash>,
+    ) -> Result<(VersionVector, Metadata), WhError> {
+        let mut itree = ITree::write_lock(&self.network_interface.itree, "new_version")?;
+        let inode = itree.get_inode_mut(ino)?;
+        inode.version.increment(self.network_interface.id);
+        inode.meta.hash = hash;
+        Ok((inode.version.clone(), inode.meta.clone()))
+    }
+
     /// Flush file changes to all connected peers
     /// With a handle, the changed will be first attempted with the handle's base signature
     /// the handle's signature will be flushed. The dirty flag will be reset
@@ -53,6 +68,15 @@ impl FsInterface {
             let old_sig = signature.clone();
             *signature = Signature::new(&file)?;
             *dirty = false;
+            let (version, meta) = self.new_version(ino, Some(file.hash()))?;
+            // Sent to each peer on its own, recorded once
+            self.network_interface.log_operation(&Request::FileDelta(
+                ino,
+                meta.clone(),
+                version.clone(),
+                old_sig.clone(),
+                delta.clone(),
+            ));
 
             for peer in peers.iter() {
                 if tracking.contains(peer) {
@@ -61,7 +85,8 @@ impl FsInterface {
                         .send(ToNetworkMessage::SpecificMessage(
                             Request::FileDelta(
                                 ino,
-                                inode.meta.clone(),
+                                meta.clone(),
+                                version.clone(),
                                 old_sig.clone(),
                                 delta.clone(),
                             ),
@@ -74,7 +99,7 @@ impl FsInterface {
                     self.network_interface
                         .to_network_message_tx
                         .send(ToNetworkMessage::SpecificMessage(
-                            Request::FileChanged(ino, inode.meta.clone()),
+                            Request::FileChanged(ino, meta.clone(), version.clone()),
                             vec![*peer],
                         ))
                         .map_err(|e| WhError::WouldBlock {
@@ -83,11 +108,12 @@ impl FsInterface {
                 }
             }
         } else {
+            let hash = self.get_local_file(ino)?.map(|file| file.hash());
+            let (version, meta) = self.new_version(ino, hash)?;
             self.network_interface
                 .to_network_message_tx
                 .send(ToNetworkMessage::BroadcastMessage(Request::FileChanged(
-                    ino,
-                    inode.meta.clone(),
+                    ino, meta, version,
                 )))
                 .map_err(|e| WhError::WouldBlock {
                     called_from: e.to_string(),
@@ -99,19 +125,33 @@ impl FsInterface {
     /// Apply a delta received from the network
     /// deltas are in reference to a base signature, in case of signature mismatch
     /// [Request::DeltaRequest] is emitted back to get the correct diff
+    /// Outdated versions are ignored, see [FsInterface::settle_version]
     ///
     pub fn accept_delta(
         &self,
         ino: Ino,
         meta: Metadata,
+        version: VersionVector,
         sig: Signature,
         delta: Delta,
     ) -> Result<Response, FlushError> {
         log::trace!("accept_delta({ino})");
+        if !self.settle_version(ino, &meta, &version)? {
+            return Ok(Response::Success);
+        }
+        let hash = meta.hash;
         let file = match self.get_local_file(ino)? {
             Some(file) => file,
             None => {
                 log::warn!("accept_delta: received delta but isn't currently tracking the file!");
+                self.drop_cached_copies(&[ino]);
+                // not hosting it, peers can't pull the merged version from here
+                self.set_version(ino, version)?;
+                ITree::write_lock(&self.network_interface.itree, "accept_delta")?
+                    .set_inode_hash(ino, hash)?;
+                if self.is_pinned(ino) {
+                    self.network_interface.fetch_pinned();
+                }
                 return Ok(Response::Success);
             }
         };
@@ -128,17 +168,31 @@ impl FsInterface {
                 String::from_utf8_lossy(&patched.0)
             );
 
-            let itree = ITree::read_lock(&self.network_interface.itree, "fs_interface.write")?;
-            let path = itree.get_path_from_inode_id(ino)?;
-            drop(itree);
+            let verified = meta.matches(&patched.hash());
+            if verified {
+                let itree = ITree::read_lock(&self.network_interface.itree, "fs_interface.write")?;
+                let path = itree.get_path_from_inode_id(ino)?;
+                drop(itree);
 
-            self.disk
-                .write_file(&path, &patched.0, 0)
-                .map_err(WriteError::from)?;
+                self.disk
+                    .write_file(&path, &patched.0, 0)
+                    .map_err(WriteError::from)?;
+            } else {
+                log::warn!("accept_delta: the patched {ino} doesn't match its hash, refetching it");
+            }
             self.acknowledge_metadata(ino, meta).map_err(|e| match e {
                 AcknoledgeSetAttrError::WhError { source } => FlushError::from(source),
                 AcknoledgeSetAttrError::SetFileSizeIoError { io } => WriteError::from(io).into(),
             })?;
+            // the content of the received version, or its copy refetched below
+            ITree::write_lock(&self.network_interface.itree, "accept_delta")?
+                .set_inode_hash(ino, hash)?;
+            if self.set_version(ino, version)? {
+                self.announce_version(ino)?;
+            }
+            if !verified {
+                self.network_interface.refetch(ino);
+            }
         } else {
             log::warn!("accept_delta: signature does not match local sig!");
             return Ok(Response::DeltaRequest(ino, local_sig));
@@ -147,15 +201,37 @@ impl FsInterface {
     }
 
     /// Acknowledge a file change and request the change contents if we are tracking the file
-    pub fn accept_file_changed(&self, ino: Ino, meta: Metadata) -> Result<Response, FlushError> {
+    /// Outdated versions are ignored, see [FsInterface::settle_version]
+    pub fn accept_file_changed(
+        &self,
+        ino: Ino,
+        meta: Metadata,
+        version: VersionVector,
+    ) -> Result<Response, FlushError> {
+        if !self.settle_version(ino, &meta, &version)? {
+            return Ok(Response::Success);
+        }
+        let hash = meta.hash;
         self.acknowledge_metadata(ino, meta).map_err(|e| match e {
             AcknoledgeSetAttrError::WhError { source } => FlushError::from(source),
             AcknoledgeSetAttrError::SetFileSizeIoError { io } => WriteError::from(io).into(),
         })?;
         let file = match self.get_local_file(ino)? {
             Some(file) => file,
-            None => return Ok(Response::Success),
+            None => {
+                // the cached copy is outdated
+                self.drop_cached_copies(&[ino]);
+                // not hosting it, peers can't pull the merged version from here
+                self.set_version(ino, version)?;
+                ITree::write_lock(&self.network_interface.itree, "accept_file_changed")?
+                    .set_inode_hash(ino, hash)?;
+                if self.is_pinned(ino) {
+                    self.network_interface.fetch_pinned();
+                }
+                return Ok(Response::Success);
+            }
         };
+        // The version is adopted with the content, when the requested delta is applied
         let local_sig = Signature::new(&file)?;
         Ok(Response::DeltaRequest(ino, local_sig))
     }
@@ -174,7 +250,7 @@ impl FsInterface {
         self.network_interface
             .to_network_message_tx
             .send(ToNetworkMessage::SpecificMessage(
-                Request::FileDelta(ino, inode.meta, sig, delta),
+                Request::FileDelta(ino, inode.meta, inode.version, sig, delta),
                 vec![origin],
             ))
             .map_err(|e| WhError::WouldBlock {
@@ -183,3 +259,100 @@ impl FsInterface {
         Ok(())
     }
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use std::sync::Arc;
+
+    use parking_lot::RwLock;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        config::GlobalConfig,
+        pods::{
+            disk_managers::unix_disk_manager::UnixDiskManager,
+            filesystem::File,
+            itree::ROOT,
+            network::{
+                compression::CompressionStats, network_interface::NetworkInterface,
+                operation_log::OperationLog, redundancy::RedundancyMessage,
+            },
+            whpath::InodeName,
+        },
+    };
+
+    #[test]
+    fn patches_not_matching_their_hash_are_refetched() {
+        // the interfaces keep a handle on the runtime of the pod
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _runtime = runtime.enter();
+        let mountpoint = assert_fs::TempDir::new().unwrap();
+        let (network_tx, _network_rx) = mpsc::unbounded_channel();
+        let (redundancy_tx, mut redundancy_rx) = mpsc::unbounded_channel();
+        let network_interface = Arc::new(NetworkInterface::new(
+            Arc::new(RwLock::new(ITree::new())),
+            PeerId::random(),
+            "laptop:pod".to_owned(),
+            network_tx,
+            redundancy_tx,
+            Arc::new(RwLock::new(vec![])),
+            Arc::new(RwLock::new(GlobalConfig::default())),
+            libp2p::stream::Behaviour::new().new_control(),
+            OperationLog::default(),
+            None,
+            Arc::new(CompressionStats::default()),
+        ));
+        let fs = FsInterface::new(
+            network_interface,
+            Box::new(UnixDiskManager::new(mountpoint.path()).unwrap()),
+            mountpoint.path().to_owned(),
+            0,
+        );
+
+        let name = InodeName::try_from("notes.txt".to_owned()).unwrap();
+        let file = fs
+            .make_inode(ROOT, name, 0o644, FsEntry::File(vec![]))
+            .unwrap();
+        std::fs::write(mountpoint.path().join("notes.txt"), b"local").unwrap();
+        let local = File(Arc::new(b"local".to_vec()));
+        let (meta, mut version) = {
+            let mut itree = fs.network_interface.itree.write();
+            let inode = itree.get_inode_mut(file.id).unwrap();
+            inode.meta.size = 5;
+            inode.meta.hash = Some(local.hash());
+            (inode.meta.clone(), inode.version.clone())
+        };
+        while redundancy_rx.try_recv().is_ok() {}
+
+        // a delta that doesn't give the content its hash announces
+        let sig = Signature::new(&local).unwrap();
+        let delta = sig.diff(&File(Arc::new(b"tampered".to_vec()))).unwrap();
+        let remote = File(Arc::new(b"remote".to_vec()));
+        let mut remote_meta = meta;
+        remote_meta.hash = Some(remote.hash());
+        version.increment(PeerId::random());
+
+        fs.accept_delta(file.id, remote_meta, version, sig, delta)
+            .unwrap();
+        assert_eq!(
+            std::fs::read(mountpoint.path().join("notes.txt")).unwrap(),
+            b"local"
+        );
+        assert!(matches!(
+            redundancy_rx.try_recv(),
+            Ok(RedundancyMessage::Refetch(ino)) if ino == file.id
+        ));
+        // the version is adopted, its content is fetched again
+        assert_eq!(
+            fs.network_interface
+                .itree
+                .read()
+                .get_inode(file.id)
+                .unwrap()
+                .meta
+                .hash,
+            Some(remote.hash())
+        );
+    }
+}
@@ -2,8 +2,9 @@ use crate::error::WhResult;
 use crate::network::message::Response;
 use crate::pods::disk_managers::DiskManager;
 use crate::pods::filesystem::permissions::has_execute_perm;
-use crate::pods::itree::{FsEntry, ITree, Ino, Inode, Metadata};
+use crate::pods::itree::{FsEntry, ITree, Ino, Inode, Metadata, Shards};
 use crate::pods::network::network_interface::NetworkInterface;
+use crate::pods::network::pull_file::PullLocks;
 
 use futures::io;
 use libp2p::PeerId;
@@ -15,12 +16,23 @@ use ts_rs::TS;
 
 use super::file_handle::FileHandleManager;
 use super::make_inode::MakeInodeError;
+use super::pins::Pins;
+use super::sparse_cache::SparseCache;
 
 pub struct FsInterface {
     pub network_interface: Arc<NetworkInterface>,
-    pub disk: Box<dyn DiskManager>,
+    pub disk: Arc<dyn DiskManager>,
     pub file_handles: Arc<RwLock<FileHandleManager>>,
     pub mountpoint: PathBuf,
+    /// Parts of the files read from other pods
+    pub sparse: Arc<RwLock<SparseCache>>,
+    /// Pulls running from the filesystem threads
+    pub pulls: PullLocks,
+    /// Subtrees kept available offline
+    pub pins: Arc<RwLock<Pins>>,
+    /// Tells the kernel about changes it can't see, once the filesystem is mounted
+    #[cfg(target_os = "linux")]
+    pub kernel: parking_lot::Mutex<Option<fuser::Notifier>>,
 }
 
 #[derive(PartialEq, Debug, Serialize, Deserialize, Clone, TS)]
@@ -59,12 +71,18 @@ impl FsInterface {
         network_interface: Arc<NetworkInterface>,
         disk_manager: Box<dyn DiskManager>,
         mountpoint: PathBuf,
+        cache_size: u64,
     ) -> Self {
         Self {
             network_interface,
-            disk: disk_manager,
+            disk: disk_manager.into(),
             file_handles: Arc::new(RwLock::new(FileHandleManager::new())),
             mountpoint,
+            sparse: Arc::new(RwLock::new(SparseCache::new(cache_size))),
+            pulls: PullLocks::default(),
+            pins: Arc::new(RwLock::new(Pins::default())),
+            #[cfg(target_os = "linux")]
+            kernel: parking_lot::Mutex::new(None),
         }
     }
 
@@ -97,11 +115,14 @@ impl FsInterface {
 
     // SECTION - remote -> write
     pub fn recept_inode(&self, inode: Inode) -> Result<Response, MakeInodeError> {
-        self.network_interface.acknowledge_new_file(inode.clone())?;
+        let (id, renumbered) = self.network_interface.acknowledge_new_file(inode.clone())?;
+        if let Some(to) = renumbered {
+            self.renumbered(inode.id, to)?;
+        }
 
         let new_path = {
             let itree = ITree::read_lock(&self.network_interface.itree, "recept_inode")?;
-            itree.get_path_from_inode_id(inode.id)?
+            itree.get_path_from_inode_id(id)?
         };
 
         match &inode.entry {
@@ -123,26 +144,40 @@ impl FsInterface {
             // TODO - remove when merge is handled because new file should create folder
             // FsEntry::Directory(_) => {}
         }?;
+        if matches!(inode.entry, FsEntry::File(_)) && self.is_pinned(id) {
+            self.network_interface.fetch_pinned();
+        }
         Ok(Response::Success)
     }
 
-    pub fn recept_redundancy(&self, ino: Ino, binary: Arc<Vec<u8>>) -> WhResult<Response> {
-        let itree = ITree::write_lock(&self.network_interface.itree, "recept_binary")
-            .expect("recept_binary: can't read lock itree");
-        let (path, perms) = itree
-            .get_path_from_inode_id(ino)
-            .and_then(|path| itree.get_inode(ino).map(|inode| (path, inode.meta.perm)))?;
-        drop(itree);
-
-        let _created = self.disk.new_file(&path, perms);
-        self.disk
-            .write_file(&path, &binary, 0)
-            .inspect_err(|e| log::error!("{e}: writing to {path}"))
-            .expect("disk error");
-
-        self.network_interface
-            .add_inode_hosts(ino, &[self.network_interface.id])?;
-        Ok(Response::Success)
+    /// Follow a local inode given a new number, as it duplicated a received one
+    ///
+    /// The open handles and the pins move to the new number, and the kernel forgets what it
+    /// knew of the old one, which now belongs to the received inode.
+    pub fn renumbered(&self, from: Ino, to: Ino) -> WhResult<()> {
+        for handle in FileHandleManager::write_lock(&self.file_handles, "renumbered")?
+            .handles
+            .values_mut()
+            .filter(|handle| handle.ino == from)
+        {
+            handle.ino = to;
+        }
+        self.sparse.write().renumber(from, to);
+        self.pins.write().renumber(from, to);
+
+        #[cfg(target_os = "linux")]
+        if let Some(kernel) = self.kernel.lock().as_ref() {
+            let (parent, name) = {
+                let itree = ITree::read_lock(&self.network_interface.itree, "renumbered")?;
+                let inode = itree.get_inode(to)?;
+                (inode.parent, inode.name.clone())
+            };
+            let name: &str = name.as_ref();
+            // Entries and inodes the kernel doesn't have cached are refused, nothing to forget then
+            let _ = kernel.inval_entry(parent, name.as_ref());
+            let _ = kernel.inval_inode(from, 0, 0);
+        }
+        Ok(())
     }
 
     pub fn recept_add_hosts(&self, id: Ino, hosts: &[PeerId]) -> WhResult<Response> {
@@ -161,39 +196,31 @@ impl FsInterface {
         }
 
         self.network_interface.aknowledge_hosts_removal(id, hosts)?;
+        // a pinned file stays available, as a cached copy
+        if hosts.contains(&self.network_interface.id) && self.is_pinned(id) {
+            self.network_interface.fetch_pinned();
+        }
         Ok(Response::Success)
     }
 
-    // !SECTION
-
-    // SECTION remote -> read
-    pub fn send_file(&self, inode: Ino) -> io::Result<Response> {
-        let itree = ITree::read_lock(&self.network_interface.itree, "send_itree")
-            .map_err(io::Error::other)?;
-        let path = itree
-            .get_path_from_inode_id(inode)
-            .map_err(io::Error::other)?;
-        let mut size = itree.get_inode(inode).map_err(io::Error::other)?.meta.size as usize;
-        let mut data = vec![0; size];
-        size = self.disk.read_file(&path, 0, &mut data)?;
-        data.resize(size, 0);
-        Ok(Response::RequestedFile(data))
+    pub fn recept_shards(&self, id: Ino, shards: Shards) -> WhResult<Response> {
+        let me = self.network_interface.id;
+        let held = ITree::read_lock(&self.network_interface.itree, "recept_shards")?
+            .get_inode(id)?
+            .shards
+            .as_ref()
+            .map(|old| old.held_by(&me))
+            .unwrap_or_default();
+        // the shards this pod keeps were overwritten when they were pushed again
+        let kept = shards.held_by(&me);
+        let dropped: Vec<usize> = held.into_iter().filter(|i| !kept.contains(i)).collect();
+        self.remove_shards(id, &dropped);
+
+        self.network_interface.acknowledge_shards(id, shards)?;
+        Ok(Response::Success)
     }
 
-    pub fn read_local_file(&self, inode: Ino) -> WhResult<Vec<u8>> {
-        let itree = ITree::read_lock(&self.network_interface.itree, "send_itree")?;
-        let path = itree
-            .get_path_from_inode_id(inode)
-            .map_err(|_| crate::error::WhError::InodeNotFound)?;
-        let size = itree.get_inode(inode)?.meta.size;
-        drop(itree);
-
-        let mut buff = vec![0; size as usize];
-        self.disk
-            .read_file(&path, 0, &mut buff)
-            .map_err(|_| crate::error::WhError::InodeNotFound)?;
-        Ok(buff)
-    }
+    // !SECTION
 
     //REVIEW - I don't really like to lock the arbo here, but it's the only way to get the inode countwithout just using an arbitrary high number
     /// Get complete filesystem size information including inode counts
@@ -207,16 +234,9 @@ impl FsInterface {
                 })
             })?;
         let files = itree.iter().count() as u64;
-        let next_ino = itree.next_ino.start;
-
-        let ffree = if next_ino < u64::MAX / 2 {
-            u64::MAX - next_ino
-        } else {
-            1_000_000_000
-        };
 
         disk_info.files = files;
-        disk_info.ffree = ffree;
+        disk_info.ffree = itree.free_inos();
 
         Ok(disk_info)
     }
@@ -8,7 +8,7 @@ use crate::{
             permissions::has_write_perm,
             File,
         },
-        itree::{FsEntry, ITree, Ino, Inode},
+        itree::{FsEntry, ITree, Ino, Inode, LOCAL_FOLDER_INO},
         whpath::InodeName,
     },
 };
@@ -27,6 +27,7 @@ custom_error! {pub MakeInodeError
     LocalCreationFailed{io: std::io::Error} = "Local creation failed: {io}",
     ProtectedNameIsFolder = "Protected name can't be used for folders",
     PermissionDenied = "Permission Denied",
+    Removed = "File was removed from the network",
 }
 
 custom_error! {pub CreateError
@@ -63,6 +64,7 @@ impl FsInterface {
 
         let mut file_handles = FileHandleManager::write_lock(&self.file_handles, "create")?;
         let file_handle = file_handles.insert_new_file_handle(flags, perm, inode.id, sig)?;
+        self.sparse.write().acquire(inode.id);
         Ok((inode, file_handle))
     }
 
@@ -77,6 +79,10 @@ impl FsInterface {
         mut entry: FsEntry,
     ) -> Result<Inode, MakeInodeError> {
         let special_ino = ITree::get_special(name.as_ref(), parent_ino);
+        if special_ino == Some(LOCAL_FOLDER_INO) {
+            // the folder this pod keeps for itself is always there
+            return Err(MakeInodeError::AlreadyExist);
+        }
         if let FsEntry::File(hosts) = &mut entry {
             if special_ino.is_some() {
                 return Err(MakeInodeError::ProtectedNameIsFolder);
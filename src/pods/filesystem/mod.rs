@@ -1,4 +1,5 @@
 pub mod attrs;
+pub mod conflict;
 pub mod diffs;
 pub mod file_handle;
 pub mod flush;
@@ -6,16 +7,22 @@ pub mod fs_interface;
 pub mod make_inode;
 pub mod open;
 pub mod permissions;
+pub mod pins;
 pub mod read;
 pub mod readdir;
 pub mod release;
 pub mod remove_inode;
 pub mod rename;
+pub mod sparse_cache;
 pub mod write;
 pub mod xattrs;
 
 use std::{fmt::Debug, sync::Arc};
 
+use sha2::{Digest, Sha256};
+
+use crate::pods::itree::ContentHash;
+
 #[derive(Clone)]
 pub struct File(pub Arc<Vec<u8>>);
 
@@ -23,6 +30,10 @@ impl File {
     pub fn empty() -> Self {
         Self(Arc::new(Vec::new()))
     }
+
+    pub fn hash(&self) -> ContentHash {
+        Sha256::digest(self.0.as_slice()).into()
+    }
 }
 
 impl Debug for File {
@@ -110,8 +110,8 @@ impl FsInterface {
         // libc::O_CREAT is never set, The flag is set only with the create syscall
 
         let mut file_handles = FileHandleManager::write_lock(&self.file_handles, "open")?;
-        file_handles
-            .insert_new_file_handle(flags, perm, ino, sig)
-            .map_err(|err| err.into())
+        let file_handle = file_handles.insert_new_file_handle(flags, perm, ino, sig)?;
+        self.sparse.write().acquire(ino);
+        Ok(file_handle)
     }
 }
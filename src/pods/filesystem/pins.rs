@@ -0,0 +1,198 @@
+//! Files and folders kept available offline
+//!
+//! The files of a pinned subtree are fetched into the local cache as soon as a host can send
+//! them, and again whenever they change. Their copies are never evicted from the
+//! [SparseCache](crate::pods::filesystem::sparse_cache::SparseCache) and are kept when the pod
+//! restarts. Pinning doesn't make this pod a host: the redundancy still places the replicas.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::WhResult,
+    pods::{
+        filesystem::fs_interface::FsInterface,
+        itree::{FsEntry, ITree, Ino, VersionVector, ROOT},
+    },
+};
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct Pins {
+    /// Pinned files and folders, everything under them is pinned too
+    roots: HashSet<Ino>,
+    /// Versions of the pinned copies entirely present, only filled when the pod is saved
+    copies: HashMap<Ino, VersionVector>,
+}
+
+impl Pins {
+    pub fn roots(&self) -> &HashSet<Ino> {
+        &self.roots
+    }
+
+    /// Copies kept on disk from the previous run, and the version they hold
+    pub fn copies(&self) -> &HashMap<Ino, VersionVector> {
+        &self.copies
+    }
+
+    /// If the inode or one of its parents is pinned
+    pub fn covers(&self, itree: &ITree, ino: Ino) -> bool {
+        let mut current = ino;
+        loop {
+            if self.roots.contains(&current) {
+                return true;
+            }
+            match itree.get_inode(current) {
+                Ok(inode) if current != ROOT => current = inode.parent,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Follow a pinned inode given a new number
+    pub fn renumber(&mut self, from: Ino, to: Ino) {
+        if self.roots.remove(&from) {
+            self.roots.insert(to);
+        }
+        if let Some(version) = self.copies.remove(&from) {
+            self.copies.insert(to, version);
+        }
+    }
+
+    /// Files of the pinned subtrees
+    pub fn files(&self, itree: &ITree) -> HashSet<Ino> {
+        let mut files = HashSet::new();
+        let mut pending: Vec<Ino> = self.roots.iter().copied().collect();
+        while let Some(ino) = pending.pop() {
+            match itree.get_inode(ino).map(|inode| &inode.entry) {
+                Ok(FsEntry::File(_)) => {
+                    files.insert(ino);
+                }
+                Ok(FsEntry::Directory(children)) => pending.extend(children),
+                _ => {}
+            }
+        }
+        files
+    }
+}
+
+impl FsInterface {
+    /// Pin a file or folder, its files are fetched in the background
+    pub fn pin(&self, ino: Ino) -> WhResult<()> {
+        ITree::read_lock(&self.network_interface.itree, "pin")?.get_inode(ino)?;
+        self.pins.write().roots.insert(ino);
+        self.network_interface.fetch_pinned();
+        Ok(())
+    }
+
+    /// Unpin a file or folder, returns false if it wasn't pinned itself
+    /// Its copies stay in the cache, but can be evicted again
+    pub fn unpin(&self, ino: Ino) -> WhResult<bool> {
+        let unpinned = self.pins.write().roots.remove(&ino);
+        self.protect_pinned()?;
+        Ok(unpinned)
+    }
+
+    pub fn is_pinned(&self, ino: Ino) -> bool {
+        self.pins
+            .read()
+            .covers(&self.network_interface.itree.read(), ino)
+    }
+
+    /// Shield the copies of the pinned files from eviction, returns these files
+    pub fn protect_pinned(&self) -> WhResult<HashSet<Ino>> {
+        let files = {
+            let itree = ITree::read_lock(&self.network_interface.itree, "protect_pinned")?;
+            self.pins.read().files(&itree)
+        };
+        self.sparse.write().set_pinned(files.clone());
+        Ok(files)
+    }
+
+    /// Pins to save with the pod, with the versions of the pinned copies present
+    pub fn saved_pins(&self) -> Pins {
+        let itree = self.network_interface.itree.read();
+        let pins = self.pins.read();
+        let files = pins.files(&itree);
+
+        Pins {
+            roots: pins
+                .roots
+                .iter()
+                .copied()
+                .filter(|ino| itree.get_inode(*ino).is_ok())
+                .collect(),
+            copies: self
+                .sparse
+                .read()
+                .whole_copies()
+                .filter(|(ino, _)| files.contains(ino))
+                .map(|(ino, version)| (ino, version.clone()))
+                .collect(),
+        }
+    }
+
+    /// Restore the pins saved by the previous run
+    /// The copies it left are adopted if the file didn't change since, and removed otherwise
+    pub fn restore_pins(&self, saved: Pins) -> WhResult<()> {
+        self.pins.write().roots = saved.roots;
+        self.protect_pinned()?;
+
+        let mut outdated = Vec::new();
+        for (ino, version) in saved.copies {
+            let size = {
+                let itree = ITree::read_lock(&self.network_interface.itree, "restore_pins")?;
+                match itree.get_inode(ino) {
+                    Ok(inode) if inode.version == version => Some(inode.meta.size),
+                    _ => None,
+                }
+            };
+            match size {
+                Some(size) => {
+                    self.sparse.write().insert_whole(ino, version, size);
+                }
+                None => outdated.push(ino),
+            }
+        }
+        self.drop_cached_copies(&outdated);
+        Ok(())
+    }
+}
+
+/// Fetch the pinned files missing locally, one at a time
+pub async fn fetch_pinned(fs_interface: Arc<FsInterface>) {
+    let files = match fs_interface.protect_pinned() {
+        Ok(files) => files,
+        Err(err) => {
+            log::error!("Couldn't list the pinned files: {err}");
+            return;
+        }
+    };
+    for ino in files {
+        let fs_interface = fs_interface.clone();
+        let fetched = tokio::task::spawn_blocking(move || fs_interface.cache_file(ino)).await;
+        if let Ok(Err(err)) = fetched {
+            log::warn!("Couldn't fetch the pinned file {ino}: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pins_follow_renumbered_inodes() {
+        let mut pins = Pins {
+            roots: HashSet::from([11]),
+            copies: HashMap::from([(11, VersionVector::default())]),
+        };
+        pins.renumber(11, 14);
+        pins.renumber(12, 15);
+        assert_eq!(pins.roots(), &HashSet::from([14]));
+        assert_eq!(pins.copies().keys().collect::<Vec<_>>(), vec![&14]);
+    }
+}
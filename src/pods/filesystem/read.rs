@@ -66,56 +66,36 @@ impl FsInterface {
         offset: usize,
         buf: &mut [u8],
     ) -> Result<usize, ReadError> {
-        match self.pull_file_sync(ino)? {
-            None => Ok(self.disk.read_file(
-                &ITree::read_lock(&self.network_interface.itree, "read_file")?
-                    .get_path_from_inode_id(ino)?,
-                offset,
-                buf,
-            )?),
-            Some(data) => {
-                let size = data.len().saturating_sub(offset);
-                if size > 0 {
-                    buf[..size].copy_from_slice(&data[offset..offset + size]);
-                }
-                Ok(size)
-            }
-        }
+        // the copy isn't evicted by other reads until this one is done
+        self.sparse.write().acquire(ino);
+        let read = self.read_fetched(ino, offset, buf);
+        self.sparse.write().release(ino);
+        read
     }
 
-    /// Pull the file from the network
-    /// Affects the itree and the disk
-    ///
-    /// # Panics
-    ///
-    /// This function panics if called within an asynchronous execution
-    /// context.
-    ///
-    pub fn pull_file_sync(&self, ino: Ino) -> Result<Option<Vec<u8>>, PullError> {
-        let data = match self.network_interface.pull_file(ino)? {
-            Some(data) => data,
-            None => return Ok(None),
-        };
-
-        let (path, perms) = {
-            let itree = self.network_interface.itree.read();
-
-            let path = itree.get_path_from_inode_id(ino)?;
-            let inode = itree.get_inode(ino)?;
-            (path, inode.meta.perm)
-        };
-
-        let _created = self.disk.new_file(&path, perms);
-
-        self.disk
-            .write_file(&path, &data, 0)
-            .inspect_err(|e| log::error!("writing pulled file: {e}"))
-            .map_err(|e| PullError::WriteError { io: Arc::new(e) })?;
+    /// Fetch what the read needs if the file isn't hosted here, then read it from the disk
+    fn read_fetched(&self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize, ReadError> {
+        if !self.pull_range(ino, offset as u64, buf.len() as u64)? {
+            self.cache_file(ino)?;
+        }
+        Ok(self.disk.read_file(
+            &ITree::read_lock(&self.network_interface.itree, "read_file")?
+                .get_path_from_inode_id(ino)?,
+            offset,
+            buf,
+        )?)
+    }
 
+    /// If the content of the file is on this pod, so reading it doesn't need the network
+    pub fn is_stored_locally(&self, ino: Ino) -> bool {
         self.network_interface
-            .add_inode_hosts(ino, &[self.network_interface.id])
-            .expect("can't update inode hosts");
-        Ok(Some(data))
+            .itree
+            .read()
+            .get_inode(ino)
+            .is_ok_and(|inode| match &inode.entry {
+                FsEntry::File(hosts) => hosts.contains(&self.network_interface.id),
+                _ => false,
+            })
     }
 
     /// Get or pull the file from storage or network
@@ -126,12 +106,9 @@ impl FsInterface {
     /// context.
     ///
     pub fn get_whole_file_sync(&self, ino: Ino) -> Result<File, ReadError> {
-        match self.pull_file_sync(ino)? {
-            None => self
-                .get_local_file(ino)
-                .map(|o| o.expect("promised by pull_file_sync")),
-            Some(data) => Ok(File(Arc::new(data))),
-        }
+        self.pull_file(ino)?;
+        self.get_local_file(ino)
+            .map(|o| o.expect("promised by pull_file"))
     }
 
     /// Get locally stored file as-is if it exists without accessing the network
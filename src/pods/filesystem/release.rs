@@ -9,6 +9,7 @@ impl FsInterface {
     pub fn release(&self, file_handle: UUID) -> Result<(), FlushError> {
         let mut file_handles = FileHandleManager::write_lock(&self.file_handles, "release")?;
         if let Some(mut handle) = file_handles.handles.remove(&file_handle) {
+            self.sparse.write().release(handle.ino);
             if handle.dirty {
                 self.flush(handle.ino, Some(&mut handle))?;
                 self.network_interface.apply_redundancy(handle.ino);
@@ -4,7 +4,7 @@ use crate::pods::{filesystem::permissions::has_write_perm, whpath::InodeName};
 use crate::{
     error::WhError,
     network::message::Response,
-    pods::itree::{FsEntry, ITree, Ino},
+    pods::itree::{FsEntry, ITree, Ino, Tombstone},
 };
 use custom_error::custom_error;
 
@@ -57,8 +57,16 @@ impl FsInterface {
     pub fn remove_inode_locally(&self, id: Ino) -> Result<(), RemoveFileError> {
         let itree = ITree::read_lock(&self.network_interface.itree, "fs_interface::remove_inode")?;
         let to_remove_path = itree.get_path_from_inode_id(id)?;
-        let entry = itree.get_inode(id)?.entry.to_owned();
+        let inode = itree.get_inode(id)?;
+        let entry = inode.entry.to_owned();
+        let shards = inode
+            .shards
+            .as_ref()
+            .map(|shards| shards.held_by(&self.network_interface.id))
+            .unwrap_or_default();
         drop(itree);
+        self.sparse.write().forget(id);
+        self.remove_shards(id, &shards);
 
         match entry {
             FsEntry::File(hosts) if hosts.contains(&self.network_interface.id) => self
@@ -96,9 +104,14 @@ impl FsInterface {
         Ok(())
     }
 
-    pub fn recept_remove_inode(&self, id: Ino) -> Result<Response, RemoveFileError> {
+    pub fn recept_remove_inode(
+        &self,
+        id: Ino,
+        tombstone: Tombstone,
+    ) -> Result<Response, RemoveFileError> {
         self.remove_inode_locally(id)?;
-        self.network_interface.acknowledge_unregister_inode(id)?;
+        self.network_interface
+            .acknowledge_unregister_inode(id, tombstone)?;
         Ok(Response::Success)
     }
 }
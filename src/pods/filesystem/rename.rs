@@ -7,7 +7,7 @@ use crate::{
     network::message::Response,
     pods::{
         filesystem::{flush::FlushError, permissions::has_write_perm},
-        itree::{FsEntry, ITree, Ino, Metadata},
+        itree::{FsEntry, ITree, Ino, Metadata, Tombstone},
         whpath::{InodeName, WhPath},
     },
 };
@@ -125,6 +125,9 @@ impl FsInterface {
                     MakeInodeError::PermissionDenied => RenameError::LocalRenamingFailed {
                         io: std::io::ErrorKind::PermissionDenied.into(),
                     },
+                    MakeInodeError::Removed => RenameError::WhError {
+                        source: WhError::InodeNotFound,
+                    },
                 })?
                 .id
         };
@@ -211,14 +214,15 @@ impl FsInterface {
 
         if let Some(dest_ino) = dest_ino {
             log::debug!("overwriting!!");
-            self.recept_remove_inode(dest_ino).map_err(|e| match e {
-                RemoveFileError::LocalDeletionFailed { io } => {
-                    RenameError::LocalOverwriteFailed { io }
-                }
-                RemoveFileError::NonEmpty => RenameError::OverwriteNonEmpty,
-                RemoveFileError::WhError { source } => RenameError::WhError { source },
-                RemoveFileError::PermissionDenied => RenameError::PermissionDenied,
-            })?;
+            self.recept_remove_inode(dest_ino, Tombstone::now(self.network_interface.id))
+                .map_err(|e| match e {
+                    RemoveFileError::LocalDeletionFailed { io } => {
+                        RenameError::LocalOverwriteFailed { io }
+                    }
+                    RemoveFileError::NonEmpty => RenameError::OverwriteNonEmpty,
+                    RemoveFileError::WhError { source } => RenameError::WhError { source },
+                    RemoveFileError::PermissionDenied => RenameError::PermissionDenied,
+                })?;
         }
 
         self.rename_locally(parent, new_parent, &name, &new_name)?;
@@ -246,14 +250,15 @@ impl FsInterface {
         if let Some(dest_ino) = dest_ino {
             if overwrite {
                 log::debug!("overwriting!!");
-                self.recept_remove_inode(dest_ino).map_err(|e| match e {
-                    RemoveFileError::LocalDeletionFailed { io } => {
-                        RenameError::LocalOverwriteFailed { io }
-                    }
-                    RemoveFileError::NonEmpty => RenameError::OverwriteNonEmpty,
-                    RemoveFileError::WhError { source } => RenameError::WhError { source },
-                    RemoveFileError::PermissionDenied => RenameError::PermissionDenied,
-                })?;
+                self.recept_remove_inode(dest_ino, Tombstone::now(self.network_interface.id))
+                    .map_err(|e| match e {
+                        RemoveFileError::LocalDeletionFailed { io } => {
+                            RenameError::LocalOverwriteFailed { io }
+                        }
+                        RemoveFileError::NonEmpty => RenameError::OverwriteNonEmpty,
+                        RemoveFileError::WhError { source } => RenameError::WhError { source },
+                        RemoveFileError::PermissionDenied => RenameError::PermissionDenied,
+                    })?;
             } else {
                 log::debug!("not overwriting!!");
                 return Err(RenameError::DestinationExists);
@@ -0,0 +1,347 @@
+//! Copies of remote files stored locally
+//!
+//! A pod reading a file it doesn't host only fetches the blocks it reads, see
+//! [FsInterface::pull_range](crate::pods::filesystem::fs_interface::FsInterface::pull_range),
+//! or the whole file when its hosts can't send parts of it.
+//! They are written at their place in a sparse file, and this cache remembers which ranges
+//! are present. The pod doesn't become a host of the file for that: the copy is only promoted
+//! to a replica when the redundancy places one on this pod.
+//! The ranges are forgotten as soon as the file changes, or once it is hosted here.
+//! The cache is bounded, the least recently read files are evicted first.
+//! Copies of pinned files are never evicted, see [Pins](crate::pods::filesystem::pins::Pins),
+//! nor those of files open or being read.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    time::Instant,
+};
+
+use crate::pods::itree::{Ino, VersionVector};
+
+/// Default bound of the cache, in MiB
+pub const DEFAULT_CACHE_SIZE: u64 = 1024;
+
+/// Sorted and disjoint ranges of bytes
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct RangeSet(Vec<Range<u64>>);
+
+impl RangeSet {
+    /// Add a range, merging it with the ranges it touches
+    pub fn insert(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+        let first = self.0.partition_point(|held| held.end < range.start);
+        let last = self.0.partition_point(|held| held.start <= range.end);
+
+        let touched = &self.0[first..last];
+        let merged = match (touched.first(), touched.last()) {
+            (Some(first), Some(last)) => first.start.min(range.start)..last.end.max(range.end),
+            _ => range,
+        };
+        self.0.splice(first..last, [merged]);
+    }
+
+    /// Number of bytes in the set
+    pub fn bytes(&self) -> u64 {
+        self.0.iter().map(|range| range.end - range.start).sum()
+    }
+
+    /// Parts of `range` not in the set
+    pub fn missing(&self, range: Range<u64>) -> Vec<Range<u64>> {
+        let mut missing = Vec::new();
+        let mut start = range.start;
+
+        for held in &self.0 {
+            if held.end <= start {
+                continue;
+            }
+            if held.start >= range.end {
+                break;
+            }
+            if held.start > start {
+                missing.push(start..held.start);
+            }
+            start = held.end;
+        }
+        if start < range.end {
+            missing.push(start..range.end);
+        }
+        missing
+    }
+}
+
+/// Ranges of a file present locally, valid for one version of it
+#[derive(Debug)]
+struct CachedFile {
+    version: VersionVector,
+    size: u64,
+    ranges: RangeSet,
+    last_used: Instant,
+}
+
+#[derive(Debug)]
+pub struct SparseCache {
+    files: HashMap<Ino, CachedFile>,
+    /// Bytes the cached copies can take on the disk
+    capacity: u64,
+    /// Files whose copies are kept whatever the bound
+    pinned: HashSet<Ino>,
+    /// Open handles and running reads of the files, their copies can't be removed under them
+    in_use: HashMap<Ino, usize>,
+}
+
+impl Default for SparseCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_SIZE * 1024 * 1024)
+    }
+}
+
+impl SparseCache {
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            files: HashMap::new(),
+            capacity,
+            pinned: HashSet::new(),
+            in_use: HashMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Bytes taken by the cached copies
+    pub fn used(&self) -> u64 {
+        self.files
+            .values()
+            .map(|cached| cached.ranges.bytes())
+            .sum()
+    }
+
+    /// Parts of `range` that must be fetched to read this version of the file
+    /// The first value is true if nothing of this version is present yet:
+    /// the local copy must then be resized, as what it contains is outdated
+    pub fn missing(
+        &mut self,
+        ino: Ino,
+        version: &VersionVector,
+        size: u64,
+        range: Range<u64>,
+    ) -> (bool, Vec<Range<u64>>) {
+        match self.files.get_mut(&ino) {
+            Some(cached) if cached.version == *version && cached.size == size => {
+                cached.last_used = Instant::now();
+                (false, cached.ranges.missing(range))
+            }
+            _ => {
+                self.files.insert(
+                    ino,
+                    CachedFile {
+                        version: version.clone(),
+                        size,
+                        ranges: RangeSet::default(),
+                        last_used: Instant::now(),
+                    },
+                );
+                (true, vec![range])
+            }
+        }
+    }
+
+    /// Record that a range of the file was fetched
+    /// Returns the files evicted to make room for it, their local copies must be removed
+    pub fn insert(&mut self, ino: Ino, range: Range<u64>) -> Vec<Ino> {
+        match self.files.get_mut(&ino) {
+            Some(cached) => cached.ranges.insert(range),
+            None => return vec![],
+        }
+        self.evict(ino)
+    }
+
+    /// Record that this version of the file was fetched whole
+    /// Returns the files evicted to make room for it, their local copies must be removed
+    pub fn insert_whole(&mut self, ino: Ino, version: VersionVector, size: u64) -> Vec<Ino> {
+        let mut ranges = RangeSet::default();
+        ranges.insert(0..size);
+        self.files.insert(
+            ino,
+            CachedFile {
+                version,
+                size,
+                ranges,
+                last_used: Instant::now(),
+            },
+        );
+        self.evict(ino)
+    }
+
+    /// If this version of the file is entirely present
+    pub fn is_whole(&self, ino: Ino, version: &VersionVector) -> bool {
+        self.files.get(&ino).is_some_and(|cached| {
+            cached.version == *version && cached.ranges.missing(0..cached.size).is_empty()
+        })
+    }
+
+    /// Versions of the files entirely present
+    pub fn whole_copies(&self) -> impl Iterator<Item = (Ino, &VersionVector)> {
+        self.files
+            .iter()
+            .filter(|(_, cached)| cached.ranges.missing(0..cached.size).is_empty())
+            .map(|(ino, cached)| (*ino, &cached.version))
+    }
+
+    /// Replace the files protected from eviction
+    pub fn set_pinned(&mut self, files: HashSet<Ino>) {
+        self.pinned = files;
+    }
+
+    /// A handle is opened on the file or a read starts, its copy is kept until [Self::release]
+    pub fn acquire(&mut self, ino: Ino) {
+        *self.in_use.entry(ino).or_default() += 1;
+    }
+
+    /// A handle of the file is released or a read is done
+    pub fn release(&mut self, ino: Ino) {
+        if let Some(count) = self.in_use.get_mut(&ino) {
+            *count -= 1;
+            if *count == 0 {
+                self.in_use.remove(&ino);
+            }
+        }
+    }
+
+    /// Drop the least recently used files until the cache fits,
+    /// except `keep`, the pinned ones and those in use
+    /// Pinned and used copies still count in the used space
+    fn evict(&mut self, keep: Ino) -> Vec<Ino> {
+        let mut used = self.used();
+        let mut candidates: Vec<(Instant, Ino, u64)> = self
+            .files
+            .iter()
+            .filter(|(ino, _)| {
+                **ino != keep && !self.pinned.contains(ino) && !self.in_use.contains_key(ino)
+            })
+            .map(|(ino, cached)| (cached.last_used, *ino, cached.ranges.bytes()))
+            .collect();
+        candidates.sort_unstable();
+
+        let mut evicted = Vec::new();
+        for (_, ino, len) in candidates {
+            if used <= self.capacity {
+                break;
+            }
+            self.files.remove(&ino);
+            used -= len;
+            evicted.push(ino);
+        }
+        evicted
+    }
+
+    /// Stop tracking a file, when it is removed, changed or hosted here
+    pub fn forget(&mut self, ino: Ino) {
+        self.files.remove(&ino);
+    }
+
+    /// Follow a file given a new number
+    pub fn renumber(&mut self, from: Ino, to: Ino) {
+        if let Some(file) = self.files.remove(&from) {
+            self.files.insert(to, file);
+        }
+        if self.pinned.remove(&from) {
+            self.pinned.insert(to);
+        }
+        if let Some(count) = self.in_use.remove(&from) {
+            self.in_use.insert(to, count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ranges_are_merged() {
+        let mut set = RangeSet::default();
+        set.insert(10..20);
+        set.insert(30..40);
+        set.insert(20..25);
+        assert_eq!(set, RangeSet(vec![10..25, 30..40]));
+
+        set.insert(0..35);
+        assert_eq!(set, RangeSet(vec![0..40]));
+    }
+
+    #[test]
+    fn missing_ranges() {
+        let mut set = RangeSet::default();
+        set.insert(10..20);
+        set.insert(30..40);
+
+        assert_eq!(set.missing(0..50), vec![0..10, 20..30, 40..50]);
+        assert_eq!(set.missing(12..18), vec![]);
+        assert_eq!(set.missing(15..35), vec![20..30]);
+    }
+
+    #[test]
+    fn changed_files_start_over() {
+        let mut cache = SparseCache::default();
+        let mut version = VersionVector::default();
+
+        assert_eq!(cache.missing(5, &version, 100, 0..50), (true, vec![0..50]));
+        cache.insert(5, 0..50);
+        assert_eq!(
+            cache.missing(5, &version, 100, 0..100),
+            (false, vec![50..100])
+        );
+
+        version.increment(libp2p::PeerId::random());
+        assert_eq!(cache.missing(5, &version, 100, 0..50), (true, vec![0..50]));
+    }
+
+    #[test]
+    fn least_recently_used_files_are_evicted() {
+        let mut cache = SparseCache::new(100);
+        let version = VersionVector::default();
+
+        assert_eq!(cache.insert_whole(1, version.clone(), 40), vec![]);
+        assert_eq!(cache.insert_whole(2, version.clone(), 40), vec![]);
+        cache.missing(1, &version, 40, 0..40);
+        assert_eq!(cache.insert_whole(3, version.clone(), 40), vec![2]);
+        assert!(cache.is_whole(1, &version));
+        assert!(!cache.is_whole(2, &version));
+        assert_eq!(cache.used(), 80);
+    }
+
+    #[test]
+    fn pinned_files_are_not_evicted() {
+        let mut cache = SparseCache::new(100);
+        let version = VersionVector::default();
+        cache.set_pinned(HashSet::from([1]));
+
+        assert_eq!(cache.insert_whole(1, version.clone(), 40), vec![]);
+        assert_eq!(cache.insert_whole(2, version.clone(), 40), vec![]);
+        assert_eq!(cache.insert_whole(3, version.clone(), 40), vec![2]);
+        assert!(cache.is_whole(1, &version));
+        assert_eq!(cache.whole_copies().count(), 2);
+    }
+
+    #[test]
+    fn files_in_use_are_not_evicted() {
+        let mut cache = SparseCache::new(100);
+        let version = VersionVector::default();
+
+        assert_eq!(cache.insert_whole(1, version.clone(), 40), vec![]);
+        assert_eq!(cache.insert_whole(2, version.clone(), 40), vec![]);
+        cache.acquire(1);
+        cache.acquire(1);
+        assert_eq!(cache.insert_whole(3, version.clone(), 40), vec![2]);
+
+        cache.release(1);
+        assert_eq!(cache.insert_whole(4, version.clone(), 40), vec![3]);
+        cache.release(1);
+        assert_eq!(cache.insert_whole(5, version.clone(), 40), vec![1]);
+    }
+}
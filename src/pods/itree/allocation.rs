@@ -0,0 +1,161 @@
+//! Allocation of inode numbers without coordination between pods
+//!
+//! The upper bits of an [Ino] are a namespace owned by the pod that allocated it,
+//! derived from its [PeerId]. Pods creating files at the same moment then pick
+//! numbers from different ranges and can't give out the same one.
+//!
+//! Namespace 0 holds the special inodes and the ones of trees made before namespaces.
+//! A duplicate can still show up from those, or if two pods get the same namespace:
+//! every pod then renumbers the loser of the two the same way, see [ITree::settle_duplicate].
+
+use libp2p::PeerId;
+
+use crate::{
+    error::{WhError, WhResult},
+    pods::itree::{FsEntry, ITree, Ino, Inode, FIRST_INO},
+};
+
+/// Bits of an [Ino] counting the inodes of a namespace
+const NAMESPACE_SHIFT: u32 = 40;
+
+const COUNTER_MASK: Ino = (1 << NAMESPACE_SHIFT) - 1;
+
+/// Namespace of the inodes renumbered after a duplicate
+const RENUMBERED_NAMESPACE: Ino = Ino::MAX >> NAMESPACE_SHIFT;
+
+pub fn namespace_of(ino: Ino) -> Ino {
+    ino >> NAMESPACE_SHIFT
+}
+
+/// Namespace in which a pod allocates its inodes, never 0 nor [RENUMBERED_NAMESPACE]
+pub fn peer_namespace(peer: &PeerId) -> Ino {
+    // A PeerId ends with a hash of the pod's key, its last bytes are as good as random
+    let bytes = peer.to_bytes();
+    let tail = bytes
+        .iter()
+        .rev()
+        .take(3)
+        .fold(0, |namespace, byte| namespace << 8 | *byte as Ino);
+
+    tail % (RENUMBERED_NAMESPACE - 1) + 1
+}
+
+/// Stable hash of an inode, the same on every pod and across versions (FNV-1a)
+fn identity_hash(inode: &Inode) -> Ino {
+    let crtime = inode
+        .meta
+        .crtime
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|crtime| crtime.as_nanos())
+        .unwrap_or_default();
+
+    inode
+        .id
+        .to_le_bytes()
+        .iter()
+        .chain(crtime.to_le_bytes().iter())
+        .chain(inode.name.as_ref().as_bytes())
+        .fold(0xcbf29ce484222325, |hash: u64, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+impl ITree {
+    /// Allocate the next inodes in the namespace of `peer`, after the ones it already has in the tree
+    /// Must be called whenever the tree is replaced, as the allocation state isn't shared
+    pub fn claim_namespace(&mut self, peer: &PeerId) {
+        let namespace = peer_namespace(peer);
+        let start = namespace << NAMESPACE_SHIFT;
+        let next = self
+            .entries
+            .keys()
+            .filter(|ino| namespace_of(**ino) == namespace)
+            .max()
+            .map_or(start.max(FIRST_INO), |last| last + 1);
+
+        self.next_ino = next..;
+    }
+
+    /// Number of inodes this pod can still allocate
+    pub fn free_inos(&self) -> u64 {
+        COUNTER_MASK - (self.next_ino.start & COUNTER_MASK)
+    }
+
+    /// If `inode` comes from an other pod but its number is already used by an other inode
+    /// Special inodes exist on every pod and are never duplicates
+    pub fn is_duplicate(&self, inode: &Inode) -> bool {
+        !ITree::is_special(inode.id)
+            && self
+                .entries
+                .get(&inode.id)
+                .is_some_and(|local| local.meta.crtime != inode.meta.crtime)
+    }
+
+    /// Settle a duplicated inode number, before adding `inode` to the tree
+    ///
+    /// The last created of the two inodes is renumbered, to a number computed from
+    /// the inode itself, so every pod seeing the duplicate ends up with the same tree.
+    /// Returns the number `inode` must be added with, and the new number of the local inode
+    /// if it is the one renumbered.
+    pub fn settle_duplicate(&mut self, inode: &Inode) -> WhResult<(Ino, Option<Ino>)> {
+        let local = self.get_inode(inode.id)?;
+        let local_loses = (local.meta.crtime, local.name.as_ref(), local.parent)
+            > (inode.meta.crtime, inode.name.as_ref(), inode.parent);
+
+        if local_loses {
+            let renumbered = self.renumbered_ino(local);
+            log::warn!(
+                "Inode {} was also given to {}, renumbering the local one to {renumbered}",
+                inode.id,
+                inode.name
+            );
+            self.renumber(inode.id, renumbered)?;
+            Ok((inode.id, Some(renumbered)))
+        } else {
+            let renumbered = self.renumbered_ino(inode);
+            log::warn!(
+                "Inode {} received for {} is already used, renumbering it to {renumbered}",
+                inode.id,
+                inode.name
+            );
+            Ok((renumbered, None))
+        }
+    }
+
+    fn renumbered_ino(&self, inode: &Inode) -> Ino {
+        let mut counter = identity_hash(inode) & COUNTER_MASK;
+
+        loop {
+            let ino = RENUMBERED_NAMESPACE << NAMESPACE_SHIFT | counter;
+            if !self.entries.contains_key(&ino) && !self.is_buried(ino) {
+                return ino;
+            }
+            counter = (counter + 1) & COUNTER_MASK;
+        }
+    }
+
+    /// Give a new number to an inode, updating its parent and children
+    ///
+    /// Only the tree is updated, the owner of the tree moves the rest
+    /// see [FsInterface::renumbered](crate::pods::filesystem::fs_interface::FsInterface::renumbered)
+    fn renumber(&mut self, from: Ino, to: Ino) -> WhResult<()> {
+        let inode = self.get_inode(from)?;
+        let parent = inode.parent;
+        let children = inode.entry.get_children().cloned().unwrap_or_default();
+
+        for child in children {
+            self.get_inode_mut(child)?.parent = to;
+        }
+        if let FsEntry::Directory(siblings) = &mut self.get_inode_mut(parent)?.entry {
+            for sibling in siblings.iter_mut().filter(|sibling| **sibling == from) {
+                *sibling = to;
+            }
+        }
+
+        let mut inode = self.entries.remove(&from).ok_or(WhError::InodeNotFound)?;
+        inode.id = to;
+        inode.meta.ino = to;
+        self.entries.insert(to, inode);
+        Ok(())
+    }
+}
@@ -1,4 +1,9 @@
-use std::{io, path::Path};
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
 use libp2p::PeerId;
 
@@ -7,8 +12,8 @@ use crate::{
     pods::{
         disk_managers::DiskManager,
         itree::{
-            index_folder_recursive, FsEntry, ITree, Ino, GLOBAL_CONFIG_FNAME, GLOBAL_CONFIG_INO,
-            ROOT,
+            index_entry, index_folder_recursive, FsEntry, ITree, Ino, BLOCK_SIZE,
+            GLOBAL_CONFIG_FNAME, GLOBAL_CONFIG_INO, LOCAL_FOLDER, ROOT,
         },
         whpath::WhPath,
     },
@@ -16,11 +21,158 @@ use crate::{
 
 pub fn generate_itree(mountpoint: &Path, host: &PeerId) -> io::Result<ITree> {
     let mut itree = ITree::new();
+    itree.claim_namespace(host);
 
     index_folder_recursive(&mut itree, ROOT, mountpoint, host, mountpoint)?;
     Ok(itree)
 }
 
+/// Disk and tree times of a file written through the pod differ slightly,
+/// it was only changed behind its back past this
+const MTIME_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// Changes made on disk while the pod was stopped, found by [reconcile_itree]
+#[derive(Default, Debug)]
+pub struct Reconciliation {
+    /// New files and folders, parents first
+    pub added: Vec<Ino>,
+    /// Files hosted here whose content changed
+    pub changed: Vec<Ino>,
+    /// Files that were hosted here but are gone
+    pub lost: Vec<Ino>,
+    /// Copies of files hosted elsewhere, cached by the previous run
+    cached: Vec<(Ino, PathBuf)>,
+}
+
+/// Bring a saved tree up to date with what is actually on disk
+///
+/// Unknown entries are indexed, hosted files changed on disk get a new version
+/// and this pod stops hosting the ones that disappeared.
+/// Copies of files not hosted here are removed, except the `kept` ones.
+/// Everything else is kept as saved: hosts of the other files, xattrs, inode numbers.
+pub fn reconcile_itree(
+    itree: &mut ITree,
+    mountpoint: &Path,
+    host: &PeerId,
+    kept: &HashSet<Ino>,
+) -> io::Result<Reconciliation> {
+    itree.claim_namespace(host);
+
+    let mut reconciliation = Reconciliation::default();
+    let mut found = HashSet::new();
+    reconcile_folder(
+        itree,
+        ROOT,
+        mountpoint,
+        host,
+        mountpoint,
+        &mut found,
+        &mut reconciliation,
+    )?;
+
+    // the cache starts empty, only the kept copies are adopted again
+    for (ino, path) in reconciliation.cached.drain(..) {
+        if !kept.contains(&ino) {
+            log::debug!("Removing the cached copy {path:?}");
+            fs::remove_file(path)?;
+        }
+    }
+
+    for inode in itree.inodes_mut() {
+        if let FsEntry::File(hosts) = &mut inode.entry {
+            if hosts.contains(host) && !found.contains(&inode.id) && !ITree::is_special(inode.id) {
+                hosts.retain(|hosting| hosting != host);
+                reconciliation.lost.push(inode.id);
+            }
+        }
+    }
+    Ok(reconciliation)
+}
+
+fn reconcile_folder(
+    itree: &mut ITree,
+    parent: Ino,
+    path: &Path,
+    host: &PeerId,
+    mountpoint: &Path,
+    found: &mut HashSet<Ino>,
+    reconciliation: &mut Reconciliation,
+) -> io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        let name = entry.file_name();
+        if parent == ROOT && name == LOCAL_FOLDER {
+            continue;
+        }
+
+        let known = itree
+            .get_inode(parent)
+            .and_then(|parent| itree.get_inode_child_by_name(parent, &name.to_string_lossy()))
+            .map(|inode| inode.id)
+            .ok();
+
+        let Some(ino) = known else {
+            let ino = index_entry(itree, parent, &entry, host, mountpoint)?;
+            found.insert(ino);
+            reconciliation.added.push(ino);
+            if meta.is_dir() {
+                reconcile_folder(
+                    itree,
+                    ino,
+                    &entry.path(),
+                    host,
+                    mountpoint,
+                    found,
+                    reconciliation,
+                )?;
+            }
+            continue;
+        };
+        found.insert(ino);
+
+        let (is_dir, is_file, hosted) = match &itree.get_inode(ino)?.entry {
+            FsEntry::Directory(_) => (true, false, false),
+            FsEntry::File(hosts) => (false, true, hosts.contains(host)),
+            FsEntry::Symlink(_) => (false, false, false),
+        };
+        if is_dir != meta.is_dir() {
+            log::warn!(
+                "Ignoring {:?}, its kind changed since the tree was saved",
+                entry.path()
+            );
+        } else if is_dir {
+            reconcile_folder(
+                itree,
+                ino,
+                &entry.path(),
+                host,
+                mountpoint,
+                found,
+                reconciliation,
+            )?;
+        } else if hosted && !ITree::is_special(ino) {
+            let inode = itree.get_inode_mut(ino)?;
+            let mtime = meta.modified()?;
+            let modified = mtime > inode.meta.mtime + MTIME_TOLERANCE;
+
+            if modified || meta.len() != inode.meta.size {
+                inode.meta.size = meta.len();
+                inode.meta.blocks = meta.len().div_ceil(BLOCK_SIZE);
+                inode.meta.mtime = mtime;
+                inode.meta.ctime = SystemTime::now();
+                // changed while the pod was down, the hash is known again at the next flush
+                inode.meta.hash = None;
+                inode.version.increment(*host);
+                reconciliation.changed.push(ino);
+            }
+        } else if is_file && !hosted && !ITree::is_special(ino) {
+            reconciliation.cached.push((ino, entry.path()));
+        }
+    }
+    Ok(())
+}
+
 pub fn initiate_itree(
     itree: &ITree,
     global: &GlobalConfig,
@@ -9,7 +9,7 @@ use ts_rs::TS;
 
 use crate::pods::{
     filesystem::fs_interface::SimpleFileType,
-    itree::{FsEntry, BLOCK_SIZE},
+    itree::{FsEntry, Shards, VersionVector, BLOCK_SIZE},
     whpath::InodeName,
 };
 
@@ -22,6 +22,9 @@ pub const ROOT: Ino = 1;
 
 pub type XAttrs = HashMap<String, Vec<u8>>;
 
+/// SHA-256 of the content of a file
+pub type ContentHash = [u8; 32];
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Inode {
     pub parent: Ino,
@@ -30,6 +33,12 @@ pub struct Inode {
     pub entry: FsEntry,
     pub meta: Metadata,
     pub xattrs: XAttrs,
+    /// Version of the file's content, to detect concurrent writes
+    #[serde(default, with = "crate::network::wire::since_2_0")]
+    pub version: VersionVector,
+    /// Where the erasure-coded shards of the file are, in `erasure` redundancy mode
+    #[serde(default, with = "crate::network::wire::since_2_0")]
+    pub shards: Option<Shards>,
 }
 
 impl Inode {
@@ -50,6 +59,7 @@ impl Inode {
             rdev: 0,
             blksize: BLOCK_SIZE as u32,
             flags: 0,
+            hash: None,
         };
 
         let xattrs = HashMap::new();
@@ -61,6 +71,8 @@ impl Inode {
             entry,
             meta,
             xattrs,
+            version: VersionVector::default(),
+            shards: None,
         }
     }
 }
@@ -106,6 +118,21 @@ pub struct Metadata {
     pub blksize: u32,
     /// Flags (macOS only, see chflags(2))
     pub flags: u32,
+    /// Hash of the content at this version, set when a change is flushed
+    /// None while unknown, then the content isn't verified
+    #[ts(as = "Option<Vec<u8>>")]
+    #[serde(default, with = "crate::network::wire::since_2_0")]
+    pub hash: Option<ContentHash>,
+}
+
+impl Metadata {
+    /// If `hash` is the one of this version of the content, or it can't be told
+    pub fn matches(&self, hash: &ContentHash) -> bool {
+        match &self.hash {
+            Some(expected) => expected == hash,
+            None => true,
+        }
+    }
 }
 
 #[cfg(target_os = "linux")]
@@ -132,6 +159,7 @@ impl TryInto<Metadata> for fs::Metadata {
             rdev: self.rdev() as u32,
             blksize: self.blksize() as u32,
             flags: 0,
+            hash: None,
         })
     }
 }
@@ -160,6 +188,7 @@ impl TryInto<Metadata> for fs::Metadata {
             rdev: 0,
             blksize: 0,
             flags: 0,
+            hash: None,
         })
     }
 }
@@ -1,10 +1,18 @@
+mod allocation;
 pub mod creation;
 mod fsentry;
 mod inode;
+mod shards;
+mod tombstone;
+mod version;
 
+pub use allocation::{namespace_of, peer_namespace};
 pub use fsentry::*;
 pub use inode::*;
 use libp2p::PeerId;
+pub use shards::Shards;
+pub use tombstone::Tombstone;
+pub use version::*;
 
 #[cfg(target_os = "windows")]
 pub use crate::pods::itree::WINDOWS_DEFAULT_PERMS_MODE;
@@ -16,7 +24,7 @@ use crate::{
 use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs, io,
     ops::RangeFrom,
     path::Path,
@@ -42,6 +50,12 @@ pub const GLOBAL_CONFIG_INO: u64 = 2;
 pub const GLOBAL_CONFIG_FNAME: &str = ".global_config.toml";
 pub const LOCAL_CONFIG_INO: u64 = 3;
 pub const LOCAL_CONFIG_FNAME: &str = ".local_config.toml";
+/// Folder at the root of the disk for what this pod keeps for itself, never indexed
+/// Its name is reserved: it can't be created in the filesystem
+pub const LOCAL_FOLDER_INO: u64 = 4;
+pub const LOCAL_FOLDER: &str = ".wormhole";
+/// Folder of the [LOCAL_FOLDER] where the shards held by this pod are
+pub const SHARDS_FOLDER: &str = ".wormhole/shards";
 pub const FIRST_INO: u64 = 11;
 
 // SECTION types
@@ -51,6 +65,12 @@ pub type ITreeIndex = HashMap<Ino, Inode>;
 pub struct ITree {
     pub entries: ITreeIndex,
     pub next_ino: RangeFrom<Ino>,
+    /// Inodes removed while some known peers were away, see [Tombstone]
+    #[serde(default, with = "crate::network::wire::since_2_0")]
+    pub tombstones: HashMap<Ino, Tombstone>,
+    /// Tombstones each known peer didn't see yet
+    #[serde(default, with = "crate::network::wire::since_2_0")]
+    pub unseen_tombstones: HashMap<PeerId, HashSet<Ino>>,
 }
 
 pub const BLOCK_SIZE: u64 = 512;
@@ -62,6 +82,8 @@ impl ITree {
         let mut itree: Self = Self {
             entries: HashMap::new(),
             next_ino: FIRST_INO..,
+            tombstones: HashMap::new(),
+            unseen_tombstones: HashMap::new(),
         };
 
         itree.entries.insert(
@@ -87,8 +109,11 @@ impl ITree {
                     rdev: 0,
                     blksize: 1,
                     flags: 0,
+                    hash: None,
                 },
                 xattrs: HashMap::new(),
+                version: VersionVector::default(),
+                shards: None,
             },
         );
         itree
@@ -97,20 +122,29 @@ impl ITree {
     /// Reserve an Ino
     /// when this function returns, the Ino is permanently reserved
     pub fn reserve_ino(&mut self) -> WhResult<Ino> {
-        self.next_ino
-            .next()
-            .ok_or(WhError::WouldBlock {
+        let next = self.next_ino.start;
+
+        // past the end of this pod's namespace, the Ino would be an other pod's
+        if namespace_of(next) != namespace_of(next.saturating_add(1)) {
+            log::error!("Ran out of Ino, returning Wh::WouldBlock");
+            return Err(WhError::WouldBlock {
                 called_from: "reserve_ino".to_owned(),
-            })
-            .inspect_err(|_| log::error!("Ran out of Ino, returning Wh::WouldBlock"))
+            });
+        }
+        self.next_ino.start += 1;
+        Ok(next)
     }
 
     /// Mark Ino-s already reserved
     /// This is for example if a peer reserves an Ino and we need to catch up
     /// to not give this ino out again
+    /// Ino-s of other namespaces are ignored, they can't be given out by this pod
     pub fn mark_reserved_ino(&mut self, new: Ino) -> WhResult<()> {
         let next = &mut self.next_ino;
 
+        if namespace_of(next.start) != namespace_of(new) {
+            return Ok(());
+        }
         if next.start > new {
             return Err(WhError::WouldBlock {
                 called_from: "mark_reserved_ino: new is less than current".to_owned(),
@@ -150,6 +184,7 @@ impl ITree {
         match (name, parent_ino) {
             (GLOBAL_CONFIG_FNAME, 1) => Some(GLOBAL_CONFIG_INO),
             (LOCAL_CONFIG_FNAME, 1) => Some(LOCAL_CONFIG_INO),
+            (LOCAL_FOLDER, 1) => Some(LOCAL_FOLDER_INO),
             _ => None,
         }
     }
@@ -160,6 +195,7 @@ impl ITree {
 
     pub fn is_local_only(ino: u64) -> bool {
         ino == LOCAL_CONFIG_INO // ".local_config.toml"
+            || ino == LOCAL_FOLDER_INO // ".wormhole"
     }
 
     pub fn read_lock<'a>(
@@ -214,6 +250,8 @@ impl ITree {
                 entry: FsEntry::Directory(parent_children),
                 meta: _,
                 xattrs: _,
+                version: _,
+                shards: _,
             }) => {
                 parent_children.push(inode.id);
                 self.entries.insert(inode.id, inode);
@@ -393,10 +431,36 @@ impl ITree {
         Ok(())
     }
 
+    /// Set where the shards of a file are
+    ///
+    /// Only works on inodes pointing files (no folders)
+    pub fn set_inode_shards(&mut self, ino: Ino, shards: Shards) -> WhResult<()> {
+        let inode = self.get_inode_mut(ino)?;
+
+        match inode.entry {
+            FsEntry::File(_) => inode.shards = Some(shards),
+            _ => return Err(WhError::InodeIsADirectory),
+        };
+        Ok(())
+    }
+
+    /// Metadata edits keep the hash of the content, only content changes set it,
+    /// see [ITree::set_inode_hash]. A new size is a new content though, its hash is unknown.
     pub fn set_inode_meta(&mut self, ino: Ino, meta: Metadata) -> WhResult<()> {
         let inode = self.get_inode_mut(ino)?;
 
-        inode.meta = meta;
+        let hash = if meta.size == inode.meta.size {
+            inode.meta.hash
+        } else {
+            None
+        };
+        inode.meta = Metadata { hash, ..meta };
+        Ok(())
+    }
+
+    /// Hash of the content the inode now has
+    pub fn set_inode_hash(&mut self, ino: Ino, hash: Option<ContentHash>) -> WhResult<()> {
+        self.get_inode_mut(ino)?.meta.hash = hash;
         Ok(())
     }
 
@@ -428,6 +492,62 @@ impl Default for ITree {
 
 // !SECTION
 
+/// Add an entry found on disk to the tree, files are hosted by `host`
+fn index_entry(
+    itree: &mut ITree,
+    parent: Ino,
+    entry: &fs::DirEntry,
+    host: &PeerId,
+    mountpoint: &Path,
+) -> io::Result<Ino> {
+    let fname: InodeName = entry
+        .file_name()
+        .try_into()
+        .map_err(|e: InodeNameError| e.to_io())?;
+    let meta = entry.metadata()?;
+
+    let ftype = meta.file_type();
+
+    let special_ino = ITree::get_special(fname.as_ref(), parent);
+
+    let used_ino = match special_ino {
+        Some(_) if !ftype.is_file() => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Protected name is a folder",
+            ))
+        }
+        Some(ino) => ino,
+        None => itree.reserve_ino().map_err(io::Error::other)?,
+    };
+
+    #[cfg(target_os = "linux")]
+    let mut perm_mode = meta.permissions().mode() as u16;
+    #[cfg(target_os = "windows")]
+    let mut perm_mode = WINDOWS_DEFAULT_PERMS_MODE;
+
+    let fs_entry = match ftype.try_into()? {
+        SimpleFileType::Directory => FsEntry::new_directory(),
+        SimpleFileType::File => FsEntry::File(vec![*host]),
+        SimpleFileType::Symlink => {
+            perm_mode = 0o777; // symlink's metadata.mode() is unreliable
+            let target = std::fs::read_link(entry.path());
+            let link = EntrySymlink::parse(&target?, mountpoint);
+            FsEntry::Symlink(link.unwrap_or_else(|e| e))
+        }
+    };
+
+    itree
+        .add_inode(Inode::new(fname, parent, used_ino, fs_entry, perm_mode))
+        .map_err(io::Error::other)?;
+    let mut meta: Metadata = meta.try_into()?;
+    meta.ino = used_ino;
+    itree
+        .set_inode_meta(used_ino, meta)
+        .map_err(io::Error::other)?;
+    Ok(used_ino)
+}
+
 fn index_folder_recursive(
     itree: &mut ITree,
     parent: Ino,
@@ -437,63 +557,13 @@ fn index_folder_recursive(
 ) -> io::Result<()> {
     for entry in fs::read_dir(path)? {
         let entry = entry.expect("error in filesystem indexion (1)");
-        let fname: InodeName = entry
-            .file_name()
-            .try_into()
-            .map_err(|e: InodeNameError| e.to_io())?;
-        let meta = entry.metadata()?;
-
-        let ftype = meta.file_type();
-
-        let special_ino = ITree::get_special(fname.as_ref(), parent);
-
-        let used_ino = match special_ino {
-            Some(_) if !ftype.is_file() => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Protected name is a folder",
-                ))
-            }
-            Some(ino) => ino,
-            None => itree
-                .next_ino
-                .next()
-                .ok_or(io::Error::other("ran out of Inodes"))?,
-        };
-
-        #[cfg(target_os = "linux")]
-        let mut perm_mode = meta.permissions().mode() as u16;
-        #[cfg(target_os = "windows")]
-        let mut perm_mode = WINDOWS_DEFAULT_PERMS_MODE;
-
-        let fs_entry = match ftype.try_into()? {
-            SimpleFileType::Directory => FsEntry::new_directory(),
-            SimpleFileType::File => FsEntry::File(vec![*host]),
-            SimpleFileType::Symlink => {
-                perm_mode = 0o777; // symlink's metadata.mode() is unreliable
-                let target = std::fs::read_link(entry.path());
-                let link = EntrySymlink::parse(&target?, mountpoint);
-                FsEntry::Symlink(link.unwrap_or_else(|e| e))
-            }
-        };
-
-        itree
-            .add_inode(Inode::new(
-                fname.clone(),
-                parent,
-                used_ino,
-                fs_entry,
-                perm_mode,
-            ))
-            .map_err(io::Error::other)?;
-        let mut meta: Metadata = meta.try_into()?;
-        meta.ino = used_ino;
-        itree
-            .set_inode_meta(used_ino, meta)
-            .map_err(io::Error::other)?;
+        if parent == ROOT && entry.file_name() == LOCAL_FOLDER {
+            continue;
+        }
+        let used_ino = index_entry(itree, parent, &entry, host, mountpoint)?;
 
-        if ftype.is_dir() {
-            index_folder_recursive(itree, used_ino, &path.join(&fname), host, mountpoint)
+        if entry.file_type()?.is_dir() {
+            index_folder_recursive(itree, used_ino, &entry.path(), host, mountpoint)
                 .expect("error in filesystem indexion (3)");
         };
     }
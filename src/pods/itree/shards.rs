@@ -0,0 +1,115 @@
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::pods::itree::{ContentHash, VersionVector};
+
+/// Erasure-coded pieces of a file, each held by a different pod
+///
+/// The content is cut in `data` shards of `size` bytes, the last ones padded with zeroes,
+/// and the other shards are parity computed from them. Any `data` shards rebuild the file,
+/// see [erasure](crate::pods::network::erasure).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Shards {
+    /// Version of the content they were computed from, older shards are useless
+    pub version: VersionVector,
+    /// Number of data shards, the others are parity
+    pub data: usize,
+    /// Length of each shard
+    pub size: u64,
+    /// Pod holding each shard, data shards first. None once lost
+    pub holders: Vec<Option<PeerId>>,
+    /// Hash of each shard, as stored: data shards without their padding
+    pub hashes: Vec<ContentHash>,
+}
+
+impl Shards {
+    pub fn parity(&self) -> usize {
+        self.holders.len() - self.data
+    }
+
+    /// If they hold this version of the content, cut in this many data and parity shards
+    pub fn is_current(&self, version: &VersionVector, data: usize, parity: usize) -> bool {
+        self.version == *version && self.data == data && self.parity() == parity
+    }
+
+    /// Shards still held, by index
+    pub fn held(&self) -> impl Iterator<Item = (usize, PeerId)> + '_ {
+        self.holders
+            .iter()
+            .enumerate()
+            .filter_map(|(index, holder)| holder.map(|holder| (index, holder)))
+    }
+
+    /// If enough shards are held to rebuild the content
+    pub fn is_readable(&self) -> bool {
+        self.held().count() >= self.data
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.holders.iter().all(Option::is_some)
+    }
+
+    /// Shards held by `peer`
+    pub fn held_by(&self, peer: &PeerId) -> Vec<usize> {
+        self.held()
+            .filter(|(_, holder)| holder == peer)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Forget the shards held by `peer`, returns false if it held none
+    pub fn lose(&mut self, peer: &PeerId) -> bool {
+        let mut lost = false;
+        for holder in &mut self.holders {
+            if holder.as_ref() == Some(peer) {
+                *holder = None;
+                lost = true;
+            }
+        }
+        lost
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn shards(holders: Vec<Option<PeerId>>) -> Shards {
+        Shards {
+            version: VersionVector::default(),
+            data: 2,
+            size: 10,
+            hashes: vec![[0; 32]; holders.len()],
+            holders,
+        }
+    }
+
+    #[test]
+    fn readable_while_enough_shards_are_held() {
+        let (first, second, third) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut shards = shards(vec![Some(first), Some(second), Some(third)]);
+        assert!(shards.is_complete());
+        assert_eq!(shards.parity(), 1);
+
+        assert!(shards.lose(&second));
+        assert!(!shards.lose(&second));
+        assert!(!shards.is_complete());
+        assert!(shards.is_readable());
+        assert_eq!(shards.held_by(&third), vec![2]);
+
+        shards.lose(&first);
+        assert!(!shards.is_readable());
+    }
+
+    #[test]
+    fn older_versions_are_not_current() {
+        let peer = PeerId::random();
+        let shards = shards(vec![Some(peer); 3]);
+        let mut version = VersionVector::default();
+        assert!(shards.is_current(&version, 2, 1));
+        assert!(!shards.is_current(&version, 3, 1));
+
+        version.increment(peer);
+        assert!(!shards.is_current(&version, 2, 1));
+    }
+}
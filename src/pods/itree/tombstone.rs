@@ -0,0 +1,111 @@
+//! Records of removed inodes
+//!
+//! A removal only reaches the peers connected at that moment. Until every known peer
+//! has seen it, the tree keeps a [Tombstone] so that a peer coming back with the inode
+//! can't bring it back, through its tree or by replicating it.
+//! A peer that doesn't come back within the grace period stops being waited for,
+//! see [NetworkInterface::expire_suspect](crate::pods::network::network_interface::NetworkInterface::expire_suspect).
+//! Inode numbers are never given out twice, a tombstone can't hide a newer inode.
+
+use std::{collections::HashSet, time::SystemTime};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::pods::itree::{FsEntry, ITree, Ino};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tombstone {
+    /// When the inode was removed, by the clock of the pod removing it
+    pub deleted_at: SystemTime,
+    /// Pod that removed it
+    pub by: PeerId,
+}
+
+impl Tombstone {
+    pub fn now(by: PeerId) -> Self {
+        Self {
+            deleted_at: SystemTime::now(),
+            by,
+        }
+    }
+}
+
+impl ITree {
+    /// If the inode was removed from the network
+    pub fn is_buried(&self, ino: Ino) -> bool {
+        self.tombstones.contains_key(&ino)
+    }
+
+    /// Record the removal of an inode, kept until every peer of `unseen_by` saw it
+    pub fn bury(&mut self, ino: Ino, tombstone: Tombstone, unseen_by: &[PeerId]) {
+        if ITree::is_special(ino) {
+            return;
+        }
+        for peer in unseen_by.iter().filter(|peer| **peer != tombstone.by) {
+            self.unseen_tombstones.entry(*peer).or_default().insert(ino);
+        }
+        self.tombstones.entry(ino).or_insert(tombstone);
+        self.collect_tombstones();
+    }
+
+    /// `peer` is up to date with this tree, forget the tombstones only it was missing
+    pub fn tombstones_seen_by(&mut self, peer: &PeerId) {
+        if self.unseen_tombstones.remove(peer).is_some() {
+            self.collect_tombstones();
+        }
+    }
+
+    /// Drop the tombstones every known peer saw
+    fn collect_tombstones(&mut self) {
+        let unseen: HashSet<Ino> = self.unseen_tombstones.values().flatten().copied().collect();
+        self.tombstones.retain(|ino, _| unseen.contains(ino));
+    }
+
+    /// Apply the removals known by `previous` to this tree, received from a peer that may have missed them
+    /// The inodes they cover are dropped with their content, and the tombstones are kept
+    /// Returns the removals the peer missed
+    pub fn keep_tombstones(&mut self, previous: &ITree) -> Vec<(Ino, Tombstone)> {
+        let mut missed = Vec::new();
+        for (ino, tombstone) in &previous.tombstones {
+            if self.entries.contains_key(ino) {
+                log::info!("Dropping {ino} from the received tree, it was removed");
+                self.drop_subtree(*ino);
+                missed.push((*ino, *tombstone));
+            }
+            self.tombstones.entry(*ino).or_insert(*tombstone);
+        }
+        for (peer, unseen) in &previous.unseen_tombstones {
+            self.unseen_tombstones
+                .entry(*peer)
+                .or_default()
+                .extend(unseen.iter().copied());
+        }
+        missed
+    }
+
+    fn drop_subtree(&mut self, ino: Ino) {
+        let Some(inode) = self.entries.remove(&ino) else {
+            return;
+        };
+        if let Some(FsEntry::Directory(siblings)) = self
+            .entries
+            .get_mut(&inode.parent)
+            .map(|parent| &mut parent.entry)
+        {
+            siblings.retain(|sibling| *sibling != ino);
+        }
+
+        let mut orphans = match inode.entry {
+            FsEntry::Directory(children) => children,
+            _ => return,
+        };
+        while let Some(orphan) = orphans.pop() {
+            if let Some(FsEntry::Directory(children)) =
+                self.entries.remove(&orphan).map(|inode| inode.entry)
+            {
+                orphans.extend(children);
+            }
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+/// Number of content changes each pod made to a file
+///
+/// Two versions where neither has seen all the changes of the other are concurrent:
+/// the file was written on two pods at the same time and the contents conflict.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct VersionVector(BTreeMap<PeerId, u64>);
+
+impl VersionVector {
+    /// Count a new change made by `peer`
+    pub fn increment(&mut self, peer: PeerId) {
+        *self.0.entry(peer).or_default() += 1;
+    }
+
+    /// If no change was counted, as in the versions sent by peers older than version vectors
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Also know the changes known by `other`
+    pub fn merge(&mut self, other: &Self) {
+        for (peer, changes) in &other.0 {
+            let known = self.0.entry(*peer).or_default();
+            *known = (*known).max(*changes);
+        }
+    }
+
+    /// Changes made by `peer` known by this version
+    pub fn get(&self, peer: &PeerId) -> u64 {
+        self.0.get(peer).copied().unwrap_or_default()
+    }
+
+    /// Causal order of the versions, None if they are concurrent
+    pub fn compare(&self, other: &Self) -> Option<Ordering> {
+        let mut ordering = Ordering::Equal;
+
+        for peer in self.0.keys().chain(other.0.keys()) {
+            let step = self.get(peer).cmp(&other.get(peer));
+            ordering = match (ordering, step) {
+                (ordering, Ordering::Equal) => ordering,
+                (Ordering::Equal, step) => step,
+                (ordering, step) if ordering == step => ordering,
+                _ => return None,
+            };
+        }
+        Some(ordering)
+    }
+
+    /// Arbitrary but total order, only to pick the same side of a conflict on every pod
+    pub fn tie_break(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl PartialOrd for VersionVector {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.compare(other)
+    }
+}
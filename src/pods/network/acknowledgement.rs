@@ -0,0 +1,237 @@
+//! Acknowledged broadcasts
+//!
+//! Metadata operations are broadcasted to every peer, each of them must acknowledge it.
+//! A delivery that fails or is refused is retried with an exponential backoff, with the
+//! content of the tree at the time of the retry so it never undoes a later change.
+//! After [MAX_ATTEMPTS] the peer is recorded as behind and the missed requests are kept
+//! to be replayed, with up to date content, once the peer reconnects.
+
+use std::{collections::HashMap, time::Duration};
+
+use libp2p::{request_response::OutboundRequestId, PeerId};
+use tokio::time::Instant;
+
+use crate::{
+    network::message::Request,
+    pods::{itree::ITree, network::network_interface::NetworkInterface},
+};
+
+/// Attempts made before considering a peer behind
+pub const MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry, doubled on each attempt
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How often the event loop checks for retries to send
+pub const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A request sent to a peer, waiting for its acknowledgement
+#[derive(Clone)]
+pub struct Delivery {
+    pub peer: PeerId,
+    pub request: Request,
+    /// Number of times it has been sent, starting at 1
+    pub attempt: u32,
+    /// Which operations of a batch must be acknowledged, the others were only broadcasted
+    /// Empty when every one must
+    pub acknowledged: Vec<bool>,
+}
+
+impl Delivery {
+    pub fn new(peer: PeerId, request: Request) -> Self {
+        Self {
+            peer,
+            request,
+            attempt: 1,
+            acknowledged: Vec::new(),
+        }
+    }
+
+    /// The operations of this delivery that must be acknowledged, None if there is none
+    pub fn acknowledged_only(self) -> Option<Self> {
+        if self.acknowledged.iter().all(|acknowledged| *acknowledged) {
+            return Some(Self {
+                acknowledged: Vec::new(),
+                ..self
+            });
+        }
+        let request = match self.request {
+            Request::Batch(requests) => {
+                let requests: Vec<Request> = requests
+                    .into_iter()
+                    .zip(&self.acknowledged)
+                    .filter(|(_, acknowledged)| **acknowledged)
+                    .map(|(request, _)| request)
+                    .collect();
+                if requests.is_empty() {
+                    return None;
+                }
+                Request::Batch(requests)
+            }
+            // a lone operation only broadcasted
+            _ => return None,
+        };
+        Some(Self {
+            request,
+            acknowledged: Vec::new(),
+            ..self
+        })
+    }
+
+    fn backoff(&self) -> Duration {
+        BASE_BACKOFF * 2u32.pow(self.attempt.saturating_sub(1).min(8))
+    }
+}
+
+/// Deliveries waiting for an acknowledgement or for their next attempt
+#[derive(Default)]
+pub struct Acknowledgements {
+    awaiting: HashMap<OutboundRequestId, Delivery>,
+    retries: Vec<(Instant, Delivery)>,
+}
+
+impl Acknowledgements {
+    pub fn sent(&mut self, request_id: OutboundRequestId, delivery: Delivery) {
+        self.awaiting.insert(request_id, delivery);
+    }
+
+    /// Stop waiting for this request, returning its delivery if it was an acknowledged one
+    pub fn answered(&mut self, request_id: &OutboundRequestId) -> Option<Delivery> {
+        self.awaiting.remove(request_id)
+    }
+
+    /// Schedule the next attempt of a failed delivery
+    /// Returns the delivery back if it has no attempt left
+    pub fn schedule(&mut self, mut delivery: Delivery) -> Result<(), Delivery> {
+        if delivery.attempt >= MAX_ATTEMPTS {
+            return Err(delivery);
+        }
+        // retries are refreshed when sent, the one already waiting will carry this change too
+        if self.retries.iter().any(|(_, waiting)| {
+            waiting.peer == delivery.peer && same_state(&waiting.request, &delivery.request)
+        }) {
+            return Ok(());
+        }
+        let at = Instant::now() + delivery.backoff();
+        delivery.attempt += 1;
+        self.retries.push((at, delivery));
+        Ok(())
+    }
+
+    /// Deliveries whose next attempt is due
+    pub fn due(&mut self) -> Vec<Delivery> {
+        let now = Instant::now();
+        let (due, later) = std::mem::take(&mut self.retries)
+            .into_iter()
+            .partition(|(at, _)| *at <= now);
+        self.retries = later;
+        due.into_iter().map(|(_, delivery)| delivery).collect()
+    }
+}
+
+/// If both requests send the same part of the state of an inode, only the latest matters
+fn same_state(a: &Request, b: &Request) -> bool {
+    match (a, b) {
+        (Request::Inode(a), Request::Inode(b)) => a.id == b.id,
+        (Request::EditMetadata(a, _), Request::EditMetadata(b, _)) => a == b,
+        (
+            Request::SetXAttr(a, a_key, _) | Request::RemoveXAttr(a, a_key),
+            Request::SetXAttr(b, b_key, _) | Request::RemoveXAttr(b, b_key),
+        ) => a == b && a_key == b_key,
+        _ => false,
+    }
+}
+
+/// The request as it would be sent now, with the state of `itree`
+/// None if it is about an inode removed since, its removal is sent on its own
+fn refreshed(itree: &ITree, request: Request) -> Option<Request> {
+    match request {
+        Request::Inode(inode) => itree.get_inode(inode.id).ok().cloned().map(Request::Inode),
+        Request::EditMetadata(ino, _) => itree
+            .get_inode(ino)
+            .ok()
+            .map(|inode| Request::EditMetadata(ino, inode.meta.clone())),
+        Request::SetXAttr(ino, key, _) | Request::RemoveXAttr(ino, key) => {
+            let inode = itree.get_inode(ino).ok()?;
+            Some(match inode.xattrs.get(&key) {
+                Some(data) => Request::SetXAttr(ino, key, data.clone()),
+                None => Request::RemoveXAttr(ino, key),
+            })
+        }
+        Request::Batch(requests) => {
+            let requests: Vec<Request> = requests
+                .into_iter()
+                .filter_map(|request| refreshed(itree, request))
+                .collect();
+            (!requests.is_empty()).then_some(Request::Batch(requests))
+        }
+        request => Some(request),
+    }
+}
+
+impl NetworkInterface {
+    /// Refresh a request about to be sent again, see [refreshed]
+    pub fn refresh(&self, request: Request) -> Option<Request> {
+        match ITree::read_lock(&self.itree, "refresh") {
+            Ok(itree) => refreshed(&itree, request),
+            Err(err) => {
+                log::warn!("Couldn't refresh {request}: {err}");
+                Some(request)
+            }
+        }
+    }
+
+    /// Record a request a peer missed, to send it again when it reconnects
+    pub fn fall_behind(&self, peer: PeerId, request: Request) {
+        if let Request::Batch(requests) = request {
+            for request in requests {
+                self.fall_behind(peer, request);
+            }
+            return;
+        }
+        log::warn!("{peer} is behind, it didn't acknowledge {request}");
+        let mut behind = self.behind_peers.write();
+        let missed = behind.entry(peer).or_default();
+
+        match (&request, request.inode()) {
+            // Only the latest metadata matters
+            (Request::EditMetadata(..), Some(ino)) => missed.retain(|old| {
+                !(matches!(old, Request::EditMetadata(..)) && old.inode() == Some(ino))
+            }),
+            // Nothing else about a removed inode needs to be sent,
+            // not even the removal if the peer never learned about its creation
+            (Request::Remove(..), Some(ino)) => {
+                let created = missed
+                    .iter()
+                    .any(|old| matches!(old, Request::Inode(inode) if inode.id == ino));
+                missed.retain(|old| old.inode() != Some(ino));
+                if created {
+                    return;
+                }
+            }
+            _ => {}
+        }
+        missed.push(request);
+    }
+
+    /// Requests missed by a peer, refreshed with the current state of the tree
+    pub fn catch_up(&self, peer: &PeerId) -> Vec<Request> {
+        let Some(missed) = self.behind_peers.write().remove(peer) else {
+            return Vec::new();
+        };
+        let itree = match ITree::read_lock(&self.itree, "catch_up") {
+            Ok(itree) => itree,
+            Err(err) => {
+                log::error!("Couldn't catch up {peer}: {err}");
+                self.behind_peers.write().insert(*peer, missed);
+                return Vec::new();
+            }
+        };
+        log::info!("Catching up {peer} on {} missed requests", missed.len());
+
+        missed
+            .into_iter()
+            .filter_map(|request| refreshed(&itree, request))
+            .collect()
+    }
+}
@@ -0,0 +1,136 @@
+use libp2p::PeerId;
+
+use crate::{
+    config::types::NetworkAccess,
+    error::{WhError, WhResult},
+    network::{
+        self,
+        message::{Request, Response, ToNetworkMessage},
+    },
+    pods::network::network_interface::NetworkInterface,
+};
+
+/// Decision taken on a peer trying to reach this pod
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Admitted,
+    /// Waiting for a member to run `wormhole approve`
+    Pending,
+    Denied,
+}
+
+impl Admission {
+    /// Answer sent to a non-admitted peer instead of handling its request
+    pub fn refusal(&self) -> Option<Response> {
+        match self {
+            Admission::Admitted => None,
+            Admission::Pending => Some(Response::AdmissionPending),
+            Admission::Denied => Some(Response::AccessDenied),
+        }
+    }
+}
+
+impl NetworkInterface {
+    /// Check if a peer is allowed in the network according to the `general.access` setting
+    /// Peers already connected as members are always admitted
+    pub fn admission(&self, peer: &PeerId) -> Admission {
+        if self.peers.read().contains(peer) {
+            return Admission::Admitted;
+        }
+        self.access_rules(peer)
+    }
+
+    /// Connected peers the `general.access` setting no longer admits, after it changed
+    pub fn revoked_peers(&self) -> Vec<PeerId> {
+        self.peers
+            .read()
+            .iter()
+            .filter(|peer| self.access_rules(peer) != Admission::Admitted)
+            .copied()
+            .collect()
+    }
+
+    fn access_rules(&self, peer: &PeerId) -> Admission {
+        let config = self.global_config.read();
+        let general = &config.general;
+
+        match general.access {
+            NetworkAccess::Open => Admission::Admitted,
+            NetworkAccess::Demand if general.whitelist.contains(peer) => Admission::Admitted,
+            NetworkAccess::Demand => Admission::Pending,
+            NetworkAccess::Whitelist if general.whitelist.contains(peer) => Admission::Admitted,
+            NetworkAccess::Whitelist => Admission::Denied,
+            NetworkAccess::Blacklist if general.blacklist.contains(peer) => Admission::Denied,
+            NetworkAccess::Blacklist => Admission::Admitted,
+        }
+    }
+
+    /// Keep a peer aside until a member approves it
+    pub fn add_pending_peer(&self, peer_id: PeerId, info: network::PeerInfo) {
+        log::info!("{} ({peer_id}) is waiting for approval", info.nickname);
+        self.pending_peers.write().insert(peer_id, info);
+    }
+
+    /// Find a pending peer from its PeerId or its nickname
+    fn find_pending_peer(&self, peer: &str) -> Option<PeerId> {
+        let pending = self.pending_peers.read();
+
+        match peer.parse::<PeerId>() {
+            Ok(id) => pending.contains_key(&id).then_some(id),
+            Err(_) => pending
+                .iter()
+                .find(|(_, info)| info.nickname == peer)
+                .map(|(id, _)| *id),
+        }
+    }
+
+    /// Approve a pending peer and inform the network (the peer included) of its admission
+    /// Returns Ok(None) if no pending peer matches
+    pub fn approve_peer(&self, peer: &str) -> WhResult<Option<PeerId>> {
+        let peer_id = match self.find_pending_peer(peer) {
+            Some(peer_id) => peer_id,
+            None => return Ok(None),
+        };
+        let info = match self.pending_peers.write().remove(&peer_id) {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+
+        self.remember_member(peer_id);
+        self.connect_peer(peer_id, info);
+
+        self.to_network_message_tx
+            .send(ToNetworkMessage::BroadcastMessage(Request::AdmitPeer(
+                peer_id,
+            )))
+            .or(Err(WhError::NetworkDied {
+                called_from: "approve_peer".to_owned(),
+            }))?;
+        Ok(Some(peer_id))
+    }
+
+    /// Another member approved a peer
+    pub fn recept_admit_peer(&self, peer_id: PeerId) -> WhResult<Response> {
+        self.remember_member(peer_id);
+
+        let pending = self.pending_peers.write().remove(&peer_id);
+        if let Some(info) = pending {
+            self.connect_peer(peer_id, info);
+        }
+        Ok(Response::Success)
+    }
+
+    /// Add a peer to the members of the network, so it is admitted again when reconnecting
+    pub fn remember_member(&self, peer_id: PeerId) {
+        let mut config = self.global_config.write();
+        let general = &mut config.general;
+
+        if matches!(
+            general.access,
+            NetworkAccess::Demand | NetworkAccess::Whitelist
+        ) && !general.whitelist.contains(&peer_id)
+        {
+            general.whitelist.push(peer_id);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Batching of the broadcasted operations (`[network] frequency`)
+//!
+//! Broadcasts are kept for a while before being sent together as one [Request::Batch],
+//! redundant operations on the same inode are merged meanwhile.
+//! Creating many files at once then costs a few messages instead of several per file.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::network::message::Request;
+
+/// Longest time a batch is kept with the automatic frequency (0)
+pub const SMART_FREQUENCY: Duration = Duration::from_secs(1);
+
+/// With the automatic frequency, a batch is sent once no operation came for this long,
+/// so isolated operations are barely delayed while bursts are grouped
+const SMART_IDLE: Duration = Duration::from_millis(50);
+
+/// A batch is sent right away past this many encoded bytes, or half the message size limit
+pub const MAX_BATCH_SIZE: u64 = 1024 * 1024;
+
+/// An operation waiting in the batch
+struct Pending {
+    request: Request,
+    /// The peers must acknowledge it, see [Delivery](super::acknowledgement::Delivery)
+    acknowledged: bool,
+    /// Encoded size
+    size: u64,
+}
+
+/// Broadcasts waiting to be sent
+#[derive(Default)]
+pub struct Batch {
+    requests: Vec<Pending>,
+    /// Encoded size of the pending operations
+    size: u64,
+    opened: Option<Instant>,
+    last: Option<Instant>,
+}
+
+impl Batch {
+    pub fn push(&mut self, request: Request, acknowledged: bool) {
+        let now = Instant::now();
+        self.opened.get_or_insert(now);
+        self.last = Some(now);
+        let size = bincode::serialized_size(&request).unwrap_or_default();
+        coalesce(
+            &mut self.requests,
+            Pending {
+                request,
+                acknowledged,
+                size,
+            },
+        );
+        self.size = self.requests.iter().map(|pending| pending.size).sum();
+    }
+
+    /// If the batch must be sent right away, to stay well under `max_message_size` (in bytes)
+    pub fn is_full(&self, max_message_size: u64) -> bool {
+        self.size >= MAX_BATCH_SIZE.min(max_message_size / 2)
+    }
+
+    /// When the batch must be sent, None if it is empty
+    /// A `frequency` of zero lets the batch adapt to the activity
+    pub fn deadline(&self, frequency: Duration) -> Option<Instant> {
+        let opened = self.opened?;
+
+        if frequency.is_zero() {
+            let idle = self.last.unwrap_or(opened) + SMART_IDLE;
+            Some(idle.min(opened + SMART_FREQUENCY))
+        } else {
+            Some(opened + frequency)
+        }
+    }
+
+    /// Empty the batch, returning the request to send and which of its operations
+    /// must be acknowledged. A lone operation is sent as is
+    pub fn take(&mut self) -> Option<(Request, Vec<bool>)> {
+        let (mut requests, acknowledged): (Vec<Request>, Vec<bool>) =
+            std::mem::take(&mut self.requests)
+                .into_iter()
+                .map(|pending| (pending.request, pending.acknowledged))
+                .unzip();
+        self.size = 0;
+        self.opened = None;
+        self.last = None;
+
+        match requests.len() {
+            0 => None,
+            1 => requests.pop().map(|request| (request, acknowledged)),
+            _ => Some((Request::Batch(requests), acknowledged)),
+        }
+    }
+}
+
+/// Drop the pending operations matching `redundant`
+/// Returns if one of them had to be acknowledged, the operation replacing them must then be
+fn drop_redundant(requests: &mut Vec<Pending>, redundant: impl Fn(&Request) -> bool) -> bool {
+    let mut acknowledged = false;
+    requests.retain(|pending| {
+        let dropped = redundant(&pending.request);
+        acknowledged |= dropped && pending.acknowledged;
+        !dropped
+    });
+    acknowledged
+}
+
+/// Add an operation to the pending ones, merging it with an earlier one when it makes it redundant
+fn coalesce(requests: &mut Vec<Pending>, mut pending: Pending) {
+    match pending.request {
+        // Metadata are sent whole, only the latest matters. The earlier one is dropped
+        // rather than updated so it can't be applied after a newer one
+        Request::EditMetadata(ino, _) => {
+            pending.acknowledged |= drop_redundant(
+                requests,
+                |request| matches!(request, Request::EditMetadata(id, _) if *id == ino),
+            );
+            requests.push(pending);
+        }
+        Request::FileChanged(ino, _, _) => {
+            pending.acknowledged |= drop_redundant(requests, |request| {
+                matches!(
+                    request,
+                    Request::EditMetadata(..) | Request::FileChanged(..)
+                ) && request.inode() == Some(ino)
+            });
+            requests.push(pending);
+        }
+        Request::SetXAttr(ino, ref key, _) | Request::RemoveXAttr(ino, ref key) => {
+            let position = requests.iter().position(|other| match &other.request {
+                Request::SetXAttr(id, other_key, _) | Request::RemoveXAttr(id, other_key) => {
+                    *id == ino && other_key == key
+                }
+                _ => false,
+            });
+            match position {
+                Some(position) => {
+                    pending.acknowledged |= requests[position].acknowledged;
+                    requests[position] = pending;
+                }
+                None => requests.push(pending),
+            }
+        }
+        Request::Remove(ino, _) => {
+            // the state of a removed inode doesn't matter anymore
+            drop_redundant(requests, |request| {
+                matches!(
+                    request,
+                    Request::EditMetadata(..)
+                        | Request::FileChanged(..)
+                        | Request::SetXAttr(..)
+                        | Request::RemoveXAttr(..)
+                ) && request.inode() == Some(ino)
+            });
+            requests.push(pending);
+        }
+        _ => requests.push(pending),
+    }
+}
@@ -1,4 +1,4 @@
-use libp2p::{identify, request_response, swarm};
+use libp2p::{identify, mdns, ping, request_response, swarm};
 
 use crate::pods::network::codec::BincodeCodec;
 
@@ -6,4 +6,10 @@ use crate::pods::network::codec::BincodeCodec;
 pub struct Behaviour {
     pub request_response: request_response::Behaviour<BincodeCodec>,
     pub identify: identify::Behaviour,
+    /// Round trip times of the peers, see [peer_scores](crate::pods::network::peer_scores)
+    pub ping: ping::Behaviour,
+    /// Local network discovery, disabled with `[network] discovery = false`
+    pub mdns: swarm::behaviour::toggle::Toggle<mdns::tokio::Behaviour>,
+    /// Dedicated streams for file transfers, see [file_stream](crate::pods::network::file_stream)
+    pub streams: libp2p::stream::Behaviour,
 }
@@ -1,4 +1,6 @@
 use crate::network::message::{Request, Response};
+use crate::network::wire::{self, WireVersion};
+use crate::pods::network::compression::{self, CompressionStats};
 use async_trait::async_trait;
 use futures::prelude::*;
 use libp2p::request_response::Codec;
@@ -6,30 +8,233 @@ use libp2p::StreamProtocol;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::io;
+use std::sync::Arc;
 
-#[derive(Clone, Default)]
-pub struct BincodeCodec {}
+/// Default maximum size of a single message, in MiB
+pub const DEFAULT_MAX_MESSAGE_SIZE: u64 = 64;
+
+/// Read one length-prefixed bincode frame
+/// The length is checked before allocating, so a peer can't make us allocate more than `max_size`
+pub async fn read_frame<T, M>(io: &mut T, max_size: u64) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: DeserializeOwned,
+{
+    let buf = read_bytes(io, max_size).await?;
+    bincode::deserialize::<M>(&buf).map_err(io::Error::other)
+}
+
+/// Write one length-prefixed bincode frame, refusing to send more than `max_size`
+pub async fn write_frame<T, M>(io: &mut T, message: &M, max_size: u64) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    let serialized = bincode::serialize(message).map_err(io::Error::other)?;
+    write_bytes(io, &serialized, max_size).await
+}
+
+async fn read_bytes<T>(io: &mut T, max_size: u64) -> io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut len = [0u8; 8];
+    io.read_exact(&mut len).await?;
+    let len = u64::from_be_bytes(len);
+
+    if len > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {len} bytes exceeds the maximum of {max_size} bytes"),
+        ));
+    }
+
+    let mut buf = vec![0; len as usize];
+    io.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+async fn write_bytes<T>(io: &mut T, serialized: &[u8], max_size: u64) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    let len = serialized.len() as u64;
+
+    if len > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("message of {len} bytes exceeds the maximum of {max_size} bytes"),
+        ));
+    }
+
+    io.write_all(&len.to_be_bytes()).await?;
+    io.write_all(serialized).await?;
+    io.flush().await
+}
+
+/// Read a whole stream holding a single message, as the pods of 1.0 send them
+/// At most `max_size` bytes are read, the stream is refused past that
+async fn read_to_end<T>(io: &mut T, max_size: u64) -> io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut buf = Vec::new();
+    io.take(max_size.saturating_add(1))
+        .read_to_end(&mut buf)
+        .await?;
+
+    if buf.len() as u64 > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message exceeds the maximum of {max_size} bytes"),
+        ));
+    }
+    Ok(buf)
+}
+
+/// Write a single message as the whole stream, read by [read_to_end]
+async fn write_to_end<T>(io: &mut T, serialized: &[u8], max_size: u64) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    let len = serialized.len() as u64;
+
+    if len > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("message of {len} bytes exceeds the maximum of {max_size} bytes"),
+        ));
+    }
+
+    io.write_all(serialized).await?;
+    io.flush().await
+}
+
+/// Read the content of one frame written by [write_compressed_frame]
+/// `max_size` bounds the frame both before and after decompression
+pub async fn read_compressed_frame<T>(io: &mut T, max_size: u64) -> io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut compressed = [0u8; 1];
+    io.read_exact(&mut compressed).await?;
+    let buf = read_bytes(io, max_size).await?;
+    if compressed[0] != 0 {
+        compression::decompress(&buf, max_size as usize)
+    } else {
+        Ok(buf)
+    }
+}
+
+/// Write one length-prefixed frame of `serialized`, compressed at `level` if it is worth it
+/// The frame starts with a byte telling if it is compressed
+pub async fn write_compressed_frame<T>(
+    io: &mut T,
+    serialized: Vec<u8>,
+    max_size: u64,
+    level: i32,
+    stats: &CompressionStats,
+) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    let len = serialized.len();
+
+    if len as u64 > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("message of {len} bytes exceeds the maximum of {max_size} bytes"),
+        ));
+    }
+
+    let (flag, payload) = match compression::compress(&serialized, level) {
+        Some(compressed) => (1u8, compressed),
+        None => (0u8, serialized),
+    };
+    stats.record(len, payload.len());
+
+    io.write_all(&[flag]).await?;
+    io.write_all(&(payload.len() as u64).to_be_bytes()).await?;
+    io.write_all(&payload).await?;
+    io.flush().await
+}
+
+#[derive(Clone)]
+pub struct BincodeCodec {
+    /// in bytes
+    max_message_size: u64,
+    /// zstd level of the messages sent with a [compressed](WireVersion::compressed) version
+    compression: i32,
+    stats: Arc<CompressionStats>,
+}
 
 impl BincodeCodec {
-    async fn read_message<T, M>(&mut self, io: &mut T) -> io::Result<M>
+    /// `max_message_size` in MiB
+    pub fn new(max_message_size: u64, compression: i32, stats: Arc<CompressionStats>) -> Self {
+        Self {
+            max_message_size: max_message_size.saturating_mul(1024 * 1024),
+            compression,
+            stats,
+        }
+    }
+
+    async fn read<T, M>(&self, protocol: &StreamProtocol, io: &mut T) -> io::Result<M>
     where
         T: AsyncRead + Unpin + Send,
         M: DeserializeOwned,
     {
-        let mut recived_answer = Vec::new();
-
-        io.read_to_end(&mut recived_answer).await?;
-        bincode::deserialize::<M>(recived_answer.as_slice()).map_err(std::io::Error::other)
+        let version = wire_version(protocol)?;
+        let buf = if version.compressed() {
+            read_compressed_frame(io, self.max_message_size).await?
+        } else {
+            read_to_end(io, self.max_message_size).await?
+        };
+        wire::decode(version, &buf).map_err(io::Error::other)
     }
 
-    async fn write_message<T, M>(&mut self, io: &mut T, message: M) -> io::Result<()>
+    async fn write<T, M>(
+        &self,
+        protocol: &StreamProtocol,
+        io: &mut T,
+        message: &M,
+    ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
         M: Serialize,
     {
-        let serialized = bincode::serialize(&message).map_err(std::io::Error::other)?;
-        io.write_all(&serialized).await?;
-        Ok(())
+        let version = wire_version(protocol)?;
+        let serialized = wire::encode(version, message).map_err(io::Error::other)?;
+        if version.compressed() {
+            write_compressed_frame(
+                io,
+                serialized,
+                self.max_message_size,
+                self.compression,
+                &self.stats,
+            )
+            .await
+        } else {
+            write_to_end(io, &serialized, self.max_message_size).await
+        }
+    }
+}
+
+fn wire_version(protocol: &StreamProtocol) -> io::Result<WireVersion> {
+    WireVersion::of(protocol).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{protocol} isn't a version of the protocol"),
+        )
+    })
+}
+
+impl Default for BincodeCodec {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_MAX_MESSAGE_SIZE,
+            compression::DEFAULT_COMPRESSION_LEVEL,
+            Arc::default(),
+        )
     }
 }
 
@@ -39,45 +244,49 @@ impl Codec for BincodeCodec {
     type Request = Request;
     type Response = Response;
 
-    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    async fn read_request<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Request>
     where
         T: AsyncRead + Unpin + Send,
     {
-        self.read_message(io).await
+        self.read(protocol, io).await
     }
 
     async fn read_response<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
     ) -> io::Result<Self::Response>
     where
         T: AsyncRead + Unpin + Send,
     {
-        self.read_message(io).await
+        self.read(protocol, io).await
     }
 
     async fn write_request<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         req: Self::Request,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
-        self.write_message(io, req).await
+        self.write(protocol, io, &req).await
     }
 
     async fn write_response<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         resp: Self::Response,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
-        self.write_message(io, resp).await
+        self.write(protocol, io, &resp).await
     }
 }
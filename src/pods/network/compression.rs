@@ -0,0 +1,89 @@
+//! Compression of what is sent to other pods
+//!
+//! Messages and file contents are compressed with zstd frame by frame, and each frame tells
+//! if it is compressed, so the sender decides alone: frames smaller than [COMPRESSION_THRESHOLD]
+//! and frames that barely shrink, like media or archives, are sent as they are.
+//! Compressed frames are only sent to peers understanding them, see
+//! [WireVersion::compressed](crate::network::wire::WireVersion::compressed) and
+//! [Capability::Compression](crate::network::protocol::Capability::Compression).
+
+use std::{
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Frames smaller than this are never compressed
+pub const COMPRESSION_THRESHOLD: usize = 4096;
+
+/// Default zstd level of `[network] compression`
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// Compress `data` at `level` if it is worth it, None to send it as it is
+/// A level of 0 disables the compression
+pub fn compress(data: &[u8], level: i32) -> Option<Vec<u8>> {
+    if level == 0 || data.len() < COMPRESSION_THRESHOLD {
+        return None;
+    }
+    let compressed = zstd::bulk::compress(data, level)
+        .inspect_err(|err| log::warn!("Failed to compress a frame: {err}"))
+        .ok()?;
+    // less than an eighth saved, the data was most likely compressed already
+    (compressed.len() < data.len() - data.len() / 8).then_some(compressed)
+}
+
+/// Decompress a frame, refusing to produce more than `max_size` bytes
+pub fn decompress(data: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+    zstd::bulk::decompress(data, max_size)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Bytes sent to peers understanding compression, before and after it
+#[derive(Default, Debug)]
+pub struct CompressionStats {
+    raw: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl CompressionStats {
+    pub fn record(&self, raw: usize, sent: usize) {
+        self.raw.fetch_add(raw as u64, Ordering::Relaxed);
+        self.sent.fetch_add(sent as u64, Ordering::Relaxed);
+    }
+
+    pub fn raw(&self) -> u64 {
+        self.raw.load(Ordering::Relaxed)
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn repetitive_data_is_compressed() {
+        let data = b"wormhole ".repeat(1024);
+        let compressed = compress(&data, DEFAULT_COMPRESSION_LEVEL).expect("compressible");
+        assert!(compressed.len() < data.len() / 10);
+        assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn small_and_random_data_is_sent_as_is() {
+        assert_eq!(compress(b"small", DEFAULT_COMPRESSION_LEVEL), None);
+        assert_eq!(compress(&[7; 8192], 0), None);
+
+        let random: Vec<u8> = (0..8192).map(|_| rand::random()).collect();
+        assert_eq!(compress(&random, DEFAULT_COMPRESSION_LEVEL), None);
+    }
+
+    #[test]
+    fn decompression_is_bounded() {
+        let data = vec![0; 64 * 1024];
+        let compressed = compress(&data, DEFAULT_COMPRESSION_LEVEL).expect("compressible");
+        assert!(decompress(&compressed, 1024).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use libp2p::{
+    identify, mdns,
+    swarm::{dial_opts::DialOpts, SwarmEvent},
+    Multiaddr, PeerId, Swarm,
+};
+
+use crate::pods::network::{
+    behaviour::{Behaviour, BehaviourEvent},
+    swarm::network_protocol,
+};
+
+/// Time given to mDNS to find a pod of the same network before starting a new network
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Look for a running pod of the same network on the local network
+///
+/// Every pod found through mDNS is dialed, and recognized from the network name it advertises through identify.
+/// Pods from other networks are disconnected and forgotten.
+/// As the event loop isn't running yet, the swarm events received meanwhile are returned
+/// to be handled by the event loop once created, as if it had been running from the start.
+///
+/// Returns true if a pod of the network was found
+pub async fn discover_network(
+    swarm: &mut Swarm<Behaviour>,
+    network_name: &str,
+) -> (bool, Vec<SwarmEvent<BehaviourEvent>>) {
+    let expected = network_protocol(network_name);
+    let deadline = tokio::time::Instant::now() + DISCOVERY_TIMEOUT;
+    let mut events = Vec::new();
+    let mut dialed: HashSet<PeerId> = HashSet::new();
+    let mut strangers: HashSet<PeerId> = HashSet::new();
+
+    loop {
+        let Ok(event) = tokio::time::timeout_at(deadline, swarm.select_next_some()).await else {
+            log::debug!("No pod of the network '{network_name}' found on the local network");
+            return (false, events);
+        };
+
+        match event {
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+                let mut found: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+                for (peer, addr) in list {
+                    found.entry(peer).or_default().push(addr);
+                }
+
+                for (peer, addresses) in found {
+                    if strangers.contains(&peer) || !dialed.insert(peer) {
+                        continue;
+                    }
+                    log::trace!("Discovered {peer} on the local network");
+                    if let Err(err) =
+                        swarm.dial(DialOpts::peer_id(peer).addresses(addresses).build())
+                    {
+                        log::debug!("Couldn't dial discovered pod {peer}: {err}");
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(_)) => {}
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                ref info,
+                ..
+            })) if info.protocol_version != expected => {
+                log::trace!("{peer_id} is part of an other network, disconnecting");
+                strangers.insert(peer_id);
+                // the event loop must not take it for the pod it joins through
+                events.retain(|event| match event {
+                    SwarmEvent::ConnectionEstablished {
+                        peer_id: stranger, ..
+                    } => *stranger != peer_id,
+                    _ => true,
+                });
+                let _ = swarm.disconnect_peer_id(peer_id);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                ..
+            })) => {
+                log::info!("Found {peer_id} of the network '{network_name}' on the local network");
+                events.push(event);
+                return (true, events);
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, .. }
+            | SwarmEvent::ConnectionClosed { peer_id, .. }
+                if strangers.contains(&peer_id) => {}
+            event => events.push(event),
+        }
+    }
+}
@@ -0,0 +1,687 @@
+//! Erasure-coded redundancy, the `erasure` redundancy mode
+//!
+//! Instead of whole copies, a file is cut in `data_shards` contiguous pieces and `parity_shards`
+//! more are computed from them with a Reed-Solomon code. Each shard goes to a different pod,
+//! in its [SHARDS_FOLDER], and any `data_shards` of them rebuild the file: the network survives
+//! the loss of `parity_shards` pods for a fraction of the space of as many replicas.
+//!
+//! The pod writing a file hosts it whole until the redundancy worker placed all of its shards,
+//! then it keeps it as a cached copy only. Until then, the file is replicated as in the
+//! `replicas` mode, so it isn't left with one copy while too few pods can hold its shards.
+//! Reading a file no pod hosts assembles it from its shards.
+//! Shards are encoded and rebuilt by stripes of [CHUNK_SIZE] bytes on the blocking threads,
+//! a file is never held in memory.
+
+use std::{collections::VecDeque, io, sync::Arc};
+
+use custom_error::custom_error;
+use futures::{stream::FuturesUnordered, StreamExt};
+use libp2p::PeerId;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::WhError,
+    network::protocol::Capability,
+    pods::{
+        disk_managers::DiskManager,
+        filesystem::fs_interface::FsInterface,
+        itree::{ContentHash, FsEntry, ITree, Ino, Inode, Shards, VersionVector, SHARDS_FOLDER},
+        network::file_stream::{create_local_folder, Storage, CHUNK_SIZE},
+        whpath::WhPath,
+    },
+};
+
+custom_error! {
+    /// Error describing the placement or the assembly of the shards of a file
+    pub ErasureError
+    WhError{source: WhError} = "{source}",
+    Io{source: io::Error} = "{source}",
+    Coding{source: reed_solomon_erasure::Error} = "Reed-Solomon: {source}",
+    NotAFile = "Only files are cut in shards",
+    InsufficientPods{needed: usize, available: usize} = "{needed} pods are needed to hold the shards, only {available} are available",
+    Unreadable = "Not enough shards are left to rebuild the file",
+}
+
+/// Length of each shard of a file of `size` bytes, cut in `data` shards
+pub fn shard_size(size: u64, data: usize) -> u64 {
+    size.div_ceil(data as u64)
+}
+
+/// Length stored for the shard `index`: a data shard holds what is left of the file
+/// after the previous ones, without padding. Parity shards are whole
+pub fn stored_len(size: u64, shard_size: u64, data: usize, index: usize) -> u64 {
+    if index < data {
+        size.saturating_sub(index as u64 * shard_size)
+            .min(shard_size)
+    } else {
+        shard_size
+    }
+}
+
+/// Where the shard `index` starts in the file, parity shards aren't part of it
+fn file_offset(shard_size: u64, data: usize, index: usize) -> Option<u64> {
+    (index < data).then_some(index as u64 * shard_size)
+}
+
+/// Fill `buf` from `offset` in a local file
+fn read_exact_at(
+    disk: &dyn DiskManager,
+    path: &WhPath,
+    offset: u64,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    let mut read = 0;
+    while read < buf.len() {
+        let len = disk.read_file(path, offset as usize + read, &mut buf[read..])?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        read += len;
+    }
+    Ok(())
+}
+
+/// Copy `len` bytes of a local file from `start` to `offset` in another
+fn copy_range(
+    disk: &dyn DiskManager,
+    from: &WhPath,
+    start: u64,
+    len: u64,
+    to: &WhPath,
+    offset: u64,
+) -> io::Result<()> {
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut copied = 0;
+
+    while copied < len {
+        let size = CHUNK_SIZE.min((len - copied) as usize);
+        read_exact_at(disk, from, start + copied, &mut chunk[..size])?;
+        disk.write_file(to, &chunk[..size], (offset + copied) as usize)?;
+        copied += size as u64;
+    }
+    Ok(())
+}
+
+/// Hash every shard of a local file of `size` bytes, writing the parity shards
+/// to the paths given for them, by parity index
+fn encode_shards(
+    disk: &dyn DiskManager,
+    path: &WhPath,
+    size: u64,
+    data: usize,
+    parity: usize,
+    parity_paths: &[Option<WhPath>],
+) -> Result<Vec<ContentHash>, ErasureError> {
+    let coder = ReedSolomon::new(data, parity)?;
+    let shard_size = shard_size(size, data);
+    let mut hashers = vec![Sha256::new(); data + parity];
+    let mut stripe = vec![vec![0; CHUNK_SIZE]; data + parity];
+    let mut offset = 0;
+
+    for parity_path in parity_paths.iter().flatten() {
+        disk.new_file(parity_path, 0o600)?;
+    }
+    while offset < shard_size {
+        let len = CHUNK_SIZE.min((shard_size - offset) as usize);
+        for (index, shard) in stripe.iter_mut().enumerate().take(data) {
+            let stored = stored_len(size, shard_size, data, index);
+            let part = (stored.saturating_sub(offset) as usize).min(len);
+            shard[..len].fill(0);
+            read_exact_at(
+                disk,
+                path,
+                index as u64 * shard_size + offset,
+                &mut shard[..part],
+            )?;
+        }
+        let mut shards: Vec<&mut [u8]> = stripe.iter_mut().map(|shard| &mut shard[..len]).collect();
+        coder.encode(&mut shards)?;
+
+        for (index, shard) in shards.iter().enumerate() {
+            let stored = stored_len(size, shard_size, data, index);
+            let part = (stored.saturating_sub(offset) as usize).min(len);
+            hashers[index].update(&shard[..part]);
+        }
+        for (parity_index, parity_path) in parity_paths.iter().enumerate() {
+            if let Some(parity_path) = parity_path {
+                let shard = &shards[data + parity_index];
+                disk.write_file(parity_path, shard, offset as usize)?;
+            }
+        }
+        offset += len as u64;
+    }
+    Ok(hashers
+        .into_iter()
+        .map(|hasher| hasher.finalize().into())
+        .collect())
+}
+
+/// Rebuild the missing data shards of a file from the shards received,
+/// given by index with the local file holding them and where they start in it
+fn rebuild_data(
+    disk: &dyn DiskManager,
+    path: &WhPath,
+    size: u64,
+    shards: &Shards,
+    sources: &[Option<(WhPath, u64)>],
+) -> Result<(), ErasureError> {
+    let coder = ReedSolomon::new(shards.data, shards.parity())?;
+    let mut offset = 0;
+
+    while offset < shards.size {
+        let len = CHUNK_SIZE.min((shards.size - offset) as usize);
+        let mut stripe = Vec::with_capacity(sources.len());
+        for (index, source) in sources.iter().enumerate() {
+            let Some((source, start)) = source else {
+                stripe.push(None);
+                continue;
+            };
+            let stored = stored_len(size, shards.size, shards.data, index);
+            let part = (stored.saturating_sub(offset) as usize).min(len);
+            let mut shard = vec![0; len];
+            read_exact_at(disk, source, start + offset, &mut shard[..part])?;
+            stripe.push(Some(shard));
+        }
+        coder.reconstruct_data(&mut stripe)?;
+
+        for index in (0..shards.data).filter(|index| sources[*index].is_none()) {
+            let stored = stored_len(size, shards.size, shards.data, index);
+            let part = (stored.saturating_sub(offset) as usize).min(len);
+            if let Some(shard) = &stripe[index] {
+                let at = index as u64 * shards.size + offset;
+                disk.write_file(path, &shard[..part], at as usize)?;
+            }
+        }
+        offset += len as u64;
+    }
+    Ok(())
+}
+
+/// If the shards of the current version of a file are all placed, its replicas aren't needed
+pub fn shards_placed(inode: &Inode, data: usize, parity: usize) -> bool {
+    inode.shards.as_ref().is_some_and(|shards| {
+        shards.is_current(&inode.version, data, parity) && shards.is_complete()
+    })
+}
+
+impl FsInterface {
+    /// Path of the shard `index` of a file held by this pod
+    /// The folder is created with the shards, see [create_local_folder]
+    pub fn shard_path(&self, ino: Ino, index: usize) -> io::Result<WhPath> {
+        WhPath::try_from(format!("{SHARDS_FOLDER}/{ino}.{index}")).map_err(|err| err.to_io())
+    }
+
+    /// Path of a shard received only to rebuild the file
+    fn temporary_shard_path(&self, ino: Ino, index: usize) -> io::Result<WhPath> {
+        WhPath::try_from(format!("{SHARDS_FOLDER}/{ino}.{index}.tmp")).map_err(|err| err.to_io())
+    }
+
+    /// Path and length of a shard this pod holds, of the current version of the file
+    pub fn held_shard(&self, ino: Ino, index: usize) -> io::Result<(WhPath, u64)> {
+        let itree = ITree::read_lock(&self.network_interface.itree, "erasure::held_shard")
+            .map_err(io::Error::other)?;
+        let inode = itree.get_inode(ino).map_err(io::Error::other)?;
+        let held = inode.shards.as_ref().filter(|shards| {
+            shards.version == inode.version
+                && shards.held_by(&self.network_interface.id).contains(&index)
+        });
+        let Some(shards) = held else {
+            return Err(io::Error::other(format!(
+                "the shard {index} of {ino} isn't held here"
+            )));
+        };
+        let len = stored_len(inode.meta.size, shards.size, shards.data, index);
+        drop(itree);
+        Ok((self.shard_path(ino, index)?, len))
+    }
+
+    /// Length the shard `index` of a file has, cut as the configuration tells
+    /// Pushed shards longer than that are refused
+    pub fn expected_shard_len(&self, ino: Ino, index: usize) -> io::Result<u64> {
+        let (data, parity) = {
+            let config = self.network_interface.global_config.read();
+            (
+                config.redundancy.data_shards as usize,
+                config.redundancy.parity_shards as usize,
+            )
+        };
+        if index >= data + parity {
+            return Err(io::Error::other(format!("{ino} has no shard {index}")));
+        }
+        let size = ITree::read_lock(&self.network_interface.itree, "erasure::expected_shard_len")
+            .map_err(io::Error::other)?
+            .get_inode(ino)
+            .map_err(io::Error::other)?
+            .meta
+            .size;
+        Ok(stored_len(size, shard_size(size, data), data, index))
+    }
+
+    /// Remove shards of a file from the disk, this pod doesn't hold them anymore
+    pub fn remove_shards(&self, ino: Ino, indexes: &[usize]) {
+        for index in indexes {
+            let Ok(path) = self.shard_path(ino, *index) else {
+                continue;
+            };
+            if let Err(err) = self.disk.remove_file(&path) {
+                log::debug!("Couldn't remove the shard {index} of {ino}: {err}");
+            }
+        }
+    }
+
+    /// If the file is opened here, its whole copy is still needed
+    fn is_open(&self, ino: Ino) -> bool {
+        self.file_handles
+            .read()
+            .handles
+            .values()
+            .any(|handle| handle.ino == ino)
+    }
+
+    /// Get a shard from its holder into `to`, from `offset`, checked against its hash
+    #[allow(clippy::too_many_arguments)]
+    async fn fetch_shard(
+        &self,
+        ino: Ino,
+        index: usize,
+        holder: PeerId,
+        len: u64,
+        hash: ContentHash,
+        to: &WhPath,
+        offset: u64,
+    ) -> io::Result<()> {
+        if holder == self.network_interface.id {
+            let (path, _) = self.held_shard(ino, index)?;
+            let copied = to.clone();
+            self.on_disk(move |disk| copy_range(disk, &path, 0, len, &copied, offset))
+                .await?;
+        } else if self.download_shard(holder, ino, index, to, offset).await? != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if self.range_checksum(to, offset, len).await? != hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the shard {index} of {ino} doesn't match its hash"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Rebuild a file from any `data` of its shards, then store it as asked
+    ///
+    /// Data shards are fetched first, straight to their place in the file,
+    /// the parity shards only rebuild the data shards missing.
+    pub async fn assemble_shards(&self, ino: Ino, storage: Storage) -> Result<(), ErasureError> {
+        let me = self.network_interface.id;
+        let (path, perm, size, version, hash, shards) = {
+            let itree = ITree::read_lock(&self.network_interface.itree, "assemble_shards")?;
+            let inode = itree.get_inode(ino)?;
+            let current = inode
+                .shards
+                .clone()
+                .filter(|shards| shards.version == inode.version);
+            let Some(shards) = current else {
+                return Err(ErasureError::Unreadable);
+            };
+            (
+                itree.get_path_from_inode_id(ino)?,
+                inode.meta.perm,
+                inode.meta.size,
+                inode.version.clone(),
+                inode.meta.hash,
+                shards,
+            )
+        };
+        let peers = self.network_interface.peers.read().clone();
+        let mut holders: VecDeque<(usize, PeerId)> = shards
+            .held()
+            .filter(|(_, holder)| *holder == me || peers.contains(holder))
+            .collect();
+        if holders.len() < shards.data {
+            return Err(ErasureError::Unreadable);
+        }
+
+        self.sparse.write().forget(ino);
+        let created = path.clone();
+        self.on_disk(move |disk| {
+            disk.new_file(&created, perm)?;
+            disk.set_file_size(&created, size as usize)
+        })
+        .await?;
+
+        let mut sources: Vec<Option<(WhPath, u64)>> = vec![None; shards.holders.len()];
+        let mut temporary = Vec::new();
+        let mut received = 0;
+        let mut transfers = FuturesUnordered::new();
+        while received < shards.data {
+            while received + transfers.len() < shards.data {
+                let Some((index, holder)) = holders.pop_front() else {
+                    break;
+                };
+                let (to, offset) = match file_offset(shards.size, shards.data, index) {
+                    Some(offset) => (path.clone(), offset),
+                    None => {
+                        let to = self.temporary_shard_path(ino, index)?;
+                        let created = to.clone();
+                        self.on_disk(move |disk| {
+                            create_local_folder(disk, SHARDS_FOLDER)?;
+                            disk.new_file(&created, 0o600)
+                        })
+                        .await?;
+                        temporary.push(to.clone());
+                        (to, 0)
+                    }
+                };
+                let len = stored_len(size, shards.size, shards.data, index);
+                let hash = shards.hashes[index];
+                transfers.push(async move {
+                    let result = self
+                        .fetch_shard(ino, index, holder, len, hash, &to, offset)
+                        .await;
+                    (index, holder, to, offset, result)
+                });
+            }
+            let Some((index, holder, to, offset, result)) = transfers.next().await else {
+                break;
+            };
+            match result {
+                Ok(()) => {
+                    sources[index] = Some((to, offset));
+                    received += 1;
+                }
+                Err(err) => {
+                    log::warn!("Failed to get the shard {index} of {ino} from {holder}: {err}")
+                }
+            }
+        }
+        drop(transfers);
+
+        let rebuilt = if received < shards.data {
+            Err(ErasureError::Unreadable)
+        } else if sources[..shards.data].iter().any(Option::is_none) {
+            let (path, shards) = (path.clone(), shards.clone());
+            self.on_disk(move |disk| Ok(rebuild_data(disk, &path, size, &shards, &sources)))
+                .await
+                .map_err(ErasureError::from)
+                .and_then(|rebuilt| rebuilt)
+        } else {
+            Ok(())
+        };
+        let _ = self
+            .on_disk(move |disk| {
+                for to in temporary {
+                    let _ = disk.remove_file(&to);
+                }
+                Ok(())
+            })
+            .await;
+        rebuilt?;
+
+        self.verify_received(ino, &path, size, hash).await?;
+        self.store_received(ino, version, size, storage)?;
+        Ok(())
+    }
+
+    /// Cut a file in shards and spread them on distinct pods, or place again the shards lost
+    ///
+    /// Only one pod does it for each file: the smallest connected host of the whole file,
+    /// or without one the holder of the first shard left, that first assembles the file.
+    /// Once every shard is placed, the hosts of the whole file keep it as a cached copy only.
+    pub async fn place_shards(&self, ino: Ino) -> Result<(), ErasureError> {
+        if ITree::is_special(ino) {
+            return Ok(());
+        }
+        let me = self.network_interface.id;
+        let (data, parity) = {
+            let config = self.network_interface.global_config.read();
+            (
+                config.redundancy.data_shards as usize,
+                config.redundancy.parity_shards as usize,
+            )
+        };
+        let (hosts, path, size, version, previous) = {
+            let itree = ITree::read_lock(&self.network_interface.itree, "place_shards")?;
+            let inode = itree.get_inode(ino)?;
+            let FsEntry::File(hosts) = &inode.entry else {
+                return Err(ErasureError::NotAFile);
+            };
+            (
+                hosts.clone(),
+                itree.get_path_from_inode_id(ino)?,
+                inode.meta.size,
+                inode.version.clone(),
+                inode.shards.clone(),
+            )
+        };
+        let current = previous
+            .clone()
+            .filter(|shards| shards.is_current(&version, data, parity));
+
+        if current.as_ref().is_some_and(Shards::is_complete) {
+            if hosts.contains(&me) && !self.is_open(ino) {
+                self.release_whole_copy(ino, version, size)?;
+            }
+            return Ok(());
+        }
+
+        let peers = self.network_interface.peers.read().clone();
+        let live = |peer: &PeerId| *peer == me || peers.contains(peer);
+        let placer = match hosts.iter().filter(|&host| live(host)).min() {
+            Some(host) => *host,
+            None => current
+                .as_ref()
+                .filter(|shards| shards.is_readable())
+                .and_then(|shards| shards.held().map(|(_, holder)| holder).find(live))
+                .ok_or(ErasureError::Unreadable)?,
+        };
+        if placer != me {
+            return Ok(());
+        }
+        if !hosts.contains(&me) && !self.sparse.read().is_whole(ino, &version) {
+            self.assemble_shards(ino, Storage::Cache).await?;
+        }
+
+        let total = data + parity;
+        let mut holders = match &current {
+            Some(shards) => shards.holders.clone(),
+            None => vec![None; total],
+        };
+        let needed: Vec<usize> = (0..total)
+            .filter(|index| holders[*index].is_none())
+            .collect();
+        let mut candidates = vec![me];
+        candidates.extend(
+            self.network_interface
+                .available_hosts(&peers, &[Capability::Shards]),
+        );
+        candidates.retain(|candidate| !holders.contains(&Some(*candidate)));
+        if candidates.len() < needed.len() {
+            return Err(ErasureError::InsufficientPods {
+                needed: total,
+                available: total - needed.len() + candidates.len(),
+            });
+        }
+        let targets: Vec<(usize, PeerId)> = needed.into_iter().zip(candidates).collect();
+
+        // the parity shards are written where this pod keeps them, or sends them from
+        let parity_paths = (data..total)
+            .map(
+                |index| match targets.iter().find(|(needed, _)| *needed == index) {
+                    Some((_, target)) if *target == me => self.shard_path(ino, index).map(Some),
+                    Some(_) => self.temporary_shard_path(ino, index).map(Some),
+                    None => Ok(None),
+                },
+            )
+            .collect::<io::Result<Vec<_>>>()?;
+        let encoded = {
+            let path = path.clone();
+            self.on_disk(move |disk| {
+                create_local_folder(disk, SHARDS_FOLDER)?;
+                Ok(encode_shards(
+                    disk,
+                    &path,
+                    size,
+                    data,
+                    parity,
+                    &parity_paths,
+                ))
+            })
+            .await
+            .map_err(ErasureError::from)
+            .and_then(|encoded| encoded)
+        };
+        let hashes = match encoded {
+            Ok(hashes) => hashes,
+            Err(err) => {
+                self.remove_temporary_shards(ino, &targets).await;
+                return Err(err);
+            }
+        };
+        let shard_size = shard_size(size, data);
+
+        let mut uploads = FuturesUnordered::new();
+        for (index, target) in targets.iter().copied() {
+            let len = stored_len(size, shard_size, data, index);
+            let (from, start) = match file_offset(shard_size, data, index) {
+                Some(start) => (path.clone(), start),
+                None => (self.temporary_shard_path(ino, index)?, 0),
+            };
+            let hash = hashes[index];
+            uploads.push(async move {
+                let result = match file_offset(shard_size, data, index) {
+                    Some(_) if target == me => match self.shard_path(ino, index) {
+                        Ok(to) => {
+                            self.on_disk(move |disk| {
+                                disk.new_file(&to, 0o600)?;
+                                copy_range(disk, &from, start, len, &to, 0)
+                            })
+                            .await
+                        }
+                        Err(err) => Err(err),
+                    },
+                    None if target == me => Ok(()),
+                    _ => {
+                        self.upload_shard(target, ino, index, &from, start, len, hash)
+                            .await
+                    }
+                };
+                (index, target, result)
+            });
+        }
+        while let Some((index, target, result)) = uploads.next().await {
+            match result {
+                Ok(()) => holders[index] = Some(target),
+                Err(err) => {
+                    log::warn!("Failed to place the shard {index} of {ino} on {target}: {err}")
+                }
+            }
+        }
+        self.remove_temporary_shards(ino, &targets).await;
+
+        let shards = Shards {
+            version: version.clone(),
+            data,
+            size: shard_size,
+            holders,
+            hashes,
+        };
+        let kept = shards.held_by(&me);
+        if let Some(previous) = &previous {
+            let dropped: Vec<usize> = previous
+                .held_by(&me)
+                .into_iter()
+                .filter(|index| !kept.contains(index))
+                .collect();
+            self.remove_shards(ino, &dropped);
+        }
+        let complete = shards.is_complete();
+        self.network_interface.set_shards(ino, shards)?;
+
+        if complete && hosts.contains(&me) && !self.is_open(ino) {
+            self.release_whole_copy(ino, version, size)?;
+        }
+        Ok(())
+    }
+
+    async fn remove_temporary_shards(&self, ino: Ino, targets: &[(usize, PeerId)]) {
+        let paths: Vec<WhPath> = targets
+            .iter()
+            .filter_map(|(index, _)| self.temporary_shard_path(ino, *index).ok())
+            .collect();
+        let _ = self
+            .on_disk(move |disk| {
+                for path in paths.iter().filter(|path| disk.file_exists(path)) {
+                    let _ = disk.remove_file(path);
+                }
+                Ok(())
+            })
+            .await;
+    }
+
+    /// Stop hosting a file whose shards are all placed, the local copy stays as a cached one
+    fn release_whole_copy(
+        &self,
+        ino: Ino,
+        version: VersionVector,
+        size: u64,
+    ) -> Result<(), ErasureError> {
+        self.network_interface
+            .remove_inode_hosts(ino, &[self.network_interface.id])?;
+        let evicted = self.sparse.write().insert_whole(ino, version, size);
+        self.drop_cached_copies(&evicted);
+        Ok(())
+    }
+}
+
+/// Place the shards of a file, see [FsInterface::place_shards]
+/// Returns the file, its replicas are still needed if its shards aren't all placed
+pub async fn spread_shards(fs_interface: Arc<FsInterface>, ino: Ino) -> Ino {
+    if let Err(err) = fs_interface.place_shards(ino).await {
+        log::warn!("Couldn't place the shards of {ino}: {err}");
+    }
+    ino
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn data_shards_cover_the_file_without_padding() {
+        let size = 10;
+        let shard_size = shard_size(size, 4);
+        assert_eq!(shard_size, 3);
+
+        let stored: Vec<u64> = (0..6)
+            .map(|index| stored_len(size, shard_size, 4, index))
+            .collect();
+        assert_eq!(stored, vec![3, 3, 3, 1, 3, 3]);
+        assert_eq!(stored[..4].iter().sum::<u64>(), size);
+
+        // a small file leaves the last data shards empty
+        assert_eq!(stored_len(2, shard_size(2, 4), 4, 3), 0);
+        assert_eq!(file_offset(shard_size, 4, 2), Some(6));
+        assert_eq!(file_offset(shard_size, 4, 4), None);
+    }
+
+    #[test]
+    fn any_data_shards_rebuild_the_others() {
+        let coder = ReedSolomon::new(3, 2).unwrap();
+        let mut shards: Vec<Vec<u8>> = vec![
+            b"wor".to_vec(),
+            b"mho".to_vec(),
+            b"le\0".to_vec(),
+            vec![0; 3],
+            vec![0; 3],
+        ];
+        coder.encode(&mut shards).unwrap();
+
+        let mut received: Vec<Option<Vec<u8>>> = shards.iter().cloned().map(Some).collect();
+        received[0] = None;
+        received[2] = None;
+        coder.reconstruct_data(&mut received).unwrap();
+        assert_eq!(received[0].as_deref(), Some(&b"wor"[..]));
+        assert_eq!(received[2].as_deref(), Some(&b"le\0"[..]));
+    }
+}
@@ -1,20 +1,39 @@
-use std::{collections::HashMap, io, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::Arc,
+};
 
 use futures::StreamExt;
 use libp2p::{
-    identify,
+    identify, ping,
     request_response::{self, OutboundRequestId, ResponseChannel},
     swarm::{ConnectionError, SwarmEvent},
     PeerId, Swarm,
 };
-use tokio::sync::{mpsc::UnboundedReceiver, oneshot};
+use tokio::{
+    sync::{mpsc::UnboundedReceiver, oneshot},
+    task::JoinSet,
+};
 
 use crate::{
-    network::message::{Request, Response, ToNetworkMessage},
+    network::{
+        self,
+        message::{Request, Response, ToNetworkMessage},
+        protocol::{self, Capability},
+    },
     pods::{
         filesystem::fs_interface::FsInterface,
         itree::creation::initiate_itree,
-        network::behaviour::{Behaviour, BehaviourEvent},
+        network::{
+            acknowledgement::{self, Acknowledgements, Delivery},
+            admission::Admission,
+            batch::Batch,
+            behaviour::{Behaviour, BehaviourEvent},
+            file_stream::Storage,
+            operation_log::OperationLog,
+            swarm,
+        },
     },
 };
 
@@ -28,8 +47,20 @@ pub struct EventLoop {
     fs_interface: Arc<FsInterface>,
     to_network: UnboundedReceiver<ToNetworkMessage>,
     answers: HashMap<OutboundRequestId, oneshot::Sender<Option<Response>>>,
+    acknowledgements: Acknowledgements,
+    /// Broadcasts waiting to be sent together
+    batch: Batch,
     closing: bool,
     need_initialisation: Option<Option<OutboundRequestId>>,
+    /// Peer this pod joins the network through, the first one it dialed
+    /// It is trusted until the network's configuration is received, see [EventLoop::admission]
+    joining_through: Option<PeerId>,
+    /// The tree was restored from a snapshot, peers knowing it catch it up instead of sending theirs
+    resumed: bool,
+    /// Catch-ups sent, the peer may answer them with its whole tree
+    catch_ups: HashSet<OutboundRequestId>,
+    /// Files sent or asked in messages by pods of 1.0, answered once on the disk
+    file_messages: JoinSet<(ResponseChannel<Response>, Response)>,
 }
 
 impl EventLoop {
@@ -38,18 +69,25 @@ impl EventLoop {
         fs_interface: Arc<FsInterface>,
         to_network: UnboundedReceiver<ToNetworkMessage>,
         need_initialisation: bool,
+        resumed: bool,
     ) -> Self {
         EventLoop {
             swarm,
             to_network,
             fs_interface,
             answers: HashMap::new(),
+            acknowledgements: Acknowledgements::default(),
+            batch: Batch::default(),
             closing: false,
             need_initialisation: if need_initialisation {
                 Some(None)
             } else {
                 None
             },
+            joining_through: None,
+            resumed,
+            catch_ups: HashSet::new(),
+            file_messages: JoinSet::new(),
         }
     }
 
@@ -67,37 +105,120 @@ impl EventLoop {
     }
 
     pub async fn run(mut self) {
+        let mut retries = tokio::time::interval(acknowledgement::RETRY_INTERVAL);
+        retries.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
         loop {
+            let frequency = self
+                .fs_interface
+                .network_interface
+                .global_config
+                .read()
+                .network
+                .frequency();
+            let deadline = self.batch.deadline(frequency);
+
             tokio::select! {
                 event = self.swarm.select_next_some() => if self.handle_event(event) {
                     return
                 },
+                _ = retries.tick() => self.retry_deliveries(),
+                Some(answered) = self.file_messages.join_next(), if !self.file_messages.is_empty() => {
+                    if let Ok((channel, response)) = answered {
+                        let _ = self.swarm.behaviour_mut().request_response.send_response(channel, response);
+                    }
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)), if deadline.is_some() => self.send_batch(),
                 to_network = self.to_network.recv() => match to_network {
-                    Some(ToNetworkMessage::AnswerMessage(message, status, peer)) => self.send_with_answer(message, status, peer),
-                    Some(ToNetworkMessage::SpecificMessage(message, to)) => self.send_to_multiple(message, &to),
-                    Some(ToNetworkMessage::BroadcastMessage(message)) => {
-                        let peers = self.fs_interface.network_interface.peers.read().clone();
-                        self.send_to_multiple(message, &peers)
+                    Some(ToNetworkMessage::BroadcastMessage(message)) => self.queue(message, false),
+                    Some(ToNetworkMessage::AcknowledgedBroadcast(message)) => self.queue(message, true),
+                    // Sent after the pending broadcasts, they may depend on them
+                    Some(ToNetworkMessage::AnswerMessage(message, status, peer)) => {
+                        self.send_batch();
+                        self.send_with_answer(message, status, peer)
                     },
-                    Some(ToNetworkMessage::CloseNetwork) => {
+                    Some(ToNetworkMessage::SpecificMessage(message, to)) => {
+                        self.send_batch();
+                        self.send_to_multiple(message, &to)
+                    },
+                    Some(ToNetworkMessage::CloseNetwork) | None => {
+                        self.send_batch();
                         self.close();
                         return;
                     }
-                    None => {
-                        self.close();
-                        return;
-                    },
                 }
             }
         }
     }
 
+    fn queue(&mut self, message: Request, acknowledged: bool) {
+        // Broadcasts are the operations made on this pod
+        self.fs_interface.network_interface.log_operation(&message);
+        self.batch.push(message, acknowledged);
+        let max_message_size = self
+            .fs_interface
+            .network_interface
+            .global_config
+            .read()
+            .network
+            .max_message_size
+            .saturating_mul(1024 * 1024);
+        if self.batch.is_full(max_message_size) {
+            self.send_batch();
+        }
+    }
+
+    /// Broadcast the pending operations
+    fn send_batch(&mut self) {
+        let Some((message, acknowledged)) = self.batch.take() else {
+            return;
+        };
+        let peers = self.fs_interface.network_interface.peers.read().clone();
+
+        if acknowledged.contains(&true) {
+            for peer in peers {
+                self.deliver(Delivery {
+                    acknowledged: acknowledged.clone(),
+                    ..Delivery::new(peer, message.clone())
+                });
+            }
+        } else {
+            self.send_to_multiple(message, &peers)
+        }
+    }
+
+    /// Adapt a request to the capabilities of the peer, empty if it can't be sent to it
+    /// Peers not identified yet are expected to run the same release
+    fn prepare(&self, message: Request, peer: &PeerId) -> Vec<Request> {
+        match self
+            .fs_interface
+            .network_interface
+            .capabilities
+            .read()
+            .get(peer)
+        {
+            Some(capabilities) => {
+                let prepared = message.downgrade(capabilities);
+                if prepared.is_empty() {
+                    log::debug!("Not sending a request to {peer}, its version doesn't support it");
+                }
+                prepared
+            }
+            None => vec![message],
+        }
+    }
+
     fn send_with_answer(
         &mut self,
         message: Request,
         status: oneshot::Sender<Option<Response>>,
         peer: PeerId,
     ) {
+        // Requests waiting for an answer are never batches, they stay a single request
+        let Some(message) = self.prepare(message, &peer).pop() else {
+            let _ = status.send(None);
+            return;
+        };
         let answer = self
             .swarm
             .behaviour_mut()
@@ -111,25 +232,196 @@ impl EventLoop {
         if let Some(last) = to.first() {
             // Just to don't clone the message on first peer, lot's of message have only one peer and messages can be very heavy quickly
             for peer in &to[1..] {
+                for message in self.prepare(message.clone(), peer) {
+                    self.swarm
+                        .behaviour_mut()
+                        .request_response
+                        .send_request(peer, message);
+                }
+            }
+
+            for message in self.prepare(message, last) {
                 self.swarm
                     .behaviour_mut()
                     .request_response
-                    .send_request(peer, message.clone());
+                    .send_request(last, message);
             }
+        }
+    }
 
-            self.swarm
+    /// If the peer understands batches, peers not identified yet are expected to
+    fn batches_with(&self, peer: &PeerId) -> bool {
+        self.fs_interface
+            .network_interface
+            .capabilities
+            .read()
+            .get(peer)
+            .is_none_or(|capabilities| capabilities.contains(&Capability::Batches))
+    }
+
+    /// Send a request the peer has to acknowledge
+    /// Of a batch, only the operations to acknowledge are waited for
+    fn deliver(&mut self, delivery: Delivery) {
+        let prepared: Vec<(Request, Vec<bool>)> = match delivery.request {
+            Request::Batch(requests) if !delivery.acknowledged.is_empty() => {
+                // prepared one by one to keep track of the operations to acknowledge
+                let (requests, acknowledged): (Vec<Request>, Vec<bool>) = requests
+                    .into_iter()
+                    .zip(delivery.acknowledged)
+                    .flat_map(|(request, acknowledged)| {
+                        self.prepare(request, &delivery.peer)
+                            .into_iter()
+                            .map(move |request| (request, acknowledged))
+                    })
+                    .unzip();
+                if requests.is_empty() {
+                    vec![]
+                } else if self.batches_with(&delivery.peer) {
+                    vec![(Request::Batch(requests), acknowledged)]
+                } else {
+                    requests
+                        .into_iter()
+                        .zip(acknowledged)
+                        .map(|(request, acknowledged)| (request, vec![acknowledged]))
+                        .collect()
+                }
+            }
+            request => self
+                .prepare(request, &delivery.peer)
+                .into_iter()
+                .map(|request| (request, Vec::new()))
+                .collect(),
+        };
+
+        for (request, acknowledged) in prepared {
+            let request_id = self
+                .swarm
                 .behaviour_mut()
                 .request_response
-                .send_request(last, message);
+                .send_request(&delivery.peer, request.clone());
+            if acknowledged.is_empty() || acknowledged.contains(&true) {
+                self.acknowledgements.sent(
+                    request_id,
+                    Delivery {
+                        request,
+                        acknowledged,
+                        ..delivery
+                    },
+                );
+            }
+        }
+    }
+
+    /// Retry a delivery later, or record the peer as behind when out of attempts or gone
+    /// The operations that were only broadcasted are dropped
+    fn delivery_failed(&mut self, delivery: Delivery) {
+        let Some(delivery) = delivery.acknowledged_only() else {
+            return;
+        };
+        log::debug!(
+            "{} failed to acknowledge {} (attempt {})",
+            delivery.peer,
+            delivery.request,
+            delivery.attempt
+        );
+        let connected = self
+            .fs_interface
+            .network_interface
+            .peers
+            .read()
+            .contains(&delivery.peer);
+
+        let result = if connected {
+            self.acknowledgements.schedule(delivery)
+        } else {
+            Err(delivery)
+        };
+        if let Err(delivery) = result {
+            self.fs_interface
+                .network_interface
+                .fall_behind(delivery.peer, delivery.request);
+        }
+    }
+
+    /// Retry what the peer couldn't apply, only the failed operations of a batch
+    fn check_acknowledgement(&mut self, delivery: Delivery, response: &Response) {
+        match (delivery.request, response) {
+            (Request::Batch(requests), Response::Batch(responses)) => {
+                for (position, (request, response)) in
+                    requests.into_iter().zip(responses).enumerate()
+                {
+                    // only broadcasted, see Delivery::acknowledged
+                    if delivery.acknowledged.get(position) == Some(&false) {
+                        continue;
+                    }
+                    self.check_acknowledgement(
+                        Delivery {
+                            peer: delivery.peer,
+                            request,
+                            attempt: delivery.attempt,
+                            acknowledged: Vec::new(),
+                        },
+                        response,
+                    );
+                }
+            }
+            (request, Response::Failed) => self.delivery_failed(Delivery {
+                request,
+                ..delivery
+            }),
+            _ => {}
+        }
+    }
+
+    fn retry_deliveries(&mut self) {
+        for delivery in self.acknowledgements.due() {
+            if self
+                .fs_interface
+                .network_interface
+                .peers
+                .read()
+                .contains(&delivery.peer)
+            {
+                // sent with the current state, not the one of the first attempt
+                if let Some(request) = self
+                    .fs_interface
+                    .network_interface
+                    .refresh(delivery.request)
+                {
+                    self.deliver(Delivery {
+                        request,
+                        ..delivery
+                    });
+                }
+            } else {
+                self.fs_interface
+                    .network_interface
+                    .fall_behind(delivery.peer, delivery.request);
+            }
+        }
+    }
+
+    /// A pod joining a network trusts the peer it joins through until it receives the network's configuration
+    /// Other peers are checked against the configuration it has so far
+    fn admission(&self, peer: &PeerId) -> Admission {
+        if self.need_initialisation.is_some() && self.joining_through == Some(*peer) {
+            Admission::Admitted
+        } else {
+            self.fs_interface.network_interface.admission(peer)
         }
     }
 
     fn retry_fs_request(&mut self, failing_host: PeerId) {
-        let retry_peer = *self
-            .swarm
-            .connected_peers()
-            .find(|peer| **peer != failing_host)
-            .unwrap_or(&failing_host);
+        // only admitted members are trusted with the network's configuration
+        let retry_peer = self
+            .fs_interface
+            .network_interface
+            .peers
+            .read()
+            .iter()
+            .copied()
+            .find(|peer| *peer != failing_host)
+            .unwrap_or(failing_host);
 
         let request_id = self
             .swarm
@@ -137,10 +429,20 @@ impl EventLoop {
             .request_response
             .send_request(&retry_peer, Request::RequestFs);
 
+        self.joining_through = Some(retry_peer);
         self.need_initialisation = Some(Some(request_id));
     }
 
-    fn handle_response_message(&mut self, response: Response, peer: PeerId) {
+    /// If the answer to `request_id` may be the whole tree of `peer`: the [Request::RequestFs]
+    /// of a joining pod to the peer it joins through, or a catch-up
+    /// Any other tree is ignored, it would replace the configuration of this pod
+    fn asked_tree(&mut self, request_id: OutboundRequestId, peer: PeerId) -> bool {
+        let joining = self.need_initialisation == Some(Some(request_id))
+            && self.joining_through == Some(peer);
+        self.catch_ups.remove(&request_id) || joining
+    }
+
+    fn handle_response_message(&mut self, response: Response, peer: PeerId, asked_tree: bool) {
         log::trace!("Network Response: {:?}", response);
 
         let result = match response {
@@ -148,22 +450,40 @@ impl EventLoop {
                 .fs_interface
                 .respond_delta(ino, sig, peer)
                 .map_err(into_boxed_io),
-            Response::FsAnswer(tree, peers, global_config) => {
+            Response::FsAnswer(..) if !asked_tree => {
+                log::warn!("Ignored the tree {peer} sent without being asked");
+                Ok(())
+            }
+            Response::FsAnswer(mut tree, peers, global_config) => {
                 self.need_initialisation = None;
+                self.joining_through = None;
 
                 for (peer, info) in peers {
                     log::trace!("Trying to connect to the other peer: {peer}");
-                    for addr in info.listen_addrs {
-                        match self.swarm.dial(addr.clone()) {
-                            Ok(_) => break,
-                            Err(e) => log::error!("Couldn't connect to {peer} at {addr}: {e}"),
-                        };
+                    // dial the best transport we share, the next ones if it fails
+                    if let Err(e) = self.swarm.dial(info.dial_opts(peer)) {
+                        log::error!("Couldn't connect to {peer}: {e}");
                     }
                 }
 
+                {
+                    let mut config = self.fs_interface.network_interface.global_config.write();
+                    // The network key is never shared, keep ours
+                    let key = config.key;
+                    *config = global_config.clone();
+                    config.key = key;
+                }
+                // Members the new access rules refuse are not kept connected
+                for revoked in self.fs_interface.network_interface.revoked_peers() {
+                    log::info!("{revoked} is no longer allowed in the network, disconnecting");
+                    let _ = self.swarm.disconnect_peer_id(revoked);
+                }
+
                 let mut current = self.fs_interface.network_interface.itree.write();
-                // Overwrite local tree
+                // Overwrite local tree, the peer may not know about removals made here
+                let resurrected = tree.keep_tombstones(&current);
                 *current = tree;
+                current.claim_namespace(&self.fs_interface.network_interface.id);
 
                 if let Err(err) =
                     initiate_itree(&current, &global_config, self.fs_interface.disk.as_ref())
@@ -171,9 +491,65 @@ impl EventLoop {
                     log::error!("New itree failed: {err}, asking for an other");
                     drop(current);
                     self.retry_fs_request(peer);
+                    return;
+                }
+                drop(current);
+                // Let the peer drop them too
+                for (ino, tombstone) in resurrected {
+                    self.swarm
+                        .behaviour_mut()
+                        .request_response
+                        .send_request(&peer, Request::Remove(ino, tombstone));
                 }
                 Ok(())
             }
+            Response::AdmissionPending => {
+                log::info!("Waiting for a member of the network to approve this pod ({peer})");
+                Ok(())
+            }
+            Response::AccessDenied => {
+                log::error!("{peer} refused this pod access to the network");
+                Ok(())
+            }
+            // Answering a catch-up: its tree is the network's, what this pod did meanwhile is dropped
+            // with the removals it missed
+            Response::Forgotten if asked_tree && self.need_initialisation.is_none() => {
+                log::warn!("{peer} forgot this pod while it was away, fetching the whole tree");
+                let request_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .request_response
+                    .send_request(&peer, Request::RequestFs);
+                self.joining_through = Some(peer);
+                self.need_initialisation = Some(Some(request_id));
+                Ok(())
+            }
+            Response::Batch(responses) => {
+                for response in responses {
+                    self.handle_response_message(response, peer, false);
+                }
+                Ok(())
+            }
+            Response::Operations(operations) | Response::OperationsPage(operations, None) => {
+                log::info!("Caught up by {peer} on {} operations", operations.len());
+                self.apply_operations(operations, peer);
+                // it answered once it had applied what this pod sent
+                self.fs_interface
+                    .network_interface
+                    .tombstones_seen_by(&peer)
+                    .map_err(into_boxed_io)
+            }
+            Response::OperationsPage(operations, Some(after)) => {
+                log::debug!("Catching up from {peer}, {} operations", operations.len());
+                self.apply_operations(operations, peer);
+                let request_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .request_response
+                    .send_request(&peer, Request::ContinueOperations(after));
+                self.catch_ups.insert(request_id);
+                Ok(())
+            }
             _ => Ok(()),
         };
         if let Err(err) = result {
@@ -181,6 +557,50 @@ impl EventLoop {
         }
     }
 
+    /// Replay operations a peer applied while this pod was away, in order
+    /// Some may already be known or be superseded, they are only logged when failing
+    /// The contents this pod hosts are then requested from the peer, as for a live change
+    fn apply_operations(&mut self, operations: Vec<Request>, peer: PeerId) {
+        for operation in operations {
+            match self.apply_request(operation, peer) {
+                Ok(Response::DeltaRequest(ino, sig)) => {
+                    for request in self.prepare(Request::RequestDelta(ino, sig), &peer) {
+                        self.swarm
+                            .behaviour_mut()
+                            .request_response
+                            .send_request(&peer, request);
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => log::debug!("Skipped an operation missed from {peer}: {err}"),
+            }
+        }
+    }
+
+    /// Exchange with a returning peer the operations each side missed
+    fn request_operations(&mut self, peer: PeerId) {
+        let operations = self.fs_interface.network_interface.operations.read();
+        if !operations.knows(&peer) {
+            return;
+        }
+        let made = operations.missed_by(&peer).unwrap_or_else(|| {
+            log::warn!(
+                "The log doesn't go back to when {peer} left, local changes since may not reach it"
+            );
+            Vec::new()
+        });
+        drop(operations);
+
+        for request in self.prepare(Request::RequestOperations(made), &peer) {
+            let request_id = self
+                .swarm
+                .behaviour_mut()
+                .request_response
+                .send_request(&peer, request);
+            self.catch_ups.insert(request_id);
+        }
+    }
+
     fn handle_request_message(
         &mut self,
         request: Request,
@@ -188,11 +608,93 @@ impl EventLoop {
         peer: PeerId,
     ) {
         log::trace!("Network Request: {:?}", request);
-        let result = match request {
-            Request::RedundancyFile(id, binary) => self
-                .fs_interface
-                .recept_redundancy(id, binary)
-                .map_err(into_boxed_io),
+        if let Some(refusal) = self.admission(&peer).refusal() {
+            log::debug!("Refusing {request} from non-admitted peer {peer}");
+            let _ = self
+                .swarm
+                .behaviour_mut()
+                .request_response
+                .send_response(channel, refusal);
+            return;
+        }
+        if let Some(refusal) = self
+            .fs_interface
+            .network_interface
+            .forgotten_refusal(&peer, &request)
+        {
+            log::debug!("Refusing {request} from {peer}, forgotten until it fetches the tree");
+            let _ = self
+                .swarm
+                .behaviour_mut()
+                .request_response
+                .send_response(channel, refusal);
+            return;
+        }
+        if matches!(
+            request,
+            Request::RedundancyFile(_, _) | Request::RequestFile(_)
+        ) {
+            return self.answer_file_message(request, channel, peer);
+        }
+
+        let response = self.apply_request(request, peer).unwrap_or_else(|err| {
+            log::trace!("Request Message Failed: {err}");
+            Response::Failed
+        });
+        let _ = self
+            .swarm
+            .behaviour_mut()
+            .request_response
+            .send_response(channel, response);
+    }
+
+    /// Store or read a whole file for a pod of 1.0, on the runtime rather than the event loop
+    fn answer_file_message(
+        &mut self,
+        request: Request,
+        channel: ResponseChannel<Response>,
+        peer: PeerId,
+    ) {
+        let fs_interface = self.fs_interface.clone();
+        self.file_messages.spawn(async move {
+            let response = match request {
+                Request::RedundancyFile(ino, content) => fs_interface
+                    .receive_file_message(ino, content, Storage::Replica)
+                    .await
+                    .map(|()| Response::Success),
+                Request::RequestFile(ino) => fs_interface
+                    .read_file_message(ino)
+                    .await
+                    .map(Response::RequestedFile),
+                _ => Ok(Response::Failed),
+            };
+            let response = response.unwrap_or_else(|err| {
+                log::error!("Failed to answer {peer} about a file: {err}");
+                Response::Failed
+            });
+            (channel, response)
+        });
+    }
+
+    /// Apply a request, recording it in the operation log once it succeeded
+    fn apply_request(&mut self, request: Request, peer: PeerId) -> io::Result<Response> {
+        let operation = OperationLog::loggable(&request);
+        let response = self.execute_request(request, peer)?;
+
+        if let Some(operation) = operation {
+            self.fs_interface
+                .network_interface
+                .operations
+                .write()
+                .record(operation);
+        }
+        Ok(response)
+    }
+
+    fn execute_request(&mut self, request: Request, peer: PeerId) -> io::Result<Response> {
+        match request {
+            // Only sent alone, see answer_file_message
+            Request::RedundancyFile(_, _) | Request::RequestFile(_) => Ok(Response::Failed),
             Request::Inode(inode) => self.fs_interface.recept_inode(inode).map_err(into_boxed_io),
             Request::AddHosts(id, hosts) => self
                 .fs_interface
@@ -202,17 +704,18 @@ impl EventLoop {
                 .fs_interface
                 .recept_remove_hosts(id, &hosts)
                 .map_err(into_boxed_io),
+            Request::SetShards(id, shards) => self
+                .fs_interface
+                .recept_shards(id, shards)
+                .map_err(into_boxed_io),
             Request::EditMetadata(id, meta) => self
                 .fs_interface
                 .acknowledge_metadata(id, meta)
                 .map_err(into_boxed_io),
-            Request::Remove(id) => self
+            Request::Remove(id, tombstone) => self
                 .fs_interface
-                .recept_remove_inode(id)
+                .recept_remove_inode(id, tombstone)
                 .map_err(into_boxed_io),
-            Request::RequestFile(inode) => {
-                self.fs_interface.send_file(inode).map_err(into_boxed_io)
-            }
             Request::RequestFs => self
                 .fs_interface
                 .network_interface
@@ -232,33 +735,101 @@ impl EventLoop {
                 .network_interface
                 .recept_remove_inode_xattr(ino, &key)
                 .map_err(into_boxed_io),
-            Request::FileDelta(ino, meta, sig, delta) => self
+            Request::FileDelta(ino, meta, version, sig, delta) => {
+                let version = self
+                    .fs_interface
+                    .received_version(ino, version, peer)
+                    .map_err(into_boxed_io)?;
+                self.fs_interface
+                    .accept_delta(ino, meta, version, sig, delta)
+                    .map_err(into_boxed_io)
+            }
+            Request::FileChanged(ino, meta, version) => {
+                let version = self
+                    .fs_interface
+                    .received_version(ino, version, peer)
+                    .map_err(into_boxed_io)?;
+                self.fs_interface
+                    .accept_file_changed(ino, meta, version)
+                    .map_err(into_boxed_io)
+            }
+            Request::AdmitPeer(admitted) => {
+                if admitted == self.fs_interface.network_interface.id
+                    && self.need_initialisation.is_some()
+                    && self.joining_through == Some(peer)
+                {
+                    log::info!("Approved by {peer}, requesting the filesystem");
+                    let request_id = self
+                        .swarm
+                        .behaviour_mut()
+                        .request_response
+                        .send_request(&peer, Request::RequestFs);
+                    self.need_initialisation = Some(Some(request_id));
+                }
+                self.fs_interface
+                    .network_interface
+                    .recept_admit_peer(admitted)
+                    .map_err(into_boxed_io)
+            }
+            Request::Lock(ino, lock) => self
                 .fs_interface
-                .accept_delta(ino, meta, sig, delta)
+                .network_interface
+                .recept_lock(ino, lock)
                 .map_err(into_boxed_io),
-            Request::FileChanged(ino, meta) => self
+            Request::Unlock(ino, owner, start, end) => self
                 .fs_interface
-                .accept_file_changed(ino, meta)
+                .network_interface
+                .recept_unlock(ino, owner, start, end)
                 .map_err(into_boxed_io),
-        };
-
-        match result {
-            Ok(response) => {
-                let _ = self
-                    .swarm
-                    .behaviour_mut()
-                    .request_response
-                    .send_response(channel, response);
+            // A pod without a tree yet has nothing to compare with, it must not answer with it
+            Request::RequestOperations(_) if self.need_initialisation.is_some() => {
+                Ok(Response::Failed)
             }
-            Err(err) => {
-                let _ = self
-                    .swarm
-                    .behaviour_mut()
-                    .request_response
-                    .send_response(channel, Response::Failed);
-                log::trace!("Request Message Failed: {err}")
+            Request::RequestOperations(operations) => {
+                log::info!(
+                    "{peer} is back with {} operations made meanwhile",
+                    operations.len()
+                );
+                self.apply_operations(operations, peer);
+                self.fs_interface
+                    .network_interface
+                    .send_missed_operations(peer)
+                    .map_err(into_boxed_io)
             }
-        };
+            Request::ContinueOperations(after) => {
+                match self
+                    .fs_interface
+                    .network_interface
+                    .operations_after(&peer, after)
+                {
+                    Some(page) => Ok(page),
+                    None => {
+                        log::info!("The log was truncated while catching up {peer}, sending the whole tree");
+                        self.fs_interface
+                            .network_interface
+                            .send_filesystem(peer)
+                            .map_err(into_boxed_io)
+                    }
+                }
+            }
+            Request::RequestDelta(ino, sig) => self
+                .fs_interface
+                .respond_delta(ino, sig, peer)
+                .map(|()| Response::Success)
+                .map_err(into_boxed_io),
+            // Each operation is answered on its own, a failure doesn't stop the following ones
+            Request::Batch(requests) => Ok(Response::Batch(
+                requests
+                    .into_iter()
+                    .map(|request| {
+                        self.apply_request(request, peer).unwrap_or_else(|err| {
+                            log::trace!("Batched Request Failed: {err}");
+                            Response::Failed
+                        })
+                    })
+                    .collect(),
+            )),
+        }
     }
 
     fn handle_rr_event(&mut self, event: request_response::Event<Request, Response>) {
@@ -274,12 +845,17 @@ impl EventLoop {
                     if let Some(answer) = self.answers.remove(&request_id) {
                         let _ = answer.send(Some(response.clone()));
                     };
-                    self.handle_response_message(response, peer);
+                    if let Some(delivery) = self.acknowledgements.answered(&request_id) {
+                        self.check_acknowledgement(delivery, &response);
+                    }
+                    let asked_tree = self.asked_tree(request_id, peer);
+                    self.handle_response_message(response, peer, asked_tree);
                 }
             },
             request_response::Event::OutboundFailure {
                 peer, request_id, ..
             } => {
+                self.catch_ups.remove(&request_id);
                 if let Some(Some(id)) = self.need_initialisation {
                     if id == request_id {
                         self.retry_fs_request(peer);
@@ -289,6 +865,12 @@ impl EventLoop {
                 if let Some(answer) = self.answers.remove(&request_id) {
                     let _ = answer.send(None);
                 }
+                if let Some(delivery) = self.acknowledgements.answered(&request_id) {
+                    self.delivery_failed(delivery);
+                }
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                log::warn!("Couldn't answer a request from {peer}: {error}");
             }
             e => log::trace!("rr: {e:?}"),
         }
@@ -302,17 +884,107 @@ impl EventLoop {
                 info,
             } => {
                 log::trace!("id received!: {} {} {:?}", connection_id, peer_id, info);
-                if let Some(None) = self.need_initialisation {
-                    let request_id = self
-                        .swarm
-                        .behaviour_mut()
-                        .request_response
-                        .send_request(&peer_id, Request::RequestFs);
-                    self.need_initialisation = Some(Some(request_id));
+
+                let Some(version) = protocol::common_version(&info.protocols) else {
+                    log::warn!(
+                        "Refused {} ({peer_id}): no protocol version in common, it runs '{}'",
+                        info.agent_version,
+                        info.protocols
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                    let _ = self.swarm.disconnect_peer_id(peer_id);
+                    return;
+                };
+                let network_name = self
+                    .fs_interface
+                    .network_interface
+                    .global_config
+                    .read()
+                    .general
+                    .name
+                    .clone();
+                let peer_network = swarm::network_name(&info.protocol_version).unwrap_or_default();
+                if !network_name.is_empty()
+                    && !peer_network.is_empty()
+                    && peer_network != network_name
+                {
+                    log::warn!(
+                        "Refused {} ({peer_id}): it is part of the network '{peer_network}', not '{network_name}'",
+                        info.agent_version
+                    );
+                    let _ = self.swarm.disconnect_peer_id(peer_id);
+                    return;
+                }
+                let capabilities = Capability::from_protocols(&info.protocols);
+                log::debug!("{peer_id} speaks {version} with {capabilities:?}");
+                self.fs_interface
+                    .network_interface
+                    .capabilities
+                    .write()
+                    .insert(peer_id, capabilities);
+                self.fs_interface
+                    .network_interface
+                    .scores
+                    .write()
+                    .set_zone(peer_id, protocol::zone_from_protocols(&info.protocols));
+
+                let info = network::PeerInfo {
+                    nickname: info.agent_version,
+                    listen_addrs: info.listen_addrs,
+                };
+
+                match self.admission(&peer_id) {
+                    Admission::Admitted => {}
+                    Admission::Pending => {
+                        self.fs_interface
+                            .network_interface
+                            .add_pending_peer(peer_id, info);
+                        return;
+                    }
+                    Admission::Denied => {
+                        log::warn!(
+                            "Refused {} ({peer_id}): not allowed by the network access rules",
+                            info.nickname
+                        );
+                        let _ = self.swarm.disconnect_peer_id(peer_id);
+                        return;
+                    }
+                }
+
+                if matches!(self.need_initialisation, Some(None))
+                    && self.joining_through == Some(peer_id)
+                {
+                    let known = self
+                        .fs_interface
+                        .network_interface
+                        .operations
+                        .read()
+                        .knows(&peer_id);
+                    if self.resumed && known {
+                        // The saved tree is caught up from the operation log, see request_operations
+                        self.need_initialisation = None;
+                    } else {
+                        let request_id = self
+                            .swarm
+                            .behaviour_mut()
+                            .request_response
+                            .send_request(&peer_id, Request::RequestFs);
+                        self.need_initialisation = Some(Some(request_id));
+                    }
                 };
                 self.fs_interface
                     .network_interface
                     .connect_peer(peer_id, info);
+
+                for request in self.fs_interface.network_interface.catch_up(&peer_id) {
+                    self.deliver(Delivery::new(peer_id, request));
+                }
+                if self.need_initialisation.is_none() {
+                    self.request_operations(peer_id);
+                }
             }
             e => log::trace!("identify: {e:?}"),
         }
@@ -326,6 +998,21 @@ impl EventLoop {
             SwarmEvent::Behaviour(BehaviourEvent::Identify(event)) => {
                 self.handle_identify_event(event)
             }
+            SwarmEvent::Behaviour(BehaviourEvent::Ping(ping::Event { peer, result, .. })) => {
+                match result {
+                    Ok(rtt) => self
+                        .fs_interface
+                        .network_interface
+                        .scores
+                        .write()
+                        .record_rtt(peer, rtt),
+                    Err(err) => log::trace!("ping of {peer} failed: {err}"),
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(event)) => {
+                // Only used to find the network when starting, see discover_network
+                log::trace!("mdns: {event:?}")
+            }
             SwarmEvent::NewListenAddr { address, .. } => {
                 self.fs_interface
                     .network_interface
@@ -342,8 +1029,16 @@ impl EventLoop {
                     .remove(&address);
                 log::trace!("expired listen address: {address:?}")
             }
-            SwarmEvent::ConnectionEstablished { .. } => {
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
                 // Peer interaction start at identify
+                if matches!(self.need_initialisation, Some(None))
+                    && self.joining_through.is_none()
+                    && endpoint.is_dialer()
+                {
+                    self.joining_through = Some(peer_id);
+                }
             }
             SwarmEvent::IncomingConnection { connection_id, .. } => {
                 if self.closing {
@@ -369,6 +1064,16 @@ impl EventLoop {
                     .peers_info
                     .write()
                     .remove(&peer_id);
+                self.fs_interface
+                    .network_interface
+                    .capabilities
+                    .write()
+                    .remove(&peer_id);
+                self.fs_interface
+                    .network_interface
+                    .pending_peers
+                    .write()
+                    .remove(&peer_id);
                 self.fs_interface
                     .network_interface
                     .peers
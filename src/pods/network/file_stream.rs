@@ -0,0 +1,1373 @@
+//! File transfers on dedicated libp2p streams
+//!
+//! File contents never go through the request-response codec: a stream is opened per transfer,
+//! a small header frame describes it, then the content follows in raw chunks
+//! read from and written to the disk directly, so a file is never held whole in memory.
+//! With peers understanding it, each chunk is framed and compressed if it is worth it,
+//! see [compression](crate::pods::network::compression).
+//! The shards of the `erasure` redundancy mode go through the same streams,
+//! see [erasure](crate::pods::network::erasure).
+//! Pods of 1.0 have no file streams, whole files are exchanged with them in
+//! [Request::RedundancyFile] and [Request::RequestFile] messages.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    future::Future,
+    io,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{stream::FuturesUnordered, AsyncReadExt, AsyncWriteExt, StreamExt};
+use libp2p::{PeerId, Stream, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::oneshot;
+
+use crate::network::{
+    message::{Request, Response, ToNetworkMessage},
+    protocol::Capability,
+};
+use crate::pods::{
+    disk_managers::DiskManager,
+    filesystem::fs_interface::FsInterface,
+    itree::{ContentHash, ITree, Ino, VersionVector, LOCAL_FOLDER, SHARDS_FOLDER},
+    network::{
+        admission::Admission,
+        batch::SMART_FREQUENCY,
+        codec::{read_frame, write_frame},
+        compression,
+        peer_scores::PeerScores,
+    },
+    whpath::WhPath,
+};
+
+pub const FILE_PROTOCOL: StreamProtocol = StreamProtocol::new("/wormhole/file/1.0.0");
+
+/// Size of the chunks read from the disk and sent on the stream
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Headers are tiny, anything bigger is a misbehaving peer
+const MAX_HEADER_SIZE: u64 = 4096;
+
+/// Files at least this big are pulled from several hosts at once
+pub const PARALLEL_PULL_THRESHOLD: u64 = 8 * 1024 * 1024;
+
+/// Size of the parts of a file pulled from several hosts
+const PULL_PART_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Time left to the creation of a pushed file to arrive, after the batch window of the sender
+const UNKNOWN_INODE_MARGIN: Duration = Duration::from_secs(1);
+
+/// Create a folder of the [LOCAL_FOLDER], or the local folder itself
+pub fn create_local_folder(disk: &dyn DiskManager, folder: &str) -> io::Result<()> {
+    for folder in [LOCAL_FOLDER, folder] {
+        let path = WhPath::try_from(folder).map_err(|err| err.to_io())?;
+        match disk.new_dir(&path, 0o700) {
+            Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Temporary file a whole file is received in, see [FsInterface::adopt_received]
+fn receiving_path(ino: Ino) -> io::Result<WhPath> {
+    WhPath::try_from(format!("{LOCAL_FOLDER}/{ino}.part")).map_err(|err| err.to_io())
+}
+
+/// Parts of a file pulled from several hosts, and the hosts downloading them
+struct PullParts {
+    /// Parts no host is downloading yet
+    pending: VecDeque<Range<u64>>,
+    /// Start of the parts received
+    done: HashSet<u64>,
+    count: usize,
+    running: Vec<(PeerId, Range<u64>)>,
+}
+
+impl PullParts {
+    fn new(size: u64) -> Self {
+        let pending: VecDeque<Range<u64>> = (0..size)
+            .step_by(PULL_PART_SIZE as usize)
+            .map(|start| start..(start + PULL_PART_SIZE).min(size))
+            .collect();
+        Self {
+            count: pending.len(),
+            pending,
+            done: HashSet::new(),
+            running: Vec::new(),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.done.len() == self.count
+    }
+
+    fn downloading(&self, part: &Range<u64>) -> usize {
+        self.running
+            .iter()
+            .filter(|(_, other)| other.start == part.start)
+            .count()
+    }
+
+    /// Part for an idle host to download
+    /// Once every part is asked, a part only one other host is downloading
+    fn next(&mut self, host: PeerId) -> Option<Range<u64>> {
+        let part = self.pending.pop_front().or_else(|| {
+            self.running
+                .iter()
+                .find(|(_, part)| self.downloading(part) == 1)
+                .map(|(_, part)| part.clone())
+        })?;
+        self.running.push((host, part.clone()));
+        Some(part)
+    }
+
+    /// `host` sent the whole part, the other hosts downloading it are no longer waited for
+    fn received(&mut self, host: PeerId, part: &Range<u64>) {
+        self.running.retain(|(other, _)| *other != host);
+        self.done.insert(part.start);
+        self.running.retain(|(_, other)| other.start != part.start);
+    }
+
+    /// `host` failed to send the part, it goes back to the others unless one is on it
+    fn failed(&mut self, host: PeerId, part: Range<u64>) {
+        self.running.retain(|(other, _)| *other != host);
+        if !self.done.contains(&part.start) && self.downloading(&part) == 0 {
+            self.pending.push_back(part);
+        }
+    }
+}
+
+/// What a file received whole becomes on this pod
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Storage {
+    /// This pod hosts the file, and the network is told
+    Replica,
+    /// A copy in the [SparseCache](crate::pods::filesystem::sparse_cache::SparseCache), never advertised
+    Cache,
+}
+
+/// Opens a transfer
+#[derive(Serialize, Deserialize, Debug)]
+enum TransferRequest {
+    /// Send me this file
+    Pull(Ino),
+    /// Store this file (redundancy), of this size
+    Push(Ino, u64),
+    /// Send me this range of the file: offset, length
+    PullRange(Ino, u64, u64),
+    /// Send me the checksum of this file
+    Checksum(Ino),
+    /// The content of this transfer is sent in compressed chunks, both ways
+    Compressed(Box<TransferRequest>),
+    /// Store this shard of the file: index, length, hash
+    PushShard(Ino, usize, u64, ContentHash),
+    /// Send me this shard of the file, held by you
+    PullShard(Ino, usize),
+}
+
+impl TransferRequest {
+    fn compressed_if(self, compressed: bool) -> Self {
+        if compressed {
+            Self::Compressed(Box::new(self))
+        } else {
+            self
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum TransferAnswer {
+    /// The transfer goes on, with the file's size for a pull
+    Accepted(u64),
+    Refused(String),
+    /// A pushed file was entirely received and stored
+    Done,
+    /// SHA-256 of the whole file
+    Checksum([u8; 32]),
+}
+
+fn refused(reason: String) -> io::Error {
+    io::Error::other(format!("transfer refused by the peer: {reason}"))
+}
+
+/// Read the next piece of content, of `len` bytes on a raw stream and at most `len` bytes
+/// on a compressed one, into `chunk`. Returns the length read
+async fn read_content(
+    stream: &mut Stream,
+    chunk: &mut [u8],
+    len: usize,
+    compressed: bool,
+) -> io::Result<usize> {
+    if !compressed {
+        stream.read_exact(&mut chunk[..len]).await?;
+        return Ok(len);
+    }
+    // compressed flag, length of the content, length sent
+    let mut header = [0u8; 9];
+    stream.read_exact(&mut header).await?;
+    let raw = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    let sent = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+    if raw == 0 || raw > len || sent > CHUNK_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid chunk of content",
+        ));
+    }
+
+    if header[0] == 0 {
+        if sent != raw {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        stream.read_exact(&mut chunk[..raw]).await?;
+    } else {
+        let mut payload = vec![0; sent];
+        stream.read_exact(&mut payload).await?;
+        let content = compression::decompress(&payload, raw)?;
+        if content.len() != raw {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        chunk[..raw].copy_from_slice(&content);
+    }
+    Ok(raw)
+}
+
+impl FsInterface {
+    /// Run a disk operation on the blocking threads, the transfers being async tasks
+    pub async fn on_disk<T, F>(&self, operation: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn DiskManager) -> io::Result<T> + Send + 'static,
+    {
+        let disk = self.disk.clone();
+        tokio::task::spawn_blocking(move || operation(disk.as_ref()))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    fn file_path(&self, ino: Ino) -> io::Result<(WhPath, u16, u64)> {
+        let itree = ITree::read_lock(&self.network_interface.itree, "file_stream::file_path")
+            .map_err(io::Error::other)?;
+        let path = itree
+            .get_path_from_inode_id(ino)
+            .map_err(io::Error::other)?;
+        let inode = itree.get_inode(ino).map_err(io::Error::other)?;
+        Ok((path, inode.meta.perm, inode.meta.size))
+    }
+
+    fn file_version(&self, ino: Ino) -> io::Result<VersionVector> {
+        Ok(
+            ITree::read_lock(&self.network_interface.itree, "file_stream::version")
+                .map_err(io::Error::other)?
+                .get_inode(ino)
+                .map_err(io::Error::other)?
+                .version
+                .clone(),
+        )
+    }
+
+    /// Hash of the content of the file, None while unknown
+    fn file_hash(&self, ino: Ino) -> io::Result<Option<ContentHash>> {
+        Ok(
+            ITree::read_lock(&self.network_interface.itree, "file_stream::hash")
+                .map_err(io::Error::other)?
+                .get_inode(ino)
+                .map_err(io::Error::other)?
+                .meta
+                .hash,
+        )
+    }
+
+    /// Check a file entirely received against the hash its version had when the transfer started
+    /// A mismatching copy is dropped, unless this pod hosts the file already
+    pub async fn verify_received(
+        &self,
+        ino: Ino,
+        path: &WhPath,
+        size: u64,
+        expected: Option<ContentHash>,
+    ) -> io::Result<()> {
+        match expected {
+            Some(expected) if self.range_checksum(path, 0, size).await? != expected => {
+                self.drop_cached_copies(&[ino]);
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("the content received for {ino} doesn't match its hash"),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Keep a file entirely received, of the version it had when the transfer started
+    pub fn store_received(
+        &self,
+        ino: Ino,
+        version: VersionVector,
+        size: u64,
+        storage: Storage,
+    ) -> io::Result<()> {
+        match storage {
+            Storage::Replica => {
+                self.sparse.write().forget(ino);
+                self.network_interface
+                    .add_inode_hosts(ino, &[self.network_interface.id])
+                    .map_err(io::Error::other)
+            }
+            Storage::Cache => {
+                let evicted = self.sparse.write().insert_whole(ino, version, size);
+                self.drop_cached_copies(&evicted);
+                Ok(())
+            }
+        }
+    }
+
+    /// Promote the cached copy of a file to a replica, if it is whole and up to date
+    fn promote_cached_copy(&self, ino: Ino) -> io::Result<bool> {
+        let version = self.file_version(ino)?;
+        if !self.sparse.read().is_whole(ino, &version) {
+            return Ok(false);
+        }
+        self.sparse.write().forget(ino);
+        self.network_interface
+            .add_inode_hosts(ino, &[self.network_interface.id])
+            .map_err(io::Error::other)?;
+        Ok(true)
+    }
+
+    /// Path and size of a file this pod hosts entirely, only those can serve ranges
+    fn hosted_file_path(&self, ino: Ino) -> io::Result<(WhPath, u64)> {
+        let hosted = ITree::read_lock(&self.network_interface.itree, "file_stream::hosted")
+            .map_err(io::Error::other)?
+            .get_inode_hosts(ino)
+            .map_err(io::Error::other)?
+            .contains(&self.network_interface.id);
+        if !hosted {
+            return Err(io::Error::other(format!("{ino} isn't hosted here")));
+        }
+        let (path, _, size) = self.file_path(ino)?;
+        Ok((path, size))
+    }
+
+    async fn open_file_stream(&self, peer: PeerId) -> io::Result<Stream> {
+        self.network_interface
+            .streams
+            .clone()
+            .open_stream(peer, FILE_PROTOCOL)
+            .await
+            .map_err(io::Error::other)
+    }
+
+    /// If the content of the transfers with `peer` can be compressed
+    fn compresses_for(&self, peer: &PeerId) -> bool {
+        self.network_interface
+            .capabilities
+            .read()
+            .get(peer)
+            .is_some_and(|capabilities| capabilities.contains(&Capability::Compression))
+    }
+
+    /// Send a piece of content, in a frame compressed if it is worth it
+    /// Returns false if it didn't shrink: the rest of the content most likely won't either
+    async fn write_compressed(
+        &self,
+        stream: &mut Stream,
+        content: &[u8],
+        level: i32,
+    ) -> io::Result<bool> {
+        let compressed = compression::compress(content, level);
+        let shrunk = compressed.is_some();
+        let (flag, payload) = match &compressed {
+            Some(compressed) => (1u8, compressed.as_slice()),
+            None => (0u8, content),
+        };
+        let mut header = [flag; 9];
+        header[1..5].copy_from_slice(&(content.len() as u32).to_be_bytes());
+        header[5..].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+
+        stream.write_all(&header).await?;
+        stream.write_all(payload).await?;
+        self.network_interface
+            .compression
+            .record(content.len(), header.len() + payload.len());
+        Ok(shrunk)
+    }
+
+    /// Send `len` bytes of the local file from `start` on the stream
+    async fn send_content(
+        &self,
+        stream: &mut Stream,
+        path: &WhPath,
+        start: u64,
+        len: u64,
+        compressed: bool,
+    ) -> io::Result<()> {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut offset = start;
+        let end = start + len;
+        let mut level = self
+            .network_interface
+            .global_config
+            .read()
+            .network
+            .compression;
+
+        while offset < end {
+            let len = CHUNK_SIZE.min((end - offset) as usize);
+            let path = path.clone();
+            let (returned, read) = self
+                .on_disk(move |disk| {
+                    let read = disk.read_file(&path, offset as usize, &mut chunk[..len])?;
+                    Ok((chunk, read))
+                })
+                .await?;
+            chunk = returned;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if !compressed {
+                stream.write_all(&chunk[..read]).await?;
+            } else if !self.write_compressed(stream, &chunk[..read], level).await? {
+                level = 0;
+            }
+            offset += read as u64;
+        }
+        stream.flush().await
+    }
+
+    /// Receive `len` bytes from the stream, written from `offset` in the local file
+    async fn receive_into(
+        &self,
+        stream: &mut Stream,
+        path: &WhPath,
+        offset: u64,
+        len: u64,
+        compressed: bool,
+    ) -> io::Result<()> {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut received = 0;
+
+        while received < len {
+            let size = CHUNK_SIZE.min((len - received) as usize);
+            let size = read_content(stream, &mut chunk, size, compressed).await?;
+            let path = path.clone();
+            let at = (offset + received) as usize;
+            chunk = self
+                .on_disk(move |disk| {
+                    disk.write_file(&path, &chunk[..size], at)?;
+                    Ok(chunk)
+                })
+                .await?;
+            received += size as u64;
+        }
+        Ok(())
+    }
+
+    /// Receive a shard of `len` bytes from the stream into its local file, checked against its hash
+    async fn receive_shard(
+        &self,
+        stream: &mut Stream,
+        path: &WhPath,
+        len: u64,
+        hash: ContentHash,
+        compressed: bool,
+    ) -> io::Result<()> {
+        let created = path.clone();
+        self.on_disk(move |disk| {
+            create_local_folder(disk, SHARDS_FOLDER)?;
+            disk.new_file(&created, 0o600)
+        })
+        .await?;
+        self.receive_into(stream, path, 0, len, compressed).await?;
+        if self.range_checksum(path, 0, len).await? != hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the shard received in {path} doesn't match its hash"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Receive a whole file of `size` bytes with `receive`, then store it as asked
+    /// It is written to a temporary file first, the local copy is only replaced once it is
+    /// checked against the hash, or the checksum `receive` returns while the hash is unknown
+    async fn receive_whole<F, Fut>(
+        &self,
+        ino: Ino,
+        size: u64,
+        storage: Storage,
+        receive: F,
+    ) -> io::Result<()>
+    where
+        F: FnOnce(WhPath) -> Fut,
+        Fut: Future<Output = io::Result<Option<ContentHash>>>,
+    {
+        let (path, perm, _) = self.file_path(ino)?;
+        let version = self.file_version(ino)?;
+        let hash = self.file_hash(ino)?;
+        let received = receiving_path(ino)?;
+        let created = received.clone();
+        self.on_disk(move |disk| {
+            create_local_folder(disk, LOCAL_FOLDER)?;
+            disk.new_file(&created, perm)?;
+            disk.set_file_size(&created, size as usize)
+        })
+        .await?;
+
+        let result = async {
+            let checksum = receive(received.clone()).await?;
+            self.adopt_received(ino, &received, &path, size, hash.or(checksum))
+                .await
+        }
+        .await;
+        if result.is_err() {
+            let received = received.clone();
+            let _ = self.on_disk(move |disk| disk.remove_file(&received)).await;
+        }
+        result?;
+        self.store_received(ino, version, size, storage)
+    }
+
+    /// Receive `size` bytes from the stream, then store them as asked
+    async fn receive_content(
+        &self,
+        stream: &mut Stream,
+        ino: Ino,
+        size: u64,
+        storage: Storage,
+        compressed: bool,
+    ) -> io::Result<()> {
+        self.receive_whole(ino, size, storage, |received| async move {
+            self.receive_into(stream, &received, 0, size, compressed)
+                .await?;
+            Ok(None)
+        })
+        .await
+    }
+
+    /// Store a whole file sent in a message by a pod of 1.0
+    pub async fn receive_file_message(
+        &self,
+        ino: Ino,
+        content: Arc<Vec<u8>>,
+        storage: Storage,
+    ) -> io::Result<()> {
+        let size = content.len() as u64;
+        self.receive_whole(ino, size, storage, |received| async move {
+            self.on_disk(move |disk| disk.write_file(&received, &content, 0))
+                .await?;
+            Ok(None)
+        })
+        .await
+    }
+
+    /// Read a whole file to send it in a message to a pod of 1.0
+    pub async fn read_file_message(&self, ino: Ino) -> io::Result<Vec<u8>> {
+        let (path, size) = self.hosted_file_path(ino)?;
+        self.on_disk(move |disk| {
+            let mut content = vec![0; size as usize];
+            let read = disk.read_file(&path, 0, &mut content)?;
+            content.truncate(read);
+            Ok(content)
+        })
+        .await
+    }
+
+    /// If `peer` has file streams, else it is a pod of 1.0
+    /// Peers not identified yet are expected to run the same release
+    fn streams_files(&self, peer: &PeerId) -> bool {
+        self.network_interface
+            .capabilities
+            .read()
+            .get(peer)
+            .is_none_or(|capabilities| capabilities.contains(&Capability::FileStreams))
+    }
+
+    /// Send a request to `peer` and wait for its answer, for the files exchanged with pods of 1.0
+    async fn ask(&self, peer: PeerId, request: Request) -> io::Result<Response> {
+        let (answer_tx, answer_rx) = oneshot::channel();
+        self.network_interface
+            .to_network_message_tx
+            .send(ToNetworkMessage::AnswerMessage(request, answer_tx, peer))
+            .map_err(|_| io::Error::other("the network is closed"))?;
+        answer_rx
+            .await
+            .ok()
+            .flatten()
+            .ok_or_else(|| io::Error::other(format!("{peer} didn't answer")))
+    }
+
+    /// Replace the local copy of a file by the one received in `received`, if it matches the
+    /// hash its version had when the transfer started
+    pub async fn adopt_received(
+        &self,
+        ino: Ino,
+        received: &WhPath,
+        path: &WhPath,
+        size: u64,
+        expected: Option<ContentHash>,
+    ) -> io::Result<()> {
+        if let Some(expected) = expected {
+            if self.range_checksum(received, 0, size).await? != expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("the content received for {ino} doesn't match its hash"),
+                ));
+            }
+        }
+        // the cached ranges are replaced
+        self.sparse.write().forget(ino);
+        let (received, path) = (received.clone(), path.clone());
+        self.on_disk(move |disk| disk.mv_file(&received, &path))
+            .await
+    }
+
+    /// Download a range of a file from `peer`, written at its place in the local copy
+    /// Returns the length received, shorter than asked past the end of the file
+    pub async fn download_range(
+        &self,
+        peer: PeerId,
+        ino: Ino,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<u64> {
+        let (path, _, _) = self.file_path(ino)?;
+        self.download_range_into(peer, ino, &path, offset, len)
+            .await
+    }
+
+    /// Download a range of a file from `peer`, written at its place in `path`
+    async fn download_range_into(
+        &self,
+        peer: PeerId,
+        ino: Ino,
+        path: &WhPath,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<u64> {
+        let _in_flight = PeerScores::start_transfer(&self.network_interface.scores, peer);
+        let started = Instant::now();
+        let compressed = self.compresses_for(&peer);
+        let mut stream = self.open_file_stream(peer).await?;
+        write_frame(
+            &mut stream,
+            &TransferRequest::PullRange(ino, offset, len).compressed_if(compressed),
+            MAX_HEADER_SIZE,
+        )
+        .await?;
+
+        let len = match read_frame(&mut stream, MAX_HEADER_SIZE).await? {
+            TransferAnswer::Accepted(len) => len,
+            TransferAnswer::Refused(reason) => return Err(refused(reason)),
+            TransferAnswer::Done | TransferAnswer::Checksum(_) => {
+                return Err(io::ErrorKind::InvalidData.into())
+            }
+        };
+
+        self.receive_into(&mut stream, path, offset, len, compressed)
+            .await?;
+        self.network_interface
+            .scores
+            .write()
+            .record_transfer(peer, len, started.elapsed());
+        Ok(len)
+    }
+
+    /// SHA-256 of `len` bytes of a local file from `start`, hashed on the blocking threads
+    pub async fn range_checksum(
+        &self,
+        path: &WhPath,
+        start: u64,
+        len: u64,
+    ) -> io::Result<ContentHash> {
+        let path = path.clone();
+        self.on_disk(move |disk| {
+            let mut hasher = Sha256::new();
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let mut offset = start;
+            let end = start + len;
+
+            while offset < end {
+                let len = CHUNK_SIZE.min((end - offset) as usize);
+                let read = disk.read_file(&path, offset as usize, &mut chunk[..len])?;
+                if read == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                hasher.update(&chunk[..read]);
+                offset += read as u64;
+            }
+            Ok(hasher.finalize().into())
+        })
+        .await
+    }
+
+    /// Checksum of a file hosted by `peer`
+    async fn remote_checksum(&self, peer: PeerId, ino: Ino) -> io::Result<ContentHash> {
+        let mut stream = self.open_file_stream(peer).await?;
+        write_frame(
+            &mut stream,
+            &TransferRequest::Checksum(ino),
+            MAX_HEADER_SIZE,
+        )
+        .await?;
+
+        match read_frame(&mut stream, MAX_HEADER_SIZE).await? {
+            TransferAnswer::Checksum(checksum) => Ok(checksum),
+            TransferAnswer::Refused(reason) => Err(refused(reason)),
+            TransferAnswer::Accepted(_) | TransferAnswer::Done => {
+                Err(io::ErrorKind::InvalidData.into())
+            }
+        }
+    }
+
+    /// Download a file from several hosts at once, each sending parts of it
+    ///
+    /// Idle hosts are given the next part. Once every part is asked, they also ask the parts
+    /// still downloading from another host, so a slow host doesn't hold the transfer.
+    /// A failing host isn't asked anything else, and its part goes back to the others.
+    /// The parts are received like a whole file, see [FsInterface::receive_whole], checked
+    /// against the checksum of a host while the hash is unknown.
+    pub async fn download_file_parallel(
+        &self,
+        hosts: &[PeerId],
+        ino: Ino,
+        size: u64,
+        storage: Storage,
+    ) -> io::Result<()> {
+        let hash = self.file_hash(ino)?;
+        self.receive_whole(ino, size, storage, |received| async move {
+            self.download_parts(hosts, ino, &received, size).await?;
+            if hash.is_some() {
+                return Ok(None);
+            }
+            for host in hosts {
+                match self.remote_checksum(*host, ino).await {
+                    Ok(checksum) => return Ok(Some(checksum)),
+                    Err(err) => log::warn!("{host} didn't send the checksum of {ino}: {err}"),
+                }
+            }
+            Err(io::Error::other("no host sent the checksum of the file"))
+        })
+        .await
+    }
+
+    /// Download the parts of a file from `hosts` into `path`, see [FsInterface::download_file_parallel]
+    async fn download_parts(
+        &self,
+        hosts: &[PeerId],
+        ino: Ino,
+        path: &WhPath,
+        size: u64,
+    ) -> io::Result<()> {
+        let mut parts = PullParts::new(size);
+        let mut idle = hosts.to_vec();
+        let mut transfers = FuturesUnordered::new();
+
+        while !parts.is_complete() {
+            for host in std::mem::take(&mut idle) {
+                let Some(part) = parts.next(host) else {
+                    idle.push(host);
+                    continue;
+                };
+                transfers.push(async move {
+                    let len = part.end - part.start;
+                    let result = self
+                        .download_range_into(host, ino, path, part.start, len)
+                        .await;
+                    (host, part, result)
+                });
+            }
+
+            let Some((host, part, result)) = transfers.next().await else {
+                return Err(io::Error::other("every host failed to send its part"));
+            };
+            match result {
+                Ok(len) if len == part.end - part.start => {
+                    parts.received(host, &part);
+                    idle.push(host);
+                }
+                Ok(len) => {
+                    // the file may have shrunk on this host, the others may still have it whole
+                    log::warn!("{host} only sent {len} bytes of {part:?} of {ino}");
+                    parts.failed(host, part);
+                }
+                Err(err) => {
+                    log::warn!("Failed to pull {part:?} of {ino} from {host}: {err}");
+                    parts.failed(host, part);
+                }
+            }
+        }
+        // the parts still downloading elsewhere are dropped with the transfers
+        Ok(())
+    }
+
+    /// Download a file from `peer` straight to the disk
+    pub async fn download_file(&self, peer: PeerId, ino: Ino, storage: Storage) -> io::Result<()> {
+        if !self.streams_files(&peer) {
+            return match self.ask(peer, Request::RequestFile(ino)).await? {
+                Response::RequestedFile(content) => {
+                    self.receive_file_message(ino, Arc::new(content), storage)
+                        .await
+                }
+                _ => Err(io::Error::other(format!("{peer} didn't send {ino}"))),
+            };
+        }
+        let _in_flight = PeerScores::start_transfer(&self.network_interface.scores, peer);
+        let started = Instant::now();
+        let compressed = self.compresses_for(&peer);
+        let mut stream = self.open_file_stream(peer).await?;
+        write_frame(
+            &mut stream,
+            &TransferRequest::Pull(ino).compressed_if(compressed),
+            MAX_HEADER_SIZE,
+        )
+        .await?;
+
+        match read_frame(&mut stream, MAX_HEADER_SIZE).await? {
+            TransferAnswer::Accepted(size) => {
+                self.receive_content(&mut stream, ino, size, storage, compressed)
+                    .await?;
+                self.network_interface.scores.write().record_transfer(
+                    peer,
+                    size,
+                    started.elapsed(),
+                );
+                Ok(())
+            }
+            TransferAnswer::Refused(reason) => Err(refused(reason)),
+            TransferAnswer::Done | TransferAnswer::Checksum(_) => {
+                Err(io::ErrorKind::InvalidData.into())
+            }
+        }
+    }
+
+    /// Upload a local file to `peer`, that will host it
+    pub async fn upload_file(&self, peer: PeerId, ino: Ino) -> io::Result<()> {
+        if !self.streams_files(&peer) {
+            let content = Arc::new(self.read_file_message(ino).await?);
+            return match self
+                .ask(peer, Request::RedundancyFile(ino, content))
+                .await?
+            {
+                Response::Success => Ok(()),
+                _ => Err(io::Error::other(format!("{peer} didn't store {ino}"))),
+            };
+        }
+        let (path, _, size) = self.file_path(ino)?;
+        let compressed = self.compresses_for(&peer);
+        let mut stream = self.open_file_stream(peer).await?;
+        write_frame(
+            &mut stream,
+            &TransferRequest::Push(ino, size).compressed_if(compressed),
+            MAX_HEADER_SIZE,
+        )
+        .await?;
+
+        match read_frame(&mut stream, MAX_HEADER_SIZE).await? {
+            TransferAnswer::Accepted(_) => {
+                self.send_content(&mut stream, &path, 0, size, compressed)
+                    .await?
+            }
+            // the peer had a cached copy of the file
+            TransferAnswer::Done => return Ok(()),
+            TransferAnswer::Refused(reason) => return Err(refused(reason)),
+            TransferAnswer::Checksum(_) => return Err(io::ErrorKind::InvalidData.into()),
+        }
+
+        match read_frame(&mut stream, MAX_HEADER_SIZE).await? {
+            TransferAnswer::Done => Ok(()),
+            TransferAnswer::Refused(reason) => Err(refused(reason)),
+            TransferAnswer::Accepted(_) | TransferAnswer::Checksum(_) => {
+                Err(io::ErrorKind::InvalidData.into())
+            }
+        }
+    }
+
+    /// Upload `len` bytes of a local file from `start` to `peer`, that will hold them
+    /// as the shard `index` of the file
+    #[allow(clippy::too_many_arguments)]
+    pub async fn upload_shard(
+        &self,
+        peer: PeerId,
+        ino: Ino,
+        index: usize,
+        path: &WhPath,
+        start: u64,
+        len: u64,
+        hash: ContentHash,
+    ) -> io::Result<()> {
+        let compressed = self.compresses_for(&peer);
+        let mut stream = self.open_file_stream(peer).await?;
+        write_frame(
+            &mut stream,
+            &TransferRequest::PushShard(ino, index, len, hash).compressed_if(compressed),
+            MAX_HEADER_SIZE,
+        )
+        .await?;
+
+        match read_frame(&mut stream, MAX_HEADER_SIZE).await? {
+            TransferAnswer::Accepted(_) => {
+                self.send_content(&mut stream, path, start, len, compressed)
+                    .await?
+            }
+            TransferAnswer::Refused(reason) => return Err(refused(reason)),
+            TransferAnswer::Done | TransferAnswer::Checksum(_) => {
+                return Err(io::ErrorKind::InvalidData.into())
+            }
+        }
+
+        match read_frame(&mut stream, MAX_HEADER_SIZE).await? {
+            TransferAnswer::Done => Ok(()),
+            TransferAnswer::Refused(reason) => Err(refused(reason)),
+            TransferAnswer::Accepted(_) | TransferAnswer::Checksum(_) => {
+                Err(io::ErrorKind::InvalidData.into())
+            }
+        }
+    }
+
+    /// Download the shard `index` of a file from `peer`, written from `offset` in the local file
+    /// Returns its length, the caller checks it against the hash of the shard
+    pub async fn download_shard(
+        &self,
+        peer: PeerId,
+        ino: Ino,
+        index: usize,
+        path: &WhPath,
+        offset: u64,
+    ) -> io::Result<u64> {
+        let _in_flight = PeerScores::start_transfer(&self.network_interface.scores, peer);
+        let started = Instant::now();
+        let compressed = self.compresses_for(&peer);
+        let mut stream = self.open_file_stream(peer).await?;
+        write_frame(
+            &mut stream,
+            &TransferRequest::PullShard(ino, index).compressed_if(compressed),
+            MAX_HEADER_SIZE,
+        )
+        .await?;
+
+        let len = match read_frame(&mut stream, MAX_HEADER_SIZE).await? {
+            TransferAnswer::Accepted(len) => len,
+            TransferAnswer::Refused(reason) => return Err(refused(reason)),
+            TransferAnswer::Done | TransferAnswer::Checksum(_) => {
+                return Err(io::ErrorKind::InvalidData.into())
+            }
+        };
+
+        self.receive_into(&mut stream, path, offset, len, compressed)
+            .await?;
+        self.network_interface
+            .scores
+            .write()
+            .record_transfer(peer, len, started.elapsed());
+        Ok(len)
+    }
+
+    /// Wait for a file pushed before its creation arrived: the creation may still be in the
+    /// batch of the sender (`[network] frequency`), while its uploads start right away
+    async fn wait_for_inode(&self, ino: Ino) {
+        let window = self
+            .network_interface
+            .global_config
+            .read()
+            .network
+            .frequency()
+            .max(SMART_FREQUENCY);
+        let deadline = Instant::now() + window + UNKNOWN_INODE_MARGIN;
+
+        let unknown = || {
+            let itree = self.network_interface.itree.read();
+            itree.get_inode(ino).is_err() && !itree.is_buried(ino)
+        };
+        while unknown() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// Answer a transfer opened by `peer`
+    async fn serve_file_stream(&self, peer: PeerId, mut stream: Stream) -> io::Result<()> {
+        if !matches!(self.network_interface.admission(&peer), Admission::Admitted) {
+            let answer = TransferAnswer::Refused("not admitted in the network".to_owned());
+            return write_frame(&mut stream, &answer, MAX_HEADER_SIZE).await;
+        }
+
+        let (request, compressed) = match read_frame(&mut stream, MAX_HEADER_SIZE).await? {
+            TransferRequest::Compressed(request) => (*request, true),
+            request => (request, false),
+        };
+        if let TransferRequest::Push(ino, _) | TransferRequest::PushShard(ino, _, _, _) = request {
+            self.wait_for_inode(ino).await;
+        }
+        match request {
+            TransferRequest::Pull(ino) => match self.file_path(ino) {
+                Ok((path, _, size)) => {
+                    write_frame(
+                        &mut stream,
+                        &TransferAnswer::Accepted(size),
+                        MAX_HEADER_SIZE,
+                    )
+                    .await?;
+                    self.send_content(&mut stream, &path, 0, size, compressed)
+                        .await
+                }
+                Err(err) => {
+                    let answer = TransferAnswer::Refused(err.to_string());
+                    write_frame(&mut stream, &answer, MAX_HEADER_SIZE).await
+                }
+            },
+            TransferRequest::Checksum(ino) => {
+                let checksum = match self.hosted_file_path(ino) {
+                    Ok((path, size)) => self.range_checksum(&path, 0, size).await,
+                    Err(err) => Err(err),
+                };
+                let answer = match checksum {
+                    Ok(checksum) => TransferAnswer::Checksum(checksum),
+                    Err(err) => TransferAnswer::Refused(err.to_string()),
+                };
+                write_frame(&mut stream, &answer, MAX_HEADER_SIZE).await
+            }
+            TransferRequest::PullRange(ino, offset, len) => match self.hosted_file_path(ino) {
+                Ok((path, size)) => {
+                    let len = len.min(size.saturating_sub(offset));
+                    write_frame(&mut stream, &TransferAnswer::Accepted(len), MAX_HEADER_SIZE)
+                        .await?;
+                    self.send_content(&mut stream, &path, offset, len, compressed)
+                        .await
+                }
+                Err(err) => {
+                    let answer = TransferAnswer::Refused(err.to_string());
+                    write_frame(&mut stream, &answer, MAX_HEADER_SIZE).await
+                }
+            },
+            TransferRequest::Compressed(_) => {
+                let answer = TransferAnswer::Refused("compressed twice".to_owned());
+                write_frame(&mut stream, &answer, MAX_HEADER_SIZE).await
+            }
+            TransferRequest::PullShard(ino, index) => match self.held_shard(ino, index) {
+                Ok((path, len)) => {
+                    write_frame(&mut stream, &TransferAnswer::Accepted(len), MAX_HEADER_SIZE)
+                        .await?;
+                    self.send_content(&mut stream, &path, 0, len, compressed)
+                        .await
+                }
+                Err(err) => {
+                    let answer = TransferAnswer::Refused(err.to_string());
+                    write_frame(&mut stream, &answer, MAX_HEADER_SIZE).await
+                }
+            },
+            TransferRequest::PushShard(ino, index, len, hash) => {
+                let path = match self.expected_shard_len(ino, index).and_then(|expected| {
+                    if len > expected {
+                        return Err(io::Error::other(format!(
+                            "the shard {index} of {ino} is {expected} bytes long, not {len}"
+                        )));
+                    }
+                    self.shard_path(ino, index)
+                }) {
+                    Ok(path) => path,
+                    Err(err) => {
+                        let answer = TransferAnswer::Refused(err.to_string());
+                        return write_frame(&mut stream, &answer, MAX_HEADER_SIZE).await;
+                    }
+                };
+                write_frame(&mut stream, &TransferAnswer::Accepted(len), MAX_HEADER_SIZE).await?;
+                let answer = match self
+                    .receive_shard(&mut stream, &path, len, hash, compressed)
+                    .await
+                {
+                    Ok(()) => TransferAnswer::Done,
+                    Err(err) => {
+                        log::error!(
+                            "Failed to receive the shard {index} of {ino} from {peer}: {err}"
+                        );
+                        let _ = self.on_disk(move |disk| disk.remove_file(&path)).await;
+                        TransferAnswer::Refused(err.to_string())
+                    }
+                };
+                write_frame(&mut stream, &answer, MAX_HEADER_SIZE).await
+            }
+            TransferRequest::Push(ino, _) if self.network_interface.itree.read().is_buried(ino) => {
+                let answer = TransferAnswer::Refused(format!("{ino} was removed"));
+                write_frame(&mut stream, &answer, MAX_HEADER_SIZE).await
+            }
+            TransferRequest::Push(ino, size)
+                if self.file_path(ino).is_ok_and(|(_, _, known)| known != size) =>
+            {
+                let answer = TransferAnswer::Refused(format!("{ino} isn't {size} bytes long here"));
+                write_frame(&mut stream, &answer, MAX_HEADER_SIZE).await
+            }
+            TransferRequest::Push(ino, size) => {
+                let promotable = self
+                    .network_interface
+                    .capabilities
+                    .read()
+                    .get(&peer)
+                    .is_some_and(|capabilities| capabilities.contains(&Capability::CachePromotion));
+                if promotable && self.promote_cached_copy(ino)? {
+                    log::debug!("Promoted the cached copy of {ino} to a replica");
+                    return write_frame(&mut stream, &TransferAnswer::Done, MAX_HEADER_SIZE).await;
+                }
+                write_frame(
+                    &mut stream,
+                    &TransferAnswer::Accepted(size),
+                    MAX_HEADER_SIZE,
+                )
+                .await?;
+                let answer = match self
+                    .receive_content(&mut stream, ino, size, Storage::Replica, compressed)
+                    .await
+                {
+                    Ok(()) => TransferAnswer::Done,
+                    Err(err) => {
+                        log::error!("Failed to receive {ino} from {peer}: {err}");
+                        TransferAnswer::Refused(err.to_string())
+                    }
+                };
+                write_frame(&mut stream, &answer, MAX_HEADER_SIZE).await
+            }
+        }
+    }
+}
+
+/// Serve the file transfers opened by other pods until the pod stops
+///
+/// Transfers are polled inside this task rather than spawned,
+/// so aborting it releases the FsInterface right away.
+pub async fn serve_file_streams(
+    fs_interface: std::sync::Arc<FsInterface>,
+    mut incoming: libp2p::stream::IncomingStreams,
+) {
+    let mut transfers = FuturesUnordered::new();
+
+    loop {
+        tokio::select! {
+            Some((peer, stream)) = incoming.next() => {
+                let fs_interface = &fs_interface;
+                transfers.push(async move {
+                    (peer, fs_interface.serve_file_stream(peer, stream).await)
+                });
+            }
+            Some((peer, result)) = transfers.next(), if !transfers.is_empty() => {
+                if let Err(err) = result {
+                    log::warn!("File transfer with {peer} failed: {err}");
+                }
+            }
+            else => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parts_a_host_fails_to_send_go_to_the_others() {
+        let (slow, fast) = (PeerId::random(), PeerId::random());
+        let mut parts = PullParts::new(2 * PULL_PART_SIZE + 1);
+
+        let first = parts.next(slow).unwrap();
+        let second = parts.next(fast).unwrap();
+        parts.received(fast, &second);
+        let third = parts.next(fast).unwrap();
+        assert_eq!(third, 2 * PULL_PART_SIZE..2 * PULL_PART_SIZE + 1);
+        // a short part is failed, and asked again to the host left
+        parts.failed(fast, third.clone());
+        parts.received(slow, &first);
+        assert_eq!(parts.next(slow), Some(third.clone()));
+        parts.received(slow, &third);
+        assert!(parts.is_complete());
+    }
+
+    #[test]
+    fn slow_hosts_are_helped() {
+        let (slow, fast) = (PeerId::random(), PeerId::random());
+        let mut parts = PullParts::new(2 * PULL_PART_SIZE);
+
+        let first = parts.next(slow).unwrap();
+        let second = parts.next(fast).unwrap();
+        parts.received(fast, &second);
+        assert_eq!(parts.next(fast), Some(first.clone()));
+        // no third host on the same part
+        assert_eq!(parts.next(PeerId::random()), None);
+
+        // the part is still downloading from the other host, it isn't asked again
+        parts.failed(slow, first.clone());
+        assert!(parts.pending.is_empty());
+        parts.received(fast, &first);
+        assert!(parts.is_complete());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn received_files_replace_the_copy_once_verified() {
+        use std::sync::Arc;
+
+        use parking_lot::RwLock;
+        use tokio::sync::mpsc;
+
+        use crate::{
+            config::GlobalConfig,
+            pods::{
+                disk_managers::unix_disk_manager::UnixDiskManager,
+                filesystem::File,
+                network::{
+                    compression::CompressionStats, network_interface::NetworkInterface,
+                    operation_log::OperationLog,
+                },
+            },
+        };
+
+        // the interfaces keep a handle on the runtime of the pod
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _runtime = runtime.enter();
+        let mountpoint = assert_fs::TempDir::new().unwrap();
+        let (network_tx, _network_rx) = mpsc::unbounded_channel();
+        let (redundancy_tx, _redundancy_rx) = mpsc::unbounded_channel();
+        let network_interface = Arc::new(NetworkInterface::new(
+            Arc::new(RwLock::new(ITree::new())),
+            PeerId::random(),
+            "laptop:pod".to_owned(),
+            network_tx,
+            redundancy_tx,
+            Arc::new(RwLock::new(vec![])),
+            Arc::new(RwLock::new(GlobalConfig::default())),
+            libp2p::stream::Behaviour::new().new_control(),
+            OperationLog::default(),
+            None,
+            Arc::new(CompressionStats::default()),
+        ));
+        let fs = FsInterface::new(
+            network_interface,
+            Box::new(UnixDiskManager::new(mountpoint.path()).unwrap()),
+            mountpoint.path().to_owned(),
+            0,
+        );
+
+        let path = WhPath::try_from("notes.txt").unwrap();
+        let received = receiving_path(11).unwrap();
+        std::fs::write(mountpoint.path().join("notes.txt"), b"local").unwrap();
+        std::fs::create_dir(mountpoint.path().join(LOCAL_FOLDER)).unwrap();
+        std::fs::write(
+            mountpoint.path().join(LOCAL_FOLDER).join("11.part"),
+            b"remote",
+        )
+        .unwrap();
+
+        let tampered = File(Arc::new(b"tampered".to_vec())).hash();
+        assert!(runtime
+            .block_on(fs.adopt_received(11, &received, &path, 6, Some(tampered)))
+            .is_err());
+        assert_eq!(
+            std::fs::read(mountpoint.path().join("notes.txt")).unwrap(),
+            b"local"
+        );
+
+        let hash = File(Arc::new(b"remote".to_vec())).hash();
+        runtime
+            .block_on(fs.adopt_received(11, &received, &path, 6, Some(hash)))
+            .unwrap();
+        assert_eq!(
+            std::fs::read(mountpoint.path().join("notes.txt")).unwrap(),
+            b"remote"
+        );
+        assert!(!mountpoint
+            .path()
+            .join(LOCAL_FOLDER)
+            .join("11.part")
+            .exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bad_parts_leave_the_local_copy_untouched() {
+        use std::sync::Arc;
+
+        use parking_lot::RwLock;
+        use tokio::sync::mpsc;
+
+        use crate::{
+            config::GlobalConfig,
+            pods::{
+                disk_managers::unix_disk_manager::UnixDiskManager,
+                filesystem::File,
+                itree::{FsEntry, InodeName, ROOT},
+                network::{
+                    compression::CompressionStats, network_interface::NetworkInterface,
+                    operation_log::OperationLog,
+                },
+            },
+        };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _runtime = runtime.enter();
+        let mountpoint = assert_fs::TempDir::new().unwrap();
+        let (network_tx, _network_rx) = mpsc::unbounded_channel();
+        let (redundancy_tx, _redundancy_rx) = mpsc::unbounded_channel();
+        let mut itree = ITree::new();
+        itree
+            .add_inode_from_parameters(
+                InodeName::try_from("notes.txt".to_owned()).unwrap(),
+                11,
+                ROOT,
+                FsEntry::File(vec![]),
+                0o644,
+            )
+            .unwrap();
+        let meta = &mut itree.get_inode_mut(11).unwrap().meta;
+        meta.size = 6;
+        meta.hash = Some(File(Arc::new(b"remote".to_vec())).hash());
+        let network_interface = Arc::new(NetworkInterface::new(
+            Arc::new(RwLock::new(itree)),
+            PeerId::random(),
+            "laptop:pod".to_owned(),
+            network_tx,
+            redundancy_tx,
+            Arc::new(RwLock::new(vec![])),
+            Arc::new(RwLock::new(GlobalConfig::default())),
+            libp2p::stream::Behaviour::new().new_control(),
+            OperationLog::default(),
+            None,
+            Arc::new(CompressionStats::default()),
+        ));
+        let fs = FsInterface::new(
+            network_interface,
+            Box::new(UnixDiskManager::new(mountpoint.path()).unwrap()),
+            mountpoint.path().to_owned(),
+            0,
+        );
+        std::fs::write(mountpoint.path().join("notes.txt"), b"local!").unwrap();
+        // parts written where the parallel pulls write them
+        let fs = &fs;
+        let pull = move |parts: [&'static [u8]; 2]| {
+            fs.receive_whole(11, 6, Storage::Replica, |received| async move {
+                for (at, part) in [(0, parts[0]), (3, parts[1])] {
+                    let received = received.clone();
+                    fs.on_disk(move |disk| disk.write_file(&received, part, at))
+                        .await?;
+                }
+                Ok(None)
+            })
+        };
+
+        assert!(runtime.block_on(pull([b"rem", b"0te"])).is_err());
+        assert_eq!(
+            std::fs::read(mountpoint.path().join("notes.txt")).unwrap(),
+            b"local!"
+        );
+        assert!(!mountpoint
+            .path()
+            .join(LOCAL_FOLDER)
+            .join("11.part")
+            .exists());
+
+        runtime.block_on(pull([b"rem", b"ote"])).unwrap();
+        assert_eq!(
+            std::fs::read(mountpoint.path().join("notes.txt")).unwrap(),
+            b"remote"
+        );
+    }
+}
@@ -0,0 +1,413 @@
+//! Advisory file locks shared by the whole network
+//!
+//! Every pod keeps a copy of the lock table. A pod taking a lock first records it locally,
+//! then asks every peer to record it too: any peer knowing of a conflicting lock refuses,
+//! and the attempt is rolled back everywhere. Two pods racing for the same lock
+//! both fail and retry, so at most one of them holds it.
+//! The locks of a pod are released by everyone when it disconnects.
+//!
+//! A peer that doesn't answer, or doesn't know about locks, can't be told about the lock:
+//! it is refused rather than risking two holders. The peers are asked all at once,
+//! and have a single [LOCK_REQUEST_TIMEOUT] to answer.
+//! Blocking lock requests wait in a single queue, retried each time a lock is released.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    mem,
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::future::join_all;
+use libp2p::PeerId;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{oneshot, Notify};
+
+use crate::{
+    error::{WhError, WhResult},
+    network::{
+        message::{Request, Response, ToNetworkMessage},
+        protocol::Capability,
+    },
+    pods::{
+        itree::{ITree, Ino},
+        network::network_interface::NetworkInterface,
+    },
+};
+
+/// Time given to the peers to answer a lock request before refusing the lock
+const LOCK_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait before retrying the waiting locks after a release, randomized so racing pods don't collide again
+const LOCK_RETRY_DELAY: std::ops::Range<u64> = 50..500;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockKind {
+    /// Read lock, can be held by several owners at once
+    Shared,
+    /// Write lock
+    Exclusive,
+}
+
+/// Holder of a lock: a process (POSIX locks) or an open file (flock) on a pod
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LockOwner {
+    pub peer: PeerId,
+    pub owner: u64,
+}
+
+/// A lock on a range of a file, `end` included
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FileLock {
+    pub owner: LockOwner,
+    pub kind: LockKind,
+    pub start: u64,
+    pub end: u64,
+    /// Process holding the lock, only informative
+    pub pid: u32,
+}
+
+impl FileLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    /// Two locks conflict if different owners want overlapping ranges and one of them writes
+    fn conflicts(&self, other: &FileLock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (self.kind == LockKind::Exclusive || other.kind == LockKind::Exclusive)
+    }
+}
+
+/// Why a lock couldn't be taken
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LockRefusal {
+    /// A conflicting lock is held
+    Held(FileLock),
+    /// A peer didn't answer in time, it may hold a conflicting lock
+    Unanswered(PeerId),
+    /// A peer of an older version doesn't know about locks
+    Unsupported(PeerId),
+}
+
+/// Locks currently held in the network
+#[derive(Default, Debug)]
+pub struct LockTable(HashMap<Ino, Vec<FileLock>>);
+
+impl LockTable {
+    /// Lock preventing `lock` from being taken, if any
+    pub fn conflict(&self, ino: Ino, lock: &FileLock) -> Option<&FileLock> {
+        self.0.get(&ino)?.iter().find(|held| held.conflicts(lock))
+    }
+
+    /// Locks of an owner on a range of the file
+    fn owned(&self, ino: Ino, owner: LockOwner, start: u64, end: u64) -> Vec<FileLock> {
+        self.0
+            .get(&ino)
+            .map(|locks| {
+                locks
+                    .iter()
+                    .filter(|held| held.owner == owner && held.overlaps(start, end))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Record a lock if it doesn't conflict, replacing the owner's locks on the same range
+    pub fn lock(&mut self, ino: Ino, lock: FileLock) -> Result<(), FileLock> {
+        if let Some(held) = self.conflict(ino, &lock) {
+            return Err(held.clone());
+        }
+        self.unlock(ino, lock.owner, lock.start, lock.end);
+        self.0.entry(ino).or_default().push(lock);
+        Ok(())
+    }
+
+    /// Release the owner's locks on a range, the parts outside the range are kept
+    pub fn unlock(&mut self, ino: Ino, owner: LockOwner, start: u64, end: u64) {
+        let Some(locks) = self.0.get_mut(&ino) else {
+            return;
+        };
+        let mut kept = Vec::with_capacity(locks.len());
+
+        for held in locks.drain(..) {
+            if held.owner != owner || !held.overlaps(start, end) {
+                kept.push(held);
+                continue;
+            }
+            if held.start < start {
+                kept.push(FileLock {
+                    end: start - 1,
+                    ..held.clone()
+                });
+            }
+            if held.end > end {
+                kept.push(FileLock {
+                    start: end + 1,
+                    ..held
+                });
+            }
+        }
+
+        if kept.is_empty() {
+            self.0.remove(&ino);
+        } else {
+            *locks = kept;
+        }
+    }
+
+    /// Move the locks of an inode given a new number
+    pub fn renumber(&mut self, from: Ino, to: Ino) {
+        if let Some(locks) = self.0.remove(&from) {
+            self.0.insert(to, locks);
+        }
+    }
+
+    /// Release every lock held by a pod
+    pub fn release_peer(&mut self, peer: PeerId) {
+        self.0.retain(|_, locks| {
+            locks.retain(|held| held.owner.peer != peer);
+            !locks.is_empty()
+        });
+    }
+}
+
+/// A blocking lock request, waiting for the conflicting locks to be released
+struct LockWaiter {
+    ino: Ino,
+    lock: FileLock,
+    reply: Box<dyn FnOnce(WhResult<Result<(), LockRefusal>>) + Send>,
+}
+
+#[derive(Default)]
+struct WaitQueue {
+    waiters: VecDeque<LockWaiter>,
+    /// If a task is retrying the waiters
+    retrying: bool,
+    /// If a lock was released since the waiters were last retried
+    released: bool,
+}
+
+/// Blocking lock requests of this pod, retried by a single task when locks are released
+#[derive(Default)]
+pub struct LockWaiters {
+    queue: Mutex<WaitQueue>,
+    released: Notify,
+}
+
+impl LockWaiters {
+    /// Retry the waiting locks, a lock was released
+    pub fn wake(&self) {
+        self.queue.lock().released = true;
+        self.released.notify_one();
+    }
+}
+
+impl NetworkInterface {
+    /// Lock preventing `lock` from being taken, as known by this pod
+    pub fn get_lock(&self, ino: Ino, lock: &FileLock) -> Option<FileLock> {
+        self.locks.read().conflict(ino, lock).cloned()
+    }
+
+    /// Try to take a lock in the whole network
+    /// Returns why it is refused: a conflicting lock is held, or a peer couldn't be asked
+    pub async fn set_lock(&self, ino: Ino, lock: FileLock) -> WhResult<Result<(), LockRefusal>> {
+        if !ITree::is_local_only(ino) {
+            let capabilities = self.capabilities.read();
+            let unsupported = self.peers.read().iter().copied().find(|peer| {
+                capabilities
+                    .get(peer)
+                    .is_some_and(|capabilities| !capabilities.contains(&Capability::Locks))
+            });
+            if let Some(peer) = unsupported {
+                log::warn!("{peer} doesn't support locks, refusing a lock on {ino}");
+                return Ok(Err(LockRefusal::Unsupported(peer)));
+            }
+        }
+
+        let previous = {
+            let mut locks = self.locks.write();
+            let previous = locks.owned(ino, lock.owner, lock.start, lock.end);
+            if let Err(held) = locks.lock(ino, lock.clone()) {
+                return Ok(Err(LockRefusal::Held(held)));
+            }
+            previous
+        };
+        if ITree::is_local_only(ino) {
+            return Ok(Ok(()));
+        }
+
+        let mut answers = Vec::new();
+        for peer in self.peers.read().iter() {
+            let (status_tx, status_rx) = oneshot::channel();
+            self.to_network_message_tx
+                .send(ToNetworkMessage::AnswerMessage(
+                    Request::Lock(ino, lock.clone()),
+                    status_tx,
+                    *peer,
+                ))
+                .or(Err(WhError::NetworkDied {
+                    called_from: "set_lock".to_owned(),
+                }))?;
+            answers.push((*peer, status_rx));
+        }
+
+        let deadline = tokio::time::Instant::now() + LOCK_REQUEST_TIMEOUT;
+        let answers = join_all(answers.into_iter().map(|(peer, answer)| async move {
+            (peer, tokio::time::timeout_at(deadline, answer).await)
+        }))
+        .await;
+
+        let mut refusal = None;
+        for (peer, answer) in answers {
+            match answer {
+                Ok(Ok(Some(Response::LockHeld(held)))) => refusal = Some(LockRefusal::Held(held)),
+                Ok(Ok(Some(_))) => {}
+                // It may hold a conflicting lock, the one with the lock is known if it answered
+                _ => {
+                    log::warn!("{peer} didn't answer a lock request on {ino}, refusing it");
+                    refusal.get_or_insert(LockRefusal::Unanswered(peer));
+                }
+            }
+        }
+
+        match refusal {
+            None => Ok(Ok(())),
+            Some(refusal) => {
+                self.restore_locks(ino, &lock, previous)?;
+                Ok(Err(refusal))
+            }
+        }
+    }
+
+    /// Take a lock in the whole network once the conflicting locks are released
+    /// `reply` is called with the outcome from the task retrying the waiting locks,
+    /// a refusal that waiting can't solve is given back
+    pub fn wait_lock(
+        self: &Arc<Self>,
+        ino: Ino,
+        lock: FileLock,
+        reply: impl FnOnce(WhResult<Result<(), LockRefusal>>) + Send + 'static,
+    ) {
+        let mut queue = self.lock_waiters.queue.lock();
+        queue.waiters.push_back(LockWaiter {
+            ino,
+            lock,
+            reply: Box::new(reply),
+        });
+        queue.released = true;
+        self.lock_waiters.released.notify_one();
+        if !queue.retrying {
+            queue.retrying = true;
+            let network_interface = self.clone();
+            self.runtime
+                .spawn(async move { network_interface.retry_waiting_locks().await });
+        }
+    }
+
+    /// Retry the waiting locks each time a lock is released, until none is left
+    /// Peers that didn't answer are asked again from time to time
+    async fn retry_waiting_locks(&self) {
+        loop {
+            let waiters = mem::take(&mut self.lock_waiters.queue.lock().waiters);
+            let mut waiting = VecDeque::new();
+            for waiter in waiters {
+                match self.set_lock(waiter.ino, waiter.lock.clone()).await {
+                    Ok(Err(LockRefusal::Held(_) | LockRefusal::Unanswered(_))) => {
+                        waiting.push_back(waiter)
+                    }
+                    outcome => (waiter.reply)(outcome),
+                }
+            }
+
+            let released = {
+                let mut queue = self.lock_waiters.queue.lock();
+                // the requests queued meanwhile come after the older ones
+                waiting.append(&mut queue.waiters);
+                queue.waiters = waiting;
+                if queue.waiters.is_empty() {
+                    queue.retrying = false;
+                    return;
+                }
+                mem::take(&mut queue.released)
+            };
+            if !released {
+                // a release notified meanwhile is kept, it ends the wait right away
+                let _ = tokio::time::timeout(
+                    LOCK_REQUEST_TIMEOUT,
+                    self.lock_waiters.released.notified(),
+                )
+                .await;
+                self.lock_waiters.queue.lock().released = false;
+            }
+            tokio::time::sleep(Duration::from_millis(rand::random_range(LOCK_RETRY_DELAY))).await;
+        }
+    }
+
+    /// Roll back a lock attempt, giving the owner back the locks it had on the range
+    fn restore_locks(&self, ino: Ino, lock: &FileLock, previous: Vec<FileLock>) -> WhResult<()> {
+        {
+            let mut locks = self.locks.write();
+            locks.unlock(ino, lock.owner, lock.start, lock.end);
+            for held in &previous {
+                let _ = locks.lock(ino, held.clone());
+            }
+        }
+        self.lock_waiters.wake();
+
+        let mut requests = vec![Request::Unlock(ino, lock.owner, lock.start, lock.end)];
+        requests.extend(previous.into_iter().map(|held| Request::Lock(ino, held)));
+        for request in requests {
+            self.to_network_message_tx
+                .send(ToNetworkMessage::AcknowledgedBroadcast(request))
+                .or(Err(WhError::NetworkDied {
+                    called_from: "restore_locks".to_owned(),
+                }))?;
+        }
+        Ok(())
+    }
+
+    /// Release a range locked by an owner in the whole network
+    pub fn unlock(&self, ino: Ino, owner: LockOwner, start: u64, end: u64) -> WhResult<()> {
+        let mut locks = self.locks.write();
+        if locks.owned(ino, owner, start, end).is_empty() {
+            return Ok(());
+        }
+        locks.unlock(ino, owner, start, end);
+        drop(locks);
+        self.lock_waiters.wake();
+
+        if ITree::is_local_only(ino) {
+            return Ok(());
+        }
+        self.to_network_message_tx
+            .send(ToNetworkMessage::AcknowledgedBroadcast(Request::Unlock(
+                ino, owner, start, end,
+            )))
+            .or(Err(WhError::NetworkDied {
+                called_from: "unlock".to_owned(),
+            }))
+    }
+
+    pub fn recept_lock(&self, ino: Ino, lock: FileLock) -> WhResult<Response> {
+        Ok(match self.locks.write().lock(ino, lock) {
+            Ok(()) => Response::Success,
+            Err(held) => Response::LockHeld(held),
+        })
+    }
+
+    pub fn recept_unlock(
+        &self,
+        ino: Ino,
+        owner: LockOwner,
+        start: u64,
+        end: u64,
+    ) -> WhResult<Response> {
+        self.locks.write().unlock(ino, owner, start, end);
+        self.lock_waiters.wake();
+        Ok(Response::Success)
+    }
+}
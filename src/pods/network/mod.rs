@@ -1,7 +1,17 @@
+pub mod acknowledgement;
+pub mod admission;
+pub mod batch;
 pub mod behaviour;
 pub mod codec;
+pub mod compression;
+pub mod discovery;
+pub mod erasure;
 pub mod event_loop;
+pub mod file_stream;
+pub mod locks;
 pub mod network_interface;
+pub mod operation_log;
+pub mod peer_scores;
 pub mod pull_file;
 pub mod redundancy;
 pub mod swarm;
@@ -1,7 +1,7 @@
 use std::{
     collections::{HashMap, HashSet},
     sync::Arc,
-    time::UNIX_EPOCH,
+    time::{Instant, UNIX_EPOCH},
 };
 
 use crate::{
@@ -10,50 +10,100 @@ use crate::{
     network::{
         self,
         message::{Request, Response, ToNetworkMessage},
+        protocol::Capability,
     },
     pods::{
-        filesystem::make_inode::MakeInodeError, network::redundancy::RedundancyMessage,
+        filesystem::make_inode::MakeInodeError,
+        network::{
+            compression::CompressionStats,
+            locks::{LockTable, LockWaiters},
+            operation_log::OperationLog,
+            peer_scores::PeerScores,
+            redundancy::RedundancyMessage,
+        },
         whpath::InodeName,
     },
 };
-use libp2p::{identify::Info, Multiaddr, PeerId};
+use libp2p::{Multiaddr, PeerId};
 use parking_lot::RwLock;
 use tokio::sync::mpsc::UnboundedSender;
 
 use crate::pods::filesystem::{remove_inode::RemoveInodeError, rename::RenameError};
 use crate::pods::itree::{FsEntry, Metadata};
 
-use crate::pods::itree::{ITree, Ino, Inode, LOCK_TIMEOUT};
+use crate::pods::itree::{ITree, Ino, Inode, Shards, Tombstone, LOCK_TIMEOUT};
 
 pub struct NetworkInterface {
     pub itree: Arc<RwLock<ITree>>,
     pub id: PeerId,
+    /// Name this pod advertises to the others, `host:pod`
+    pub nickname: String,
     pub to_network_message_tx: UnboundedSender<ToNetworkMessage>,
     pub to_redundancy_tx: UnboundedSender<RedundancyMessage>,
     pub global_config: Arc<RwLock<GlobalConfig>>,
     pub listen_addrs: Arc<RwLock<HashSet<Multiaddr>>>,
     pub peers: Arc<RwLock<Vec<PeerId>>>,
     pub peers_info: Arc<RwLock<HashMap<PeerId, network::PeerInfo>>>, // Only used to store state for restart and inspect
+    /// Peers connected but waiting for a member's approval (`demand` access mode)
+    pub pending_peers: Arc<RwLock<HashMap<PeerId, network::PeerInfo>>>,
+    /// Capabilities of the connected peers, learned through identify
+    pub capabilities: Arc<RwLock<HashMap<PeerId, HashSet<Capability>>>>,
+    /// Peers that didn't acknowledge some broadcasts, with the requests they missed
+    pub behind_peers: Arc<RwLock<HashMap<PeerId, Vec<Request>>>>,
+    /// Disconnected peers still counted as hosts until their grace period ends, with when they left
+    pub suspects: Arc<RwLock<HashMap<PeerId, Instant>>>,
+    /// Advisory locks held in the network
+    pub locks: Arc<RwLock<LockTable>>,
+    /// Blocking lock requests of this pod, waiting for locks to be released
+    pub lock_waiters: Arc<LockWaiters>,
+    /// Operations applied on the tree, to catch up returning peers
+    pub operations: Arc<RwLock<OperationLog>>,
+    /// Measures of the connected peers, to read from the best ones first
+    pub scores: Arc<RwLock<PeerScores>>,
+    /// Bytes sent to the peers, before and after compression
+    pub compression: Arc<CompressionStats>,
+    /// Opens the dedicated streams used for file transfers
+    pub streams: libp2p::stream::Control,
+    /// Runtime of the pod, to run file transfers from the filesystem threads
+    pub runtime: tokio::runtime::Handle,
 }
 
 impl NetworkInterface {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         itree: Arc<RwLock<ITree>>,
         id: PeerId,
+        nickname: String,
         to_network_message_tx: UnboundedSender<ToNetworkMessage>,
         to_redundancy_tx: UnboundedSender<RedundancyMessage>,
         peers: Arc<RwLock<Vec<PeerId>>>,
         global_config: Arc<RwLock<GlobalConfig>>,
+        streams: libp2p::stream::Control,
+        operations: OperationLog,
+        zone: Option<String>,
+        compression: Arc<CompressionStats>,
     ) -> Self {
         Self {
             itree,
             id,
+            nickname,
             to_network_message_tx,
             to_redundancy_tx,
             peers,
             global_config,
             listen_addrs: Arc::new(RwLock::new(HashSet::new())),
             peers_info: Arc::new(RwLock::new(HashMap::new())),
+            pending_peers: Arc::new(RwLock::new(HashMap::new())),
+            capabilities: Arc::new(RwLock::new(HashMap::new())),
+            behind_peers: Arc::new(RwLock::new(HashMap::new())),
+            suspects: Arc::new(RwLock::new(HashMap::new())),
+            locks: Arc::new(RwLock::new(LockTable::default())),
+            lock_waiters: Arc::new(LockWaiters::default()),
+            operations: Arc::new(RwLock::new(operations)),
+            scores: Arc::new(RwLock::new(PeerScores::new(zone))),
+            compression,
+            streams,
+            runtime: tokio::runtime::Handle::current(),
         }
     }
 
@@ -63,7 +113,9 @@ impl NetworkInterface {
 
         if !ITree::is_local_only(inode.id) {
             self.to_network_message_tx
-                .send(ToNetworkMessage::BroadcastMessage(Request::Inode(inode)))
+                .send(ToNetworkMessage::AcknowledgedBroadcast(Request::Inode(
+                    inode,
+                )))
                 .expect("register inode: unable to update modification on the network thread");
         }
         Ok(())
@@ -83,7 +135,7 @@ impl NetworkInterface {
         itree.mv_inode(parent, new_parent, name.as_ref(), new_name.clone())?;
 
         self.to_network_message_tx
-            .send(ToNetworkMessage::BroadcastMessage(Request::Rename(
+            .send(ToNetworkMessage::AcknowledgedBroadcast(Request::Rename(
                 parent, new_parent, name, new_name, overwrite,
             )))
             .expect("broadcast_rename_file: unable to update modification on the network thread");
@@ -110,28 +162,81 @@ impl NetworkInterface {
 
     /// Get a new inode, add the requested entry to the itree and inform the network
     /// marks as reserved the Ino range up to the new Inode id
-    pub fn acknowledge_new_file(&self, inode: Inode) -> Result<(), MakeInodeError> {
+    /// Returns the Ino the inode was added with, which differs if it was a duplicate,
+    /// and the new number of the local inode it duplicated if that one was renumbered
+    pub fn acknowledge_new_file(
+        &self,
+        mut inode: Inode,
+    ) -> Result<(Ino, Option<Ino>), MakeInodeError> {
         let mut itree = ITree::write_lock(&self.itree, "acknowledge_new_file")?;
+        if itree.is_buried(inode.id) {
+            log::debug!("Ignoring {}, it was removed from the network", inode.name);
+            return Err(MakeInodeError::Removed);
+        }
         let _ = itree.mark_reserved_ino(inode.id); // this only happens in out-of-order handling of peer's inode creation, and isn't really an error
-        itree.add_inode(inode)
+
+        let mut renumbered = None;
+        if itree.is_duplicate(&inode) {
+            let local = inode.id;
+            (inode.id, renumbered) = itree.settle_duplicate(&inode)?;
+            inode.meta.ino = inode.id;
+            if let Some(to) = renumbered {
+                self.locks.write().renumber(local, to);
+            }
+        }
+        let id = inode.id;
+        itree.add_inode(inode)?;
+        Ok((id, renumbered))
+    }
+
+    /// Peers met before but not connected, that can't learn about a removal right away
+    fn absent_peers(&self) -> Vec<PeerId> {
+        let connected = self.peers.read().clone();
+        self.operations
+            .read()
+            .known_peers()
+            .filter(|peer| !connected.contains(peer))
+            .copied()
+            .collect()
     }
 
     /// Remove [Inode] from the [ITree] and inform the network of the removal
     pub fn unregister_inode(&self, id: Ino) -> Result<(), RemoveInodeError> {
-        ITree::write_lock(&self.itree, "unregister_inode")?.remove_inode(id)?;
+        let tombstone = Tombstone::now(self.id);
+        let absent = self.absent_peers();
+        let mut itree = ITree::write_lock(&self.itree, "unregister_inode")?;
+        itree.remove_inode(id)?;
+        itree.bury(id, tombstone, &absent);
+        drop(itree);
 
         if !ITree::is_local_only(id) {
             self.to_network_message_tx
-                .send(ToNetworkMessage::BroadcastMessage(Request::Remove(id)))
+                .send(ToNetworkMessage::AcknowledgedBroadcast(Request::Remove(
+                    id, tombstone,
+                )))
                 .expect("unregister_inode: unable to update modification on the network thread");
         }
         // TODO - if unable to update for some reason, should be passed to the background worker
         Ok(())
     }
 
-    /// Remove [Inode] from the [ITree]
-    pub fn acknowledge_unregister_inode(&self, id: Ino) -> Result<Inode, RemoveInodeError> {
-        ITree::write_lock(&self.itree, "acknowledge_unregister_inode")?.remove_inode(id)
+    /// Remove [Inode] from the [ITree], keeping its tombstone
+    pub fn acknowledge_unregister_inode(
+        &self,
+        id: Ino,
+        tombstone: Tombstone,
+    ) -> Result<Inode, RemoveInodeError> {
+        let absent = self.absent_peers();
+        let mut itree = ITree::write_lock(&self.itree, "acknowledge_unregister_inode")?;
+        let inode = itree.remove_inode(id)?;
+        itree.bury(id, tombstone, &absent);
+        Ok(inode)
+    }
+
+    /// `peer` is now up to date with this pod, it doesn't need the tombstones anymore
+    pub fn tombstones_seen_by(&self, peer: &PeerId) -> WhResult<()> {
+        ITree::write_lock(&self.itree, "tombstones_seen_by")?.tombstones_seen_by(peer);
+        Ok(())
     }
 
     pub fn add_inode_hosts(&self, ino: Ino, hosts: &[PeerId]) -> WhResult<()> {
@@ -149,6 +254,38 @@ impl NetworkInterface {
         Ok(())
     }
 
+    pub fn remove_inode_hosts(&self, ino: Ino, hosts: &[PeerId]) -> WhResult<()> {
+        ITree::write_lock(&self.itree, "network_interface::remove_inode_hosts")?
+            .remove_inode_hosts(ino, hosts)?;
+
+        if !ITree::is_local_only(ino) {
+            self.to_network_message_tx
+                .send(ToNetworkMessage::BroadcastMessage(Request::RemoveHosts(
+                    ino,
+                    hosts.to_vec(),
+                )))
+                .expect("remove_inode_hosts: unable to update modification on the network thread");
+        }
+        Ok(())
+    }
+
+    /// Set where the shards of a file are, see [Shards]
+    pub fn set_shards(&self, ino: Ino, shards: Shards) -> WhResult<()> {
+        ITree::write_lock(&self.itree, "network_interface::set_shards")?
+            .set_inode_shards(ino, shards.clone())?;
+
+        self.to_network_message_tx
+            .send(ToNetworkMessage::BroadcastMessage(Request::SetShards(
+                ino, shards,
+            )))
+            .expect("set_shards: unable to update modification on the network thread");
+        Ok(())
+    }
+
+    pub fn acknowledge_shards(&self, ino: Ino, shards: Shards) -> WhResult<()> {
+        ITree::write_lock(&self.itree, "acknowledge_shards")?.set_inode_shards(ino, shards)
+    }
+
     pub fn aknowledge_new_hosts(&self, id: Ino, new_hosts: &[PeerId]) -> WhResult<()> {
         ITree::write_lock(&self.itree, "aknowledge_new_hosts")?.add_inode_hosts(id, new_hosts)
     }
@@ -186,19 +323,12 @@ impl NetworkInterface {
 
         if !ITree::is_local_only(id) {
             self.to_network_message_tx
-                .send(ToNetworkMessage::BroadcastMessage(Request::EditMetadata(
-                    id, fixed_meta,
-                )))
+                .send(ToNetworkMessage::AcknowledgedBroadcast(
+                    Request::EditMetadata(id, fixed_meta),
+                ))
                 .expect("update_metadata: unable to update modification on the network thread");
         }
         Ok(())
-        /* REVIEW
-         * This system (and others broadcasts systems) should be reviewed as they don't check success.
-         * In this case, if another host misses this order, it will not update it's file.
-         * We could create a "broadcast" callback with the number of awaited confirmations and a timeout
-         * before resend or fail declaration.
-         * Or send a bunch of Specific messages
-         */
     }
 
     // SECTION Redundancy related
@@ -215,6 +345,20 @@ impl NetworkInterface {
             .expect("network_interface::check_integrity: tx error");
     }
 
+    /// Fetch the pinned files missing locally in the background
+    pub fn fetch_pinned(&self) {
+        self.to_redundancy_tx
+            .send(RedundancyMessage::FetchPinned)
+            .expect("network_interface::fetch_pinned: tx error");
+    }
+
+    /// Fetch a local replica again from another host, it doesn't match its hash
+    pub fn refetch(&self, ino: Ino) {
+        self.to_redundancy_tx
+            .send(RedundancyMessage::Refetch(ino))
+            .expect("network_interface::refetch: tx error");
+    }
+
     // !SECTION ^ Redundancy related
 
     // SECTION Node related
@@ -249,6 +393,9 @@ impl NetworkInterface {
     // }
 
     pub fn send_filesystem(&self, to: PeerId) -> WhResult<Response> {
+        // the tree sent carries every removal
+        self.tombstones_seen_by(&to)?;
+        self.operations.write().remember(&to);
         let clean_itree = self.itree.read().clone().clean_local();
 
         let mut peers_address_list = self.peers_info.read().clone();
@@ -265,16 +412,14 @@ impl NetworkInterface {
         ))
     }
 
-    pub fn connect_peer(&self, peer_id: PeerId, info: Info) {
-        self.peers_info.write().insert(
-            peer_id,
-            network::PeerInfo {
-                nickname: info.agent_version,
-                listen_addrs: info.listen_addrs,
-            },
-        );
+    pub fn connect_peer(&self, peer_id: PeerId, info: network::PeerInfo) {
+        if self.suspects.write().remove(&peer_id).is_some() {
+            log::info!("{peer_id} came back in time, its files are still hosted");
+        }
+        self.peers_info.write().insert(peer_id, info);
         self.peers.write().push(peer_id);
         self.check_integrity();
+        self.fetch_pinned();
     }
 
     pub fn disconnect_peer(&self, addr: PeerId) -> WhResult<Response> {
@@ -284,14 +429,228 @@ impl NetworkInterface {
                 called_from: "disconnect_peer: can't write lock peers".to_owned(),
             })?
             .retain(|p| p != &addr);
+        self.locks.write().release_peer(addr);
+        self.lock_waiters.wake();
+        self.operations.write().left(addr);
+        self.scores.write().forget(&addr);
+
+        // It may only be a short outage, its files are kept until the grace period ends
+        log::debug!("Disconnecting {addr}. Releasing its locks, it is suspected to be gone");
+        self.suspects.write().insert(addr, Instant::now());
+        self.to_redundancy_tx
+            .send(RedundancyMessage::Suspect(addr))
+            .expect("network_interface::disconnect_peer: tx error");
+        Ok(Response::Success)
+    }
+
+    /// Hosts of a file that can be asked for it: connected, and with the required capabilities
+    /// Hosts that disconnected recently are still listed in the tree, but unreachable
+    /// The best hosts to read from come first
+    pub fn available_hosts(&self, hosts: &[PeerId], required: &[Capability]) -> Vec<PeerId> {
+        let suspects = self.suspects.read();
+        let capabilities = self.capabilities.read();
+
+        let mut available: Vec<PeerId> = hosts
+            .iter()
+            .filter(|host| !suspects.contains_key(host))
+            .filter(|host| {
+                required.is_empty()
+                    || capabilities.get(host).is_some_and(|capabilities| {
+                        required
+                            .iter()
+                            .all(|capability| capabilities.contains(capability))
+                    })
+            })
+            .copied()
+            .collect();
+        self.scores.read().rank(&mut available);
+        available
+    }
 
-        log::debug!("Disconnecting {addr}. Removing from inodes hosts");
-        for inode in ITree::write_lock(&self.itree, "disconnect_peer")?.inodes_mut() {
+    /// Forget the files of a peer that didn't come back within the grace period
+    /// Returns false if it came back, or left again more recently
+    pub fn expire_suspect(&self, peer: PeerId) -> WhResult<bool> {
+        let grace_period = self.global_config.read().redundancy.grace_period();
+        {
+            let mut suspects = self.suspects.write();
+            match suspects.get(&peer) {
+                Some(left) if left.elapsed() >= grace_period => suspects.remove(&peer),
+                _ => return Ok(false),
+            };
+        }
+
+        log::info!("{peer} didn't come back, removing it from inodes hosts");
+        let mut itree = ITree::write_lock(&self.itree, "expire_suspect")?;
+        for inode in itree.inodes_mut() {
             if let FsEntry::File(hosts) = &mut inode.entry {
-                hosts.retain(|h| *h != addr);
+                hosts.retain(|h| *h != peer);
+            }
+            if let Some(shards) = &mut inode.shards {
+                shards.lose(&peer);
             }
         }
-        self.check_integrity();
-        Ok(Response::Success)
+        // The removals aren't kept for it anymore, if it ever comes back it gets the whole tree
+        itree.tombstones_seen_by(&peer);
+        drop(itree);
+        self.operations.write().forget(&peer);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{config::types::NetworkAccess, pods::itree::ROOT};
+
+    #[test]
+    fn suspects_lose_their_files_after_the_grace_period() {
+        // the interface keeps a handle on the runtime of the pod
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _runtime = runtime.enter();
+        let (network_tx, _network_rx) = mpsc::unbounded_channel();
+        let (redundancy_tx, _redundancy_rx) = mpsc::unbounded_channel();
+        let gone = PeerId::random();
+        let mut itree = ITree::new();
+        itree
+            .add_inode_from_parameters(
+                InodeName::try_from("notes.txt".to_owned()).unwrap(),
+                11,
+                ROOT,
+                FsEntry::File(vec![gone]),
+                0o644,
+            )
+            .unwrap();
+        itree.bury(12, Tombstone::now(PeerId::random()), &[gone]);
+        let network_interface = NetworkInterface::new(
+            Arc::new(RwLock::new(itree)),
+            PeerId::random(),
+            "laptop:pod".to_owned(),
+            network_tx,
+            redundancy_tx,
+            Arc::new(RwLock::new(vec![gone])),
+            Arc::new(RwLock::new(GlobalConfig::default())),
+            libp2p::stream::Behaviour::new().new_control(),
+            OperationLog::default(),
+            None,
+            Arc::new(CompressionStats::default()),
+        );
+        let hosts = |ni: &NetworkInterface| ni.itree.read().get_inode(11).unwrap().entry.clone();
+
+        // never left
+        assert!(!network_interface.expire_suspect(gone).unwrap());
+
+        // left, but may still come back
+        network_interface.disconnect_peer(gone).unwrap();
+        assert!(!network_interface.expire_suspect(gone).unwrap());
+        assert_eq!(hosts(&network_interface), FsEntry::File(vec![gone]));
+        assert!(network_interface.itree.read().is_buried(12));
+
+        // the grace period is over
+        network_interface
+            .global_config
+            .write()
+            .redundancy
+            .grace_period = 0;
+        assert!(network_interface.expire_suspect(gone).unwrap());
+        assert_eq!(hosts(&network_interface), FsEntry::File(vec![]));
+        assert!(!network_interface.itree.read().is_buried(12));
+        assert!(!network_interface.suspects.read().contains_key(&gone));
+
+        // already expired
+        assert!(!network_interface.expire_suspect(gone).unwrap());
+    }
+
+    #[test]
+    fn forgotten_peers_fetch_the_whole_tree_when_they_return() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _runtime = runtime.enter();
+        let (network_tx, _network_rx) = mpsc::unbounded_channel();
+        let (redundancy_tx, _redundancy_rx) = mpsc::unbounded_channel();
+        let (gone, back_in_time) = (PeerId::random(), PeerId::random());
+        let network_interface = NetworkInterface::new(
+            Arc::new(RwLock::new(ITree::new())),
+            PeerId::random(),
+            "laptop:pod".to_owned(),
+            network_tx,
+            redundancy_tx,
+            Arc::new(RwLock::new(vec![gone, back_in_time])),
+            Arc::new(RwLock::new(GlobalConfig::default())),
+            libp2p::stream::Behaviour::new().new_control(),
+            OperationLog::default(),
+            None,
+            Arc::new(CompressionStats::default()),
+        );
+        let catch_up = Request::RequestOperations(Vec::new());
+
+        network_interface.disconnect_peer(back_in_time).unwrap();
+        network_interface.disconnect_peer(gone).unwrap();
+        network_interface
+            .global_config
+            .write()
+            .redundancy
+            .grace_period = 0;
+        assert!(network_interface.expire_suspect(gone).unwrap());
+
+        // came back in time, caught up from the log
+        assert!(network_interface
+            .forgotten_refusal(&back_in_time, &catch_up)
+            .is_none());
+
+        // came back too late, only its request for the tree is answered
+        assert!(matches!(
+            network_interface.forgotten_refusal(&gone, &catch_up),
+            Some(Response::Forgotten)
+        ));
+        assert!(network_interface
+            .forgotten_refusal(&gone, &Request::RequestFs)
+            .is_none());
+        assert!(matches!(
+            network_interface.send_missed_operations(gone),
+            Ok(Response::FsAnswer(..))
+        ));
+
+        // once it has the tree, it is followed again
+        assert!(network_interface
+            .forgotten_refusal(&gone, &catch_up)
+            .is_none());
+    }
+
+    #[test]
+    fn members_refused_by_new_access_rules_are_revoked() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _runtime = runtime.enter();
+        let (network_tx, _network_rx) = mpsc::unbounded_channel();
+        let (redundancy_tx, _redundancy_rx) = mpsc::unbounded_channel();
+        let (member, stranger) = (PeerId::random(), PeerId::random());
+        let network_interface = NetworkInterface::new(
+            Arc::new(RwLock::new(ITree::new())),
+            PeerId::random(),
+            "laptop:pod".to_owned(),
+            network_tx,
+            redundancy_tx,
+            Arc::new(RwLock::new(vec![member, stranger])),
+            Arc::new(RwLock::new(GlobalConfig::default())),
+            libp2p::stream::Behaviour::new().new_control(),
+            OperationLog::default(),
+            None,
+            Arc::new(CompressionStats::default()),
+        );
+        assert!(network_interface.revoked_peers().is_empty());
+
+        {
+            let mut config = network_interface.global_config.write();
+            config.general.access = NetworkAccess::Whitelist;
+            config.general.whitelist = vec![member];
+        }
+        assert_eq!(network_interface.revoked_peers(), vec![stranger]);
+
+        {
+            let mut config = network_interface.global_config.write();
+            config.general.access = NetworkAccess::Blacklist;
+            config.general.blacklist = vec![member];
+        }
+        assert_eq!(network_interface.revoked_peers(), vec![member]);
     }
 }
@@ -0,0 +1,283 @@
+//! Log of the operations applied on the tree, for incremental catch-up
+//!
+//! Every pod numbers the operations it applies, local or received, and keeps the latest ones.
+//! When a peer leaves, the pod records up to where the peer has seen its log. Once they meet again,
+//! the peer sends a [Request::RequestOperations] carrying what it did meanwhile, and is answered
+//! with what it missed since then, instead of the whole tree with [Response::FsAnswer].
+//! The whole tree is still sent when the log doesn't go back that far.
+//! A peer forgotten after its grace period is refused the catch-up: the removals made meanwhile
+//! aren't kept for it, it has to fetch the whole tree with [Request::RequestFs].
+//! The missed operations are sent in pages under the message size limit, the returning peer
+//! asking for the next one with [Request::ContinueOperations] as it applies them.
+//!
+//! The log is saved next to the pod's backup by the service, see [crate::service::save].
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::WhResult,
+    network::{
+        message::{Request, Response},
+        protocol::Capability,
+    },
+    pods::{
+        itree::{creation::Reconciliation, ITree},
+        network::network_interface::NetworkInterface,
+    },
+};
+
+/// Operations kept in the log, the oldest are dropped past this
+pub const MAX_LOGGED_OPERATIONS: usize = 50_000;
+
+/// Deltas up to this many encoded bytes are kept as they are, larger ones as a change of version
+const MAX_LOGGED_DELTA: u64 = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct OperationLog {
+    /// Sequence number of the last operation
+    last: u64,
+    /// Latest operations with their sequence number, oldest first
+    operations: VecDeque<(u64, Request)>,
+    /// Last operation each peer had seen when it left
+    positions: HashMap<PeerId, u64>,
+    /// Peers that didn't come back in time, until they fetch the whole tree
+    #[serde(default)]
+    forgotten: HashSet<PeerId>,
+}
+
+impl OperationLog {
+    /// Form in which an applied request is kept, None if it doesn't change the tree
+    /// Large deltas are kept as a change of version: the hosts request the delta afterwards
+    pub fn loggable(request: &Request) -> Option<Request> {
+        match request {
+            Request::Inode(_)
+            | Request::Rename(..)
+            | Request::AddHosts(..)
+            | Request::RemoveHosts(..)
+            | Request::FileChanged(..)
+            | Request::Remove(..)
+            | Request::EditMetadata(..)
+            | Request::SetXAttr(..)
+            | Request::RemoveXAttr(..)
+            | Request::SetShards(..) => Some(request.clone()),
+            Request::FileDelta(_, _, _, _, delta)
+                if bincode::serialized_size(delta).is_ok_and(|size| size <= MAX_LOGGED_DELTA) =>
+            {
+                Some(request.clone())
+            }
+            Request::FileDelta(ino, meta, version, _, _) => {
+                Some(Request::FileChanged(*ino, meta.clone(), version.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Record an applied operation, as given by [OperationLog::loggable]
+    pub fn record(&mut self, operation: Request) {
+        self.last += 1;
+        self.operations.push_back((self.last, operation));
+        if self.operations.len() > MAX_LOGGED_OPERATIONS {
+            self.operations.pop_front();
+        }
+    }
+
+    /// Record the changes made on disk while the pod was stopped, so its peers learn about them
+    pub fn record_reconciliation(
+        &mut self,
+        itree: &ITree,
+        reconciliation: &Reconciliation,
+        host: PeerId,
+    ) {
+        for ino in &reconciliation.added {
+            if ITree::is_local_only(*ino) {
+                continue;
+            }
+            if let Ok(inode) = itree.get_inode(*ino) {
+                self.record(Request::Inode(inode.clone()));
+            }
+        }
+        for ino in &reconciliation.changed {
+            if let Ok(inode) = itree.get_inode(*ino) {
+                self.record(Request::FileChanged(
+                    *ino,
+                    inode.meta.clone(),
+                    inode.version.clone(),
+                ));
+            }
+        }
+        for ino in &reconciliation.lost {
+            self.record(Request::RemoveHosts(*ino, vec![host]));
+        }
+    }
+
+    /// Remember that a peer has seen every operation so far
+    pub fn left(&mut self, peer: PeerId) {
+        self.positions.insert(peer, self.last);
+    }
+
+    /// Stop following a peer that won't come back, it can't be caught up from the log anymore
+    pub fn forget(&mut self, peer: &PeerId) {
+        self.positions.remove(peer);
+        self.forgotten.insert(*peer);
+    }
+
+    /// If the peer was forgotten and hasn't fetched the whole tree since
+    pub fn is_forgotten(&self, peer: &PeerId) -> bool {
+        self.forgotten.contains(peer)
+    }
+
+    /// The peer fetched the whole tree, it can be caught up again
+    pub fn remember(&mut self, peer: &PeerId) {
+        self.forgotten.remove(peer);
+    }
+
+    /// If the peer was met before, so it can be caught up from the log
+    pub fn knows(&self, peer: &PeerId) -> bool {
+        self.positions.contains_key(peer)
+    }
+
+    /// Peers met before, that left since
+    pub fn known_peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.positions.keys()
+    }
+
+    /// Last operation a peer had seen when it left
+    pub fn position(&self, peer: &PeerId) -> Option<u64> {
+        self.positions.get(peer).copied()
+    }
+
+    /// Operations the peer missed since it left
+    /// None if it is unknown or if the log has been truncated since
+    pub fn missed_by(&self, peer: &PeerId) -> Option<Vec<Request>> {
+        let (missed, _) = self.page(self.position(peer)?, u64::MAX)?;
+        Some(missed)
+    }
+
+    /// Operations after the `after`th, up to `max_size` encoded bytes of them
+    /// Returns them with the sequence number to continue after if some are left,
+    /// None if the log has been truncated since
+    pub fn page(&self, after: u64, max_size: u64) -> Option<(Vec<Request>, Option<u64>)> {
+        let first = self
+            .operations
+            .front()
+            .map_or(self.last + 1, |(sequence, _)| *sequence);
+
+        if after > self.last || after + 1 < first {
+            return None;
+        }
+        let mut page = Vec::new();
+        let mut size = 0;
+        for (sequence, request) in self
+            .operations
+            .iter()
+            .filter(|(sequence, _)| *sequence > after)
+        {
+            size += bincode::serialized_size(request).unwrap_or_default();
+            // a single operation larger than a page is still sent on its own
+            if size > max_size && !page.is_empty() {
+                return Some((page, Some(sequence - 1)));
+            }
+            page.push(request.clone());
+        }
+        Some((page, None))
+    }
+}
+
+impl NetworkInterface {
+    /// Record an operation applied on this pod
+    pub fn log_operation(&self, request: &Request) {
+        if let Some(operation) = OperationLog::loggable(request) {
+            self.operations.write().record(operation);
+        }
+    }
+
+    /// Answer a peer coming back, once the operations it made while away are applied
+    /// Falls back to the whole tree when the log can't tell what it missed
+    pub fn send_missed_operations(&self, peer: PeerId) -> WhResult<Response> {
+        let position = self.operations.read().position(&peer);
+        match position.and_then(|position| self.operations_after(&peer, position)) {
+            Some(missed) => {
+                log::info!("Catching up {peer} on the operations it missed");
+                self.tombstones_seen_by(&peer)?;
+                return Ok(missed);
+            }
+            None => log::info!("The log doesn't cover what {peer} missed, sending the whole tree"),
+        }
+        self.send_filesystem(peer)
+    }
+
+    /// Answer to the requests of a forgotten peer other than [Request::RequestFs]
+    /// Its tree may still hold what was removed meanwhile, nothing it sends is applied until it fetched ours
+    pub fn forgotten_refusal(&self, peer: &PeerId, request: &Request) -> Option<Response> {
+        if matches!(request, Request::RequestFs) || !self.operations.read().is_forgotten(peer) {
+            return None;
+        }
+        let understood = self
+            .capabilities
+            .read()
+            .get(peer)
+            .is_none_or(|capabilities| capabilities.contains(&Capability::OperationLog));
+        Some(if understood {
+            Response::Forgotten
+        } else {
+            Response::Failed
+        })
+    }
+
+    /// Operations applied after the `after`th, in pages for the peers that understand them
+    /// None if the log has been truncated since
+    pub fn operations_after(&self, peer: &PeerId, after: u64) -> Option<Response> {
+        let paged = self
+            .capabilities
+            .read()
+            .get(peer)
+            .is_none_or(|capabilities| capabilities.contains(&Capability::OperationPages));
+        if !paged {
+            let (missed, _) = self.operations.read().page(after, u64::MAX)?;
+            return Some(Response::Operations(missed));
+        }
+
+        // kept well under the limit, like batches
+        let max_size = self
+            .global_config
+            .read()
+            .network
+            .max_message_size
+            .saturating_mul(1024 * 1024)
+            / 2;
+        let (missed, next) = self.operations.read().page(after, max_size)?;
+        Some(Response::OperationsPage(missed, next))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn missed_operations_are_paged() {
+        let mut log = OperationLog::default();
+        for ino in 0..10 {
+            log.record(Request::RemoveXAttr(ino, "user.key".to_owned()));
+        }
+        let size =
+            bincode::serialized_size(&Request::RemoveXAttr(0, "user.key".to_owned())).unwrap();
+
+        let (page, next) = log.page(2, size * 3).unwrap();
+        assert_eq!(page.len(), 3);
+        assert_eq!(next, Some(5));
+        assert!(matches!(page[0], Request::RemoveXAttr(2, _)));
+
+        let (page, next) = log.page(5, size * 100).unwrap();
+        assert_eq!(page.len(), 5);
+        assert_eq!(next, None);
+
+        // larger than a page, still sent
+        let (page, next) = log.page(8, 1).unwrap();
+        assert_eq!((page.len(), next), (1, Some(9)));
+        assert_eq!(log.page(11, size), None);
+    }
+}
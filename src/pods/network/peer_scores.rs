@@ -0,0 +1,197 @@
+//! Scores of the connected peers, to read from the best replica first
+//!
+//! Each peer is measured by its round trip time, given by libp2p ping, the throughput
+//! of its recent transfers and the number of transfers currently running with it.
+//! Peers in the same zone as this pod, set in its local configuration, always come first.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use libp2p::PeerId;
+use parking_lot::RwLock;
+
+/// Weight of a new measure in the averages, older ones fade away
+const SMOOTHING: f64 = 0.3;
+
+/// Round trip time assumed for a peer not pinged yet
+const DEFAULT_RTT: Duration = Duration::from_millis(100);
+
+/// Throughput assumed for a peer no transfer was measured with, in bytes per second
+const DEFAULT_THROUGHPUT: f64 = 10.0 * 1024.0 * 1024.0;
+
+/// Amount of data the cost of a peer is computed for
+const REFERENCE_READ: f64 = 1024.0 * 1024.0;
+
+#[derive(Default, Debug, Clone)]
+pub struct PeerScore {
+    /// Average round trip time
+    pub rtt: Option<Duration>,
+    /// Average throughput of the transfers, in bytes per second
+    pub throughput: Option<f64>,
+    /// Transfers running with the peer
+    pub in_flight: usize,
+    pub zone: Option<String>,
+}
+
+impl PeerScore {
+    /// Expected time to read a MiB from the peer, in milliseconds
+    /// Each running transfer shares the link, so they multiply the cost
+    pub fn cost(&self) -> f64 {
+        let latency = self.rtt.unwrap_or(DEFAULT_RTT).as_secs_f64() * 1000.0;
+        let transfer = REFERENCE_READ / self.throughput.unwrap_or(DEFAULT_THROUGHPUT) * 1000.0;
+        (latency + transfer) * (1 + self.in_flight) as f64
+    }
+}
+
+fn smooth(average: Option<f64>, measure: f64) -> f64 {
+    match average {
+        Some(average) => average * (1.0 - SMOOTHING) + measure * SMOOTHING,
+        None => measure,
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct PeerScores {
+    /// Zone of this pod
+    zone: Option<String>,
+    peers: HashMap<PeerId, PeerScore>,
+}
+
+impl PeerScores {
+    pub fn new(zone: Option<String>) -> Self {
+        Self {
+            zone,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn zone(&self) -> Option<&String> {
+        self.zone.as_ref()
+    }
+
+    pub fn get(&self, peer: &PeerId) -> PeerScore {
+        self.peers.get(peer).cloned().unwrap_or_default()
+    }
+
+    pub fn set_zone(&mut self, peer: PeerId, zone: Option<String>) {
+        self.peers.entry(peer).or_default().zone = zone;
+    }
+
+    pub fn record_rtt(&mut self, peer: PeerId, rtt: Duration) {
+        let score = self.peers.entry(peer).or_default();
+        let average = smooth(score.rtt.map(|rtt| rtt.as_secs_f64()), rtt.as_secs_f64());
+        score.rtt = Some(Duration::from_secs_f64(average));
+    }
+
+    pub fn record_transfer(&mut self, peer: PeerId, bytes: u64, elapsed: Duration) {
+        // too small to tell anything about the bandwidth
+        if bytes == 0 || elapsed.is_zero() {
+            return;
+        }
+        let score = self.peers.entry(peer).or_default();
+        score.throughput = Some(smooth(
+            score.throughput,
+            bytes as f64 / elapsed.as_secs_f64(),
+        ));
+    }
+
+    pub fn forget(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    fn same_zone(&self, peer: &PeerId) -> bool {
+        self.zone.is_some()
+            && self
+                .peers
+                .get(peer)
+                .is_some_and(|score| score.zone == self.zone)
+    }
+
+    /// Order peers from the best to the worst to read from
+    pub fn rank(&self, peers: &mut [PeerId]) {
+        peers.sort_by(|a, b| {
+            self.same_zone(b)
+                .cmp(&self.same_zone(a))
+                .then(self.get(a).cost().total_cmp(&self.get(b).cost()))
+        });
+    }
+
+    /// Count a transfer with `peer` as running until the returned guard is dropped
+    pub fn start_transfer(scores: &Arc<RwLock<Self>>, peer: PeerId) -> InFlight {
+        scores.write().peers.entry(peer).or_default().in_flight += 1;
+        InFlight {
+            scores: scores.clone(),
+            peer,
+        }
+    }
+}
+
+/// A running transfer, see [PeerScores::start_transfer]
+pub struct InFlight {
+    scores: Arc<RwLock<PeerScores>>,
+    peer: PeerId,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(score) = self.scores.write().peers.get_mut(&self.peer) {
+            score.in_flight = score.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn faster_peers_come_first() {
+        let (fast, slow, unknown) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut scores = PeerScores::default();
+        scores.record_rtt(fast, Duration::from_millis(5));
+        scores.record_rtt(slow, Duration::from_millis(80));
+        scores.record_transfer(slow, 1024, Duration::from_secs(1));
+
+        let mut peers = vec![slow, unknown, fast];
+        scores.rank(&mut peers);
+        assert_eq!(peers, vec![fast, unknown, slow]);
+    }
+
+    #[test]
+    fn unmeasured_peers_come_after_fast_ones() {
+        let (fast, unknown) = (PeerId::random(), PeerId::random());
+        let mut scores = PeerScores::default();
+        scores.record_rtt(fast, DEFAULT_RTT);
+        scores.record_rtt(unknown, DEFAULT_RTT);
+        scores.record_transfer(fast, 100 * 1024 * 1024, Duration::from_secs(1));
+
+        let mut peers = vec![unknown, fast];
+        scores.rank(&mut peers);
+        assert_eq!(peers, vec![fast, unknown]);
+    }
+
+    #[test]
+    fn same_zone_comes_first() {
+        let (near, far) = (PeerId::random(), PeerId::random());
+        let mut scores = PeerScores::new(Some("paris".to_owned()));
+        scores.set_zone(near, Some("paris".to_owned()));
+        scores.set_zone(far, Some("tokyo".to_owned()));
+        scores.record_rtt(near, Duration::from_millis(50));
+        scores.record_rtt(far, Duration::from_millis(10));
+
+        let mut peers = vec![far, near];
+        scores.rank(&mut peers);
+        assert_eq!(peers, vec![near, far]);
+    }
+
+    #[test]
+    fn busy_peers_cost_more() {
+        let peer = PeerId::random();
+        let scores = Arc::new(RwLock::new(PeerScores::default()));
+        let idle = scores.read().get(&peer).cost();
+
+        let transfer = PeerScores::start_transfer(&scores, peer);
+        assert!(scores.read().get(&peer).cost() > idle);
+        drop(transfer);
+        assert_eq!(scores.read().get(&peer).cost(), idle);
+    }
+}
@@ -1,12 +1,15 @@
+use std::collections::HashMap;
 use std::io;
 use std::sync::Arc;
 
-use crate::network::message::{Request, Response, ToNetworkMessage};
+use crate::network::protocol::Capability;
+use crate::pods::filesystem::fs_interface::FsInterface;
 use crate::pods::itree::{FsEntry, ITree};
-use crate::pods::network::network_interface::NetworkInterface;
+use crate::pods::network::file_stream::{Storage, PARALLEL_PULL_THRESHOLD};
 use crate::{error::WhError, pods::itree::Ino};
 use custom_error::custom_error;
-use tokio::sync::oneshot;
+use libp2p::PeerId;
+use tokio::sync::{Mutex, OwnedMutexGuard};
 
 custom_error! {
     #[derive(Clone)]
@@ -14,6 +17,7 @@ custom_error! {
     pub PullError
     WhError{source: WhError} = "{source}",
     NoHostAvailable = "No host available",
+    Corrupted = "The content received doesn't match its hash",
 
     // Arc required to keep clonability
     WriteError{io: Arc<io::Error>} = "failed to write: {io}",
@@ -23,63 +27,361 @@ custom_error! {
     //No Host to hold the file
 }
 
-impl NetworkInterface {
-    /// Pull the file from the network
-    /// Doesn't affect the itree or the disk
+/// Ranges are fetched by blocks of this size, so neighbouring reads share them
+const RANGE_BLOCK: u64 = 64 * 1024;
+
+/// Pulls running on the files, one at a time per file
+/// A reader of a file being pulled waits for that pull and then finds the data on the disk,
+/// instead of downloading it again. Pulls of different files run concurrently.
+#[derive(Default)]
+pub struct PullLocks(parking_lot::Mutex<HashMap<Ino, Arc<Mutex<()>>>>);
+
+impl PullLocks {
+    /// Wait for the pulls of this file running on other threads
     ///
     /// # Panics
     ///
     /// This function panics if called within an asynchronous execution
     /// context.
     ///
-    pub fn pull_file(&self, ino: Ino) -> Result<Option<Vec<u8>>, PullError> {
-        let hosts = {
-            let itree = ITree::read_lock(&self.itree, "pull file sync")?;
+    pub fn lock(&self, ino: Ino) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.0.lock();
+            // the locks no one holds or waits for anymore
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(ino).or_default().clone()
+        };
+        lock.blocking_lock_owned()
+    }
+}
 
-            if let FsEntry::File(hosts) = &itree.get_inode(ino)?.entry {
-                hosts.clone()
+impl FsInterface {
+    /// Pull the file from the network onto the disk, if it isn't already stored locally
+    /// The content is streamed straight to the disk, and this pod becomes a host of the file
+    ///
+    /// # Panics
+    ///
+    /// This function panics if called within an asynchronous execution
+    /// context.
+    ///
+    pub fn pull_file(&self, ino: Ino) -> Result<(), PullError> {
+        self.fetch_file(ino, Storage::Replica)
+    }
+
+    /// Pull the whole file into the local cache to read it, if it isn't already there
+    /// This pod doesn't become a host of the file, see [SparseCache](crate::pods::filesystem::sparse_cache::SparseCache)
+    ///
+    /// # Panics
+    ///
+    /// This function panics if called within an asynchronous execution
+    /// context.
+    ///
+    pub fn cache_file(&self, ino: Ino) -> Result<(), PullError> {
+        self.fetch_file(ino, Storage::Cache)
+    }
+
+    /// Replace the local replica of a file by the copy of another host
+    /// Used once it is found not to match its hash
+    ///
+    /// # Panics
+    ///
+    /// This function panics if called within an asynchronous execution
+    /// context.
+    ///
+    pub fn refetch_file(&self, ino: Ino) -> Result<(), PullError> {
+        let _pulling = self.pulls.lock(ino);
+        let (hosts, size) = {
+            let itree = ITree::read_lock(&self.network_interface.itree, "refetch file")?;
+            let inode = itree.get_inode(ino)?;
+
+            if let FsEntry::File(hosts) = &inode.entry {
+                (hosts.clone(), inode.meta.size)
+            } else {
+                return Err(WhError::InodeIsADirectory.into());
+            }
+        };
+        if !hosts.contains(&self.network_interface.id) {
+            // not a replica anymore, nothing to repair
+            return Ok(());
+        }
+        let others: Vec<PeerId> = hosts
+            .into_iter()
+            .filter(|host| *host != self.network_interface.id)
+            .collect();
+        self.download_from(&others, ino, size, Storage::Replica)
+    }
+
+    fn fetch_file(&self, ino: Ino, storage: Storage) -> Result<(), PullError> {
+        let _pulling = self.pulls.lock(ino);
+        let (hosts, size, version) = {
+            let itree = ITree::read_lock(&self.network_interface.itree, "pull file sync")?;
+            let inode = itree.get_inode(ino)?;
+
+            if let FsEntry::File(hosts) = &inode.entry {
+                (hosts.clone(), inode.meta.size, inode.version.clone())
             } else {
                 return Err(WhError::InodeIsADirectory.into());
             }
         };
 
+        if hosts.contains(&self.network_interface.id) {
+            // if the asked file is already on disk
+            return Ok(());
+        }
+        if storage == Storage::Cache && self.sparse.read().is_whole(ino, &version) {
+            return Ok(());
+        }
+        match self.download_from(&hosts, ino, size, storage) {
+            Err(PullError::NoHostAvailable) if self.has_readable_shards(ino) => self
+                .network_interface
+                .runtime
+                .block_on(self.assemble_shards(ino, storage))
+                .map_err(|err| {
+                    log::error!("Couldn't rebuild {ino} from its shards: {err}");
+                    PullError::NoHostAvailable
+                }),
+            result => result,
+        }
+    }
+
+    /// If enough shards of the current version of the file are held to rebuild it
+    fn has_readable_shards(&self, ino: Ino) -> bool {
+        self.network_interface
+            .itree
+            .read()
+            .get_inode(ino)
+            .is_ok_and(|inode| {
+                inode
+                    .shards
+                    .as_ref()
+                    .is_some_and(|shards| shards.version == inode.version && shards.is_readable())
+            })
+    }
+
+    /// Download a file from the first of `hosts` able to send it, or from several at once
+    /// The content is checked against its hash, a host sending something else is skipped
+    fn download_from(
+        &self,
+        hosts: &[PeerId],
+        ino: Ino,
+        size: u64,
+        storage: Storage,
+    ) -> Result<(), PullError> {
+        let hosts = self.network_interface.available_hosts(hosts, &[]);
         if hosts.is_empty() {
             return Err(PullError::NoHostAvailable);
         }
 
-        if hosts.contains(&self.id) {
-            // if the asked file is already on disk
-            Ok(None)
-        } else {
-            let mut file = None;
-
-            // will try to pull on all redundancies until success
-            for host in hosts {
-                let (tx, rx) = oneshot::channel();
-                // trying on host `pull_from`
-                self.to_network_message_tx
-                    .send(ToNetworkMessage::AnswerMessage(
-                        Request::RequestFile(ino),
-                        tx,
-                        host, // NOTE - naive choice for now
-                    ))
-                    .expect("pull_file: unable to request on the network thread");
-
-                // processing status
-                match rx.blocking_recv() {
-                    Ok(Some(Response::RequestedFile(request))) => file = Some(request),
-                    Ok(Some(_)) => panic!("Wrong Reponse received!"),
-                    _ => continue,
-                };
+        let parallel = self.network_interface.available_hosts(
+            &hosts,
+            &[Capability::RangedReads, Capability::ParallelPulls],
+        );
+        if size >= PARALLEL_PULL_THRESHOLD && parallel.len() > 1 {
+            match self
+                .network_interface
+                .runtime
+                .block_on(self.download_file_parallel(&parallel, ino, size, storage))
+            {
+                Ok(()) => return Ok(()),
+                Err(err) => log::warn!("Failed to pull {ino} from several hosts: {err}"),
             }
+        }
+
+        // will try to pull on all redundancies until success
+        for host in hosts {
+            match self
+                .network_interface
+                .runtime
+                .block_on(self.download_file(host, ino, storage))
+            {
+                Ok(()) => return Ok(()),
+                Err(err) => log::warn!("Failed to pull {ino} from {host}: {err}"),
+            }
+        }
+
+        log::error!("No host is currently able to send the file.\nFile: {ino}");
+        Err(PullError::NoHostAvailable)
+    }
+
+    /// Fetch the part of a file needed to read `len` bytes from `offset`, plus the readahead
+    /// Only the blocks missing locally are fetched, and this pod doesn't become a host
+    /// Returns false if no host can send parts of the file, it must then be pulled whole
+    /// Ranges can't be checked alone: the copy is checked against the hash once it is whole,
+    /// and dropped if it doesn't match
+    ///
+    /// # Panics
+    ///
+    /// This function panics if called within an asynchronous execution
+    /// context.
+    ///
+    pub fn pull_range(&self, ino: Ino, offset: u64, len: u64) -> Result<bool, PullError> {
+        let _pulling = self.pulls.lock(ino);
+        let (hosts, size, version, path, perm, hash) = {
+            let itree = ITree::read_lock(&self.network_interface.itree, "pull range")?;
+            let inode = itree.get_inode(ino)?;
+            let FsEntry::File(hosts) = &inode.entry else {
+                return Err(WhError::InodeIsADirectory.into());
+            };
+            (
+                hosts.clone(),
+                inode.meta.size,
+                inode.version.clone(),
+                itree.get_path_from_inode_id(ino)?,
+                inode.meta.perm,
+                inode.meta.hash,
+            )
+        };
+
+        if hosts.contains(&self.network_interface.id) {
+            return Ok(true);
+        }
+
+        let readahead = self
+            .network_interface
+            .global_config
+            .read()
+            .network
+            .readahead();
+        let start = offset.min(size) / RANGE_BLOCK * RANGE_BLOCK;
+        let end = offset
+            .saturating_add(len)
+            .saturating_add(readahead)
+            .div_ceil(RANGE_BLOCK)
+            .saturating_mul(RANGE_BLOCK)
+            .min(size);
+        if start >= end {
+            return Ok(true);
+        }
+
+        let hosts = self
+            .network_interface
+            .available_hosts(&hosts, &[Capability::RangedReads]);
+        if hosts.is_empty() {
+            return Ok(false);
+        }
 
-            match file {
-                Some(data) => Ok(Some(data)),
+        let (outdated, missing) = self.sparse.write().missing(ino, &version, size, start..end);
+        if outdated {
+            // what the local copy holds belongs to an other version
+            self.disk
+                .new_file(&path, perm)
+                .and_then(|()| self.disk.set_file_size(&path, size as usize))
+                .map_err(|io| PullError::WriteError { io: Arc::new(io) })?;
+        }
+
+        let fetched_any = !missing.is_empty();
+        for range in missing {
+            let fetched = hosts.iter().find_map(|host| {
+                self.network_interface
+                    .runtime
+                    .block_on(self.download_range(*host, ino, range.start, range.end - range.start))
+                    .inspect_err(|err| {
+                        log::warn!("Failed to pull {range:?} of {ino} from {host}: {err}")
+                    })
+                    .ok()
+                    .filter(|len| {
+                        // a host sending less is asked nothing more, another may have it all
+                        let whole = *len == range.end - range.start;
+                        if !whole {
+                            log::warn!("{host} only sent {len} bytes of {range:?} of {ino}");
+                        }
+                        whole
+                    })
+            });
+            match fetched {
+                Some(len) => {
+                    let evicted = self
+                        .sparse
+                        .write()
+                        .insert(ino, range.start..range.start + len);
+                    self.drop_cached_copies(&evicted);
+                }
                 None => {
-                    log::error!("No host is currently able to send the file.\nFile: {ino}");
-                    Err(PullError::NoHostAvailable)
+                    log::error!("No host is currently able to send {range:?} of {ino}");
+                    return Err(PullError::NoHostAvailable);
+                }
+            }
+        }
+
+        let whole = fetched_any && self.sparse.read().is_whole(ino, &version);
+        if let Some(hash) = hash.filter(|_| whole) {
+            let checksum = self
+                .network_interface
+                .runtime
+                .block_on(self.range_checksum(&path, 0, size))
+                .map_err(|io| PullError::WriteError { io: Arc::new(io) })?;
+            if checksum != hash {
+                log::warn!("The copy of {ino} doesn't match its hash, dropping it");
+                self.drop_cached_copies(&[ino]);
+                return Err(PullError::Corrupted);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Forget the cached copies of files this pod doesn't host, and remove them from the disk
+    pub fn drop_cached_copies(&self, inos: &[Ino]) {
+        for ino in inos {
+            self.sparse.write().forget(*ino);
+            if self.is_stored_locally(*ino) {
+                continue;
+            }
+            let Ok(path) = self
+                .network_interface
+                .itree
+                .read()
+                .get_path_from_inode_id(*ino)
+            else {
+                continue;
+            };
+            if self.disk.file_exists(&path) {
+                if let Err(err) = self.disk.remove_file(&path) {
+                    log::warn!("Couldn't remove the cached copy of {ino}: {err}");
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn pulls_of_a_file_wait_for_each_other() {
+        let locks = Arc::new(PullLocks::default());
+        let pulled = Arc::new(AtomicBool::new(false));
+        let pulling = locks.lock(11);
+
+        let waiting = {
+            let (locks, pulled) = (locks.clone(), pulled.clone());
+            thread::spawn(move || {
+                let _pulling = locks.lock(11);
+                pulled.store(true, Ordering::SeqCst);
+            })
+        };
+        // other files aren't held
+        drop(locks.lock(12));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!pulled.load(Ordering::SeqCst));
+
+        drop(pulling);
+        waiting.join().unwrap();
+        assert!(pulled.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn locks_of_finished_pulls_are_dropped() {
+        let locks = PullLocks::default();
+        drop(locks.lock(11));
+        drop(locks.lock(12));
+        let _pulling = locks.lock(13);
+        assert_eq!(locks.0.lock().keys().collect::<Vec<_>>(), vec![&13]);
+    }
+}
@@ -1,20 +1,26 @@
 use super::network_interface::NetworkInterface;
 use crate::{
+    config::types::RedundancyMode,
     error::{WhError, WhResult},
-    network::message::{Request, ToNetworkMessage},
     pods::{
-        filesystem::{fs_interface::FsInterface, File},
+        filesystem::{fs_interface::FsInterface, pins::fetch_pinned},
         itree::{FsEntry, ITree, Ino},
-        network::swarm::MAX_CONCURRENT_STREAMS,
+        network::{
+            erasure::{shards_placed, spread_shards},
+            swarm::MAX_CONCURRENT_STREAMS,
+        },
         pod::Pod,
     },
 };
 use either::Either;
 use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
 use tokio::{
-    sync::{mpsc::UnboundedReceiver, oneshot, Semaphore},
+    sync::{mpsc::UnboundedReceiver, Semaphore},
     task::{AbortHandle, JoinSet},
 };
 use ts_rs::TS;
@@ -25,15 +31,19 @@ custom_error::custom_error! {pub RedundancyError
     IsLocalOnly = "Redundancy: this inode is set to not replicate.", // warning only
 }
 
-/// File structs may be kept in ram if
-/// smaller than 512KB
-const MAX_SIZE_KEEP_RAM: usize = 512 * 1024;
-
 /// Message going to the redundancy worker
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum RedundancyMessage {
     ApplyTo(Ino),
     CheckIntegrity,
+    /// A peer disconnected, its files are replicated elsewhere if it doesn't come back in time
+    Suspect(PeerId),
+    /// Fetch the pinned files missing locally, see [Pins](crate::pods::filesystem::pins::Pins)
+    FetchPinned,
+    /// Fetch a local replica again from another host, it doesn't match its hash
+    Refetch(Ino),
+    /// The pod stops, the worker ends once its transfers are
+    Stop,
 }
 
 type Tombstone = ();
@@ -43,7 +53,6 @@ type PendingStatus = Either<Tombstone, AbortHandle>;
 // #[derive(Clone)]
 struct PendingRedundancy {
     pub ino: Ino,
-    pub file: Option<File>,
     // pub sends: Vec<PeerId>,
     pub sends: Vec<(PeerId, PendingStatus)>,
     // pub hosts: Vec<PeerId>,
@@ -74,11 +83,14 @@ impl RedundancyTracker {
 
     /// check every file in the arbo if it has enough redundancies
     /// then try sending any that are below the quota
+    /// in `erasure` mode, given the data and parity shards, the files whose shards are all
+    /// placed aren't replicated
     pub async fn full_check(
         &mut self,
         fs_interface: &Arc<FsInterface>,
         all_peers: &[PeerId],
         r_count: usize,
+        erasure: Option<(usize, usize)>,
     ) {
         let needy = fs_interface
             .network_interface
@@ -86,6 +98,13 @@ impl RedundancyTracker {
             .read()
             .iter()
             .filter_map(|(ino, inode)| match &inode.entry {
+                FsEntry::File(_)
+                    if erasure.is_some_and(|(data, parity)| {
+                        !ITree::is_special(*ino) && shards_placed(inode, data, parity)
+                    }) =>
+                {
+                    None
+                }
                 FsEntry::File(hosts) => {
                     (hosts.len() < r_count && !ITree::is_local_only(*ino)).then_some(*ino)
                 }
@@ -149,26 +168,17 @@ impl RedundancyTracker {
         to.retain(|s| !hosts.contains(s));
 
         let needed = r_count.saturating_sub(hosts.len());
-        let file = self
-            .fs_interface
-            .get_local_file(ino)
-            .map_err(|e| WhError::WouldBlock {
-                called_from: format!("get_local_file: {e}"),
-            })
-            .and_then(|f| f.ok_or(WhError::InodeNotFound))?;
         let sends = Self::push_redundancy(
             &self.fs_interface,
             &self.concurrent_streams,
             &mut self.tasks,
             &to,
             ino,
-            &file.0.clone(),
             needed,
         )
         .await;
         self.pending.push(PendingRedundancy {
             ino,
-            file: (file.0.len() < MAX_SIZE_KEEP_RAM).then_some(file),
             sends,
             // hosts,
         });
@@ -226,23 +236,12 @@ impl RedundancyTracker {
                 return Err(RedundancyError::InsufficientHosts);
             }
 
-            let file = match &pending.file {
-                Some(file) => file.clone(),
-                None => self
-                    .fs_interface
-                    .get_local_file(ino)
-                    .map_err(|e| WhError::WouldBlock {
-                        called_from: format!("get_local_file: {e}"),
-                    })
-                    .and_then(|f| f.ok_or(WhError::InodeIsADirectory))?,
-            };
             let mut sent = Self::push_redundancy(
                 &self.fs_interface,
                 &self.concurrent_streams,
                 &mut self.tasks,
                 &remanining_hosts,
                 ino,
-                &file.0,
                 needed,
             )
             .await;
@@ -261,6 +260,54 @@ impl RedundancyTracker {
     }
 }
 
+/// Files whose shards are placed, one at a time
+/// see [erasure](crate::pods::network::erasure)
+#[derive(Default)]
+struct ShardQueue {
+    running: JoinSet<Ino>,
+    pending: VecDeque<Ino>,
+}
+
+impl ShardQueue {
+    pub fn push(&mut self, fs_interface: &Arc<FsInterface>, ino: Ino) {
+        if !self.pending.contains(&ino) {
+            self.pending.push_back(ino);
+        }
+        if self.running.is_empty() {
+            self.next(fs_interface);
+        }
+    }
+
+    /// start placing the shards of the next file, once the previous one is done
+    pub fn next(&mut self, fs_interface: &Arc<FsInterface>) {
+        if let Some(ino) = self.pending.pop_front() {
+            self.running.spawn(spread_shards(fs_interface.clone(), ino));
+        }
+    }
+
+    /// queue the files this pod may have to place the shards of:
+    /// outdated or missing shards, or a whole copy still hosted here
+    pub fn full_check(&mut self, fs_interface: &Arc<FsInterface>, data: usize, parity: usize) {
+        let me = fs_interface.network_interface.id;
+        let needy = fs_interface
+            .network_interface
+            .itree
+            .read()
+            .iter()
+            .filter_map(|(ino, inode)| match &inode.entry {
+                FsEntry::File(hosts) if !ITree::is_special(*ino) => {
+                    (!shards_placed(inode, data, parity) || hosts.contains(&me)).then_some(*ino)
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        for ino in needy {
+            self.push(fs_interface, ino);
+        }
+    }
+}
+
 /// Redundancy Worker
 /// Worker that applies the redundancy to files
 pub async fn redundancy_worker(
@@ -269,8 +316,65 @@ pub async fn redundancy_worker(
     fs_interface: Arc<FsInterface>,
 ) {
     let mut tracker = RedundancyTracker::new(fs_interface.clone());
+    let mut shards = ShardQueue::default();
+    // grace periods of the disconnected peers
+    let mut suspects = JoinSet::new();
+    // fetch of the pinned files, one at a time
+    let mut pinning = JoinSet::new();
+    let mut pin_again = false;
+    // corrupted replicas fetched again
+    let mut repairs = JoinSet::new();
     loop {
+        let (erasure, data, parity) = {
+            let config = nw_interface.global_config.read();
+            (
+                config.redundancy.mode == RedundancyMode::Erasure,
+                config.redundancy.data_shards as usize,
+                config.redundancy.parity_shards as usize,
+            )
+        };
+        let coding = erasure.then_some((data, parity));
         tokio::select! {
+            Some(placed) = shards.running.join_next(), if !shards.running.is_empty() => {
+                // too few pods to hold the shards: the file keeps its replicas meanwhile
+                if let Ok(ino) = placed {
+                    let placed = nw_interface
+                        .itree
+                        .read()
+                        .get_inode(ino)
+                        .is_ok_and(|inode| shards_placed(inode, data, parity));
+                    if !placed {
+                        let r_count = nw_interface.global_config.read().redundancy.number as usize;
+                        let peers = nw_interface.peers.read().clone();
+                        let _ = tracker.apply(ino, &peers, r_count).await;
+                    }
+                }
+                shards.next(&fs_interface);
+            },
+            Some(repaired) = repairs.join_next(), if !repairs.is_empty() => {
+                if let Ok((ino, Err(err))) = repaired {
+                    log::error!("Couldn't fetch {ino} again after a hash mismatch: {err}");
+                }
+            },
+            Some(_) = pinning.join_next(), if !pinning.is_empty() => {
+                if std::mem::take(&mut pin_again) {
+                    pinning.spawn(fetch_pinned(fs_interface.clone()));
+                }
+            },
+            Some(Ok(peer)) = suspects.join_next(), if !suspects.is_empty() => {
+                match nw_interface.expire_suspect(peer) {
+                    Ok(true) => {
+                        let r_count = nw_interface.global_config.read().redundancy.number as usize;
+                        let peers = nw_interface.peers.read().clone();
+                        tracker.full_check(&fs_interface, &peers, r_count, coding).await;
+                        if erasure {
+                            shards.full_check(&fs_interface, data, parity);
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(err) => log::error!("Failed to forget the files of {peer}: {err}"),
+                }
+            },
             resolve = tracker.tasks.join_next(), if !tracker.tasks.is_empty()  => {
                 match resolve {
                     Some(Ok(Ok((ino, peer)))) => tracker.resolve(ino, peer),
@@ -290,13 +394,46 @@ pub async fn redundancy_worker(
                 let peers = nw_interface.peers.read().clone();
 
                 match message {
+                    Some(RedundancyMessage::ApplyTo(ino)) if erasure && !ITree::is_special(ino) => {
+                        shards.push(&fs_interface, ino);
+                    }
                     Some(RedundancyMessage::ApplyTo(ino)) => {
                         let _ = tracker.apply(ino, &peers, r_count).await;
                     }
                     Some(RedundancyMessage::CheckIntegrity) => {
-                        tracker.full_check(&fs_interface, &peers, r_count).await;
+                        tracker.full_check(&fs_interface, &peers, r_count, coding).await;
+                        if erasure {
+                            shards.full_check(&fs_interface, data, parity);
+                        }
+                    }
+                    Some(RedundancyMessage::FetchPinned) => {
+                        if pinning.is_empty() {
+                            pinning.spawn(fetch_pinned(fs_interface.clone()));
+                        } else {
+                            // the running fetch may have listed the files before this change
+                            pin_again = true;
+                        }
+                    }
+                    Some(RedundancyMessage::Refetch(ino)) => {
+                        let fs_interface = fs_interface.clone();
+                        repairs.spawn_blocking(move || (ino, fs_interface.refetch_file(ino)));
+                    }
+                    Some(RedundancyMessage::Suspect(peer)) => {
+                        let grace_period = nw_interface.global_config.read().redundancy.grace_period();
+                        suspects.spawn(async move {
+                            tokio::time::sleep(grace_period).await;
+                            peer
+                        });
+                    }
+                    Some(RedundancyMessage::Stop) | None => {
+                        // the transfers hold the fs_interface, the pod takes it back once they ended
+                        tracker.tasks.shutdown().await;
+                        shards.running.shutdown().await;
+                        suspects.shutdown().await;
+                        pinning.shutdown().await;
+                        repairs.shutdown().await;
+                        return;
                     }
-                    None => { return }
                 }
             }
         };
@@ -304,14 +441,13 @@ pub async fn redundancy_worker(
 }
 
 impl RedundancyTracker {
-    /// start download to others concurrently
+    /// start uploads to others concurrently, each streaming the file from the disk
     async fn push_redundancy(
-        fs_interface: &FsInterface,
+        fs_interface: &Arc<FsInterface>,
         semaphore: &Arc<Semaphore>,
         tasks: &mut JoinSet<Result<(Ino, PeerId), Ino>>,
         to: &[PeerId],
         ino: Ino,
-        file_binary: &Arc<Vec<u8>>,
         target_redundancy: usize,
     ) -> Vec<(PeerId, PendingStatus)> {
         let mut workers = Vec::new();
@@ -322,10 +458,16 @@ impl RedundancyTracker {
             } else {
                 break;
             };
-            let nwi_clone = fs_interface.network_interface.clone();
-            let bin_clone = file_binary.clone();
+            let fs_clone = fs_interface.clone();
             let handle = tasks.spawn(async move {
-                let res = nwi_clone.send_file_redundancy(ino, bin_clone, to).await;
+                let res = fs_clone
+                    .upload_file(to, ino)
+                    .await
+                    .map(|()| (ino, to))
+                    .map_err(|err| {
+                        log::debug!("Failed to send redundancy of {ino} to {to}: {err}");
+                        ino
+                    });
                 drop(permit);
                 res
             });
@@ -354,11 +496,27 @@ pub enum RedundancyStatus {
     BelowTarget,
     OnTarget,
     AboveTarget,
+    /// every shard of the current version is held
+    ShardsComplete,
+    /// some shards are lost, but enough are left to rebuild the file
+    ShardsDegraded,
+    /// the file isn't cut in shards yet, or was written since
+    ShardsPending,
+    /// not enough shards are left, and no pod hosts the whole file
+    ShardsLost,
 }
 
 // Lists the number of files that goes into each RedundancyStatus field
 pub fn check_integrity(pod: &Pod) -> WhResult<HashMap<RedundancyStatus, u64>> {
-    let target = pod.global_config.read().redundancy.number;
+    let (target, erasure, data, parity) = {
+        let config = pod.global_config.read();
+        (
+            config.redundancy.number,
+            config.redundancy.mode == RedundancyMode::Erasure,
+            config.redundancy.data_shards as usize,
+            config.redundancy.parity_shards as usize,
+        )
+    };
 
     let selected_files: Vec<(Ino, RedundancyStatus)> =
         ITree::read_lock(&pod.network_interface.itree, "redundancy: check_integrity")?
@@ -367,6 +525,25 @@ pub fn check_integrity(pod: &Pod) -> WhResult<HashMap<RedundancyStatus, u64>> {
                 if ITree::is_local_only(*ino) {
                     return None;
                 }
+                if let FsEntry::File(hosts) = &inode.entry {
+                    if erasure && !ITree::is_special(*ino) {
+                        let shards = inode
+                            .shards
+                            .as_ref()
+                            .filter(|shards| shards.is_current(&inode.version, data, parity));
+                        let status = match shards {
+                            Some(shards) if shards.is_complete() => {
+                                RedundancyStatus::ShardsComplete
+                            }
+                            Some(shards) if shards.is_readable() => {
+                                RedundancyStatus::ShardsDegraded
+                            }
+                            _ if !hosts.is_empty() => RedundancyStatus::ShardsPending,
+                            _ => RedundancyStatus::ShardsLost,
+                        };
+                        return Some((*ino, status));
+                    }
+                }
                 let hosts = if let FsEntry::File(hosts) = &inode.entry {
                     hosts.len() as u64
                 } else {
@@ -398,25 +575,3 @@ pub fn check_integrity(pod: &Pod) -> WhResult<HashMap<RedundancyStatus, u64>> {
             acc
         }))
 }
-
-impl NetworkInterface {
-    /// send a file redundancy to a peer
-    pub async fn send_file_redundancy(
-        &self,
-        ino: Ino,
-        data: Arc<Vec<u8>>,
-        to: PeerId,
-    ) -> Result<(Ino, PeerId), Ino> {
-        let (status_tx, status_rx) = oneshot::channel();
-
-        self.to_network_message_tx
-            .send(ToNetworkMessage::AnswerMessage(
-                Request::RedundancyFile(ino, data),
-                status_tx,
-                to,
-            ))
-            .expect("send_file: unable to update modification on the network thread");
-
-        status_rx.await.ok().flatten().map(|_| (ino, to)).ok_or(ino)
-    }
-}
@@ -1,35 +1,124 @@
-use crate::pods::network::behaviour::Behaviour;
+use crate::{
+    config::GlobalConfig,
+    network::protocol::{self, Capability, PROTOCOL_VERSIONS},
+    pods::network::{behaviour::Behaviour, codec::BincodeCodec, compression::CompressionStats},
+};
 use libp2p::{
-    identify, noise,
+    core::upgrade::Version,
+    dns, identify,
+    identity::Keypair,
+    mdns, noise, ping,
+    pnet::{PnetConfig, PreSharedKey},
     request_response::{self, ProtocolSupport},
-    yamux, StreamProtocol, Swarm,
+    tcp, websocket, yamux, Swarm, Transport,
 };
-use std::{error::Error, time::Duration};
-
-const PROTOCOL_VERSION: &str = "/wormhole/1.0.0";
+use std::{error::Error, io, sync::Arc, time::Duration};
 
 pub const MAX_CONCURRENT_STREAMS: usize = 128;
 
-pub async fn create_swarm(nickname: String) -> Result<Swarm<Behaviour>, Box<dyn Error>> {
-    let swarm = libp2p::SwarmBuilder::with_new_identity()
-        .with_tokio()
-        .with_websocket(noise::Config::new, yamux::Config::default)
-        .await?
-        .with_behaviour(|key| {
-            let cfg = identify::Config::new(PROTOCOL_VERSION.to_string(), key.public())
-                .with_agent_version(nickname);
-
-            Behaviour {
-                request_response: request_response::Behaviour::new(
-                    [(StreamProtocol::new(PROTOCOL_VERSION), ProtocolSupport::Full)],
-                    request_response::Config::default()
-                        .with_max_concurrent_streams(MAX_CONCURRENT_STREAMS),
+/// Protocol advertised through identify, it carries the network name
+/// so pods found on the local network can be told apart
+pub fn network_protocol(network_name: &str) -> String {
+    format!("{NETWORK_PREFIX}{network_name}")
+}
+
+/// Network name advertised by a peer, see [network_protocol]
+/// Empty for a pod that doesn't know its network yet, like one joining through an entrypoint
+pub fn network_name(protocol_version: &str) -> Option<&str> {
+    protocol_version.strip_prefix(NETWORK_PREFIX)
+}
+
+const NETWORK_PREFIX: &str = "/wormhole/network/";
+
+fn create_behaviour(
+    key: &Keypair,
+    nickname: String,
+    zone: Option<&str>,
+    config: &GlobalConfig,
+    compression: Arc<CompressionStats>,
+) -> io::Result<Behaviour> {
+    let cfg = identify::Config::new(network_protocol(&config.general.name), key.public())
+        .with_agent_version(nickname);
+
+    let mdns = config
+        .network
+        .discovery
+        .then(|| mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id()))
+        .transpose()?;
+
+    Ok(Behaviour {
+        request_response: request_response::Behaviour::with_codec(
+            BincodeCodec::new(
+                config.network.max_message_size,
+                config.network.compression,
+                compression,
+            ),
+            PROTOCOL_VERSIONS
+                .iter()
+                .map(|version| (version.clone(), ProtocolSupport::Full))
+                // never negotiated as nobody sends on them, only there to be listed by identify
+                .chain(Capability::markers().map(|marker| (marker, ProtocolSupport::Inbound)))
+                .chain(
+                    zone.and_then(protocol::zone_protocol)
+                        .map(|marker| (marker, ProtocolSupport::Inbound)),
                 ),
-                identify: identify::Behaviour::new(cfg),
-            }
-        })?
-        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(u64::MAX)))
-        .build();
+            request_response::Config::default().with_max_concurrent_streams(MAX_CONCURRENT_STREAMS),
+        ),
+        identify: identify::Behaviour::new(cfg),
+        ping: ping::Behaviour::new(ping::Config::new()),
+        mdns: mdns.into(),
+        streams: libp2p::stream::Behaviour::new(),
+    })
+}
+
+pub async fn create_swarm(
+    keypair: Keypair,
+    nickname: String,
+    zone: Option<&str>,
+    config: &GlobalConfig,
+    compression: Arc<CompressionStats>,
+) -> Result<Swarm<Behaviour>, Box<dyn Error>> {
+    let builder = libp2p::SwarmBuilder::with_existing_identity(keypair).with_tokio();
+
+    let swarm = match config.key {
+        // QUIC brings its own encryption and can't be wrapped in a private network,
+        // so private networks only use TCP and websocket
+        Some(network_key) => builder
+            // Every connection first goes through a private network handshake,
+            // peers without the same key are rejected before noise even starts
+            .with_other_transport(|key| -> io::Result<_> {
+                let psk = PreSharedKey::new(network_key.as_bytes());
+                let websocket = websocket::Config::new(dns::tokio::Transport::system(
+                    tcp::tokio::Transport::new(tcp::Config::default()),
+                )?);
+                let tcp = dns::tokio::Transport::system(tcp::tokio::Transport::new(
+                    tcp::Config::default(),
+                ))?;
+
+                Ok(websocket
+                    .or_transport(tcp)
+                    .and_then(move |socket, _| PnetConfig::new(psk).handshake(socket))
+                    .upgrade(Version::V1Lazy)
+                    .authenticate(noise::Config::new(key).map_err(io::Error::other)?)
+                    .multiplex(yamux::Config::default()))
+            })?
+            .with_behaviour(|key| create_behaviour(key, nickname, zone, config, compression))?
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(u64::MAX)))
+            .build(),
+        None => builder
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )?
+            .with_quic()
+            .with_dns()?
+            .with_websocket(noise::Config::new, yamux::Config::default)
+            .await?
+            .with_behaviour(|key| create_behaviour(key, nickname, zone, config, compression))?
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(u64::MAX)))
+            .build(),
+    };
 
     Ok(swarm)
 }
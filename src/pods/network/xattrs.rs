@@ -16,7 +16,7 @@ impl NetworkInterface {
         )?;
 
         self.to_network_message_tx
-            .send(ToNetworkMessage::BroadcastMessage(Request::SetXAttr(
+            .send(ToNetworkMessage::AcknowledgedBroadcast(Request::SetXAttr(
                 ino,
                 key.to_owned(),
                 data,
@@ -37,10 +37,9 @@ impl NetworkInterface {
             .remove_inode_xattr(ino, key)?;
 
         self.to_network_message_tx
-            .send(ToNetworkMessage::BroadcastMessage(Request::RemoveXAttr(
-                ino,
-                key.to_owned(),
-            )))
+            .send(ToNetworkMessage::AcknowledgedBroadcast(
+                Request::RemoveXAttr(ino, key.to_owned()),
+            ))
             .or(Err(WhError::NetworkDied {
                 called_from: "set_inode_xattr".to_string(),
             }))
@@ -3,6 +3,7 @@ use std::path::{Path, PathBuf};
 use std::{io, sync::Arc};
 
 use crate::config::local_file::LocalConfigFile;
+use crate::config::types::NetworkAccess;
 use crate::config::GlobalConfig;
 use crate::error::WhError;
 #[cfg(target_os = "linux")]
@@ -10,18 +11,22 @@ use crate::fuse::fuse_impl::mount_fuse;
 use crate::ipc::error::IoError;
 use crate::ipc::{
     self,
-    answers::{InspectInfo, PodCreationError},
+    answers::{CompressionIPC, InspectInfo, PeerScoreIPC, PodCreationError},
 };
 use crate::network;
-use crate::network::message::{Request, Response, ToNetworkMessage};
+use crate::network::message::{Request, ToNetworkMessage};
 #[cfg(target_os = "linux")]
 use crate::pods::disk_managers::unix_disk_manager::UnixDiskManager;
 #[cfg(target_os = "windows")]
 use crate::pods::disk_managers::windows_disk_manager::WindowsDiskManager;
-use crate::pods::itree::creation::{generate_itree, initiate_itree};
+use crate::pods::filesystem::sparse_cache::DEFAULT_CACHE_SIZE;
+use crate::pods::itree::creation::{generate_itree, initiate_itree, reconcile_itree};
 use crate::pods::itree::{FsEntry, LOCAL_CONFIG_INO, LOCK_TIMEOUT};
+use crate::pods::network::compression::CompressionStats;
+use crate::pods::network::discovery::discover_network;
 use crate::pods::network::event_loop::EventLoop;
-use crate::pods::network::redundancy::redundancy_worker;
+use crate::pods::network::file_stream::{serve_file_streams, FILE_PROTOCOL};
+use crate::pods::network::redundancy::{redundancy_worker, RedundancyMessage};
 use crate::pods::network::swarm::create_swarm;
 use crate::pods::prototype::PodPrototype;
 use crate::pods::whpath::WhPath;
@@ -30,9 +35,9 @@ use crate::winfsp::winfsp_impl::{mount_fsp, WinfspHost};
 use custom_error::custom_error;
 #[cfg(target_os = "linux")]
 use fuser;
-use libp2p::{multiaddr, PeerId};
+use libp2p::{identity::Keypair, PeerId};
 use parking_lot::RwLock;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 
 use crate::pods::{
@@ -42,6 +47,9 @@ use crate::pods::{
 
 use super::itree::{Ino, GLOBAL_CONFIG_INO};
 
+/// Time the tasks of a stopping pod have to end, and to release the filesystem
+const STOP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
 #[allow(dead_code)]
 pub struct Pod {
     pub network_interface: Arc<NetworkInterface>,
@@ -53,11 +61,13 @@ pub struct Pod {
     fsp_host: WinfspHost,
     network_airport_handle: JoinHandle<()>,
     redundancy_worker_handle: JoinHandle<()>,
+    file_streams_handle: JoinHandle<()>,
     pub global_config: Arc<RwLock<GlobalConfig>>,
     pub name: String,
     pub nickname: String,
     pub should_restart: bool,
     allow_other_users: bool,
+    keypair: Keypair,
 }
 
 custom_error! {pub PodInfoError
@@ -72,6 +82,7 @@ custom_error! {
     ITreeSavingFailed{source: io::Error} = "Could not write itree to disk: {source}",
     FileNotReadable{file: Ino, source: WhError} = "Could not read file from disk: ({file}) {source}",
     FileNotSent{file: Ino} = "No pod was able to receive this file before stopping: ({file})",
+    StillInUse = "The filesystem is still in use, the disk manager couldn't be stopped",
     #[cfg(target_os = "linux")]
     DiskManagerStopFailed{e: io::Error} = "Unable to stop the disk manager properly. Should not be an error on your platform {e}",
     #[cfg(target_os = "windows")]
@@ -96,6 +107,9 @@ impl From<PodStopError> for io::Error {
             PodStopError::FileNotSent { file: _ } => {
                 io::Error::new(io::ErrorKind::NetworkUnreachable, value.to_string())
             }
+            PodStopError::StillInUse => {
+                io::Error::new(io::ErrorKind::ResourceBusy, value.to_string())
+            }
             PodStopError::DiskManagerStopFailed { ref e } => {
                 io::Error::new(e.kind(), value.to_string())
             }
@@ -123,9 +137,18 @@ impl Pod {
         nickname.push(':');
         nickname.push_str(&proto.name);
 
-        let mut swarm = create_swarm(nickname.clone())
-            .await
-            .map_err(|err| PodCreationError::TransportError(err.to_string()))?;
+        let keypair = proto.keypair.unwrap_or_else(Keypair::generate_ed25519);
+
+        let compression = Arc::new(CompressionStats::default());
+        let mut swarm = create_swarm(
+            keypair.clone(),
+            nickname.clone(),
+            proto.zone.as_deref(),
+            &proto.global_config,
+            compression.clone(),
+        )
+        .await
+        .map_err(|err| PodCreationError::TransportError(err.to_string()))?;
 
         for address in proto.listen_addrs {
             swarm
@@ -133,24 +156,75 @@ impl Pod {
                 .map_err(|err| PodCreationError::TransportError(err.to_string()))?;
         }
 
+        // a dial only fails right away on unsupported addresses, so every entrypoint is dialed
+        // and the pod joins through the first one reached
         let dialed_success = proto
             .global_config
             .general
             .entrypoints
             .iter()
-            .cloned()
-            .find_map(|peer| {
-                multiaddr::from_url(&format!("ws://{peer}"))
+            .filter_map(|peer| {
+                network::PeerInfo::parse_address(peer)
+                    .inspect_err(|err| log::warn!("Invalid entrypoint '{peer}': {err}"))
                     .ok()
-                    .and_then(|p| swarm.dial(p).ok())
             })
-            .is_some();
+            .map(|address| {
+                swarm
+                    .dial(address.clone())
+                    .inspect_err(|err| log::warn!("Couldn't dial entrypoint {address}: {err}"))
+                    .is_ok()
+            })
+            .fold(false, |dialed, success| dialed || success);
+
+        let mut global_config = proto.global_config;
 
-        let itree = if dialed_success {
-            ITree::default()
+        // Without entrypoints, look for the network on the local network before starting a new one
+        let (dialed_success, discovered_events) = if !dialed_success
+            && global_config.network.discovery
+            && !global_config.general.name.is_empty()
+        {
+            discover_network(&mut swarm, &global_config.general.name).await
         } else {
-            generate_itree(&proto.mountpoint, &swarm.local_peer_id().clone())
-                .map_err(|err| PodCreationError::ITreeIndexion(err.into()))?
+            (dialed_success, vec![])
+        };
+
+        // The founder of a network is its first member
+        if !dialed_success
+            && matches!(
+                global_config.general.access,
+                NetworkAccess::Demand | NetworkAccess::Whitelist
+            )
+            && !global_config
+                .general
+                .whitelist
+                .contains(swarm.local_peer_id())
+        {
+            global_config.general.whitelist.push(*swarm.local_peer_id());
+        }
+
+        let mut operations = proto.operations.unwrap_or_default();
+        // A saved tree only needs to catch up with the network, instead of being replaced
+        let resumed = proto.itree.is_some();
+
+        let itree = match proto.itree {
+            Some(mut itree) => {
+                let reconciliation = reconcile_itree(
+                    &mut itree,
+                    &proto.mountpoint,
+                    swarm.local_peer_id(),
+                    &proto.pins.copies().keys().copied().collect(),
+                )
+                .map_err(|err| PodCreationError::ITreeIndexion(err.into()))?;
+                operations.record_reconciliation(&itree, &reconciliation, *swarm.local_peer_id());
+                itree
+            }
+            None if dialed_success => {
+                let mut itree = ITree::default();
+                itree.claim_namespace(swarm.local_peer_id());
+                itree
+            }
+            None => generate_itree(&proto.mountpoint, &swarm.local_peer_id().clone())
+                .map_err(|err| PodCreationError::ITreeIndexion(err.into()))?,
         };
 
         #[cfg(target_os = "linux")]
@@ -164,32 +238,58 @@ impl Pod {
                 .map_err(|err| PodCreationError::DiskAccessError(err.into()))?,
         );
 
-        initiate_itree(&itree, &proto.global_config, disk_manager.as_ref())
+        initiate_itree(&itree, &global_config, disk_manager.as_ref())
             .map_err(|err| PodCreationError::ITreeIndexion(err.into()))?;
 
         let itree = Arc::new(RwLock::new(itree));
 
-        let global = Arc::new(RwLock::new(proto.global_config));
+        let global = Arc::new(RwLock::new(global_config));
+
+        let mut streams = swarm.behaviour().streams.new_control();
+        let incoming_files = streams
+            .accept(FILE_PROTOCOL)
+            .map_err(|err| PodCreationError::TransportError(err.to_string()))?;
 
         let network_interface = Arc::new(NetworkInterface::new(
             itree,
             *swarm.local_peer_id(),
+            nickname.clone(),
             senders_in.clone(),
             redundancy_tx.clone(),
             Arc::new(RwLock::new(swarm.connected_peers().cloned().collect())),
             global.clone(),
+            streams,
+            operations,
+            proto.zone,
+            compression,
         ));
 
         let fs_interface = Arc::new(FsInterface::new(
             network_interface.clone(),
             disk_manager,
             proto.mountpoint.clone(),
+            proto.cache_size.unwrap_or(DEFAULT_CACHE_SIZE) * 1024 * 1024,
         ));
+        if let Err(err) = fs_interface.restore_pins(proto.pins) {
+            log::warn!("Couldn't restore the pinned files: {err}");
+        }
 
-        let event_loop = EventLoop::new(swarm, fs_interface.clone(), senders_out, dialed_success);
+        let mut event_loop = EventLoop::new(
+            swarm,
+            fs_interface.clone(),
+            senders_out,
+            dialed_success,
+            resumed,
+        );
+        for event in discovered_events {
+            event_loop.handle_event(event);
+        }
 
         let network_airport_handle = tokio::spawn(event_loop.run());
 
+        let file_streams_handle =
+            tokio::spawn(serve_file_streams(fs_interface.clone(), incoming_files));
+
         let redundancy_worker_handle = tokio::spawn(redundancy_worker(
             redundancy_rx,
             network_interface.clone(),
@@ -222,10 +322,12 @@ impl Pod {
                 network_airport_handle,
                 global_config: global.clone(),
                 redundancy_worker_handle,
+                file_streams_handle,
                 name: proto.name,
                 nickname,
                 should_restart: proto.should_restart,
                 allow_other_users: proto.allow_other_users,
+                keypair,
             },
             dialed_success,
         ))
@@ -255,37 +357,19 @@ impl Pod {
         possible_hosts: &[PeerId],
         ino: Ino,
     ) -> Result<(), PodStopError> {
-        let file_content =
-            self.fs_interface
-                .read_local_file(ino)
-                .map_err(|e| PodStopError::FileNotReadable {
-                    file: ino,
-                    source: e,
-                })?;
-        let file_content = Arc::new(file_content);
-
         for host in possible_hosts {
-            let (status_tx, status_rx) = oneshot::channel();
-
-            self.network_interface
-                .to_network_message_tx
-                .send(ToNetworkMessage::AnswerMessage(
-                    // NOTE - file_content clone is not efficient, but no way to avoid it for now
-                    Request::RedundancyFile(ino, file_content.clone()),
-                    status_tx,
-                    *host,
-                ))
-                .expect("to_network_message_tx closed.");
-
-            if let Some(Response::Success) = status_rx.await.expect("network died") {
-                self.network_interface
-                    .to_network_message_tx
-                    .send(ToNetworkMessage::BroadcastMessage(Request::RemoveHosts(
-                        ino,
-                        vec![self.network_interface.id],
-                    )))
-                    .expect("to_network_message_tx closed.");
-                return Ok(());
+            match self.fs_interface.upload_file(*host, ino).await {
+                Ok(()) => {
+                    self.network_interface
+                        .to_network_message_tx
+                        .send(ToNetworkMessage::BroadcastMessage(Request::RemoveHosts(
+                            ino,
+                            vec![self.network_interface.id],
+                        )))
+                        .expect("to_network_message_tx closed.");
+                    return Ok(());
+                }
+                Err(err) => log::debug!("Couldn't send {ino} to {host}: {err}"),
             }
         }
         Err(PodStopError::FileNotSent { file: ino })
@@ -341,6 +425,7 @@ impl Pod {
             fsp_host,
             network_airport_handle,
             redundancy_worker_handle,
+            file_streams_handle,
             ..
         } = self;
 
@@ -358,13 +443,34 @@ impl Pod {
         #[cfg(target_os = "windows")]
         drop(fsp_host);
 
-        redundancy_worker_handle.abort();
-        let _ = redundancy_worker_handle
-            .await
-            .inspect(|_| log::error!("await error: redundancy_worker_handle"));
+        // the worker ends its transfers, they hold the fs_interface
+        let _ = self
+            .network_interface
+            .to_redundancy_tx
+            .send(RedundancyMessage::Stop);
+        let redundancy_abort = redundancy_worker_handle.abort_handle();
+        match tokio::time::timeout(STOP_TIMEOUT, redundancy_worker_handle).await {
+            Ok(Err(err)) => log::error!("await error: redundancy_worker_handle: {err}"),
+            Ok(Ok(())) => {}
+            Err(_) => {
+                log::error!("The redundancy worker didn't stop in time, aborting it");
+                redundancy_abort.abort();
+            }
+        }
 
-        let mut fs_interface = Arc::try_unwrap(fs_interface)
-            .unwrap_or_else(|_| panic!("fs_interface not released from every thread"));
+        // transfers are polled inside this task, see serve_file_streams
+        file_streams_handle.abort();
+        let _ = file_streams_handle.await;
+
+        // disk operations started on the blocking threads still run until they are done
+        let deadline = tokio::time::Instant::now() + STOP_TIMEOUT;
+        while Arc::strong_count(&fs_interface) > 1 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let mut fs_interface = Arc::try_unwrap(fs_interface).map_err(|_| {
+            log::error!("fs_interface not released from every thread");
+            PodStopError::StillInUse
+        })?;
 
         fs_interface
             .disk
@@ -384,6 +490,16 @@ impl Pod {
 
     pub fn try_generate_prototype(&self) -> Option<PodPrototype> {
         let global_config = self.global_config.try_read_for(LOCK_TIMEOUT)?.clone();
+        let itree = self
+            .network_interface
+            .itree
+            .try_read_for(LOCK_TIMEOUT)?
+            .clone();
+        let mut operations = self.network_interface.operations.read().clone();
+        // Saved before the pod stops, the peers still connected have seen everything
+        for peer in self.network_interface.peers.read().iter() {
+            operations.left(*peer);
+        }
 
         Some(PodPrototype {
             global_config,
@@ -398,6 +514,12 @@ impl Pod {
             mountpoint: self.mountpoint.clone(),
             should_restart: self.should_restart,
             allow_other_users: self.allow_other_users,
+            zone: self.network_interface.scores.read().zone().cloned(),
+            cache_size: Some(self.fs_interface.sparse.read().capacity() / (1024 * 1024)),
+            pins: self.fs_interface.saved_pins(),
+            keypair: Some(self.keypair.clone()),
+            operations: Some(operations),
+            itree: Some(itree),
         })
     }
 
@@ -405,13 +527,15 @@ impl Pod {
         LocalConfigFile {
             name: Some(self.name.clone()),
             restart: Some(self.should_restart),
+            zone: self.network_interface.scores.read().zone().cloned(),
+            cache_size: Some(self.fs_interface.sparse.read().capacity() / (1024 * 1024)),
             listen_addrs: self
                 .fs_interface
                 .network_interface
                 .listen_addrs
                 .read()
                 .iter()
-                .filter_map(|m| network::PeerInfo::display_address(m).ok())
+                .map(ToString::to_string)
                 .collect(),
         }
     }
@@ -435,13 +559,54 @@ impl Pod {
             .map(Into::into)
             .collect();
 
+        let pending_peers = self
+            .fs_interface
+            .network_interface
+            .pending_peers
+            .read()
+            .iter()
+            .map(|(id, info)| (id.to_string(), info.into()))
+            .collect();
+
+        let scores = {
+            let mut peers = self.fs_interface.network_interface.peers.read().clone();
+            let scores = self.fs_interface.network_interface.scores.read();
+            let peers_info = self.fs_interface.network_interface.peers_info.read();
+            scores.rank(&mut peers);
+
+            peers
+                .iter()
+                .map(|peer| {
+                    let score = scores.get(peer);
+                    PeerScoreIPC {
+                        nickname: peers_info
+                            .get(peer)
+                            .map_or_else(|| peer.to_string(), |info| info.nickname.clone()),
+                        zone: score.zone.clone(),
+                        rtt: score.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+                        throughput: score.throughput,
+                        in_flight: score.in_flight,
+                        cost: score.cost(),
+                    }
+                })
+                .collect()
+        };
+
         InspectInfo {
             frozen: false,
             listen_addrs,
             name: self.name.clone(),
+            peer_id: Some(self.fs_interface.network_interface.id.to_string()),
             connected_peers: peers_info,
+            pending_peers,
             mount: self.mountpoint.clone(),
             disk_space: self.fs_interface.disk.size_info().ok(),
+            zone: self.network_interface.scores.read().zone().cloned(),
+            scores,
+            compression: Some(CompressionIPC {
+                raw: self.network_interface.compression.raw(),
+                sent: self.network_interface.compression.sent(),
+            }),
         }
     }
 }
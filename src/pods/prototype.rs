@@ -2,8 +2,10 @@ use crate::config::local_file::LocalConfigFile;
 use crate::config::GlobalConfig;
 use crate::ipc::answers::InspectInfo;
 use crate::network;
+use crate::pods::filesystem::pins::Pins;
 use crate::pods::itree::ITree;
-use libp2p::{Multiaddr, PeerId};
+use crate::pods::network::operation_log::OperationLog;
+use libp2p::{identity::Keypair, Multiaddr, PeerId};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
@@ -15,6 +17,22 @@ pub struct PodPrototype {
     pub mountpoint: PathBuf,
     pub should_restart: bool,
     pub allow_other_users: bool,
+    /// Location of the pod, see [PeerScores](crate::pods::network::peer_scores::PeerScores)
+    pub zone: Option<String>,
+    /// Bound of the local cache in MiB, see [SparseCache](crate::pods::filesystem::sparse_cache::SparseCache)
+    pub cache_size: Option<u64>,
+    /// Subtrees kept available offline, and the copies of their files left on disk
+    pub pins: Pins,
+    /// Identity of the pod on the network, kept so that the PeerId survives restarts.
+    /// Never serialized with the prototype, it is saved on its own by the service.
+    #[serde(skip)]
+    pub keypair: Option<Keypair>,
+    /// Operations applied on the tree, saved on its own by the service like the keypair
+    #[serde(skip)]
+    pub operations: Option<OperationLog>,
+    /// Tree saved when the pod last ran, reconciled with the disk when it starts again
+    #[serde(skip)]
+    pub itree: Option<ITree>,
 }
 
 pub type ConnectionInfo = (ITree, Vec<PeerId>);
@@ -27,6 +45,12 @@ impl PodPrototype {
         if let Some(restart) = local.restart {
             self.should_restart = restart;
         }
+        if local.zone.is_some() {
+            self.zone = local.zone;
+        }
+        if local.cache_size.is_some() {
+            self.cache_size = local.cache_size;
+        }
     }
 
     pub fn get_inspect_info(&self) -> InspectInfo {
@@ -40,9 +64,17 @@ impl PodPrototype {
             frozen: true,
             listen_addrs,
             name: self.name.clone(),
+            peer_id: self
+                .keypair
+                .as_ref()
+                .map(|keypair| keypair.public().to_peer_id().to_string()),
             connected_peers: vec![],
+            pending_peers: vec![],
             mount: self.mountpoint.clone(),
             disk_space: None,
+            zone: self.zone.clone(),
+            scores: vec![],
+            compression: None,
         }
     }
 
@@ -0,0 +1,28 @@
+use crate::ipc::{answers::ApproveAnswer, commands::ApproveRequest, error::IoError};
+use crate::service::commands::find_pod;
+use crate::service::connection::send_answer;
+use crate::service::Service;
+
+impl Service {
+    pub async fn approve<Stream>(
+        &self,
+        request: ApproveRequest,
+        stream: &mut either::Either<&mut Stream, &mut String>,
+    ) -> std::io::Result<()>
+    where
+        Stream: tokio::io::AsyncWrite + tokio::io::AsyncRead + Unpin,
+    {
+        let answer = match find_pod(&request.pod, &self.pods) {
+            Some((_, pod)) => match pod.network_interface.approve_peer(&request.peer) {
+                Ok(Some(peer_id)) => ApproveAnswer::Success(peer_id.to_string()),
+                Ok(None) => ApproveAnswer::PeerNotPending,
+                Err(err) => ApproveAnswer::ApproveFailed(IoError {
+                    kind: err.clone().into(),
+                    error: err.to_string(),
+                }),
+            },
+            None => ApproveAnswer::PodNotFound,
+        };
+        send_answer(answer, stream).await
+    }
+}
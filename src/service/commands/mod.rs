@@ -1,9 +1,11 @@
+mod approve;
 mod config;
 mod freeze;
 mod gethosts;
 mod inspect;
 mod list_pods;
 mod new;
+mod pin;
 mod redundancy_status;
 mod remove;
 mod restart;
@@ -4,9 +4,15 @@ use libp2p::Multiaddr;
 use port_check::{free_local_port_in_range, is_local_port_free};
 
 use crate::{
-    config::{local_file::LocalConfigFile, types::Config, GlobalConfig},
+    config::{
+        local_file::LocalConfigFile,
+        types::{Config, NetworkKey},
+        GlobalConfig,
+    },
     ipc::{answers::NewAnswer, commands::NewRequest},
+    network,
     pods::{
+        filesystem::pins::Pins,
         itree::{GLOBAL_CONFIG_FNAME, LOCAL_CONFIG_FNAME},
         pod::Pod,
         prototype::PodPrototype,
@@ -43,38 +49,89 @@ impl Service {
 
         global_config = global_config.add_hosts(args.hosts);
 
-        let (ip_type, ip) = match args.ip_address {
-            Some(IpAddr::V4(v4)) => ("ip4", v4.to_string()),
-            Some(IpAddr::V6(v6)) => ("ip6", v6.to_string()),
-            None => ("ip4", "0.0.0.0".to_string()),
-        };
+        if let Some(key) = args.key {
+            match key.parse::<NetworkKey>() {
+                Ok(key) => global_config.key = Some(key),
+                Err(err) => {
+                    return send_answer(NewAnswer::InvalidNetworkKey(err.to_string()), stream).await
+                }
+            }
+        }
 
-        let port = match args.port {
-            Some(port) => match is_local_port_free(port) {
-                true => port,
-                false => return send_answer(NewAnswer::PortAlreadyTaken, stream).await,
-            },
-            None => match free_local_port_in_range(40000..=40100) {
-                Some(port) => port,
-                None => return send_answer(NewAnswer::NoFreePortInRage, stream).await,
-            },
-        };
+        let (listen_addrs, display_addr) = if !local_config.listen_addrs.is_empty() {
+            if args.port.is_some() || args.ip_address.is_some() {
+                return send_answer(
+                    NewAnswer::ConflictWithConfig("listen_addrs".to_string()),
+                    stream,
+                )
+                .await;
+            }
+            let listen_addrs = match local_config
+                .listen_addrs
+                .iter()
+                .map(|addr| network::PeerInfo::parse_address(addr))
+                .collect::<Result<Vec<Multiaddr>, String>>()
+            {
+                Ok(listen_addrs) => listen_addrs,
+                Err(err) => return send_answer(NewAnswer::InvalidIp(err), stream).await,
+            };
+            let display_addr = network::PeerInfo::display_address(&listen_addrs[0])
+                .unwrap_or_else(|_| listen_addrs[0].to_string());
+            (listen_addrs, display_addr)
+        } else {
+            let (ip_type, ip) = match args.ip_address {
+                Some(IpAddr::V4(v4)) => ("ip4", v4.to_string()),
+                Some(IpAddr::V6(v6)) => ("ip6", v6.to_string()),
+                None => ("ip4", "0.0.0.0".to_string()),
+            };
 
-        let listen_address: Multiaddr = match format!("/{}/{}/tcp/{}/ws", ip_type, ip, port).parse()
-        {
-            Ok(listen_address) => listen_address,
-            Err(err) => return send_answer(NewAnswer::InvalidIp(err.to_string()), stream).await,
-        };
+            let port = match args.port {
+                Some(port) => match is_local_port_free(port) {
+                    true => port,
+                    false => return send_answer(NewAnswer::PortAlreadyTaken, stream).await,
+                },
+                None => match free_local_port_in_range(40000..=40100) {
+                    Some(port) => port,
+                    None => return send_answer(NewAnswer::NoFreePortInRage, stream).await,
+                },
+            };
+
+            // websocket on the given port, plain TCP on any free port and QUIC on the same port in UDP
+            let mut addresses = vec![
+                format!("/{ip_type}/{ip}/tcp/{port}/ws"),
+                format!("/{ip_type}/{ip}/tcp/0"),
+            ];
+            // QUIC can't be used in a private network
+            if global_config.key.is_none() {
+                addresses.push(format!("/{ip_type}/{ip}/udp/{port}/quic-v1"));
+            }
 
-        let display_addr = format!("{}:{}", ip, port);
+            let listen_addrs = match addresses
+                .iter()
+                .map(|addr| addr.parse())
+                .collect::<Result<Vec<Multiaddr>, _>>()
+            {
+                Ok(listen_addrs) => listen_addrs,
+                Err(err) => {
+                    return send_answer(NewAnswer::InvalidIp(err.to_string()), stream).await
+                }
+            };
+            (listen_addrs, format!("{}:{}", ip, port))
+        };
 
         let prototype = PodPrototype {
             global_config,
-            listen_addrs: vec![listen_address.clone()],
+            listen_addrs,
             name: args.name.clone(),
             mountpoint: args.mountpoint,
             should_restart: local_config.restart.unwrap_or(true),
             allow_other_users: args.allow_other_users,
+            zone: local_config.zone,
+            cache_size: local_config.cache_size,
+            pins: Pins::default(),
+            keypair: None,
+            operations: None,
+            itree: None,
         };
 
         match Pod::new(prototype, self.nickname.clone()).await {
@@ -0,0 +1,65 @@
+use std::io;
+
+use crate::error::WhError;
+use crate::ipc::{answers::PinAnswer, commands::PinRequest};
+use crate::pods::itree::{ITree, Ino};
+use crate::pods::pod::Pod;
+use crate::pods::whpath::WhPath;
+use crate::service::connection::send_answer;
+use crate::service::Service;
+
+fn find_ino(pod: &Pod, req: &PinRequest) -> Result<Ino, PinAnswer> {
+    let path = WhPath::make_relative(&req.path, pod.get_mountpoint())
+        .map_err(|_| PinAnswer::FileNotFound)?;
+    ITree::read_lock(&pod.network_interface.itree, "service::find_ino")
+        .and_then(|itree| itree.get_inode_from_path(&path).map(|inode| inode.id))
+        .map_err(|err| match err {
+            WhError::InodeNotFound => PinAnswer::FileNotFound,
+            err => PinAnswer::PinFailed(io::Error::other(err).into()),
+        })
+}
+
+impl Service {
+    pub async fn pin<Stream>(
+        &self,
+        req: PinRequest,
+        stream: &mut either::Either<&mut Stream, &mut String>,
+    ) -> std::io::Result<()>
+    where
+        Stream: tokio::io::AsyncWrite + tokio::io::AsyncRead + Unpin,
+    {
+        let answer = match self.pods.iter().find(|(_, pod)| pod.contains(&req.path)) {
+            Some((_, pod)) => match find_ino(pod, &req) {
+                Ok(ino) => match pod.fs_interface.pin(ino) {
+                    Ok(()) => PinAnswer::Success,
+                    Err(err) => PinAnswer::PinFailed(io::Error::other(err).into()),
+                },
+                Err(answer) => answer,
+            },
+            None => PinAnswer::FileNotInsideARunningPod,
+        };
+        send_answer(answer, stream).await
+    }
+
+    pub async fn unpin<Stream>(
+        &self,
+        req: PinRequest,
+        stream: &mut either::Either<&mut Stream, &mut String>,
+    ) -> std::io::Result<()>
+    where
+        Stream: tokio::io::AsyncWrite + tokio::io::AsyncRead + Unpin,
+    {
+        let answer = match self.pods.iter().find(|(_, pod)| pod.contains(&req.path)) {
+            Some((_, pod)) => match find_ino(pod, &req) {
+                Ok(ino) => match pod.fs_interface.unpin(ino) {
+                    Ok(true) => PinAnswer::Success,
+                    Ok(false) => PinAnswer::NotPinned,
+                    Err(err) => PinAnswer::PinFailed(io::Error::other(err).into()),
+                },
+                Err(answer) => answer,
+            },
+            None => PinAnswer::FileNotInsideARunningPod,
+        };
+        send_answer(answer, stream).await
+    }
+}
@@ -88,6 +88,9 @@ impl Service {
             Command::RedundancyStatus(pod_id) => self.redundancy_status(pod_id, stream).await,
             Command::StatsPerFiletype(pod_id) => self.stats_per_filetype(pod_id, stream).await,
             Command::ListPods => self.list_pods(stream).await,
+            Command::Approve(request) => self.approve(request, stream).await,
+            Command::Pin(request) => self.pin(request, stream).await,
+            Command::Unpin(request) => self.unpin(request, stream).await,
         }?;
         Ok(stop)
     }
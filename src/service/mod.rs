@@ -10,7 +10,7 @@ use crate::ipc::error::ListenerError;
 use crate::pods::pod::Pod;
 use crate::pods::prototype::PodPrototype;
 use crate::service::clap::ServiceArgs;
-use crate::service::save::{delete_saved_pods, save_prototype, ServiceKey};
+use crate::service::save::{delete_saved_pods, save_prototype, ServiceKey, SNAPSHOT_INTERVAL};
 use axum::http::Method;
 use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
 use interprocess::local_socket::tokio::Listener;
@@ -87,19 +87,33 @@ impl Service {
         Some(service)
     }
 
+    /// Save a running pod so it is restarted with the service
+    fn save_pod(service_key: &ServiceKey, name: &str, pod: &Pod) {
+        if !pod.should_restart {
+            return;
+        }
+        match pod.try_generate_prototype() {
+            Some(prototype) => {
+                let _ = save_prototype(prototype, service_key, false)
+                    .inspect_err(|e| log::error!("Couldn't save the pod data: {e:?}"));
+            }
+            None => log::error!("Couldn't access pod {} while saving.", name),
+        }
+    }
+
+    /// Periodic save of the running pods, so a crash doesn't lose what they learned
+    fn snapshot_pods(&self) {
+        let service_key = ServiceKey::from_path(&self.socket);
+        for (name, pod) in self.pods.iter() {
+            Self::save_pod(&service_key, name, pod);
+        }
+    }
+
     pub async fn stop_all_pods(self) -> ExitCode {
         let service_key = ServiceKey::from_path(&self.socket);
         let mut status = ExitCode::SUCCESS;
         for (name, pod) in self.pods.into_iter() {
-            if pod.should_restart {
-                match pod.try_generate_prototype() {
-                    Some(prototype) => {
-                        let _ = save_prototype(prototype, &service_key, false)
-                            .inspect_err(|e| log::error!("Couldn't save the pod data: {e:?}"));
-                    }
-                    None => log::error!("Couldn't access pod {} while saving.", name),
-                }
-            }
+            Self::save_pod(&service_key, &name, &pod);
 
             match pod.stop().await {
                 Ok(()) => log::info!("Stopped pod '{name}'"),
@@ -125,11 +139,19 @@ impl Service {
     ) -> Result<(), ListenerError> {
         println!("Wormhole running!");
 
+        let mut snapshot = tokio::time::interval(SNAPSHOT_INTERVAL);
+        // the pods were just loaded, the first tick is immediate
+        snapshot.tick().await;
+
         loop {
             if tokio::select! {
                 Ok(stream) = self.socket_listener.accept() => self.handle_connection(stream).await,
                 Some((command, reply_tx)) = self.web_request_rx.recv() => self.handle_tcp_connection(command, reply_tx).await,
                 _ = signals_rx.recv() => true,
+                _ = snapshot.tick() => {
+                    self.snapshot_pods();
+                    false
+                }
             } {
                 self.rest_service.abort();
                 return Ok(());
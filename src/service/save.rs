@@ -1,16 +1,22 @@
 use camino::{Utf8Path, Utf8PathBuf};
 use custom_error::custom_error;
 use directories::ProjectDirs;
-use serde::{Deserialize, Serialize};
+use libp2p::identity::Keypair;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::OpenOptionsExt;
 use std::{
     ffi::OsStr,
     fs,
     io::{self, Write},
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use crate::{
-    pods::{pod::Pod, prototype::PodPrototype},
+    config::types::{NetworkKey, NetworkKeyError},
+    network::wire::{self, WireVersion},
+    pods::{itree::ITree, network::operation_log::OperationLog, pod::Pod, prototype::PodPrototype},
     service::Service,
 };
 
@@ -57,6 +63,9 @@ impl AsRef<Path> for ServiceKey {
     }
 }
 
+/// How often the running pods are saved, so that a crash loses little of their tree
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
 pub fn local_data_path(service_key: &ServiceKey) -> PathBuf {
     let mut path = ProjectDirs::from("", "Agartha-Software", "Wormhole")
         .expect("Unsupported operating system, couldn't create the local data directory.")
@@ -72,11 +81,276 @@ struct SavedPod {
     prototype: PodPrototype,
 }
 
+/// Start of the `.bak` files with a versioned format, followed by [SAVE_FORMAT] and the
+/// [SavedPod] in JSON, so that the fields added later take their default value.
+/// Saves without it come from before the format was versioned, see [legacy]
+const SAVE_MAGIC: &[u8; 4] = b"WHPD";
+
+/// Version of the `.bak` files, to bump when a change can't be read with serde defaults
+const SAVE_FORMAT: u32 = 1;
+
+custom_error! {
+    /// Error describing why a saved pod can't be loaded
+    pub LoadPodError
+    Truncated = "The save is truncated",
+    UnknownFormat{version: u32} = "Saved by a newer release, in format {version}",
+    Json{source: serde_json::Error} = "{source}",
+    Legacy{source: bincode::Error} = "{source}",
+}
+
+fn encode_saved_pod(saved_pod: &SavedPod) -> serde_json::Result<Vec<u8>> {
+    let mut bin = SAVE_MAGIC.to_vec();
+    bin.extend(SAVE_FORMAT.to_be_bytes());
+    serde_json::to_writer(&mut bin, saved_pod)?;
+    Ok(bin)
+}
+
+fn decode_saved_pod(bytes: &[u8]) -> Result<SavedPod, LoadPodError> {
+    let Some(versioned) = bytes.strip_prefix(SAVE_MAGIC) else {
+        return Ok(bincode::deserialize::<legacy::SavedPod>(bytes)?.into());
+    };
+    let Some((version, content)) = versioned.split_first_chunk() else {
+        return Err(LoadPodError::Truncated);
+    };
+    match u32::from_be_bytes(*version) {
+        SAVE_FORMAT => Ok(serde_json::from_slice(content)?),
+        version => Err(LoadPodError::UnknownFormat { version }),
+    }
+}
+
+/// Saves of the releases before the format was versioned, bincode-encoded
+mod legacy {
+    use std::path::PathBuf;
+
+    use libp2p::Multiaddr;
+    use serde::Deserialize;
+
+    use crate::{
+        config::{
+            types::{GeneralGlobalConfig, RedundancyConfig},
+            GlobalConfig,
+        },
+        pods::{filesystem::pins::Pins, prototype::PodPrototype},
+    };
+
+    #[derive(Deserialize)]
+    pub struct SavedPod {
+        frozen: bool,
+        prototype: Prototype,
+    }
+
+    #[derive(Deserialize)]
+    struct Prototype {
+        global_config: Config,
+        name: String,
+        listen_addrs: Vec<Multiaddr>,
+        mountpoint: PathBuf,
+        should_restart: bool,
+        allow_other_users: bool,
+    }
+
+    #[derive(Deserialize)]
+    struct Config {
+        general: General,
+        redundancy: Redundancy,
+    }
+
+    #[derive(Deserialize)]
+    struct General {
+        name: String,
+        entrypoints: Vec<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct Redundancy {
+        number: u64,
+    }
+
+    impl From<SavedPod> for super::SavedPod {
+        fn from(saved: SavedPod) -> Self {
+            let Prototype {
+                global_config,
+                name,
+                listen_addrs,
+                mountpoint,
+                should_restart,
+                allow_other_users,
+            } = saved.prototype;
+            let global_config = GlobalConfig {
+                general: GeneralGlobalConfig {
+                    name: global_config.general.name,
+                    entrypoints: global_config.general.entrypoints,
+                    ..Default::default()
+                },
+                redundancy: RedundancyConfig {
+                    number: global_config.redundancy.number,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            Self {
+                frozen: saved.frozen,
+                prototype: PodPrototype {
+                    global_config,
+                    name,
+                    listen_addrs,
+                    mountpoint,
+                    should_restart,
+                    allow_other_users,
+                    zone: None,
+                    cache_size: None,
+                    pins: Pins::default(),
+                    keypair: None,
+                    operations: None,
+                    itree: None,
+                },
+            }
+        }
+    }
+}
+
 custom_error! {pub SavePodError
     LockError = "Failed to lock the pod to save",
     WriteError{ io: io::Error } = "Failed to write the file: {io}",
 }
 
+/// Write a file only readable by its owner
+fn write_secret(path: PathBuf, content: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(target_os = "linux")]
+    options.mode(0o600);
+    options.open(path)?.write_all(content)
+}
+
+/// Save the pod's network identity next to its prototype backup
+/// The key file is only readable by the owner, as it allows to impersonate the pod
+fn save_identity(keypair: &Keypair, service_key: &ServiceKey, name: &str) -> io::Result<()> {
+    let mut path = local_data_path(service_key);
+    path.push(format!("{name}.key"));
+
+    let encoded = keypair.to_protobuf_encoding().map_err(io::Error::other)?;
+    write_secret(path, &encoded)
+}
+
+/// Save the private network key, which is never part of the backup
+/// as the global configuration skips it when serialized
+fn save_network_key(key: &NetworkKey, service_key: &ServiceKey, name: &str) -> io::Result<()> {
+    let mut path = local_data_path(service_key);
+    path.push(format!("{name}.psk"));
+
+    write_secret(path, key.to_string().as_bytes())
+}
+
+/// Encode a saved tree or operation log, preceded by the version of its layout
+fn encode_versioned<M: Serialize>(content: &M) -> bincode::Result<Vec<u8>> {
+    let mut bin = bincode::serialize(&WireVersion::CURRENT)?;
+    bin.extend(wire::encode(WireVersion::CURRENT, content)?);
+    Ok(bin)
+}
+
+/// Decode what [encode_versioned] wrote, in the layout of the release that saved it
+fn decode_versioned<M: DeserializeOwned>(bytes: &[u8]) -> bincode::Result<M> {
+    let version: WireVersion = bincode::deserialize(bytes)?;
+    let header = bincode::serialized_size(&version)? as usize;
+    wire::decode(version, &bytes[header..])
+}
+
+/// Save the log of the operations applied by the pod, to catch up with its peers when it comes back
+fn save_operations(
+    operations: &OperationLog,
+    service_key: &ServiceKey,
+    name: &str,
+) -> io::Result<()> {
+    let mut path = local_data_path(service_key);
+    path.push(format!("{name}.log"));
+
+    let bin = encode_versioned(operations).map_err(io::Error::other)?;
+    fs::write(path, bin)
+}
+
+/// Load the operation log saved by [save_prototype]
+/// Returns None if there is none or if it is unreadable, the pod then starts a new one
+fn load_operations(service_key: &ServiceKey, name: &str) -> Option<OperationLog> {
+    let mut path = local_data_path(service_key);
+    path.push(format!("{name}.log"));
+
+    if !path.exists() {
+        return None;
+    }
+    let bytes = fs::read(&path)
+        .inspect_err(|err| log::warn!("No operation log could be read at {path:?}: {err}"))
+        .ok()?;
+    decode_versioned(&bytes)
+        .inspect_err(|err| log::error!("Invalid operation log found at {path:?}: {err}"))
+        .ok()
+}
+
+/// Save the pod's tree, with the hosts of the files stored elsewhere
+/// Written aside then moved, so an interrupted save leaves the previous snapshot intact
+fn save_itree(itree: &ITree, service_key: &ServiceKey, name: &str) -> io::Result<()> {
+    let mut path = local_data_path(service_key);
+    path.push(format!("{name}.tree"));
+    let partial = path.with_extension("tree.partial");
+
+    let bin = encode_versioned(itree).map_err(io::Error::other)?;
+    fs::write(&partial, bin)?;
+    fs::rename(partial, path)
+}
+
+/// Load the tree saved by [save_prototype]
+/// Returns None if there is none or if it is unreadable, the pod then indexes its folder again
+fn load_itree(service_key: &ServiceKey, name: &str) -> Option<ITree> {
+    let mut path = local_data_path(service_key);
+    path.push(format!("{name}.tree"));
+
+    if !path.exists() {
+        return None;
+    }
+    let bytes = fs::read(&path)
+        .inspect_err(|err| log::warn!("No tree could be read at {path:?}: {err}"))
+        .ok()?;
+    decode_versioned(&bytes)
+        .inspect_err(|err| log::error!("Invalid tree found at {path:?}: {err}"))
+        .ok()
+}
+
+/// Load the private network key saved by [save_prototype]
+/// Fails if the key is there but can't be read: the pod must not start without it
+fn load_network_key(service_key: &ServiceKey, name: &str) -> io::Result<Option<NetworkKey>> {
+    let mut path = local_data_path(service_key);
+    path.push(format!("{name}.psk"));
+
+    if !path.exists() {
+        return Ok(None);
+    }
+    fs::read_to_string(&path)
+        .map_err(|err| err.to_string())
+        .and_then(|key| key.parse().map_err(|err: NetworkKeyError| err.to_string()))
+        .map(Some)
+        .map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid network key found at {path:?}: {err}"),
+            )
+        })
+}
+
+/// Load the pod's network identity saved by [save_prototype]
+/// Returns None if there is no saved identity or if it is unreadable,
+/// in which case the pod will come back with a new PeerId
+fn load_identity(service_key: &ServiceKey, name: &str) -> Option<Keypair> {
+    let mut path = local_data_path(service_key);
+    path.push(format!("{name}.key"));
+
+    let bytes = fs::read(&path)
+        .inspect_err(|err| log::warn!("No identity could be read at {path:?}: {err}"))
+        .ok()?;
+    Keypair::from_protobuf_encoding(&bytes)
+        .inspect_err(|err| log::error!("Invalid identity found at {path:?}: {err}"))
+        .ok()
+}
+
 pub fn save_prototype(
     prototype: PodPrototype,
     service_key: &ServiceKey,
@@ -87,23 +361,42 @@ pub fn save_prototype(
     if !path.exists() {
         fs::create_dir_all(&path)?;
     }
+
+    if let Some(keypair) = &prototype.keypair {
+        save_identity(keypair, service_key, &prototype.name)?;
+    }
+
+    if let Some(key) = &prototype.global_config.key {
+        save_network_key(key, service_key, &prototype.name)?;
+    }
+
+    if let Some(operations) = &prototype.operations {
+        save_operations(operations, service_key, &prototype.name)?;
+    }
+
+    if let Some(itree) = &prototype.itree {
+        save_itree(itree, service_key, &prototype.name)?;
+    }
+
     path.push(format!("{}.bak", prototype.name));
 
     log::trace!("Saving pod at: {path:?}");
 
     let saved_pod = SavedPod { frozen, prototype };
 
-    let bin = bincode::serialize(&saved_pod).expect("Pod Prototype should always be serializable");
+    let bin = encode_saved_pod(&saved_pod).expect("Pod Prototype should always be serializable");
     let mut file = fs::File::create(path)?;
     file.write_all(&bin)
 }
 
 pub fn delete_saved_pod(service_key: &ServiceKey, name: &String) -> io::Result<()> {
-    let mut path = local_data_path(service_key);
-    path.push(format!("{name}.bak"));
+    for extension in ["bak", "key", "psk", "log", "tree"] {
+        let mut path = local_data_path(service_key);
+        path.push(format!("{name}.{extension}"));
 
-    if path.exists() && path.is_file() {
-        fs::remove_file(path)?;
+        if path.exists() && path.is_file() {
+            fs::remove_file(path)?;
+        }
     }
     Ok(())
 }
@@ -117,7 +410,12 @@ pub fn delete_saved_pods(service_key: &ServiceKey) -> io::Result<()> {
     for dir_entry in folder.read_dir()? {
         let path = dir_entry?.path();
         log::trace!("Deleting saved: {path:?}");
-        if path.is_file() && path.extension().and_then(OsStr::to_str) == Some("bak") {
+        if path.is_file()
+            && matches!(
+                path.extension().and_then(OsStr::to_str),
+                Some("bak" | "key" | "psk" | "log" | "tree")
+            )
+        {
             fs::remove_file(path)?;
         }
     }
@@ -150,13 +448,26 @@ impl Service {
                 }
             };
 
-            let SavedPod { frozen, prototype } = match bincode::deserialize::<SavedPod>(&bytes) {
+            let SavedPod {
+                frozen,
+                mut prototype,
+            } = match decode_saved_pod(&bytes) {
                 Ok(saved) => saved,
                 Err(err) => {
-                    log::trace!("Invalid Pod data found at {path:?}: {err}");
+                    log::error!("Invalid Pod data found at {path:?}, the pod isn't loaded: {err}");
+                    continue;
+                }
+            };
+            prototype.global_config.key = match load_network_key(&key, &prototype.name) {
+                Ok(network_key) => network_key,
+                Err(err) => {
+                    log::error!("The pod '{}' isn't loaded: {err}", prototype.name);
                     continue;
                 }
             };
+            prototype.keypair = load_identity(&key, &prototype.name);
+            prototype.operations = load_operations(&key, &prototype.name);
+            prototype.itree = load_itree(&key, &prototype.name);
 
             if frozen {
                 self.frozen_pods.insert(prototype.name.clone(), prototype);
@@ -165,7 +476,7 @@ impl Service {
                 match Pod::new(prototype, self.nickname.clone()).await {
                     Ok((pod, _)) => self.pods.insert(name, pod),
                     Err(err) => {
-                        log::trace!("Failed to create the pod '{name}': {err:?}");
+                        log::error!("Failed to create the saved pod '{name}': {err:?}");
                         // Delete failing save?
                         continue;
                     }
@@ -176,3 +487,54 @@ impl Service {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn legacy_saves_are_migrated() {
+        // SavedPod { frozen, PodPrototype { GlobalConfig { general, redundancy }, .. } } before versioning
+        let legacy = bincode::serialize(&(
+            true,
+            (
+                (
+                    (
+                        "network".to_owned(),
+                        vec!["/ip4/10.0.0.1/tcp/40000".to_owned()],
+                    ),
+                    (3u64,),
+                ),
+                "pod".to_owned(),
+                Vec::<libp2p::Multiaddr>::new(),
+                PathBuf::from("/mnt/pod"),
+                true,
+                false,
+            ),
+        ))
+        .unwrap();
+
+        let SavedPod { frozen, prototype } = decode_saved_pod(&legacy).unwrap();
+        assert!(frozen);
+        assert_eq!(prototype.name, "pod");
+        assert_eq!(prototype.global_config.general.name, "network");
+        assert_eq!(prototype.global_config.redundancy.number, 3);
+        assert_eq!(prototype.mountpoint, PathBuf::from("/mnt/pod"));
+        assert!(prototype.should_restart);
+    }
+
+    #[test]
+    fn saves_of_a_newer_format_are_refused() {
+        let mut bin = SAVE_MAGIC.to_vec();
+        bin.extend((SAVE_FORMAT + 1).to_be_bytes());
+        bin.extend(b"{}");
+        assert!(matches!(
+            decode_saved_pod(&bin),
+            Err(LoadPodError::UnknownFormat { .. })
+        ));
+        assert!(matches!(
+            decode_saved_pod(SAVE_MAGIC),
+            Err(LoadPodError::Truncated)
+        ));
+    }
+}
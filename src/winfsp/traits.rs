@@ -117,6 +117,7 @@ impl From<MakeInodeError> for FspError {
             MakeInodeError::WhError { source } => source.into(),
             MakeInodeError::ProtectedNameIsFolder => STATUS_NOT_A_DIRECTORY.into(),
             MakeInodeError::PermissionDenied => STATUS_ACCESS_DENIED.into(),
+            MakeInodeError::Removed => STATUS_OBJECT_NAME_NOT_FOUND.into(),
         }
     }
 }
@@ -184,6 +185,7 @@ impl From<FlushError> for FspError {
             FlushError::WriteError { source } => source.into(),
             FlushError::DiffError { source } => source.into(),
             FlushError::PullError { source } => source.into(),
+            FlushError::ConflictCopyFailed { reason: _ } => STATUS_INTERNAL_ERROR.into(),
         }
     }
 }
@@ -1,12 +1,17 @@
 extern crate wormhole;
+use libp2p::PeerId;
 use serial_test::parallel;
 use std::{
+    cmp::Ordering,
     collections::HashMap,
     time::{Duration, SystemTime},
 };
 use wormhole::pods::{
     filesystem::fs_interface::SimpleFileType,
-    itree::{FsEntry, ITree, Inode, Metadata, BLOCK_SIZE, ROOT},
+    itree::{
+        namespace_of, peer_namespace, FsEntry, ITree, Inode, Metadata, Tombstone, VersionVector,
+        BLOCK_SIZE, ROOT,
+    },
 };
 
 fn itree_values(inode: &Inode, expected_result: Inode) {
@@ -17,6 +22,7 @@ fn itree_values(inode: &Inode, expected_result: Inode) {
     assert_eq!(inode.parent, expected_result.parent);
     assert_eq!(inode.name, expected_result.name);
     assert_eq!(inode.entry, expected_result.entry);
+    assert_eq!(inode.version, expected_result.version);
 
     // Check that the metadata is correct
     assert_eq!(inode.meta.ino, expected_result.meta.ino);
@@ -91,8 +97,11 @@ fn test_inserting_and_retreiving_files() {
             rdev: 0,
             blksize: BLOCK_SIZE as u32,
             flags: 0,
+            hash: None,
         },
         xattrs: HashMap::new(),
+        version: VersionVector::default(),
+        shards: None,
     };
 
     let result_two = Inode {
@@ -116,9 +125,221 @@ fn test_inserting_and_retreiving_files() {
             rdev: 0,
             blksize: BLOCK_SIZE as u32,
             flags: 0,
+            hash: None,
         },
         xattrs: HashMap::new(),
+        version: VersionVector::default(),
+        shards: None,
     };
     itree_values(itree.get_inode(10).unwrap(), result_one);
     itree_values(itree.get_inode(11).unwrap(), result_two);
 }
+
+#[parallel]
+#[test]
+fn test_version_vectors_order() {
+    let (first, second) = (PeerId::random(), PeerId::random());
+    let mut base = VersionVector::default();
+    base.increment(first);
+
+    let mut next = base.clone();
+    next.increment(first);
+    assert_eq!(base.compare(&next), Some(Ordering::Less));
+    assert_eq!(next.compare(&base), Some(Ordering::Greater));
+    assert_eq!(next.compare(&next.clone()), Some(Ordering::Equal));
+
+    let mut concurrent = base.clone();
+    concurrent.increment(second);
+    assert_eq!(
+        next.compare(&concurrent),
+        None,
+        "both pods wrote after base"
+    );
+    assert_eq!(
+        next.tie_break(&concurrent),
+        concurrent.tie_break(&next).reverse()
+    );
+}
+
+#[parallel]
+#[test]
+fn test_peers_allocate_distinct_inos() {
+    let (first, second) = (PeerId::random(), PeerId::random());
+    let mut first_itree = ITree::new();
+    first_itree.claim_namespace(&first);
+    let mut second_itree = first_itree.clone();
+    second_itree.claim_namespace(&second);
+
+    let first_ino = first_itree.reserve_ino().unwrap();
+    let second_ino = second_itree.reserve_ino().unwrap();
+    assert_ne!(first_ino, second_ino);
+    assert_eq!(namespace_of(first_ino), peer_namespace(&first));
+
+    // a peer's inode doesn't move the allocation into its namespace
+    first_itree.mark_reserved_ino(second_ino).unwrap();
+    assert_eq!(first_itree.reserve_ino().unwrap(), first_ino + 1);
+
+    // allocation resumes after the inodes the pod already has
+    first_itree
+        .add_inode_from_parameters(
+            "file".to_owned().try_into().unwrap(),
+            first_ino + 5,
+            ROOT,
+            FsEntry::File(vec![first]),
+            0o644,
+        )
+        .unwrap();
+    first_itree.claim_namespace(&first);
+    assert_eq!(first_itree.reserve_ino().unwrap(), first_ino + 6);
+}
+
+#[parallel]
+#[test]
+fn test_duplicate_inos_are_renumbered() {
+    let first = Inode::new(
+        "first".to_owned().try_into().unwrap(),
+        ROOT,
+        20,
+        FsEntry::File(vec![]),
+        0o644,
+    );
+    let mut second = Inode::new(
+        "second".to_owned().try_into().unwrap(),
+        ROOT,
+        20,
+        FsEntry::File(vec![]),
+        0o644,
+    );
+    second.meta.crtime = first.meta.crtime + Duration::from_secs(1);
+
+    let mut first_itree = ITree::new();
+    first_itree.add_inode(first.clone()).unwrap();
+    let mut second_itree = ITree::new();
+    second_itree.add_inode(second.clone()).unwrap();
+
+    for (itree, received) in [(&mut first_itree, &second), (&mut second_itree, &first)] {
+        assert!(itree.is_duplicate(received));
+        let mut received = received.clone();
+        let (id, renumbered) = itree.settle_duplicate(&received).unwrap();
+        // only the second pod has to move its own inode
+        assert_eq!(renumbered.is_some(), received.name == first.name);
+        received.id = id;
+        received.meta.ino = received.id;
+        itree.add_inode(received).unwrap();
+    }
+
+    // both pods kept the first created inode and renumbered the other the same way
+    for itree in [&first_itree, &second_itree] {
+        assert_eq!(itree.get_inode(20).unwrap().name, first.name);
+        let renumbered = itree
+            .get_inode_from_path(&"second".try_into().unwrap())
+            .unwrap();
+        assert_ne!(renumbered.id, 20);
+        assert_eq!(renumbered.meta.ino, renumbered.id);
+        assert_eq!(
+            itree
+                .get_inode(ROOT)
+                .unwrap()
+                .entry
+                .get_children()
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+    assert_eq!(
+        first_itree
+            .get_inode_from_path(&"second".try_into().unwrap())
+            .unwrap()
+            .id,
+        second_itree
+            .get_inode_from_path(&"second".try_into().unwrap())
+            .unwrap()
+            .id
+    );
+    assert!(!first_itree.is_duplicate(&first));
+}
+
+#[parallel]
+#[test]
+fn test_tombstones_are_kept_until_seen() {
+    let deleter = PeerId::random();
+    let away = PeerId::random();
+    let mut itree = ITree::new();
+    let folder = Inode::new(
+        "folder".to_owned().try_into().unwrap(),
+        ROOT,
+        20,
+        FsEntry::Directory(vec![]),
+        0o755,
+    );
+    let file = Inode::new(
+        "file".to_owned().try_into().unwrap(),
+        20,
+        21,
+        FsEntry::File(vec![away]),
+        0o644,
+    );
+
+    // a peer that missed the removal still has the folder
+    let mut stale = ITree::new();
+    stale.add_inode(folder.clone()).unwrap();
+    stale.add_inode(file).unwrap();
+
+    itree.add_inode(folder).unwrap();
+    itree.remove_inode(20).unwrap();
+    itree.bury(20, Tombstone::now(deleter), &[away]);
+    assert!(itree.is_buried(20));
+
+    let missed = stale.keep_tombstones(&itree);
+    assert_eq!(missed.len(), 1);
+    assert!(stale.get_inode(20).is_err());
+    assert!(stale.get_inode(21).is_err());
+    assert!(stale
+        .get_inode(ROOT)
+        .unwrap()
+        .entry
+        .get_children()
+        .unwrap()
+        .is_empty());
+    assert!(stale.is_buried(20));
+
+    itree.tombstones_seen_by(&deleter);
+    assert!(itree.is_buried(20));
+    itree.tombstones_seen_by(&away);
+    assert!(!itree.is_buried(20));
+}
+
+#[parallel]
+#[test]
+fn test_metadata_edits_keep_the_hash() {
+    let mut itree = ITree::new();
+    itree
+        .add_inode(Inode::new(
+            "file".to_owned().try_into().unwrap(),
+            ROOT,
+            20,
+            FsEntry::File(vec![]),
+            0o644,
+        ))
+        .unwrap();
+    itree.set_inode_hash(20, Some([7; 32])).unwrap();
+
+    // a permission change sent without the hash
+    let mut meta = itree.get_inode(20).unwrap().meta.clone();
+    meta.perm = 0o600;
+    meta.hash = None;
+    itree.set_inode_meta(20, meta.clone()).unwrap();
+    assert_eq!(itree.get_inode(20).unwrap().meta.perm, 0o600);
+    assert_eq!(itree.get_inode(20).unwrap().meta.hash, Some([7; 32]));
+
+    // nor can an edit change it
+    meta.hash = Some([8; 32]);
+    itree.set_inode_meta(20, meta.clone()).unwrap();
+    assert_eq!(itree.get_inode(20).unwrap().meta.hash, Some([7; 32]));
+
+    // a truncate is new content
+    meta.size = 3;
+    itree.set_inode_meta(20, meta).unwrap();
+    assert_eq!(itree.get_inode(20).unwrap().meta.hash, None);
+}